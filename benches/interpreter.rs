@@ -0,0 +1,162 @@
+//! Measures how long it takes to compile and to run some representative Lox programs, so that
+//! changes to the compiler or the VM can be checked for performance regressions.
+//!
+//! Run with:
+//!
+//! ```sh
+//! cargo bench --bench interpreter
+//! ```
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+
+use rlox::compiler::{CompileOptions, Target};
+use rlox::prelude::*;
+
+/// Lots of function calls.
+const FIB: &str = "
+fun fib(n) {
+    return n < 2 and n or fib(n - 1) + fib(n - 2);
+}
+var result = fib(20);
+";
+
+/// Lots of allocation, and therefore garbage collection.
+const CONCATENATION: &str = r#"
+var s = "";
+for (var i = 0; i < 1000; i = i + 1) {
+    s = s + "abc";
+}
+"#;
+
+/// Lots of arithmetic on locals.
+const ARITHMETIC: &str = "
+{
+    var total = 0;
+    for (var i = 0; i < 10000; i = i + 1) {
+        total = total + (i * 2 - i / 4) * (i + 1) / (i + 2) - -i;
+    }
+}
+";
+
+/// Lots of the sequences that are fused into superinstructions: adding locals, adding constants,
+/// and negated conditions.
+const LOOP: &str = "
+{
+    var total = 0;
+    var i = 0;
+    var done = false;
+    while (!done) {
+        total = total + i;
+        i = i + 1;
+        done = i == 10000;
+    }
+}
+";
+
+const PROGRAMS: [(&str, &str); 4] = [
+    ("fib", FIB),
+    ("concatenation", CONCATENATION),
+    ("arithmetic", ARITHMETIC),
+    ("loop", LOOP),
+];
+
+fn compile(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compile");
+    for (name, source) in PROGRAMS {
+        // Compiling pins the chunk's constants in the VM, so use a fresh VM every time:
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                VM::default,
+                |mut vm| vm.compile(source).unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+/// Creates a VM that collects garbage, as a long-running program would.
+fn new_vm() -> VM {
+    let mut vm = VM::default();
+    // SAFETY: the natives that every VM has never keep a value.
+    unsafe { vm.enable_garbage_collection() };
+    vm
+}
+
+fn run(c: &mut Criterion) {
+    let mut group = c.benchmark_group("run");
+    for (name, source) in PROGRAMS {
+        let mut vm = new_vm();
+        let chunk = vm.compile(source).unwrap();
+        group.bench_function(name, |b| b.iter(|| vm.interpret_chunk(&chunk).unwrap()));
+    }
+    group.finish();
+}
+
+/// Runs each program with and without superinstructions, to see how much dispatch they save.
+fn superinstructions(c: &mut Criterion) {
+    let mut group = c.benchmark_group("superinstructions");
+    for (name, source) in PROGRAMS {
+        for superinstructions in [false, true] {
+            let mut vm = new_vm();
+            vm.set_compile_options(CompileOptions {
+                superinstructions,
+                ..CompileOptions::default()
+            });
+            let chunk = vm.compile(source).unwrap();
+            let id = BenchmarkId::new(name, if superinstructions { "on" } else { "off" });
+            group.bench_function(id, |b| b.iter(|| vm.interpret_chunk(&chunk).unwrap()));
+        }
+    }
+    group.finish();
+}
+
+/// Runs each program on the stack VM and on the register VM.
+fn targets(c: &mut Criterion) {
+    let mut group = c.benchmark_group("targets");
+    for (name, source) in PROGRAMS {
+        for (target, label) in [(Target::Stack, "stack"), (Target::Register, "register")] {
+            let mut vm = new_vm();
+            vm.set_compile_options(CompileOptions {
+                target,
+                ..CompileOptions::default()
+            });
+            let chunk = vm.compile(source).unwrap();
+            let id = BenchmarkId::new(name, label);
+            group.bench_function(id, |b| b.iter(|| vm.interpret_chunk(&chunk).unwrap()));
+        }
+    }
+    group.finish();
+}
+
+/// Builds longer and longer strings, one piece at a time, then compares them (which needs their
+/// contents). Each piece should take the same time to add, no matter how long the string is.
+fn repeated_concatenation(c: &mut Criterion) {
+    let mut group = c.benchmark_group("repeated_concatenation");
+    for pieces in [1_000, 4_000, 16_000] {
+        let source = format!(
+            r#"
+            var s = "";
+            for (var i = 0; i < {pieces}; i = i + 1) {{
+                s = s + "abc";
+            }}
+            var empty = s == "";
+            "#
+        );
+        let mut vm = new_vm();
+        let chunk = vm.compile(&source).unwrap();
+        group.bench_with_input(BenchmarkId::from_parameter(pieces), &chunk, |b, chunk| {
+            b.iter(|| vm.interpret_chunk(chunk).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    compile,
+    run,
+    superinstructions,
+    targets,
+    repeated_concatenation
+);
+criterion_main!(benches);
@@ -0,0 +1,48 @@
+//! Measures how much memory the line number information of a large chunk takes up, compared to
+//! storing one line number per byte, and how long it takes to look up every line number.
+//!
+//! Run with:
+//!
+//! ```sh
+//! cargo bench --bench line_info
+//! ```
+use std::time::Instant;
+
+use rlox::compiler::compile;
+use rlox::gc::ActiveGC;
+
+/// How many lines of code to compile.
+const LINES: usize = 10_000;
+
+fn main() {
+    let gc = ActiveGC::install();
+
+    // Several statements per line, like real code. Chunks can only have 256 constants, so only
+    // locals and literals that don't need constants are used:
+    let body = "a = !b; b = a == b; print a and b or nil;\n".repeat(LINES);
+    let source = format!("{{\nvar a = true;\nvar b = false;\n{body}}}\n");
+    let chunk = compile(&source, &gc).expect("benchmark should compile");
+
+    let per_byte = chunk.len() * std::mem::size_of::<usize>();
+    let run_length_encoded = chunk.line_info_size();
+    println!("lines of code:         {LINES}");
+    println!("bytes of code:         {}", chunk.len());
+    println!("one line per byte:     {per_byte} bytes");
+    println!("run-length encoded:    {run_length_encoded} bytes");
+    println!(
+        "reduction:             {:.1}x",
+        per_byte as f64 / run_length_encoded as f64
+    );
+
+    let start = Instant::now();
+    let mut total = 0;
+    for offset in 0..chunk.len() {
+        total += chunk.line_number_for(offset).expect("line number");
+    }
+    let elapsed = start.elapsed();
+    assert!(total > 0);
+    println!(
+        "lookup:                {:.1} ns per byte",
+        elapsed.as_nanos() as f64 / chunk.len() as f64
+    );
+}
@@ -0,0 +1,21 @@
+//! Runs arbitrary bytes as a bytecode file. Nearly all of them are invalid, which must be reported
+//! as an error, rather than a panic.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rlox::chunk::{BYTECODE_MAGIC, BYTECODE_VERSION};
+use rlox::prelude::*;
+
+fuzz_target!(|data: &[u8]| {
+    // Skip the header, so that the fuzzer does not have to guess it:
+    let mut file = BYTECODE_MAGIC.to_vec();
+    file.push(BYTECODE_VERSION);
+    file.extend_from_slice(data);
+
+    let mut vm = VM::without_natives();
+    vm.set_stdout(Box::new(std::io::sink()));
+    vm.set_stderr(Box::new(std::io::sink()));
+    vm.set_fuel(100_000);
+    let _ = vm.interpret_bytecode(&file);
+});
@@ -0,0 +1,19 @@
+//! Compiles and runs arbitrary source code. It may fail to compile or run, but it must not panic.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rlox::prelude::*;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(source) = std::str::from_utf8(data) else {
+        return;
+    };
+
+    // No natives, so that nothing waits for input or sleeps; and fuel, so that infinite loops end.
+    let mut vm = VM::without_natives();
+    vm.set_stdout(Box::new(std::io::sink()));
+    vm.set_stderr(Box::new(std::io::sink()));
+    vm.set_fuel(100_000);
+    let _ = vm.interpret(source);
+});
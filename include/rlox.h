@@ -0,0 +1,70 @@
+/*
+ * C API for embedding rlox.
+ *
+ * Build the shared library with:
+ *
+ *     cargo build --release --features capi
+ *
+ * See src/capi.rs for documentation.
+ */
+#ifndef RLOX_H
+#define RLOX_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* An opaque handle to a Lox virtual machine. */
+typedef struct RloxVM RloxVM;
+
+typedef enum RloxStatus {
+    RLOX_OK = 0,
+    RLOX_COMPILE_ERROR = 1,
+    RLOX_RUNTIME_ERROR = 2,
+    RLOX_INVALID_ARGUMENT = 3,
+} RloxStatus;
+
+typedef enum RloxValueKind {
+    RLOX_NIL = 0,
+    RLOX_BOOLEAN = 1,
+    RLOX_NUMBER = 2,
+    RLOX_STRING = 3,
+    RLOX_NATIVE_FUNCTION = 4,
+    RLOX_FUNCTION = 5,
+    RLOX_CLASS = 6,
+    RLOX_INSTANCE = 7,
+    RLOX_LIST = 8,
+    RLOX_MAP = 9,
+} RloxValueKind;
+
+/* A Lox value. Only the field indicated by kind is meaningful.
+ * Strings are UTF-8 and NOT NUL-terminated. */
+typedef struct RloxValue {
+    RloxValueKind kind;
+    bool boolean;
+    double number;
+    const char *string;
+    size_t string_len;
+} RloxValue;
+
+/* Writes the return value to result and returns true; or returns false to raise a runtime
+ * error (with result set to a string, it is used as the error message). */
+typedef bool (*RloxNativeFn)(void *userdata, const RloxValue *args, size_t n_args,
+                             RloxValue *result);
+
+RloxVM *rlox_vm_new(void);
+void rlox_vm_free(RloxVM *vm);
+RloxStatus rlox_interpret(RloxVM *vm, const char *source);
+bool rlox_get_global(const RloxVM *vm, const char *name, RloxValue *value);
+RloxStatus rlox_define_native(RloxVM *vm, const char *name, uint8_t arity,
+                              RloxNativeFn function, void *userdata);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* RLOX_H */
@@ -0,0 +1,13 @@
+//! `rlox-dap`: a Debug Adapter Protocol server for Lox. See [rlox::dap].
+
+use std::io;
+
+/// Exit code for I/O errors (`EX_IOERR` from `sysexits.h`).
+const IOERR: i32 = 74;
+
+fn main() {
+    if let Err(error) = rlox::dap::serve(io::stdin().lock(), io::stdout().lock()) {
+        eprintln!("rlox-dap: {error}");
+        std::process::exit(IOERR);
+    }
+}
@@ -0,0 +1,359 @@
+//! A C API, so that rlox can be embedded in programs that are not written in Rust.
+//!
+//! The declarations are in `include/rlox.h`. A typical session looks like this:
+//!
+//! ```c
+//! RloxVM *vm = rlox_vm_new();
+//! if (rlox_interpret(vm, "var answer = 6 * 7;") == RLOX_OK) {
+//!     RloxValue answer;
+//!     if (rlox_get_global(vm, "answer", &answer)) {
+//!         printf("%g\n", answer.number);
+//!     }
+//! }
+//! rlox_vm_free(vm);
+//! ```
+//!
+//! Strings given to the API are NUL-terminated and must be valid UTF-8. Strings returned by the
+//! API (in [RloxValue]) are **not** NUL-terminated; use `string_len`. They are owned by the VM,
+//! and are only valid until the VM runs code again, since the garbage collector may free them.
+
+use std::ffi::{c_char, c_void, CStr};
+use std::ptr;
+
+use crate::prelude::{InterpretationError, Value, VM};
+
+/// The result of [rlox_interpret()].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RloxStatus {
+    /// The code ran to completion.
+    Ok = 0,
+    /// The code did not compile. The errors were printed to `stderr`.
+    CompileError = 1,
+    /// The code stopped with a runtime error. The error was printed to `stderr`.
+    RuntimeError = 2,
+    /// An argument was invalid, e.g., the source code was not UTF-8.
+    InvalidArgument = 3,
+}
+
+/// The type of an [RloxValue].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RloxValueKind {
+    /// `nil`.
+    Nil = 0,
+    /// `true` or `false`, stored in `boolean`.
+    Boolean = 1,
+    /// A number, stored in `number`.
+    Number = 2,
+    /// A string, stored in `string` and `string_len`.
+    String = 3,
+    /// A native function. It cannot be called through the C API.
+    NativeFunction = 4,
+    /// A function written in Lox. It cannot be called through the C API.
+    Function = 5,
+    /// A class. It cannot be used through the C API.
+    Class = 6,
+    /// An instance of a class. Its fields cannot be read through the C API.
+    Instance = 7,
+    /// A list. Its items cannot be read through the C API.
+    List = 8,
+    /// A map. Its entries cannot be read through the C API.
+    Map = 9,
+}
+
+/// A Lox value, as seen from C. Only the field indicated by `kind` is meaningful.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct RloxValue {
+    /// What kind of value this is.
+    pub kind: RloxValueKind,
+    /// The value of a boolean.
+    pub boolean: bool,
+    /// The value of a number.
+    pub number: f64,
+    /// The contents of a string (UTF-8, not NUL-terminated).
+    pub string: *const c_char,
+    /// The length of `string`, in bytes.
+    pub string_len: usize,
+}
+
+/// A native function implemented in C.
+///
+/// It is given the `userdata` pointer given to [rlox_define_native()], and the arguments. It
+/// should write its return value to `result` and return `true`. To raise a runtime error, it
+/// should return `false`; if `result` is set to a string, it is used as the error message.
+pub type RloxNativeFn = extern "C" fn(
+    userdata: *mut c_void,
+    args: *const RloxValue,
+    n_args: usize,
+    result: *mut RloxValue,
+) -> bool;
+
+/// Creates a new VM, with the [standard library](crate::stdlib) installed. Free it with
+/// [rlox_vm_free()].
+#[no_mangle]
+pub extern "C" fn rlox_vm_new() -> *mut VM {
+    let mut vm = VM::default();
+    crate::stdlib::install(&mut vm);
+    // SAFETY: the standard library's natives never keep a value, and natives defined by C code
+    // are only given RloxValues, whose strings are documented to be freed by the GC.
+    unsafe { vm.enable_garbage_collection() };
+    Box::into_raw(Box::new(vm))
+}
+
+/// Frees a VM created by [rlox_vm_new()], including all of its strings.
+///
+/// # Safety
+///
+/// `vm` must have been returned by [rlox_vm_new()] and not freed already. It may be `NULL`.
+#[no_mangle]
+pub unsafe extern "C" fn rlox_vm_free(vm: *mut VM) {
+    if !vm.is_null() {
+        drop(Box::from_raw(vm));
+    }
+}
+
+/// Compiles and interprets the given source code. Globals are kept between calls.
+///
+/// # Safety
+///
+/// `vm` must be a valid VM, and `source` must be a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rlox_interpret(vm: *mut VM, source: *const c_char) -> RloxStatus {
+    let vm = &mut *vm;
+    let source = match CStr::from_ptr(source).to_str() {
+        Ok(source) => source,
+        Err(_) => return RloxStatus::InvalidArgument,
+    };
+
+    match vm.interpret(source) {
+        Ok(()) | Err(InterpretationError::Exit(0)) => RloxStatus::Ok,
+        Err(InterpretationError::CompileError(_)) => RloxStatus::CompileError,
+        Err(
+            InterpretationError::RuntimeError(_)
+            | InterpretationError::OutOfFuel
+            | InterpretationError::Interrupted
+            | InterpretationError::Exit(_)
+            | InterpretationError::WriteError(_),
+        ) => RloxStatus::RuntimeError,
+        Err(
+            InterpretationError::InvalidBytecodeFile(_)
+            | InterpretationError::InvalidBytecode { .. },
+        ) => RloxStatus::InvalidArgument,
+        // Only possible when reading source code from a stream, which this does not do:
+        Err(InterpretationError::ReadError(_)) => RloxStatus::InvalidArgument,
+    }
+}
+
+/// Reads the global variable called `name` into `value`. Returns `false` if there is no such
+/// global.
+///
+/// # Safety
+///
+/// `vm` must be a valid VM, `name` must be a NUL-terminated string, and `value` must point to
+/// writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn rlox_get_global(
+    vm: *const VM,
+    name: *const c_char,
+    value: *mut RloxValue,
+) -> bool {
+    let vm = &*vm;
+    let global = CStr::from_ptr(name)
+        .to_str()
+        .ok()
+        .and_then(|name| vm.global(name));
+
+    match global {
+        Some(global) => {
+            *value = global.into();
+            true
+        }
+        None => false,
+    }
+}
+
+/// Defines a native function called `name`, that takes `arity` arguments. Every time it is
+/// called, `function` is given `userdata`.
+///
+/// # Safety
+///
+/// `vm` must be a valid VM, and `name` must be a NUL-terminated string. `userdata` must remain
+/// valid for as long as the VM exists.
+#[no_mangle]
+pub unsafe extern "C" fn rlox_define_native(
+    vm: *mut VM,
+    name: *const c_char,
+    arity: u8,
+    function: RloxNativeFn,
+    userdata: *mut c_void,
+) -> RloxStatus {
+    let vm = &mut *vm;
+    let name = match CStr::from_ptr(name).to_str() {
+        Ok(name) => name,
+        Err(_) => return RloxStatus::InvalidArgument,
+    };
+
+    let error_message = format!("{name}() failed");
+    vm.define_native(name, arity, move |arguments| {
+        let args: Vec<RloxValue> = arguments.iter().copied().map(RloxValue::from).collect();
+        let mut result = RloxValue::from(Value::Nil);
+
+        let ok = function(userdata, args.as_ptr(), args.len(), &mut result);
+        let result = result.to_value();
+        if ok {
+            return Ok(result);
+        }
+
+        match result.to_str() {
+            Some(message) => Err(message.to_owned()),
+            None => Err(error_message.clone()),
+        }
+    });
+
+    RloxStatus::Ok
+}
+
+///////////////////////////////////// Implementation details //////////////////////////////////////
+
+impl From<Value> for RloxValue {
+    fn from(value: Value) -> Self {
+        let mut c_value = RloxValue {
+            kind: RloxValueKind::Nil,
+            boolean: false,
+            number: 0.0,
+            string: ptr::null(),
+            string_len: 0,
+        };
+
+        match value {
+            Value::Nil => (),
+            Value::Boolean(boolean) => {
+                c_value.kind = RloxValueKind::Boolean;
+                c_value.boolean = boolean;
+            }
+            Value::Number(number) => {
+                c_value.kind = RloxValueKind::Number;
+                c_value.number = number;
+            }
+            #[cfg(feature = "integers")]
+            Value::Integer(integer) => {
+                c_value.kind = RloxValueKind::Number;
+                c_value.number = integer as f64;
+            }
+            Value::LoxString(_) | Value::Rope(_) => {
+                let string = value.to_str().expect("a string");
+                c_value.kind = RloxValueKind::String;
+                c_value.string = string.as_ptr().cast();
+                c_value.string_len = string.len();
+            }
+            Value::NativeFunction(_) => c_value.kind = RloxValueKind::NativeFunction,
+            Value::Function(_) => c_value.kind = RloxValueKind::Function,
+            Value::Class(_) => c_value.kind = RloxValueKind::Class,
+            Value::Instance(_) => c_value.kind = RloxValueKind::Instance,
+            Value::List(_) => c_value.kind = RloxValueKind::List,
+            Value::Map(_) => c_value.kind = RloxValueKind::Map,
+        }
+
+        c_value
+    }
+}
+
+impl RloxValue {
+    /// Converts a value given by C code back to a Lox value. Strings are copied into the GC, so
+    /// this must only be called while the VM is running. Invalid values become `nil`.
+    fn to_value(self) -> Value {
+        match self.kind {
+            RloxValueKind::Nil
+            | RloxValueKind::NativeFunction
+            | RloxValueKind::Function
+            | RloxValueKind::Class
+            | RloxValueKind::Instance
+            | RloxValueKind::List
+            | RloxValueKind::Map => Value::Nil,
+            RloxValueKind::Boolean => self.boolean.into(),
+            RloxValueKind::Number => self.number.into(),
+            RloxValueKind::String if self.string.is_null() => Value::Nil,
+            RloxValueKind::String => {
+                let bytes = unsafe {
+                    std::slice::from_raw_parts(self.string.cast::<u8>(), self.string_len)
+                };
+                String::from_utf8_lossy(bytes).into_owned().into()
+            }
+        }
+    }
+}
+
+////////////////////////////////////////////// Tests //////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serial_test::serial;
+    use std::ffi::CString;
+
+    fn c(s: &str) -> CString {
+        CString::new(s).unwrap()
+    }
+
+    extern "C" fn add_userdata(
+        userdata: *mut c_void,
+        args: *const RloxValue,
+        n_args: usize,
+        result: *mut RloxValue,
+    ) -> bool {
+        let args = unsafe { std::slice::from_raw_parts(args, n_args) };
+        let offset = unsafe { *userdata.cast::<f64>() };
+        if args[0].kind != RloxValueKind::Number {
+            let message = "expected a number";
+            unsafe {
+                *result = RloxValue {
+                    kind: RloxValueKind::String,
+                    string: message.as_ptr().cast(),
+                    string_len: message.len(),
+                    ..*result
+                };
+            }
+            return false;
+        }
+
+        unsafe {
+            (*result).kind = RloxValueKind::Number;
+            (*result).number = args[0].number + offset;
+        }
+        true
+    }
+
+    #[test]
+    #[serial]
+    fn embedding() {
+        let mut offset = 10.0_f64;
+        unsafe {
+            let vm = rlox_vm_new();
+            let userdata = ptr::addr_of_mut!(offset).cast();
+            rlox_define_native(vm, c("addOffset").as_ptr(), 1, add_userdata, userdata);
+
+            let status = rlox_interpret(vm, c("var a = addOffset(32); var s = \"hi\";").as_ptr());
+            assert_eq!(RloxStatus::Ok, status);
+
+            let mut value = RloxValue::from(Value::Nil);
+            assert!(rlox_get_global(vm, c("a").as_ptr(), &mut value));
+            assert_eq!(RloxValueKind::Number, value.kind);
+            assert_eq!(42.0, value.number);
+
+            assert!(rlox_get_global(vm, c("s").as_ptr(), &mut value));
+            assert_eq!(RloxValueKind::String, value.kind);
+            assert_eq!(2, value.string_len);
+
+            assert!(!rlox_get_global(vm, c("missing").as_ptr(), &mut value));
+
+            let status = rlox_interpret(vm, c("addOffset(nil);").as_ptr());
+            assert_eq!(RloxStatus::RuntimeError, status);
+            let status = rlox_interpret(vm, c("var = ;").as_ptr());
+            assert_eq!(RloxStatus::CompileError, status);
+
+            rlox_vm_free(vm);
+        }
+    }
+}
@@ -17,8 +17,17 @@
 //! // It should be 3 bytes:
 //! assert_eq!(3, chunk.len());
 //! ```
+//!
+//! To write jumps without counting bytes, use a [ChunkBuilder].
+
+use std::collections::HashMap;
+use std::ops::Range;
 
-use crate::value::{Value, ValueArray};
+use crate::error::InterpretationError::{InvalidBytecode, InvalidBytecodeFile};
+use crate::gc::ActiveGC;
+use crate::globals::GlobalCache;
+use crate::rvm;
+use crate::value::{Function, MapKey, Value, ValueArray};
 use crate::with_try_from_u8;
 
 with_try_from_u8! {
@@ -26,7 +35,7 @@ with_try_from_u8! {
     ///
     /// (See Crafting Interpreters, p. 244)
     #[repr(u8)]
-    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
     pub enum OpCode {
         // Opcodes for constants:
         /// Uses the operand as an index into the constant pool, and pushes that value on to the stack.
@@ -45,9 +54,15 @@ with_try_from_u8! {
         /// Uses the operand to index into value stack to find a suitable local variable
         /// and push it onto the stack.
         GetLocal,
-        /// Pops the top value from the stack and uses the operand to index into value stack,
-        /// and assigns the popped value to the location on the stack.
+        /// Uses the operand to index into value stack, and assigns the top of the stack to that
+        /// location. The value is left on top of the stack.
         SetLocal,
+        /// Like [OpCode::GetLocal], but with a two-byte operand, for functions with more than 256
+        /// local variables.
+        GetLocalLong,
+        /// Like [OpCode::SetLocal], but with a two-byte operand, for functions with more than 256
+        /// local variables.
+        SetLocalLong,
         // Opcodes for dealing with global variables
         /// Uses the operand to the constant pool to find the global name;
         /// Pushes the value of the global onto the stack.
@@ -56,11 +71,39 @@ with_try_from_u8! {
         /// Pops the top of the stack and assigns it to the global variable indicated by the
         /// operand.
         DefineGlobal,
+        /// Like [OpCode::DefineGlobal], but the global is a constant: [OpCode::SetGlobal] cannot
+        /// assign to it.
+        DefineConstGlobal,
         /// Uses the operand to the constant pool to find the global name;
         /// Pops the top of the stack and assigns it to the global variable.
         /// The global variable must already exist.
         SetGlobal,
 
+        // Opcodes for classes and instances
+        /// Uses the operand to the constant pool to find the class name;
+        /// Pushes a new class with that name.
+        Class,
+        /// Uses the operand to the constant pool to find the property name;
+        /// Pops an instance, and pushes the value of its property.
+        GetProperty,
+        /// Uses the operand to the constant pool to find the property name;
+        /// Pops the value, then the instance, and sets the property of the instance. Pushes the
+        /// value.
+        SetProperty,
+
+        // Opcodes for lists and maps
+        /// The operand is the number of items, which are on top of the stack. They are all
+        /// replaced with a new list containing them.
+        BuildList,
+        /// The operand is the number of entries, whose keys and values are on top of the stack
+        /// (key, value, key, value, ...). They are all replaced with a new map containing them.
+        BuildMap,
+        /// Pops the index (or key), then the list (or map); pushes the item at that index.
+        IndexGet,
+        /// Pops the value, the index (or key), then the list (or map), and stores the value at
+        /// that index. Pushes the value.
+        IndexSet,
+
         // Opcodes for expressions and operations
         /// Pops RHS, then LHS; pushes LHS == RHS on to the stack.
         Equal,
@@ -76,17 +119,56 @@ with_try_from_u8! {
         Multiply,
         /// Pops RHS, then LHS; pushes LHS / RHS on to the stack.
         Divide,
+        /// Pops RHS, then LHS; pushes LHS & RHS on to the stack. Like the other bitwise
+        /// operators, the operands are truncated to integers.
+        BitAnd,
+        /// Pops RHS, then LHS; pushes LHS | RHS on to the stack.
+        BitOr,
+        /// Pops RHS, then LHS; pushes LHS ^ RHS on to the stack.
+        BitXor,
+        /// Pops RHS, then LHS; pushes LHS << RHS on to the stack.
+        ShiftLeft,
+        /// Pops RHS, then LHS; pushes LHS >> RHS on to the stack. The sign is kept.
+        ShiftRight,
         /// Pops the top of the stack; pushes !TOS
         Not,
         /// Pops the top of the stack; pushes -TOS
         Negate,
+        /// Calls a function. The operand is the number of arguments, which are on top of the
+        /// stack; the function itself is just below them. All of these are replaced by the
+        /// function's return value.
+        Call,
+
+        // Opcodes for control flow:
+        /// Unconditionally jumps forward by the two-byte operand.
+        Jump,
+        /// If the top of the stack is falsy, jumps forward by the two-byte operand. Does **not**
+        /// pop the condition.
+        JumpIfFalse,
+        /// Jumps backward by the two-byte operand.
+        Loop,
 
         // Opcodes for statements:
 
         /// Pops the top value of the stack and prints it to `stdout`.
         Print,
+        /// Uses the operand to the constant pool to find the name of a module. Runs the module,
+        /// unless it has already been imported, and pushes `nil` (or what the module returned).
+        Import,
         /// Pops the top value of the stack and returns from the execution of the current chunk.
         Return,
+
+        // Superinstructions, which do the work of a common sequence of opcodes in one dispatch.
+        // Only the optimizer emits them (see [crate::optimizer::fuse()]).
+        /// Like [OpCode::GetLocal], [OpCode::GetLocal], [OpCode::Add]. The high byte of the
+        /// two-byte operand is the slot of the LHS; the low byte is the slot of the RHS.
+        AddLocals,
+        /// Like [OpCode::Constant], [OpCode::Add]: pops LHS; pushes LHS + the constant at the
+        /// operand.
+        AddConstant,
+        /// Like [OpCode::Not], [OpCode::JumpIfFalse]: replaces the top of the stack with !TOS,
+        /// and jumps forward by the two-byte operand if that is falsy (i.e., if TOS was truthy).
+        NotJumpIfFalse,
     }
 }
 
@@ -100,11 +182,45 @@ with_try_from_u8! {
 /// operands (which may be arbitrary bytes).
 ///
 /// (See Crafting Interpreters, p. 244)
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone)]
 pub struct Chunk {
     code: Vec<u8>,
+    /// With the `split_operands` feature, the operand of each instruction, decoded, at the offset
+    /// of its opcode (and zero everywhere else). See [Chunk::operands()].
+    #[cfg(feature = "split_operands")]
+    operands: Vec<u16>,
     constants: ValueArray,
-    lines: Vec<usize>,
+    /// The index of each constant added with [Chunk::add_constant()], so that it is only stored
+    /// once.
+    constant_indices: HashMap<MapKey, u8>,
+    /// Line numbers, run-length encoded: consecutive bytes from the same line share one run.
+    lines: Vec<LineRun>,
+    /// The source code that each instruction came from, run-length encoded like the lines.
+    spans: Vec<SpanRun>,
+    /// The register code for this chunk, if it was compiled for the register VM. See
+    /// [Chunk::registers()].
+    registers: Option<Box<rvm::Code>>,
+    /// Where each `GetGlobal` instruction found its global last time. See [crate::globals].
+    global_cache: GlobalCache,
+}
+
+/// A run of consecutive bytes in the byte stream that all come from the same line. The run lasts
+/// until the start of the next run (or the end of the byte stream).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct LineRun {
+    /// The offset of the first byte in the run.
+    start: usize,
+    line: usize,
+}
+
+/// A run of consecutive bytes in the byte stream that all come from the same span of source code.
+/// Like a [LineRun], the run lasts until the start of the next run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SpanRun {
+    /// The offset of the first byte in the run.
+    start: usize,
+    /// The range of bytes in the source code, or `None` if the bytes were written without a span.
+    span: Option<Range<usize>>,
 }
 
 /// A valid byte from a chunk, obtained using [Chunk::get()].
@@ -164,6 +280,29 @@ pub struct WrittenOpcode<'a> {
 
 ///////////////////////////////////////// Implementation //////////////////////////////////////////
 
+impl OpCode {
+    /// Returns how many bytes of operands follow this opcode in the byte stream.
+    pub fn operand_len(self) -> usize {
+        use OpCode::*;
+        match self {
+            Constant | GetLocal | SetLocal | GetGlobal | DefineGlobal | DefineConstGlobal
+            | SetGlobal | Class | GetProperty | SetProperty | BuildList | BuildMap | Call
+            | Import | AddConstant => 1,
+            GetLocalLong | SetLocalLong | Jump | JumpIfFalse | Loop | AddLocals
+            | NotJumpIfFalse => 2,
+            Nil | True | False | Pop | IndexGet | IndexSet | Equal | Greater | Less | Add
+            | Subtract | Multiply | Divide | BitAnd | BitOr | BitXor | ShiftLeft | ShiftRight
+            | Not | Negate | Print | Return => 0,
+        }
+    }
+
+    /// Returns true if the opcode jumps. Its operand is the two-byte distance of the jump.
+    pub fn is_jump(self) -> bool {
+        use OpCode::*;
+        matches!(self, Jump | JumpIfFalse | Loop | NotJumpIfFalse)
+    }
+}
+
 impl Chunk {
     /// Return a new, empty [Chunk].
     pub fn new() -> Self {
@@ -174,7 +313,7 @@ impl Chunk {
     /// interpreted.
     ///
     /// Returns `Some(entry)` when the offset is in `(0..self.len())`; `None` otherwise.
-    pub fn get(&self, offset: usize) -> Option<BytecodeEntry> {
+    pub fn get(&self, offset: usize) -> Option<BytecodeEntry<'_>> {
         self.code.get(offset).copied().map(|byte| BytecodeEntry {
             byte,
             provenance: self,
@@ -185,31 +324,185 @@ impl Chunk {
     ///
     /// Returns a [WrittenOpcode], which is a handle that can be used to append additional
     /// operands to the byte stream.
-    pub fn write_opcode(&mut self, opcode: OpCode, line: usize) -> WrittenOpcode {
+    pub fn write_opcode(&mut self, opcode: OpCode, line: usize) -> WrittenOpcode<'_> {
+        self.write_opcode_with_span(opcode, line, None)
+    }
+
+    /// Like [Chunk::write_opcode()], but also records the range of bytes in the source code that
+    /// the instruction came from, which can be retrieved with [Chunk::span_for()].
+    ///
+    /// ```
+    /// # use rlox::prelude::*;
+    /// let mut chunk = Chunk::new();
+    /// chunk.write_opcode_with_span(OpCode::Nil, 1, 6..9);
+    /// chunk.write_opcode(OpCode::Return, 1);
+    /// assert_eq!(Some(6..9), chunk.span_for(0));
+    /// assert_eq!(None, chunk.span_for(1));
+    /// ```
+    pub fn write_opcode_with_span(
+        &mut self,
+        opcode: OpCode,
+        line: usize,
+        span: impl Into<Option<Range<usize>>>,
+    ) -> WrittenOpcode<'_> {
+        let start = self.code.len();
         self.write(opcode as u8, line);
 
+        let span = span.into();
+        match self.spans.last() {
+            Some(run) if run.span == span => (),
+            // Nothing is known about the source code yet:
+            None if span.is_none() => (),
+            _ => self.spans.push(SpanRun { start, span }),
+        }
+
         WrittenOpcode {
             line,
             provenance: self,
         }
     }
 
-    /// Adds a constant to the constant pool, and returns its index, if successful.
+    /// Adds a constant to the constant pool, and returns its index, if successful. If an equal
+    /// constant was already added, its index is returned instead, so each constant is only stored
+    /// once.
+    ///
+    /// ```
+    /// # use rlox::prelude::*;
+    /// let mut chunk = Chunk::new();
+    /// assert_eq!(Some(0), chunk.add_constant(1.0.into()));
+    /// assert_eq!(Some(1), chunk.add_constant(2.0.into()));
+    /// assert_eq!(Some(0), chunk.add_constant(1.0.into()));
+    /// assert_eq!(2, chunk.constants().count());
+    /// ```
     ///
     /// # Errors
     ///
-    /// A constant index must fit in a [u8]; therefore, **no more than 256 constants may be
-    /// added**. This method will return `None` when there are already at least 256 constants
+    /// A constant index must fit in a [u8]; therefore, **no more than 256 distinct constants may
+    /// be added**. This method will return `None` when there are already at least 256 constants
     /// added.
     pub fn add_constant(&mut self, value: Value) -> Option<u8> {
-        let index = self.constants.len();
+        let key = match value {
+            // -0.0 == 0.0, but they print differently, so -0.0 is never merged (nor is NaN):
+            Value::Number(number) if number == 0.0 && number.is_sign_negative() => None,
+            _ => MapKey::new(value),
+        };
+        if let Some(&index) = key.and_then(|key| self.constant_indices.get(&key)) {
+            // 1.0 == 1 (with the `integers` feature), but they are different types:
+            let existing = self.constants.get(index as usize).unwrap();
+            if std::mem::discriminant(&existing) == std::mem::discriminant(&value) {
+                return Some(index);
+            }
+        }
+
+        let index = u8::try_from(self.constants.len()).ok();
         self.constants.write(value);
-        u8::try_from(index).ok()
+        if let (Some(key), Some(index)) = (key, index) {
+            self.constant_indices.insert(key, index);
+        }
+        index
+    }
+
+    /// Returns the entire byte stream. Unlike [Chunk::get()], the bytes are not wrapped in a
+    /// [BytecodeEntry], which makes this useful for tight loops like the VM's.
+    #[inline]
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+
+    /// Returns the operand stream: the operand of every instruction, already decoded, at the
+    /// same offset as its opcode in the byte stream. Instructions without an operand have zero.
+    /// The VM reads operands from here instead of decoding them from the byte stream, as an
+    /// experiment in dispatch performance (requires the `split_operands` feature).
+    ///
+    /// ```
+    /// # use rlox::prelude::*;
+    /// let mut chunk = Chunk::new();
+    /// chunk.write_opcode(OpCode::Loop, 1).with_u16_operand(300);
+    /// chunk.write_opcode(OpCode::Return, 1);
+    /// assert_eq!(&[300, 0, 0, 0], chunk.operands());
+    /// ```
+    #[cfg(feature = "split_operands")]
+    #[inline]
+    pub fn operands(&self) -> &[u16] {
+        &self.operands
+    }
+
+    /// Returns the [register code](crate::rvm) for this chunk, if it was compiled with
+    /// [Target::Register](crate::compiler::Target::Register). The VM runs it instead of the byte
+    /// stream. It is not serialized.
+    #[inline]
+    pub fn registers(&self) -> Option<&rvm::Code> {
+        self.registers.as_deref()
+    }
+
+    /// Attaches register code, translated from this chunk's byte stream.
+    pub(crate) fn set_registers(&mut self, code: rvm::Code) {
+        self.registers = Some(Box::new(code));
+    }
+
+    /// Returns the inline caches of the chunk's `GetGlobal` instructions.
+    #[inline(always)]
+    pub(crate) fn global_cache(&self) -> &GlobalCache {
+        &self.global_cache
+    }
+
+    /// Returns the constant at the given index, or `None` if the index is out of bounds.
+    #[inline]
+    pub fn constant(&self, index: usize) -> Option<Value> {
+        self.constants.get(index)
+    }
+
+    /// Returns every value in the constant pool.
+    pub fn constants(&self) -> impl Iterator<Item = Value> + '_ {
+        self.constants.iter()
+    }
+
+    /// Reads a two-byte operand (e.g., a jump offset) starting at the given offset.
+    pub fn read_u16(&self, offset: usize) -> Option<u16> {
+        let bytes = self.code.get(offset..offset + 2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// Overwrites a two-byte operand that was previously written with
+    /// [WrittenOpcode::with_u16_operand()]. This is used to patch jumps, once the destination is
+    /// known.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the operand is not within the byte stream.
+    pub fn patch_u16(&mut self, offset: usize, operand: u16) {
+        self.code[offset..offset + 2].copy_from_slice(&operand.to_be_bytes());
+        #[cfg(feature = "split_operands")]
+        {
+            self.operands[offset - 1] = operand;
+        }
     }
 
     /// Returns the line number for whatever is at the given offset.
     pub fn line_number_for(&self, offset: usize) -> Option<usize> {
-        self.lines.get(offset).copied()
+        if offset >= self.code.len() {
+            return None;
+        }
+
+        // Find the last run that starts at or before the offset:
+        let index = self.lines.partition_point(|run| run.start <= offset) - 1;
+        Some(self.lines[index].line)
+    }
+
+    /// Returns the range of bytes in the source code that the instruction at the given offset came
+    /// from, if it is known. Spans are not saved in bytecode files.
+    pub fn span_for(&self, offset: usize) -> Option<Range<usize>> {
+        if offset >= self.code.len() {
+            return None;
+        }
+
+        let index = self.spans.partition_point(|run| run.start <= offset);
+        self.spans.get(index.checked_sub(1)?)?.span.clone()
+    }
+
+    /// Returns how many bytes of memory the line number information takes up.
+    pub fn line_info_size(&self) -> usize {
+        self.lines.len() * std::mem::size_of::<LineRun>()
     }
 
     /// Returns the length of the byte stream.
@@ -226,9 +519,276 @@ impl Chunk {
 
     /// Actually writes to the byte stream.
     fn write(&mut self, payload: u8, line: usize) {
-        debug_assert_eq!(self.code.len(), self.lines.len());
+        let start = self.code.len();
         self.code.push(payload);
-        self.lines.push(line)
+        #[cfg(feature = "split_operands")]
+        self.operands.push(0);
+
+        match self.lines.last() {
+            Some(run) if run.line == line => (),
+            _ => self.lines.push(LineRun { start, line }),
+        }
+    }
+}
+
+/////////////////////////////////////// Bytecode file format ///////////////////////////////////////
+
+/// The first bytes of every serialized [Chunk].
+pub const BYTECODE_MAGIC: &[u8; 4] = b"LOXC";
+
+/// The version of the bytecode file format. Bump this whenever the format changes.
+pub const BYTECODE_VERSION: u8 = 13;
+
+/// Tags that prefix each constant in the serialized constant pool.
+mod constant_tag {
+    pub const NIL: u8 = 0;
+    pub const FALSE: u8 = 1;
+    pub const TRUE: u8 = 2;
+    pub const NUMBER: u8 = 3;
+    pub const STRING: u8 = 4;
+    pub const FUNCTION: u8 = 5;
+    #[cfg(feature = "integers")]
+    pub const INTEGER: u8 = 6;
+}
+
+impl Chunk {
+    /// Returns true if the given bytes look like a serialized [Chunk] (i.e., they start with
+    /// [BYTECODE_MAGIC]).
+    pub fn is_serialized(bytes: &[u8]) -> bool {
+        bytes.starts_with(BYTECODE_MAGIC)
+    }
+
+    /// Serializes this chunk into the bytecode file format, which can be read back in with
+    /// [Chunk::deserialize()].
+    ///
+    /// The format is:
+    ///
+    /// ```text
+    /// magic:     b"LOXC"
+    /// version:   u8
+    /// chunk:
+    ///   code:      u32 length, followed by that many bytes
+    ///   lines:     u32 count, followed by that many (u32 line, u32 run length) pairs
+    ///   constants: u32 count, followed by that many tagged constants
+    /// ```
+    ///
+    /// Function constants contain their name (u32 length, then UTF-8), arity (u8), and
+    /// their own chunk, in the same format. All integers are little-endian.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.code.len() * 5 + 16);
+        bytes.extend_from_slice(BYTECODE_MAGIC);
+        bytes.push(BYTECODE_VERSION);
+        self.write_to(&mut bytes);
+        bytes
+    }
+
+    /// Reads a chunk that was written by [Chunk::serialize()].
+    ///
+    /// String constants and functions are stored in the [ActiveGC](crate::gc::ActiveGC), so it
+    /// **must** be installed before calling this.
+    ///
+    /// # Errors
+    ///
+    /// Returns [InvalidBytecodeFile] if
+    /// the bytes are not a valid serialized chunk of the current [BYTECODE_VERSION].
+    pub fn deserialize(bytes: &[u8]) -> crate::Result<Chunk> {
+        let mut reader = ByteReader { bytes };
+
+        if reader.take(BYTECODE_MAGIC.len())? != BYTECODE_MAGIC {
+            return Err(InvalidBytecodeFile("not a Lox bytecode file"));
+        }
+        if reader.u8()? != BYTECODE_VERSION {
+            return Err(InvalidBytecodeFile("unsupported bytecode version"));
+        }
+
+        let chunk = reader.chunk()?;
+
+        if !reader.bytes.is_empty() {
+            return Err(InvalidBytecodeFile("trailing bytes after constant pool"));
+        }
+
+        Ok(chunk)
+    }
+
+    /// Writes the code, lines, and constants of this chunk.
+    fn write_to(&self, bytes: &mut Vec<u8>) {
+        write_u32(bytes, self.code.len());
+        bytes.extend_from_slice(&self.code);
+        write_u32(bytes, self.lines.len());
+        for (i, run) in self.lines.iter().enumerate() {
+            let end = self
+                .lines
+                .get(i + 1)
+                .map_or(self.code.len(), |next| next.start);
+            write_u32(bytes, run.line);
+            write_u32(bytes, end - run.start);
+        }
+
+        write_u32(bytes, self.constants.len());
+        for index in 0..self.constants.len() {
+            match self.constants.get(index).unwrap() {
+                Value::Nil => bytes.push(constant_tag::NIL),
+                Value::Boolean(false) => bytes.push(constant_tag::FALSE),
+                Value::Boolean(true) => bytes.push(constant_tag::TRUE),
+                Value::Number(number) => {
+                    bytes.push(constant_tag::NUMBER);
+                    bytes.extend_from_slice(&number.to_le_bytes());
+                }
+                #[cfg(feature = "integers")]
+                Value::Integer(integer) => {
+                    bytes.push(constant_tag::INTEGER);
+                    bytes.extend_from_slice(&integer.to_le_bytes());
+                }
+                Value::LoxString(string) => {
+                    bytes.push(constant_tag::STRING);
+                    write_str(bytes, string);
+                }
+                Value::Function(function) => {
+                    bytes.push(constant_tag::FUNCTION);
+                    write_str(bytes, function.name);
+                    bytes.push(function.arity);
+                    function.chunk.write_to(bytes);
+                }
+                Value::NativeFunction(_)
+                | Value::Rope(_)
+                | Value::Class(_)
+                | Value::Instance(_)
+                | Value::List(_)
+                | Value::Map(_) => {
+                    unreachable!("only literals and functions are in the constant pool")
+                }
+            }
+        }
+    }
+}
+
+/// Decodes the operand of every instruction in the byte stream, for [Chunk::operands()]. Decoding
+/// stops at the first invalid opcode, which the VM reports when it gets there.
+#[cfg(feature = "split_operands")]
+fn decode_operands(code: &[u8]) -> Vec<u16> {
+    let mut operands = vec![0; code.len()];
+    let mut offset = 0;
+    while let Some(Ok(opcode)) = code.get(offset).map(|&byte| OpCode::try_from(byte)) {
+        let len = opcode.operand_len();
+        operands[offset] = match code.get(offset + 1..offset + 1 + len) {
+            Some(&[byte]) => byte.into(),
+            Some(&[high, low]) => u16::from_be_bytes([high, low]),
+            _ => 0,
+        };
+        offset += 1 + len;
+    }
+    operands
+}
+
+/// Appends a little-endian u32 to the byte buffer.
+fn write_u32(bytes: &mut Vec<u8>, n: usize) {
+    let n = u32::try_from(n).expect("chunk is too large to serialize");
+    bytes.extend_from_slice(&n.to_le_bytes());
+}
+
+/// Appends a string, prefixed with its length.
+fn write_str(bytes: &mut Vec<u8>, string: &str) {
+    write_u32(bytes, string.len());
+    bytes.extend_from_slice(string.as_bytes());
+}
+
+/// A cursor over a serialized chunk.
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> ByteReader<'a> {
+    /// Reads the code, lines, and constants of a chunk.
+    fn chunk(&mut self) -> crate::Result<Chunk> {
+        let code_length = self.u32()?;
+        let code = self.take(code_length)?.to_vec();
+        let n_runs = self.u32()?;
+        let mut lines = Vec::new();
+        let mut start = 0;
+        for _ in 0..n_runs {
+            let line = self.u32()?;
+            let length = self.u32()?;
+            if length == 0 {
+                return Err(InvalidBytecodeFile("empty run of line numbers"));
+            }
+            lines.push(LineRun { start, line });
+            start += length;
+        }
+        if start != code_length {
+            return Err(InvalidBytecodeFile("line numbers do not match the code"));
+        }
+
+        let mut constants = ValueArray::new();
+        let n_constants = self.u32()?;
+        for _ in 0..n_constants {
+            let value = match self.u8()? {
+                constant_tag::NIL => Value::Nil,
+                constant_tag::FALSE => false.into(),
+                constant_tag::TRUE => true.into(),
+                constant_tag::NUMBER => {
+                    let raw = self.take(8)?.try_into().unwrap();
+                    f64::from_le_bytes(raw).into()
+                }
+                #[cfg(feature = "integers")]
+                constant_tag::INTEGER => {
+                    let raw = self.take(8)?.try_into().unwrap();
+                    i64::from_le_bytes(raw).into()
+                }
+                constant_tag::STRING => self.str()?.into(),
+                constant_tag::FUNCTION => {
+                    let name = ActiveGC::store_string(self.str()?.to_owned());
+                    let arity = self.u8()?;
+                    let chunk = self.chunk()?;
+                    let function = Function { name, arity, chunk };
+                    Value::Function(ActiveGC::store_function(function))
+                }
+                _ => return Err(InvalidBytecodeFile("unknown constant type")),
+            };
+            constants.write(value);
+        }
+
+        Ok(Chunk {
+            #[cfg(feature = "split_operands")]
+            operands: decode_operands(&code),
+            code,
+            constants,
+            // The constants in a file are used as they are, even if some of them are equal:
+            constant_indices: HashMap::new(),
+            lines,
+            // Bytecode files are run without their source code, so spans are not saved.
+            spans: Vec::new(),
+            // Nor is register code, which is only an experiment.
+            registers: None,
+            global_cache: GlobalCache::default(),
+        })
+    }
+
+    /// Consumes a string, prefixed with its length.
+    fn str(&mut self) -> crate::Result<&'a str> {
+        let length = self.u32()?;
+        std::str::from_utf8(self.take(length)?)
+            .map_err(|_| InvalidBytecodeFile("string constant is not UTF-8"))
+    }
+
+    /// Consumes and returns the next `n` bytes.
+    fn take(&mut self, n: usize) -> crate::Result<&'a [u8]> {
+        if self.bytes.len() < n {
+            return Err(InvalidBytecodeFile("unexpected end of file"));
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    /// Consumes one byte.
+    fn u8(&mut self) -> crate::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// Consumes a little-endian u32, returned as a usize.
+    fn u32(&mut self) -> crate::Result<usize> {
+        let raw = self.take(4)?.try_into().unwrap();
+        Ok(u32::from_le_bytes(raw) as usize)
     }
 }
 
@@ -279,11 +839,258 @@ impl<'a> WrittenOpcode<'a> {
     #[inline]
     pub fn with_operand(self, index: u8) {
         self.provenance.write(index, self.line);
+        #[cfg(feature = "split_operands")]
+        {
+            let opcode = self.provenance.operands.len() - 2;
+            self.provenance.operands[opcode] = index.into();
+        }
+    }
+
+    /// Consumes `self` and appends a two-byte operand (big-endian) for the last written
+    /// instruction.
+    #[inline]
+    pub fn with_u16_operand(self, operand: u16) {
+        let [high, low] = operand.to_be_bytes();
+        self.provenance.write(high, self.line);
+        self.provenance.write(low, self.line);
+        #[cfg(feature = "split_operands")]
+        {
+            let opcode = self.provenance.operands.len() - 3;
+            self.provenance.operands[opcode] = operand;
+        }
+    }
+}
+
+////////////////////////////////////////// Chunk builder //////////////////////////////////////////
+
+/// Builds a [Chunk] one instruction at a time, without any byte math.
+///
+/// Jumps go to a [Label], which can be placed before or after the jump; forward jumps are patched
+/// once their label is placed. Mistakes (like jumping to a label that is never placed) are
+/// reported by [ChunkBuilder::build()].
+///
+/// # Examples
+///
+/// Counting down from 3:
+///
+/// ```
+/// # use rlox::prelude::*;
+/// use rlox::chunk::ChunkBuilder;
+///
+/// let mut builder = ChunkBuilder::new();
+/// let (top, done) = (builder.label(), builder.label());
+/// builder
+///     .emit_constant(3.0.into())
+///     .place(top)
+///     // while (n > 0)
+///     .emit_with_operand(OpCode::GetLocal, 0)
+///     .emit_constant(0.0.into())
+///     .emit(OpCode::Greater)
+///     .jump_if_false(done)
+///     .emit(OpCode::Pop)
+///     // n = n - 1;
+///     .emit_with_operand(OpCode::GetLocal, 0)
+///     .emit_constant(1.0.into())
+///     .emit(OpCode::Subtract)
+///     .emit_with_operand(OpCode::SetLocal, 0)
+///     .emit(OpCode::Pop)
+///     .jump_to(top)
+///     .place(done)
+///     .emit(OpCode::Pop)
+///     .emit(OpCode::Return);
+/// let chunk = builder.build().unwrap();
+///
+/// assert!(VM::default().interpret_chunk(&chunk).is_ok());
+/// ```
+#[derive(Debug)]
+pub struct ChunkBuilder {
+    chunk: Chunk,
+    /// The line number given to instructions.
+    line: usize,
+    /// Where each label has been placed, if it has been placed yet.
+    labels: Vec<Option<usize>>,
+    /// Forward jumps waiting for their label: (offset of the operand, label).
+    unpatched: Vec<(usize, Label)>,
+    /// The first mistake: (offset, reason).
+    error: Option<(usize, &'static str)>,
+}
+
+/// A place in a chunk being built by a [ChunkBuilder], which can be jumped to.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Label(usize);
+
+impl ChunkBuilder {
+    /// Returns a builder for an empty chunk. Instructions are on line 1 until
+    /// [ChunkBuilder::at_line()] says otherwise.
+    pub fn new() -> Self {
+        ChunkBuilder {
+            chunk: Chunk::new(),
+            line: 1,
+            labels: Vec::new(),
+            unpatched: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// Sets the line number of the instructions emitted after this.
+    pub fn at_line(&mut self, line: usize) -> &mut Self {
+        self.line = line;
+        self
+    }
+
+    /// Returns a new label. It must be [placed](ChunkBuilder::place()) before the chunk is built.
+    pub fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    /// Places the label right before the next instruction, and patches every jump to it so far.
+    pub fn place(&mut self, label: Label) -> &mut Self {
+        let here = self.chunk.len();
+        if self.labels[label.0].replace(here).is_some() {
+            self.fail(here, "label placed more than once");
+        }
+
+        let (ready, waiting) = std::mem::take(&mut self.unpatched)
+            .into_iter()
+            .partition(|&(_, target)| target == label);
+        self.unpatched = waiting;
+        for (operand, _) in ready {
+            // The jump is relative to the end of the operand:
+            match u16::try_from(here - (operand + 2)) {
+                Ok(jump) => self.chunk.patch_u16(operand, jump),
+                Err(_) => self.fail(operand - 1, "too much code to jump over"),
+            }
+        }
+        self
+    }
+
+    /// Emits an instruction that has no operands.
+    pub fn emit(&mut self, opcode: OpCode) -> &mut Self {
+        if opcode.operand_len() != 0 {
+            self.fail(self.chunk.len(), "this opcode needs an operand");
+        }
+        self.chunk.write_opcode(opcode, self.line);
+        self
+    }
+
+    /// Emits an instruction with a one-byte operand, such as [OpCode::GetLocal] or
+    /// [OpCode::Call].
+    pub fn emit_with_operand(&mut self, opcode: OpCode, operand: u8) -> &mut Self {
+        if opcode.operand_len() != 1 {
+            self.fail(
+                self.chunk.len(),
+                "this opcode does not take a one-byte operand",
+            );
+        }
+        self.chunk
+            .write_opcode(opcode, self.line)
+            .with_operand(operand);
+        self
+    }
+
+    /// Emits an instruction with a two-byte operand, such as [OpCode::GetLocalLong]. Jumps are
+    /// emitted with [ChunkBuilder::jump_to()] and [ChunkBuilder::jump_if_false()] instead.
+    pub fn emit_with_u16_operand(&mut self, opcode: OpCode, operand: u16) -> &mut Self {
+        if opcode.operand_len() != 2 || opcode.is_jump() {
+            self.fail(
+                self.chunk.len(),
+                "this opcode does not take a two-byte operand",
+            );
+        }
+        self.chunk
+            .write_opcode(opcode, self.line)
+            .with_u16_operand(operand);
+        self
+    }
+
+    /// Adds the value to the constant pool, and emits an instruction whose operand is its index,
+    /// such as [OpCode::GetGlobal] with the global's name.
+    pub fn emit_with_constant(&mut self, opcode: OpCode, value: Value) -> &mut Self {
+        match self.chunk.add_constant(value) {
+            Some(index) => self.emit_with_operand(opcode, index),
+            None => {
+                self.fail(self.chunk.len(), "too many constants in one chunk");
+                self
+            }
+        }
+    }
+
+    /// Emits an [OpCode::Constant] that pushes the value.
+    pub fn emit_constant(&mut self, value: Value) -> &mut Self {
+        self.emit_with_constant(OpCode::Constant, value)
+    }
+
+    /// Unconditionally jumps to the label: an [OpCode::Loop] if the label has already been
+    /// placed, otherwise an [OpCode::Jump].
+    pub fn jump_to(&mut self, label: Label) -> &mut Self {
+        match self.labels[label.0] {
+            Some(target) => {
+                // +3 to jump over the Loop instruction and its operand, too.
+                let jump = u16::try_from(self.chunk.len() + 3 - target).unwrap_or_else(|_| {
+                    self.fail(self.chunk.len(), "loop body too large");
+                    0
+                });
+                self.chunk
+                    .write_opcode(OpCode::Loop, self.line)
+                    .with_u16_operand(jump);
+            }
+            None => self.forward_jump(OpCode::Jump, label),
+        }
+        self
+    }
+
+    /// Jumps to the label if the top of the stack is falsy (without popping it). The label must
+    /// come after the jump.
+    pub fn jump_if_false(&mut self, label: Label) -> &mut Self {
+        if self.labels[label.0].is_some() {
+            self.fail(self.chunk.len(), "conditional jumps cannot go backwards");
+        }
+        self.forward_jump(OpCode::JumpIfFalse, label);
+        self
+    }
+
+    /// Returns the chunk, as long as every label was placed, and every instruction was valid.
+    ///
+    /// # Errors
+    ///
+    /// Returns [InterpretationError::InvalidBytecode](crate::error::InterpretationError) for the
+    /// first mistake made while building the chunk.
+    pub fn build(self) -> crate::Result<Chunk> {
+        let unplaced = self
+            .unpatched
+            .first()
+            .map(|&(operand, _)| (operand - 1, "jumps to a label that was never placed"));
+        match self.error.or(unplaced) {
+            Some((offset, reason)) => Err(InvalidBytecode { offset, reason }),
+            None => Ok(self.chunk),
+        }
+    }
+
+    /// Emits a jump whose operand is patched when the label is placed.
+    fn forward_jump(&mut self, opcode: OpCode, label: Label) {
+        self.chunk
+            .write_opcode(opcode, self.line)
+            .with_u16_operand(u16::MAX);
+        self.unpatched.push((self.chunk.len() - 2, label));
+    }
+
+    /// Remembers the first mistake, to be returned by [ChunkBuilder::build()].
+    fn fail(&mut self, offset: usize, reason: &'static str) {
+        self.error.get_or_insert((offset, reason));
+    }
+}
+
+impl Default for ChunkBuilder {
+    fn default() -> Self {
+        ChunkBuilder::new()
     }
 }
 
 #[cfg(test)]
 mod test {
+    use serial_test::serial;
+
     use super::*;
 
     #[test]
@@ -310,4 +1117,226 @@ mod test {
         // Return
         assert_eq!(Some(OpCode::Return), c.get(2).unwrap().as_opcode());
     }
+
+    #[test]
+    fn line_numbers_are_run_length_encoded() {
+        let mut c = Chunk::new();
+        c.write_opcode(OpCode::Nil, 1);
+        c.write_opcode(OpCode::Constant, 1).with_operand(0);
+        c.write_opcode(OpCode::Pop, 2);
+        c.write_opcode(OpCode::Loop, 5).with_u16_operand(4);
+        c.write_opcode(OpCode::Return, 5);
+
+        assert_eq!(3, c.lines.len());
+        let lines: Vec<_> = (0..c.len())
+            .map(|i| c.line_number_for(i).unwrap())
+            .collect();
+        assert_eq!(vec![1, 1, 1, 2, 5, 5, 5, 5], lines);
+        assert_eq!(None, c.line_number_for(c.len()));
+    }
+
+    #[test]
+    #[serial]
+    #[cfg_attr(
+        feature = "integers",
+        ignore = "number literals are integers with the integers feature"
+    )]
+    fn equal_constants_are_stored_once() {
+        let gc = crate::gc::ActiveGC::install();
+        let options = crate::compiler::CompileOptions {
+            optimize: false,
+            ..Default::default()
+        };
+        let compile = |source: &str| crate::compiler::compile_with_options(source, &gc, options);
+
+        let chunk = compile("print 1 + 1 + 1 + 1;").unwrap();
+        assert_eq!(
+            vec![Value::from(1.0)],
+            chunk.constants().collect::<Vec<_>>()
+        );
+
+        let chunk = compile("var a = \"a\"; a = a + \"a\"; print a;").unwrap();
+        assert_eq!(1, chunk.constants().count());
+
+        // Far more than 256 uses of the same few constants:
+        let source = "print x + 1.5 + \"s\" + nil;\n".repeat(300);
+        assert_eq!(3, compile(&source).unwrap().constants().count());
+    }
+
+    #[test]
+    fn constants_that_look_equal_are_not_merged() {
+        let mut c = Chunk::new();
+        assert_eq!(Some(0), c.add_constant(0.0.into()));
+        assert_eq!(Some(1), c.add_constant((-0.0).into()));
+        assert_eq!(Some(0), c.add_constant(0.0.into()));
+
+        // NaN is not equal to anything, even itself:
+        assert_eq!(Some(2), c.add_constant(f64::NAN.into()));
+        assert_eq!(Some(3), c.add_constant(f64::NAN.into()));
+    }
+
+    #[test]
+    #[serial]
+    fn serialize_and_deserialize() {
+        let _gc = crate::gc::ActiveGC::install();
+
+        let mut c = Chunk::new();
+        for value in [1.5.into(), true.into(), Value::Nil, "héllo".into()] {
+            let i = c.add_constant(value).unwrap();
+            c.write_opcode(OpCode::Constant, 1).with_operand(i);
+            c.write_opcode(OpCode::Print, 2);
+        }
+        c.write_opcode(OpCode::Return, 3);
+
+        let bytes = c.serialize();
+        assert!(Chunk::is_serialized(&bytes));
+
+        let d = Chunk::deserialize(&bytes).unwrap();
+        assert_eq!(c.code, d.code);
+        assert_eq!(c.lines, d.lines);
+        assert_eq!(c.constants.len(), d.constants.len());
+        for i in 0..c.constants.len() {
+            assert_eq!(c.constants.get(i), d.constants.get(i));
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn serialize_and_deserialize_functions() {
+        let gc = crate::gc::ActiveGC::install();
+        let chunk = crate::compiler::compile("fun f(a, b) { return a; } print f(1, 2);", &gc)
+            .expect("should compile");
+
+        let d = Chunk::deserialize(&chunk.serialize()).unwrap();
+        let function = (0..d.constants.len())
+            .find_map(|i| match d.constants.get(i) {
+                Some(Value::Function(function)) => Some(function),
+                _ => None,
+            })
+            .expect("should have a function constant");
+        assert_eq!("f", function.name);
+        assert_eq!(2, function.arity);
+        assert!(!function.chunk.is_empty());
+    }
+
+    #[test]
+    #[serial]
+    #[cfg(feature = "split_operands")]
+    fn operands_match_the_byte_stream() {
+        let gc = crate::gc::ActiveGC::install();
+        // Jumps are patched, and the optimizer rewrites the chunk:
+        let source = "var a = 1; while (a < 10) { print a > 5 and a; a = a + 1; } print [a, a];";
+        let chunk = crate::compiler::compile(source, &gc).expect("should compile");
+        assert_eq!(decode_operands(chunk.code()), chunk.operands());
+
+        let d = Chunk::deserialize(&chunk.serialize()).unwrap();
+        assert_eq!(chunk.operands(), d.operands());
+    }
+
+    #[test]
+    fn deserialize_garbage() {
+        assert!(Chunk::deserialize(b"print 1;").is_err());
+        assert!(Chunk::deserialize(b"LOXC").is_err());
+
+        let mut c = Chunk::new();
+        c.write_opcode(OpCode::Return, 1);
+        let bytes = c.serialize();
+        assert!(Chunk::deserialize(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn builder_jumps_like_the_compiler() {
+        let gc = crate::gc::ActiveGC::install();
+        let compiled = crate::compiler::compile_with_options(
+            "while (true) print 1;",
+            &gc,
+            crate::compiler::CompileOptions {
+                optimize: false,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let mut builder = ChunkBuilder::new();
+        let (start, exit) = (builder.label(), builder.label());
+        builder
+            .place(start)
+            .emit(OpCode::True)
+            .jump_if_false(exit)
+            .emit(OpCode::Pop)
+            .emit_constant(1.0.into())
+            .emit(OpCode::Print)
+            .jump_to(start)
+            .place(exit)
+            .emit(OpCode::Pop)
+            .emit(OpCode::Return);
+        let built = builder.build().unwrap();
+
+        assert_eq!(compiled.code(), built.code());
+    }
+
+    #[test]
+    fn builder_reports_mistakes() {
+        let mistake = |build: fn(&mut ChunkBuilder)| {
+            let mut builder = ChunkBuilder::new();
+            build(&mut builder);
+            match builder.build() {
+                Err(InvalidBytecode { offset, reason }) => (offset, reason),
+                Ok(_) => panic!("expected a mistake"),
+                Err(error) => panic!("unexpected error: {error}"),
+            }
+        };
+
+        assert_eq!(
+            (1, "jumps to a label that was never placed"),
+            mistake(|b| {
+                let nowhere = b.label();
+                b.emit(OpCode::Nil).jump_to(nowhere);
+            })
+        );
+        assert_eq!(
+            (0, "conditional jumps cannot go backwards"),
+            mistake(|b| {
+                let here = b.label();
+                b.place(here).jump_if_false(here);
+            })
+        );
+        assert_eq!(
+            (0, "label placed more than once"),
+            mistake(|b| {
+                let here = b.label();
+                b.place(here).place(here);
+            })
+        );
+        assert_eq!(
+            (0, "this opcode needs an operand"),
+            mistake(|b| {
+                b.emit(OpCode::Call);
+            })
+        );
+        assert_eq!(
+            (0, "this opcode does not take a one-byte operand"),
+            mistake(|b| {
+                b.emit_with_operand(OpCode::Add, 1);
+            })
+        );
+        assert_eq!(
+            (0, "this opcode does not take a two-byte operand"),
+            mistake(|b| {
+                b.emit_with_u16_operand(OpCode::Jump, 3);
+            })
+        );
+        assert_eq!(
+            (0, "too much code to jump over"),
+            mistake(|b| {
+                let end = b.label();
+                b.jump_to(end);
+                for _ in 0..=u16::MAX {
+                    b.emit(OpCode::Nil);
+                }
+                b.place(end);
+            })
+        );
+    }
 }
@@ -1,35 +1,331 @@
 //! Contains the Lox parser and bytecode compiler.
+use std::ops::Range;
+
 use crate::chunk::WrittenOpcode;
+use crate::error::{Diagnostic, Severity};
 use crate::extension_traits::VecLast;
 use crate::gc::ActiveGC;
+use crate::optimizer::{fuse, optimize};
 use crate::prelude::*;
+use crate::rvm;
+use crate::value::Function;
 
 /////////////////////////////////////////// Public API ////////////////////////////////////////////
 
 /// Compiles the given Lox source code and, if successful returns one bytecode [Chunk].
 /// An [ActiveGC] is required because string literals will be allocated and owned by the GC.
+///
+/// Errors are printed to `stderr`. To handle the errors yourself, use
+/// [compile_with_diagnostics()].
 pub fn compile(source: &str, gc: &'_ ActiveGC) -> crate::Result<Chunk> {
-    let parser = Parser::new(source, gc);
-    let compiler = Compiler::new(parser);
+    compile_with_diagnostics(source, gc).map_err(|diagnostics| {
+        for diagnostic in diagnostics.iter() {
+            eprintln!("{diagnostic}");
+        }
+        InterpretationError::CompileError(diagnostics)
+    })
+}
+
+/// Compiles the given source code without printing any errors. Instead, every error is returned
+/// as a [Diagnostic], and it's up to the caller to report them.
+///
+/// ```
+/// # use rlox::gc::ActiveGC;
+/// # use rlox::compiler::compile_with_diagnostics;
+/// let gc = ActiveGC::install();
+/// let diagnostics = compile_with_diagnostics("print 1 +;", &gc).unwrap_err();
+/// assert_eq!(1, diagnostics.len());
+/// assert_eq!(1, diagnostics[0].line);
+/// assert_eq!(10, diagnostics[0].column);
+/// assert_eq!(9..10, diagnostics[0].span);
+/// assert!(diagnostics[0].to_string().starts_with("[line 1] Error at ';': "));
+/// ```
+pub fn compile_with_diagnostics(source: &str, gc: &'_ ActiveGC) -> Result<Chunk, Vec<Diagnostic>> {
+    compile_with_options(source, gc, CompileOptions::default())
+}
+
+/// Like [compile_with_diagnostics()], but for one entry in a REPL: expression statements at the
+/// top level print their value, instead of discarding it. So `1 + 2;` prints `3`.
+pub fn compile_for_repl(source: &str, gc: &'_ ActiveGC) -> Result<Chunk, Vec<Diagnostic>> {
+    let options = CompileOptions {
+        repl: true,
+        ..CompileOptions::default()
+    };
+    compile_with_options(source, gc, options)
+}
+
+/// Like [compile_with_diagnostics()], but with the given [CompileOptions].
+///
+/// ```
+/// # use rlox::gc::ActiveGC;
+/// # use rlox::chunk::OpCode;
+/// use rlox::compiler::{compile_with_options, CompileOptions};
+///
+/// let gc = ActiveGC::install();
+/// let options = CompileOptions {
+///     optimize: false,
+///     ..CompileOptions::default()
+/// };
+/// let chunk = compile_with_options("print !!true;", &gc, options).unwrap();
+/// let nots = chunk.code().iter().filter(|&&byte| byte == OpCode::Not as u8);
+/// assert_eq!(2, nots.count());
+/// ```
+pub fn compile_with_options(
+    source: &str,
+    gc: &'_ ActiveGC,
+    options: CompileOptions,
+) -> Result<Chunk, Vec<Diagnostic>> {
+    compile_with_warnings(source, gc, options).map(|(chunk, _warnings)| chunk)
+}
+
+/// Like [compile_with_options()], but also returns the warnings, when
+/// [CompileOptions::warnings] is on. If there are errors, the warnings are returned with them.
+///
+/// ```
+/// # use rlox::gc::ActiveGC;
+/// use rlox::compiler::{compile_with_warnings, CompileOptions};
+/// use rlox::error::Severity;
+///
+/// let gc = ActiveGC::install();
+/// let options = CompileOptions {
+///     warnings: true,
+///     ..CompileOptions::default()
+/// };
+/// let (_chunk, warnings) = compile_with_warnings("{ var a = 1; }", &gc, options).unwrap();
+/// assert_eq!(Severity::Warning, warnings[0].severity);
+/// assert!(warnings[0].to_string().starts_with("[line 1] Warning at 'a': Unused variable"));
+/// ```
+pub fn compile_with_warnings(
+    source: &str,
+    gc: &'_ ActiveGC,
+    options: CompileOptions,
+) -> Result<(Chunk, Vec<Diagnostic>), Vec<Diagnostic>> {
+    let scanner = Scanner::starting_at_line(source, options.first_line);
+    let parser = Parser::new(scanner, gc);
+    let mut compiler = Compiler::new(parser);
+    compiler.options = options;
     compiler.compile()
 }
 
+/// Compiles the source code of a module (see `import`) into a function that takes no arguments,
+/// named after the module. Like in a script, the variables declared at the top level of a module
+/// are globals. Returns the warnings, too, like [compile_with_warnings()].
+pub(crate) fn compile_module(
+    name: &str,
+    source: &str,
+    gc: &'_ ActiveGC,
+    options: CompileOptions,
+) -> Result<(Function, Vec<Diagnostic>), Vec<Diagnostic>> {
+    let scanner = Scanner::starting_at_line(source, options.first_line);
+    let parser = Parser::new(scanner, gc);
+    let mut compiler = Compiler::new(parser);
+    compiler.options = options;
+    compiler.current = FunctionCompiler::new(FunctionKind::Function, name);
+    let (chunk, warnings) = compiler.compile()?;
+    let function = Function {
+        name: ActiveGC::store_string(name.to_owned()),
+        arity: 0,
+        chunk,
+    };
+    Ok((function, warnings))
+}
+
+/// Tunes how [compile_with_options()] compiles source code. The [Default] options are the ones
+/// used by [compile()] and [compile_with_diagnostics()].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompileOptions {
+    /// Whether to run the [optimizer](crate::optimizer) on each compiled chunk.
+    pub optimize: bool,
+    /// Whether the optimizer [fuses](crate::optimizer::fuse()) common sequences of instructions
+    /// into superinstructions. Has no effect unless `optimize` is on, or when targeting the
+    /// register VM, whose instructions already read their operands straight from registers.
+    pub superinstructions: bool,
+    /// Which VM to compile for.
+    pub target: Target,
+    /// Whether top-level expression statements print their value. See [compile_for_repl()].
+    pub repl: bool,
+    /// How many constants a single chunk may have. Adding more is a compile error. The bytecode
+    /// cannot refer to more than 256 constants, so larger limits have no effect.
+    pub max_constants: usize,
+    /// The line number of the first line of the source code, for when the source code is part
+    /// of something bigger (e.g., one statement read from a stream).
+    pub first_line: usize,
+    /// Whether to print the disassembly of each compiled chunk to `stdout`, to debug code
+    /// generation. On by default with the `print_code` feature.
+    pub print_code: bool,
+    /// Whether to report warnings: problems that do not stop the code from compiling, like local
+    /// variables that are never used. Warnings are [Diagnostic]s with [Severity::Warning]. See
+    /// [compile_with_warnings()].
+    pub warnings: bool,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            optimize: true,
+            superinstructions: true,
+            target: Target::Stack,
+            repl: false,
+            max_constants: U8_COUNT,
+            first_line: 1,
+            print_code: cfg!(feature = "print_code"),
+            warnings: false,
+        }
+    }
+}
+
+/// Which VM the compiler targets. See [CompileOptions::target].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    /// The stack-based VM, which runs the byte stream of each [Chunk].
+    Stack,
+    /// The [register-based VM](crate::rvm). Each chunk still has its byte stream, plus the
+    /// register code translated from it (see [Chunk::registers()]).
+    Register,
+}
+
+/// Compiles the given source code (without printing any errors), and reports what was learned
+/// about the names in the program: where each variable is declared, and whether each use of a
+/// name refers to a local or a global. The bytecode is thrown away. This is used to build tools,
+/// like the language server.
+///
+/// ```
+/// # use rlox::gc::ActiveGC;
+/// use rlox::compiler::{analyze, Resolution};
+///
+/// let gc = ActiveGC::install();
+/// let analysis = analyze("var a = 1; { var b = a; print b; }", &gc);
+/// assert!(analysis.diagnostics.is_empty());
+///
+/// let uses: Vec<_> = analysis.references.iter().map(|name| name.name).collect();
+/// assert_eq!(vec!["a", "b"], uses);
+/// assert_eq!(Resolution::Global, analysis.references[0].resolution);
+/// assert_eq!(
+///     Resolution::Local { slot: 0, declaration: 17..18 },
+///     analysis.references[1].resolution
+/// );
+/// ```
+pub fn analyze<'a>(source: &'a str, gc: &'a ActiveGC) -> Analysis<'a> {
+    let parser = Parser::new(Scanner::new(source), gc);
+    let mut compiler = Compiler::new(parser);
+    compiler.options.warnings = true;
+    compiler.analysis = Some(Analysis::default());
+    compiler.compile_declarations();
+
+    let mut analysis = compiler.analysis.take().unwrap();
+    analysis.diagnostics = std::mem::take(&mut compiler.parser.diagnostics);
+    analysis
+}
+
+/// Everything the compiler learned about a program. See [analyze()].
+#[derive(Debug, Default)]
+pub struct Analysis<'a> {
+    /// Any compile-time errors, and warnings.
+    pub diagnostics: Vec<Diagnostic>,
+    /// Every variable declaration, in order.
+    pub declarations: Vec<Name<'a>>,
+    /// Every use of a variable (accesses and assignments), in order.
+    pub references: Vec<Name<'a>>,
+}
+
+/// A variable name, where it is found in the source code, and how it was resolved.
+#[derive(Debug, Clone)]
+pub struct Name<'a> {
+    /// The name itself.
+    pub name: &'a str,
+    /// The range of bytes in the source code where the name is.
+    pub span: Range<usize>,
+    /// What the name refers to.
+    pub resolution: Resolution,
+}
+
+/// How the compiler resolved a variable name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolution {
+    /// A local variable, stored at the given slot of the stack frame.
+    Local {
+        /// The slot in the stack frame. In functions, slot 0 holds the function itself.
+        slot: u16,
+        /// Where the local was declared.
+        declaration: Range<usize>,
+    },
+    /// A global variable, looked up by name at runtime.
+    Global,
+}
+
 ///////////////////////////////////// Implementation details //////////////////////////////////////
 
 const U8_COUNT: usize = u8::MAX as usize + 1;
+/// The most local variables (including parameters) a function can have. The first 256 are
+/// accessed with [OpCode::GetLocal] and [OpCode::SetLocal]; the rest with [OpCode::GetLocalLong]
+/// and [OpCode::SetLocalLong].
+const LOCALS_MAX: usize = u16::MAX as usize + 1;
 
-/// Contains the compiler state, which includes the [Parser] and the current chunk being produced.
+/// Contains the compiler state, which includes the [Parser] and the function being compiled.
 struct Compiler<'a> {
     parser: Parser<'a>,
-    compiling_chunk: Chunk,
+    /// The function currently being compiled.
+    current: FunctionCompiler<'a>,
+    /// The functions that enclose the current function, innermost last. Empty when compiling the
+    /// top-level script.
+    enclosing: Vec<FunctionCompiler<'a>>,
+    /// When present, records names for [analyze()].
+    analysis: Option<Analysis<'a>>,
+    /// Whether the statement that was just compiled never finishes normally, because it returns
+    /// or loops forever, so that any statement after it is unreachable. See
+    /// [Compiler::declarations_until()].
+    terminates: bool,
+    /// Options that change how code is compiled.
+    options: CompileOptions,
+}
+
+/// The state needed to compile one function (or the top-level script): the chunk being
+/// produced and the local variables in scope.
+struct FunctionCompiler<'a> {
+    kind: FunctionKind,
+    /// The name of the function. Empty for the script.
+    name: &'a str,
+    /// How many parameters the function takes.
+    arity: u8,
+    chunk: Chunk,
     locals: Vec<Local<'a>>,
     scope_depth: isize,
 }
 
+/// Whether we're compiling the top-level script or a function body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FunctionKind {
+    Script,
+    Function,
+}
+
+/// Whether an increment (`++`) or decrement (`--`) comes before or after its variable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Fixity {
+    /// `++i`: evaluates to the new value.
+    Prefix,
+    /// `i++`: evaluates to the old value.
+    Postfix,
+}
+
+/// Whether a variable was declared with `var` (or as a parameter, function, or class), or with
+/// `const`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mutability {
+    Variable,
+    /// Can never be assigned to after its declaration.
+    Constant,
+}
+
 #[derive(Clone)]
 struct Local<'a> {
-    name: Lexeme<'a>,
+    /// The name of the local. `None` for the slot that holds the function being called, which can
+    /// never be referred to by name.
+    name: Option<Lexeme<'a>>,
     depth: Option<isize>,
+    mutability: Mutability,
+    /// Whether the local is ever read.
+    used: bool,
 }
 
 /// Contains the parser state. For some strange reason, this also includes error status.
@@ -44,6 +340,8 @@ struct Parser<'a> {
     previous: Lexeme<'a>,
     had_error: bool,
     panic_mode: bool,
+    /// All errors (and warnings) encountered so far.
+    diagnostics: Vec<Diagnostic>,
     // We keep a reference to the active GC to make sure it exists, but we don't explicitly use it.
     _active_gc: &'a ActiveGC,
 }
@@ -78,6 +376,14 @@ enum Precedence {
     Equality,
     /// `<` `>` `<=` `>=`
     Comparison,
+    /// `|`
+    BitOr,
+    /// `^`
+    BitXor,
+    /// `&`
+    BitAnd,
+    /// `<<` `>>`
+    Shift,
     /// + -
     Term,
     /// `*` `/`
@@ -108,7 +414,11 @@ impl Precedence {
             Or => And,
             And => Equality,
             Equality => Comparison,
-            Comparison => Term,
+            Comparison => BitOr,
+            BitOr => BitXor,
+            BitXor => BitAnd,
+            BitAnd => Shift,
+            Shift => Term,
             Term => Factor,
             Factor => Unary,
             Unary => Call,
@@ -128,10 +438,9 @@ impl ParserRule {
 }
 
 impl<'a> Parser<'a> {
-    /// Creates a new parser for the given source code.
+    /// Creates a new parser for the source code of the given [Scanner].
     /// Note that parsing string literals requires an active GC.
-    fn new(source: &'a str, active_gc: &'a ActiveGC) -> Parser<'a> {
-        let mut scanner = Scanner::new(source);
+    fn new(mut scanner: Scanner<'a>, active_gc: &'a ActiveGC) -> Parser<'a> {
         let first_token = scanner.scan_token();
         let error_token = scanner.make_sentinel("<before first token>");
 
@@ -141,6 +450,7 @@ impl<'a> Parser<'a> {
             current: first_token,
             had_error: false,
             panic_mode: false,
+            diagnostics: Vec::new(),
             _active_gc: active_gc,
         }
     }
@@ -206,17 +516,32 @@ impl<'a> Parser<'a> {
 
         self.panic_mode = true;
         self.had_error = true;
+        self.report(lexeme, message, Severity::Error);
+    }
 
-        // Print the actual message:
-        eprint!("[line {}] Error:", lexeme.line());
-        if lexeme.token() == Token::Eof {
-            eprint!(" at end");
-        } else if lexeme.token() == Token::Error {
-            // Nothing
-        } else {
-            eprint!(" at '{}'", lexeme.text());
-        }
-        eprintln!(": {message}");
+    /// Emit a warning, located at the given [Lexeme]. Unlike errors, warnings do not stop the
+    /// code from compiling.
+    fn warning_at(&mut self, lexeme: Lexeme<'a>, message: &str) {
+        self.report(lexeme, message, Severity::Warning);
+    }
+
+    /// Records a diagnostic, located at the given [Lexeme].
+    fn report(&mut self, lexeme: Lexeme<'a>, message: &str, severity: Severity) {
+        let location = match lexeme.token() {
+            Token::Eof => " at end".to_owned(),
+            Token::Error => String::new(),
+            _ => format!(" at '{}'", lexeme.text()),
+        };
+        let diagnostic = Diagnostic {
+            line: lexeme.line(),
+            column: lexeme.column(),
+            span: lexeme.span(),
+            lexeme: lexeme.text().to_owned(),
+            location,
+            message: message.to_owned(),
+            severity,
+        };
+        self.diagnostics.push(diagnostic);
     }
 
     /// Synchronize after being in panic mode.
@@ -238,15 +563,19 @@ impl<'a> Parser<'a> {
 
             match self.current.token() {
                 Token::Class
+                | Token::Const
                 | Token::Fun
                 | Token::Var
                 | Token::For
                 | Token::If
+                | Token::Import
                 | Token::While
                 | Token::Print
                 | Token::Return => return,
                 _ => (), // continue panicing
             }
+
+            self.advance();
         }
     }
 }
@@ -256,24 +585,34 @@ impl<'a> Compiler<'a> {
     fn new(parser: Parser) -> Compiler {
         Compiler {
             parser,
-            compiling_chunk: Chunk::default(),
-            locals: Vec::with_capacity(U8_COUNT),
-            scope_depth: 0,
+            current: FunctionCompiler::new(FunctionKind::Script, ""),
+            enclosing: Vec::new(),
+            analysis: None,
+            terminates: false,
+            options: CompileOptions::default(),
         }
     }
 
-    /// Takes ownership of the compiler, and returns the chunk
-    fn compile(mut self) -> crate::Result<Chunk> {
-        while !self.match_and_advance(Token::Eof) {
-            self.declaration();
-        }
-        self.end_compiler();
+    /// Takes ownership of the compiler, and returns the chunk and its warnings, or every error
+    /// (and warning) encountered.
+    fn compile(mut self) -> Result<(Chunk, Vec<Diagnostic>), Vec<Diagnostic>> {
+        self.compile_declarations();
 
         if self.parser.had_error {
-            return Err(InterpretationError::CompileError);
+            return Err(self.parser.diagnostics);
         }
 
-        Ok(self.compiling_chunk)
+        Ok((self.current.chunk, self.parser.diagnostics))
+    }
+
+    /// Compiles every declaration until the end of the file.
+    fn compile_declarations(&mut self) {
+        self.declarations_until(Token::Eof);
+        self.parser.consume(Token::Eof, "Expect end of file.");
+        self.end_compiler();
+
+        // Warnings are found when scopes end, so they may be out of order:
+        (self.parser.diagnostics).sort_by_key(|diagnostic| diagnostic.span.start);
     }
 
     /// Signal the end of compilation.
@@ -283,9 +622,37 @@ impl<'a> Compiler<'a> {
     fn end_compiler(&mut self) {
         self.emit_return();
 
+        if self.options.optimize && !self.parser.had_error {
+            let chunk = std::mem::take(&mut self.current.chunk);
+            let chunk = optimize(chunk);
+            self.current.chunk =
+                if self.options.superinstructions && self.options.target == Target::Stack {
+                    fuse(chunk)
+                } else {
+                    chunk
+                };
+        }
+
+        if self.options.target == Target::Register && !self.parser.had_error {
+            // The registers in use from the start are the function and its arguments:
+            let locals = match self.current.kind {
+                FunctionKind::Script => 0,
+                FunctionKind::Function => 1 + self.current.arity as usize,
+            };
+            let code = rvm::translate(&self.current.chunk, locals);
+            self.current.chunk.set_registers(code);
+        }
+
         // Print a listing of the bytecode to manually inspect compiled output.
-        if cfg!(feature = "print_code") && !self.parser.had_error {
-            crate::debug::disassemble_chunk(self.current_chunk(), "code");
+        if self.options.print_code && !self.parser.had_error {
+            let name = match self.current.kind {
+                FunctionKind::Script => "code",
+                FunctionKind::Function => self.current.name,
+            };
+            crate::debug::disassemble_chunk(&self.current.chunk, name);
+            if let Some(code) = self.current.chunk.registers() {
+                print!("== {name} (registers) ==\n{code}");
+            }
         }
     }
 
@@ -294,29 +661,52 @@ impl<'a> Compiler<'a> {
     // decrements the counter. It would require interior mutability, however, and would be
     // needlessly complicated.
     fn begin_scope(&mut self) {
-        self.scope_depth += 1;
+        self.current.scope_depth += 1;
     }
 
     /// Pop one scope from the block.
     fn end_scope(&mut self) {
-        assert!(self.scope_depth > 0);
-        self.scope_depth -= 1;
+        assert!(self.current.scope_depth > 0);
+        self.current.scope_depth -= 1;
 
         // Clean up all local variables
         while self.has_locals_beyond_current_scope() {
             // The compile-time vector of locals will parallel the runtime stack;
             // so we both pop the compiler's stack AND the runtime stack! 🤯
-            self.locals.pop();
+            let local = self.current.locals.pop().unwrap();
+            self.warn_if_unused(&local);
             self.emit_instruction(OpCode::Pop);
         }
     }
 
+    /// Warns about a local that goes out of scope without ever being read, unless its name starts
+    /// with `_`.
+    fn warn_if_unused(&mut self, local: &Local<'a>) {
+        if !self.options.warnings || local.used {
+            return;
+        }
+        // After a syntax error, the "name" may be some other token:
+        let Some(name) = local.name.filter(|name| name.token() == Token::Identifier) else {
+            return;
+        };
+        if name.text().starts_with('_') {
+            return;
+        }
+
+        let message = format!(
+            "Unused variable `{}` (start its name with `_` if this is on purpose)",
+            name.text()
+        );
+        self.parser.warning_at(name, &message);
+    }
+
     /// Returns true if there is a local variable at a scope that is no longer accessible.
     fn has_locals_beyond_current_scope(&self) -> bool {
-        self.locals
+        self.current
+            .locals
             .last()
             .and_then(|local| local.depth)
-            .map(|depth| depth > self.scope_depth)
+            .map(|depth| depth > self.current.scope_depth)
             .unwrap_or(false)
     }
 
@@ -359,14 +749,14 @@ impl<'a> Compiler<'a> {
 
     /// Finds the index in the call stack for a local, or returns None if it's not a local (either
     /// a global or a mistake).
-    fn resolve_local(&mut self, name: Lexeme) -> Option<u8> {
-        for (i, local) in self.locals.iter().enumerate().rev() {
+    fn resolve_local(&mut self, name: Lexeme) -> Option<u16> {
+        for (i, local) in self.current.locals.iter().enumerate().rev() {
             if local.text() == name.text() {
                 if local.is_uninitialized() {
                     let message = format!("Cannot use `{}` in its own initializer", name.text());
                     self.parser.error(&message);
                 }
-                return u8::try_from(i).ok();
+                return u16::try_from(i).ok();
             }
         }
         None
@@ -374,7 +764,7 @@ impl<'a> Compiler<'a> {
 
     /// Indicate that we need a slot for another local variable.
     fn declare_variable(&mut self) {
-        if self.scope_depth == 0 {
+        if self.current.scope_depth == 0 {
             // Global variables don't need to be "declared"
             return;
         }
@@ -382,8 +772,8 @@ impl<'a> Compiler<'a> {
         let name = self.parser.previous;
 
         // Check whether we're redefining elements in the local scope:
-        for local in self.locals.iter().rev() {
-            if local.in_outer_scope(self.scope_depth) {
+        for local in self.current.locals.iter().rev() {
+            if local.in_outer_scope(self.current.scope_depth) {
                 // It's okay to shadow a variable from an outer scope.
                 break;
             }
@@ -400,15 +790,29 @@ impl<'a> Compiler<'a> {
     }
 
     fn add_local(&mut self, name: Lexeme<'a>) {
-        if self.local_count() >= U8_COUNT {
+        if self.local_count() >= LOCALS_MAX {
             self.parser
                 .error("Internal limit reached: too many variables declared");
             return;
         }
 
-        assert_eq!(Token::Identifier, name.token());
-        let local = Local { name, depth: None };
-        self.locals.push(local);
+        // After a syntax error, the name may be missing; the local still takes up its slot.
+        let slot = self.local_count() as u16;
+        self.record_declaration(
+            name,
+            Resolution::Local {
+                slot,
+                declaration: name.span(),
+            },
+        );
+
+        let local = Local {
+            name: Some(name),
+            depth: None,
+            mutability: Mutability::Variable,
+            used: false,
+        };
+        self.current.locals.push(local);
     }
 
     /// Consume the next identifer and interpret it as a variable.
@@ -418,25 +822,32 @@ impl<'a> Compiler<'a> {
         self.parser.consume(Token::Identifier, error_message);
 
         self.declare_variable();
-        if self.scope_depth > 0 {
+        if self.current.scope_depth > 0 {
             // In a local scope.
             return 0;
         }
 
+        self.record_declaration(self.parser.previous, Resolution::Global);
         self.identifier_constant(self.parser.previous)
     }
 
     /// Mark the last local as being initiailized.
     fn mark_initialized(&mut self) {
-        self.locals
+        if self.current.scope_depth == 0 {
+            // Globals are always initialized.
+            return;
+        }
+
+        self.current
+            .locals
             .last_mut()
             .unwrap()
-            .initialize_scope_with(self.scope_depth);
+            .initialize_scope_with(self.current.scope_depth);
     }
 
     /// Define a new variable.
     fn define_variable(&mut self, global: u8) {
-        if self.scope_depth > 0 {
+        if self.current.scope_depth > 0 {
             // It's a local variables. Set that it's ready to be used:
             self.mark_initialized();
             return;
@@ -446,35 +857,147 @@ impl<'a> Compiler<'a> {
             .with_operand(global);
     }
 
+    /// Define a new constant. Constant locals are checked at compile time (see
+    /// [Compiler::check_assignable()]); constant globals are checked by the VM.
+    fn define_constant(&mut self, global: u8) {
+        if self.current.scope_depth > 0 {
+            self.mark_initialized();
+            self.current.locals.last_mut().unwrap().mutability = Mutability::Constant;
+            return;
+        }
+
+        self.emit_instruction(OpCode::DefineConstGlobal)
+            .with_operand(global);
+    }
+
     /// Parse a variable. This could either be a variable access or an assignment, depending on
     /// `can_assign` and the syntactic context.
-    fn named_variable(&mut self, name: Lexeme, can_assign: bool) {
-        let (get_op, set_op, arg) = {
-            if let Some(arg) = self.resolve_local(name) {
-                (OpCode::GetLocal, OpCode::SetLocal, arg)
-            } else {
-                let arg = self.identifier_constant(name);
-                (OpCode::GetGlobal, OpCode::SetGlobal, arg)
-            }
-        };
+    fn named_variable(&mut self, name: Lexeme<'a>, can_assign: bool) {
+        let variable = self.resolve_variable(name);
+        let (get_op, set_op, arg) = variable;
 
         // Peek ahead and look if we're assigning.
         // This only works if we're parsing at a lower or equal precedence to assignment.
         if can_assign && self.match_and_advance(Token::Equal) {
             // We're in an assignment expression!
+            self.check_assignable(name, set_op, arg);
             // Parse the right-hand side:
             self.expression();
-            self.emit_instruction(set_op).with_operand(arg);
+            self.emit_variable(set_op, arg);
+        } else if self.match_and_advance(Token::PlusPlus) {
+            self.increment(name, variable, OpCode::Add, Fixity::Postfix);
+        } else if self.match_and_advance(Token::MinusMinus) {
+            self.increment(name, variable, OpCode::Subtract, Fixity::Postfix);
         } else {
             // A reference to an existing variable.
-            self.emit_instruction(get_op).with_operand(arg);
+            self.emit_variable(get_op, arg);
+        }
+    }
+
+    /// Emits an increment (with [OpCode::Add]) or decrement (with [OpCode::Subtract]) of the
+    /// variable, as resolved by [Compiler::resolve_variable()]. Prefix increments leave the new
+    /// value on the stack; postfix increments leave the old value.
+    fn increment(
+        &mut self,
+        name: Lexeme<'a>,
+        (get_op, set_op, arg): (OpCode, OpCode, u16),
+        operator: OpCode,
+        fixity: Fixity,
+    ) {
+        self.check_assignable(name, set_op, arg);
+
+        self.emit_variable(get_op, arg);
+        if fixity == Fixity::Postfix {
+            // Keep a copy of the old value underneath the new value:
+            self.emit_variable(get_op, arg);
+        }
+        self.emit_constant(1.0.into());
+        self.emit_instruction(operator);
+        self.emit_variable(set_op, arg);
+        if fixity == Fixity::Postfix {
+            self.emit_instruction(OpCode::Pop);
+        }
+    }
+
+    /// Figures out whether the name is a local or a global variable. Returns the opcodes to get
+    /// and set the variable, and their operand (see [Compiler::emit_variable()]).
+    fn resolve_variable(&mut self, name: Lexeme<'a>) -> (OpCode, OpCode, u16) {
+        let (get_op, set_op, arg) = {
+            match self.resolve_local(name) {
+                Some(arg) if arg <= u8::MAX.into() => (OpCode::GetLocal, OpCode::SetLocal, arg),
+                Some(arg) => (OpCode::GetLocalLong, OpCode::SetLocalLong, arg),
+                None => {
+                    let arg = self.identifier_constant(name);
+                    (OpCode::GetGlobal, OpCode::SetGlobal, arg.into())
+                }
+            }
+        };
+
+        if self.analysis.is_some() {
+            let resolution = match get_op {
+                OpCode::GetLocal | OpCode::GetLocalLong => Resolution::Local {
+                    slot: arg,
+                    declaration: self.current.locals[arg as usize]
+                        .name
+                        .expect("only named locals can be resolved")
+                        .span(),
+                },
+                _ => Resolution::Global,
+            };
+            self.record_reference(name, resolution);
+        }
+
+        (get_op, set_op, arg)
+    }
+
+    /// Reports an error if the variable that `set_op` assigns to is a constant local, pointing out
+    /// where it was declared. Constant globals can only be checked at runtime, since any script
+    /// may define a global.
+    fn check_assignable(&mut self, name: Lexeme<'a>, set_op: OpCode, arg: u16) {
+        if !matches!(set_op, OpCode::SetLocal | OpCode::SetLocalLong) {
+            return;
+        }
+
+        let local = &self.current.locals[arg as usize];
+        if local.mutability == Mutability::Constant {
+            let line = local.name.map(|name| name.line()).unwrap_or_default();
+            let message = format!(
+                "Cannot assign to `{}`, which is a constant declared on line {line}",
+                name.text()
+            );
+            self.parser.error_at(name, &message);
+        }
+    }
+
+    /// Records a variable declaration, if we're analyzing.
+    fn record_declaration(&mut self, name: Lexeme<'a>, resolution: Resolution) {
+        // After a syntax error, the "name" may be some other token:
+        if name.token() != Token::Identifier {
+            return;
+        }
+
+        if let Some(analysis) = self.analysis.as_mut() {
+            analysis.declarations.push(Name::new(name, resolution));
+        }
+    }
+
+    /// Records a use of a variable, if we're analyzing.
+    fn record_reference(&mut self, name: Lexeme<'a>, resolution: Resolution) {
+        if let Some(analysis) = self.analysis.as_mut() {
+            analysis.references.push(Name::new(name, resolution));
         }
     }
 
     /// Parse a declaration.
     fn declaration(&mut self) {
-        if self.match_and_advance(Token::Var) {
-            self.var_statement();
+        if self.match_and_advance(Token::Class) {
+            self.class_declaration();
+        } else if self.match_and_advance(Token::Fun) {
+            self.fun_declaration();
+        } else if self.match_and_advance(Token::Var) {
+            self.var_statement(Mutability::Variable);
+        } else if self.match_and_advance(Token::Const) {
+            self.var_statement(Mutability::Constant);
         } else {
             self.statement();
         }
@@ -488,6 +1011,14 @@ impl<'a> Compiler<'a> {
     fn statement(&mut self) {
         if self.match_and_advance(Token::Print) {
             self.print_statement();
+        } else if self.match_and_advance(Token::Import) {
+            self.import_statement();
+        } else if self.match_and_advance(Token::Return) {
+            self.return_statement();
+        } else if self.match_and_advance(Token::For) {
+            self.for_statement();
+        } else if self.match_and_advance(Token::While) {
+            self.while_statement();
         } else if self.match_and_advance(Token::LeftBrace) {
             self.begin_scope();
             self.block();
@@ -505,20 +1036,162 @@ impl<'a> Compiler<'a> {
     /// Parse a block.
     /// Assumes a new scope has already been created for this block.
     fn block(&mut self) {
-        while !self.parser.check(Token::RightBrace) && !self.parser.check(Token::Eof) {
+        self.declarations_until(Token::RightBrace);
+
+        self.parser
+            .consume(Token::RightBrace, "expected '}' to end block");
+    }
+
+    /// Parse declarations until the given token (or the end of the file), without consuming it.
+    /// Warns about the first declaration that can never run, because one before it never
+    /// finishes. Then the sequence as a whole never finishes, either.
+    fn declarations_until(&mut self, end: Token) {
+        let mut terminates = false;
+        let mut warned = false;
+        while !self.parser.check(end) && !self.parser.check(Token::Eof) {
+            if terminates && !warned && self.options.warnings {
+                self.parser
+                    .warning_at(self.parser.current, "Unreachable code");
+                warned = true;
+            }
+
+            self.terminates = false;
             self.declaration();
+            terminates |= self.terminates;
+        }
+        self.terminates = terminates;
+    }
+
+    /// Parse the condition of a loop. Returns true if the condition is just `true`, which means
+    /// that the loop never ends (there is no `break`).
+    fn loop_condition(&mut self) -> bool {
+        let start = self.parser.current;
+        self.expression();
+        start.token() == Token::True && self.parser.previous.span() == start.span()
+    }
+
+    /// Parse the arguments of a function call. Assumes '(' has already been consumed.
+    /// Returns how many arguments there were.
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: usize = 0;
+        if !self.parser.check(Token::RightParen) {
+            loop {
+                self.expression();
+                if arg_count == u8::MAX as usize {
+                    self.parser.error("Can't have more than 255 arguments");
+                }
+                arg_count += 1;
+
+                if !self.match_and_advance(Token::Comma) {
+                    break;
+                }
+            }
         }
 
         self.parser
-            .consume(Token::RightBrace, "expected '}' to end block");
+            .consume(Token::RightParen, "Expect ')' after arguments.");
+        arg_count.min(u8::MAX as usize) as u8
+    }
+
+    /// Parse a class declaration. Assumes `class` has already been consumed.
+    fn class_declaration(&mut self) {
+        self.parser.consume(Token::Identifier, "Expect class name.");
+        let class_name = self.parser.previous;
+        // Unlike other variables, the name is needed even for locals, to name the class itself:
+        let name_constant = self.identifier_constant(class_name);
+        self.declare_variable();
+        if self.current.scope_depth == 0 {
+            self.record_declaration(class_name, Resolution::Global);
+        }
+
+        self.emit_instruction(OpCode::Class)
+            .with_operand(name_constant);
+        self.define_variable(name_constant);
+
+        self.parser
+            .consume(Token::LeftBrace, "Expect '{' before class body.");
+        self.parser
+            .consume(Token::RightBrace, "Expect '}' after class body.");
     }
 
-    /// Parse a variable declaration. Assumes `var` has already been consumed
-    fn var_statement(&mut self) {
-        let global = self.parse_variable("need a variable name after var");
+    /// Parse a function declaration. Assumes `fun` has already been consumed.
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        // A function can refer to itself (recursion!), so it's initialized before its body.
+        self.mark_initialized();
+        self.function(FunctionKind::Function);
+        if self.current.scope_depth == 0 {
+            // Locals were already marked initialized; only globals still need to be defined.
+            self.define_variable(global);
+        }
+    }
+
+    /// Compile a function's parameters and body, and emit the resulting function as a constant.
+    /// Assumes the function's name has already been consumed.
+    fn function(&mut self, kind: FunctionKind) {
+        let function_compiler = FunctionCompiler::new(kind, self.parser.previous.text());
+        let enclosing = std::mem::replace(&mut self.current, function_compiler);
+        self.enclosing.push(enclosing);
+        self.begin_scope();
+
+        self.parser
+            .consume(Token::LeftParen, "Expect '(' after function name.");
+        if !self.parser.check(Token::RightParen) {
+            loop {
+                if self.current.arity == u8::MAX {
+                    self.parser
+                        .error_at_current("Can't have more than 255 parameters.");
+                } else {
+                    self.current.arity += 1;
+                }
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+
+                if !self.match_and_advance(Token::Comma) {
+                    break;
+                }
+            }
+        }
+        self.parser
+            .consume(Token::RightParen, "Expect ')' after parameters.");
+        self.parser
+            .consume(Token::LeftBrace, "Expect '{' before function body.");
+        // After a syntax error in the name or parameters, the parameters may not be what the
+        // programmer meant, so don't warn about them:
+        let header_is_valid = !self.parser.panic_mode;
+        self.block();
+
+        // No need to end the scope: the VM discards all of the function's locals when it returns.
+        let locals = std::mem::take(&mut self.current.locals);
+        if header_is_valid {
+            for local in locals {
+                self.warn_if_unused(&local);
+            }
+        }
+        // Even if the body never finishes, declaring the function does:
+        self.terminates = false;
+        self.end_compiler();
+        let enclosing = self
+            .enclosing
+            .pop()
+            .expect("function must have an enclosing compiler");
+        let function = std::mem::replace(&mut self.current, enclosing).into_function();
+        self.emit_constant(Value::Function(ActiveGC::store_function(function)));
+    }
+
+    /// Parse a variable declaration. Assumes `var` (or `const`) has already been consumed
+    fn var_statement(&mut self, mutability: Mutability) {
+        let global = match mutability {
+            Mutability::Variable => self.parse_variable("need a variable name after var"),
+            Mutability::Constant => self.parse_variable("need a constant name after const"),
+        };
 
         if self.match_and_advance(Token::Equal) {
             self.expression();
+        } else if mutability == Mutability::Constant {
+            // It could never be anything else:
+            self.parser
+                .error_at_current("need a value for this constant");
         } else {
             self.emit_instruction(OpCode::Nil);
         }
@@ -526,7 +1199,10 @@ impl<'a> Compiler<'a> {
         self.parser
             .consume(Token::Semicolon, "expect ; after this variable declaration");
 
-        self.define_variable(global);
+        match mutability {
+            Mutability::Variable => self.define_variable(global),
+            Mutability::Constant => self.define_constant(global),
+        }
     }
 
     /// Parse an expression statement (e.g., assignments, function calls).
@@ -540,7 +1216,13 @@ impl<'a> Compiler<'a> {
         );
         // Expressions have 0 stack effect, meaning they can't leave anything on the stack.
         // Expressions produce a thing on the stack, and we need to get rid of it!
-        self.emit_instruction(OpCode::Pop);
+        let at_top_level = self.enclosing.is_empty() && self.current.scope_depth == 0;
+        if self.options.repl && at_top_level {
+            // ...unless we're in the REPL, where the user wants to see it!
+            self.emit_instruction(OpCode::Print);
+        } else {
+            self.emit_instruction(OpCode::Pop);
+        }
     }
 
     /// Parse a print statement. Assumes `print` has already been consumed.
@@ -553,8 +1235,123 @@ impl<'a> Compiler<'a> {
         self.emit_instruction(OpCode::Print);
     }
 
-    /// Appends [OpCode::Return] to current [Chunk].
+    /// Parse an import statement, like `import "util.lox";`. Assumes `import` has already been
+    /// consumed.
+    fn import_statement(&mut self) {
+        self.parser
+            .consume(Token::StrLiteral, "Expect module name after 'import'.");
+        let name = self.parser.previous.text().trim_matches('"');
+        let index = self.make_constant(name.into());
+        self.parser
+            .consume(Token::Semicolon, "Expect ';' after module name.");
+
+        self.emit_instruction(OpCode::Import).with_operand(index);
+        // Modules do not produce a value:
+        self.emit_instruction(OpCode::Pop);
+    }
+
+    /// Parse a return statement. Assumes `return` has already been consumed.
+    fn return_statement(&mut self) {
+        if self.current.kind == FunctionKind::Script {
+            self.parser.error("Can't return from top-level code.");
+        }
+
+        if self.match_and_advance(Token::Semicolon) {
+            self.emit_return();
+        } else {
+            self.expression();
+            self.parser
+                .consume(Token::Semicolon, "Expect ';' after return value.");
+            self.emit_instruction(OpCode::Return);
+        }
+        self.terminates = true;
+    }
+
+    /// Parse a while loop. Assumes `while` has already been consumed.
+    fn while_statement(&mut self) {
+        let loop_start = self.current_chunk().len();
+        self.parser
+            .consume(Token::LeftParen, "Expect '(' after 'while'.");
+        let forever = self.loop_condition();
+        self.parser
+            .consume(Token::RightParen, "Expect ')' after condition.");
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_instruction(OpCode::Pop);
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_instruction(OpCode::Pop);
+        // Whether or not the body finishes, the loop only does if its condition can be false:
+        self.terminates = forever;
+    }
+
+    /// Parse a for loop. Assumes `for` has already been consumed.
+    ///
+    /// There is no special opcode for for loops; they are desugared into jumps, like a while loop
+    /// with an initializer and an increment.
+    fn for_statement(&mut self) {
+        // Any variable declared in the initializer is scoped to the loop.
+        self.begin_scope();
+        self.parser
+            .consume(Token::LeftParen, "Expect '(' after 'for'.");
+
+        // Initializer clause:
+        if self.match_and_advance(Token::Semicolon) {
+            // No initializer.
+        } else if self.match_and_advance(Token::Var) {
+            self.var_statement(Mutability::Variable);
+        } else {
+            self.expression_statement();
+        }
+
+        // Condition clause:
+        let mut loop_start = self.current_chunk().len();
+        let mut exit_jump = None;
+        let mut forever = true;
+        if !self.match_and_advance(Token::Semicolon) {
+            forever = self.loop_condition();
+            self.parser
+                .consume(Token::Semicolon, "Expect ';' after loop condition.");
+
+            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse));
+            self.emit_instruction(OpCode::Pop);
+        }
+
+        // Increment clause. It's written before the body, but executed after it, so jump over it
+        // now, and loop back to it after the body:
+        if !self.match_and_advance(Token::RightParen) {
+            let body_jump = self.emit_jump(OpCode::Jump);
+            let increment_start = self.current_chunk().len();
+            self.expression();
+            self.emit_instruction(OpCode::Pop);
+            self.parser
+                .consume(Token::RightParen, "Expect ')' after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.statement();
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_instruction(OpCode::Pop);
+        }
+
+        self.end_scope();
+        self.terminates = forever;
+    }
+
+    /// Appends [OpCode::Return] to current [Chunk]. Functions return `nil` by default; the
+    /// script returns nothing.
     fn emit_return(&mut self) {
+        if self.current.kind == FunctionKind::Function {
+            self.emit_instruction(OpCode::Nil);
+        }
         self.emit_instruction(OpCode::Return);
     }
 
@@ -569,29 +1366,80 @@ impl<'a> Compiler<'a> {
     /// # Error
     ///
     /// When the constant index is greater than 255 (and thus can no longer be represented as a
-    /// u8), this signals a compiler error and returns `0u8`. The current [Chunk] can still be
+    /// u8), or reaches [CompileOptions::max_constants], this signals a compiler error and returns
+    /// `0u8`. The current [Chunk] can still be
     /// appended to, however, it is invalid, and should not be emitted as a valid program.
     fn make_constant(&mut self, value: Value) -> u8 {
-        if let Some(index) = self.current_chunk().add_constant(value) {
-            index
-        } else {
-            self.parser.error("Too many constants in one chunk");
-            0
+        let max_constants = self.options.max_constants;
+        match self.current_chunk().add_constant(value) {
+            Some(index) if usize::from(index) < max_constants => index,
+            _ => {
+                self.parser.error("Too many constants in one chunk");
+                0
+            }
+        }
+    }
+
+    /// Writes an instruction that gets or sets a variable, with an operand from
+    /// [Compiler::resolve_variable()]: two bytes for the long opcodes, otherwise one.
+    fn emit_variable(&mut self, opcode: OpCode, arg: u16) {
+        if matches!(opcode, OpCode::GetLocal | OpCode::GetLocalLong) {
+            // See Compiler::warn_if_unused():
+            self.current.locals[arg as usize].used = true;
+        }
+        let written = self.emit_instruction(opcode);
+        match opcode.operand_len() {
+            2 => written.with_u16_operand(arg),
+            _ => written.with_operand(arg as u8),
         }
     }
 
     /// Writes an [OpCode] to the current [Chunk].
     /// Returns a [WrittenOpcode], with which you can write an operand.
-    fn emit_instruction(&mut self, opcode: OpCode) -> WrittenOpcode {
+    fn emit_instruction(&mut self, opcode: OpCode) -> WrittenOpcode<'_> {
         let line = self.line_number_of_prefix();
-        self.current_chunk().write_opcode(opcode, line)
+        let span = self.parser.previous.span();
+        self.current_chunk()
+            .write_opcode_with_span(opcode, line, span)
+    }
+
+    /// Writes a jump instruction with a placeholder offset. Returns the location of the offset,
+    /// which must be patched later with [Compiler::patch_jump()].
+    fn emit_jump(&mut self, opcode: OpCode) -> usize {
+        self.emit_instruction(opcode).with_u16_operand(u16::MAX);
+        self.current_chunk().len() - 2
+    }
+
+    /// Patches the jump whose offset is at the given location, so that it jumps to the next
+    /// instruction to be written.
+    fn patch_jump(&mut self, offset: usize) {
+        // -2 to adjust for the jump offset itself.
+        let jump = self.current_chunk().len() - offset - 2;
+        match u16::try_from(jump) {
+            Ok(jump) => self.current_chunk().patch_u16(offset, jump),
+            Err(_) => self.parser.error("Too much code to jump over."),
+        }
+    }
+
+    /// Writes an [OpCode::Loop] that jumps back to `loop_start`.
+    fn emit_loop(&mut self, loop_start: usize) {
+        // +3 to jump over the Loop instruction and its operand, too.
+        let jump = self.current_chunk().len() - loop_start + 3;
+        let jump = u16::try_from(jump).unwrap_or_else(|_| {
+            self.parser.error("Loop body too large.");
+            0
+        });
+        self.emit_instruction(OpCode::Loop).with_u16_operand(jump);
     }
 
-    /// Writes two [OpCode] to the current [Chunk].
-    fn emit_instructions(&mut self, op1: OpCode, op2: OpCode) -> WrittenOpcode {
+    /// Writes the [OpCode]s for an operator to the current [Chunk]. The instructions come from
+    /// the operator, so that runtime errors point to it, rather than the last operand.
+    fn emit_operator(&mut self, operator: Range<usize>, opcodes: &[OpCode]) {
         let line = self.line_number_of_prefix();
-        self.current_chunk().write_opcode(op1, line);
-        self.current_chunk().write_opcode(op2, line)
+        for &opcode in opcodes {
+            self.current_chunk()
+                .write_opcode_with_span(opcode, line, operator.clone());
+        }
     }
 
     ///////////////////////////////////////// Aliases /////////////////////////////////////////////
@@ -599,7 +1447,7 @@ impl<'a> Compiler<'a> {
     /// Returns the current [Chunk].
     #[inline(always)]
     fn current_chunk(&mut self) -> &mut Chunk {
-        &mut self.compiling_chunk
+        &mut self.current.chunk
     }
 
     /// Advance one token in scanner, such that:
@@ -644,7 +1492,41 @@ impl<'a> Compiler<'a> {
     /// Return how many locals there are in all scopes.
     #[inline(always)]
     fn local_count(&self) -> usize {
-        self.locals.len()
+        self.current.locals.len()
+    }
+}
+
+impl<'a> FunctionCompiler<'a> {
+    /// Creates the state to compile a function (or script) with the given name.
+    fn new(kind: FunctionKind, name: &'a str) -> Self {
+        let mut locals = Vec::with_capacity(U8_COUNT);
+        if kind == FunctionKind::Function {
+            // The first slot holds the function being called.
+            locals.push(Local {
+                name: None,
+                depth: Some(0),
+                mutability: Mutability::Variable,
+                used: true,
+            });
+        }
+
+        FunctionCompiler {
+            kind,
+            name,
+            arity: 0,
+            chunk: Chunk::default(),
+            locals,
+            scope_depth: 0,
+        }
+    }
+
+    /// Returns the compiled function. Its name is copied into the [ActiveGC].
+    fn into_function(self) -> Function {
+        Function {
+            name: ActiveGC::store_string(self.name.to_owned()),
+            arity: self.arity,
+            chunk: self.chunk,
+        }
     }
 }
 
@@ -672,7 +1554,18 @@ impl<'a> Local<'a> {
 
     /// Return the name of this local.
     fn text(&self) -> &'a str {
-        self.name.text()
+        self.name.map(|name| name.text()).unwrap_or_default()
+    }
+}
+
+impl<'a> Name<'a> {
+    /// Creates a name from the lexeme where it was found.
+    fn new(lexeme: Lexeme<'a>, resolution: Resolution) -> Self {
+        Name {
+            name: lexeme.text(),
+            span: lexeme.span(),
+            resolution,
+        }
     }
 }
 
@@ -694,37 +1587,49 @@ fn get_rule(token: Token) -> ParserRule {
     use Token::*;
     match token {
         //                     Prefix          Infix         Precedence
-        LeftParen    => rule!{ Some(grouping), None,         Precedence::None },
+        LeftParen    => rule!{ Some(grouping), Some(call),   Precedence::Call },
         RightParen   => rule!{ None,           None,         Precedence::None },
-        LeftBrace    => rule!{ None,           None,         Precedence::None },
+        LeftBrace    => rule!{ Some(map),      None,         Precedence::None },
         RightBrace   => rule!{ None,           None,         Precedence::None },
+        LeftBracket  => rule!{ Some(list),     Some(index),  Precedence::Call },
+        RightBracket => rule!{ None,           None,         Precedence::None },
+        Colon        => rule!{ None,           None,         Precedence::None },
         Comma        => rule!{ None,           None,         Precedence::None },
-        Dot          => rule!{ None,           None,         Precedence::None },
+        Dot          => rule!{ None,           Some(dot),    Precedence::Call },
         Minus        => rule!{ Some(unary),    Some(binary), Precedence::Term },
         Plus         => rule!{ None,           Some(binary), Precedence::Term },
+        MinusMinus   => rule!{ Some(prefix_increment), Some(invalid_increment), Precedence::Call },
+        PlusPlus     => rule!{ Some(prefix_increment), Some(invalid_increment), Precedence::Call },
         Semicolon    => rule!{ None,           None,         Precedence::None },
         Slash        => rule!{ None,           Some(binary), Precedence::Factor },
         Star         => rule!{ None,           Some(binary), Precedence::Factor },
+        Ampersand    => rule!{ None,           Some(binary), Precedence::BitAnd },
+        Pipe         => rule!{ None,           Some(binary), Precedence::BitOr },
+        Caret        => rule!{ None,           Some(binary), Precedence::BitXor },
         Bang         => rule!{ Some(unary),    None,         Precedence::None },
         BangEqual    => rule!{ None,           Some(binary), Precedence::Equality },
         Equal        => rule!{ None,           None,         Precedence::None },
         EqualEqual   => rule!{ None,           Some(binary), Precedence::Equality },
         Greater      => rule!{ None,           Some(binary), Precedence::Comparison },
         GreaterEqual => rule!{ None,           Some(binary), Precedence::Comparison },
+        GreaterGreater => rule!{ None,         Some(binary), Precedence::Shift },
         Less         => rule!{ None,           Some(binary), Precedence::Comparison },
         LessEqual    => rule!{ None,           Some(binary), Precedence::Comparison },
+        LessLess     => rule!{ None,           Some(binary), Precedence::Shift },
         Identifier   => rule!{ Some(variable), None,         Precedence::None },
         StrLiteral   => rule!{ Some(string),   None,         Precedence::None },
         Number       => rule!{ Some(number),   None,         Precedence::None },
-        And          => rule!{ None,           None,         Precedence::None },
+        And          => rule!{ None,           Some(and),    Precedence::And },
         Class        => rule!{ None,           None,         Precedence::None },
+        Const        => rule!{ None,           None,         Precedence::None },
         Else         => rule!{ None,           None,         Precedence::None },
         False        => rule!{ Some(literal),  None,         Precedence::None },
         For          => rule!{ None,           None,         Precedence::None },
         Fun          => rule!{ None,           None,         Precedence::None },
         If           => rule!{ None,           None,         Precedence::None },
+        Import       => rule!{ None,           None,         Precedence::None },
         Nil          => rule!{ Some(literal),  None,         Precedence::None },
-        Or           => rule!{ None,           None,         Precedence::None },
+        Or           => rule!{ None,           Some(or),     Precedence::Or },
         Print        => rule!{ None,           None,         Precedence::None },
         Return       => rule!{ None,           None,         Precedence::None },
         Super        => rule!{ None,           None,         Precedence::None },
@@ -732,6 +1637,8 @@ fn get_rule(token: Token) -> ParserRule {
         True         => rule!{ Some(literal),  None,         Precedence::None },
         Var          => rule!{ None,           None,         Precedence::None },
         While        => rule!{ None,           None,         Precedence::None },
+        Comment      => rule!{ None,           None,         Precedence::None },
+        Whitespace   => rule!{ None,           None,         Precedence::None },
         Error        => rule!{ None,           None,         Precedence::None },
         Eof          => rule!{ None,           None,         Precedence::None },
     }
@@ -746,9 +1653,119 @@ fn grouping(compiler: &mut Compiler, _can_assign: bool) {
         .consume(Token::RightParen, "Expect ')' after grouping.");
 }
 
-/// Parse a number literal as a prefix. Assumes number has been consumed.
+/// Parse a function call as an infix. Assumes '(' has been consumed.
+fn call(compiler: &mut Compiler, _can_assign: bool) {
+    let arg_count = compiler.argument_list();
+    compiler
+        .emit_instruction(OpCode::Call)
+        .with_operand(arg_count);
+}
+
+/// Parse a property access or assignment as an infix. Assumes '.' has been consumed.
+fn dot(compiler: &mut Compiler, can_assign: bool) {
+    compiler
+        .parser
+        .consume(Token::Identifier, "Expect property name after '.'.");
+    let name = compiler.identifier_constant(compiler.parser.previous);
+
+    if can_assign && compiler.match_and_advance(Token::Equal) {
+        compiler.expression();
+        compiler
+            .emit_instruction(OpCode::SetProperty)
+            .with_operand(name);
+    } else {
+        compiler
+            .emit_instruction(OpCode::GetProperty)
+            .with_operand(name);
+    }
+}
+
+/// Parse a list literal as a prefix. Assumes '[' has been consumed.
+fn list(compiler: &mut Compiler, _can_assign: bool) {
+    let mut item_count: usize = 0;
+    // A trailing comma is allowed, e.g., `[1, 2, 3,]`:
+    while !compiler.parser.check(Token::RightBracket) {
+        compiler.expression();
+        if item_count == u8::MAX as usize {
+            compiler
+                .parser
+                .error("Can't have more than 255 items in a list literal.");
+        }
+        item_count += 1;
+
+        if !compiler.match_and_advance(Token::Comma) {
+            break;
+        }
+    }
+
+    compiler
+        .parser
+        .consume(Token::RightBracket, "Expect ']' after list items.");
+    compiler
+        .emit_instruction(OpCode::BuildList)
+        .with_operand(item_count.min(u8::MAX as usize) as u8);
+}
+
+/// Parse a map literal as a prefix. Assumes '{' has been consumed.
+///
+/// Note that a statement that starts with '{' is always a block, never a map.
+fn map(compiler: &mut Compiler, _can_assign: bool) {
+    let mut entry_count: usize = 0;
+    // A trailing comma is allowed, e.g., `{"a": 1,}`:
+    while !compiler.parser.check(Token::RightBrace) {
+        compiler.expression();
+        compiler
+            .parser
+            .consume(Token::Colon, "Expect ':' after map key.");
+        compiler.expression();
+        if entry_count == u8::MAX as usize {
+            compiler
+                .parser
+                .error("Can't have more than 255 entries in a map literal.");
+        }
+        entry_count += 1;
+
+        if !compiler.match_and_advance(Token::Comma) {
+            break;
+        }
+    }
+
+    compiler
+        .parser
+        .consume(Token::RightBrace, "Expect '}' after map entries.");
+    compiler
+        .emit_instruction(OpCode::BuildMap)
+        .with_operand(entry_count.min(u8::MAX as usize) as u8);
+}
+
+/// Parse a subscript, like `list[i]` or `map[key]`, as an infix. Assumes '[' has been consumed.
+fn index(compiler: &mut Compiler, can_assign: bool) {
+    compiler.expression();
+    compiler
+        .parser
+        .consume(Token::RightBracket, "Expect ']' after index.");
+
+    if can_assign && compiler.match_and_advance(Token::Equal) {
+        compiler.expression();
+        compiler.emit_instruction(OpCode::IndexSet);
+    } else {
+        compiler.emit_instruction(OpCode::IndexGet);
+    }
+}
+
+/// Parse a number literal as a prefix. Assumes number has been consumed. With the `integers`
+/// feature, a literal without a decimal point is an integer.
 fn number(compiler: &mut Compiler, _can_assign: bool) {
     debug_assert_eq!(Token::Number, compiler.previous_token());
+    #[cfg(feature = "integers")]
+    if !compiler.parser.previous.text().contains('.') {
+        match compiler.parser.previous.text().parse::<i64>() {
+            Ok(integer) => compiler.emit_constant(integer.into()),
+            Err(_) => compiler.parser.error("Integer literal is too large."),
+        }
+        return;
+    }
+
     let value = compiler
         .parser
         .previous
@@ -761,36 +1778,72 @@ fn number(compiler: &mut Compiler, _can_assign: bool) {
 /// Parse an unary operator as a prefix. Assumes the operator has been consumed.
 fn unary(compiler: &mut Compiler, _can_assign: bool) {
     let operator = compiler.previous_token();
+    let span = compiler.parser.previous.span();
 
     // Compile the operand, so that it's placed on the stack.
     compiler.parse_precedence(Precedence::Unary);
 
-    match operator {
-        Token::Bang => compiler.emit_instruction(OpCode::Not),
-        Token::Minus => compiler.emit_instruction(OpCode::Negate),
+    let opcode = match operator {
+        Token::Bang => OpCode::Not,
+        Token::Minus => OpCode::Negate,
         _ => unreachable!(),
     };
+    compiler.emit_operator(span, &[opcode]);
 }
 
 /// Parse a binary operator as an infix. Assumes the operator has been consumed.
 fn binary(compiler: &mut Compiler, _can_assign: bool) {
     let operator = compiler.previous_token();
+    let span = compiler.parser.previous.span();
     let rule = get_rule(operator);
 
     compiler.parse_precedence(rule.higher_precedence());
-    match operator {
-        Token::BangEqual => compiler.emit_instructions(OpCode::Equal, OpCode::Not),
-        Token::EqualEqual => compiler.emit_instruction(OpCode::Equal),
-        Token::Greater => compiler.emit_instruction(OpCode::Greater),
-        Token::GreaterEqual => compiler.emit_instructions(OpCode::Less, OpCode::Not),
-        Token::Less => compiler.emit_instruction(OpCode::Less),
-        Token::LessEqual => compiler.emit_instructions(OpCode::Greater, OpCode::Not),
-        Token::Plus => compiler.emit_instruction(OpCode::Add),
-        Token::Minus => compiler.emit_instruction(OpCode::Subtract),
-        Token::Star => compiler.emit_instruction(OpCode::Multiply),
-        Token::Slash => compiler.emit_instruction(OpCode::Divide),
+    let opcodes: &[OpCode] = match operator {
+        Token::BangEqual => &[OpCode::Equal, OpCode::Not],
+        Token::EqualEqual => &[OpCode::Equal],
+        Token::Greater => &[OpCode::Greater],
+        Token::GreaterEqual => &[OpCode::Less, OpCode::Not],
+        Token::Less => &[OpCode::Less],
+        Token::LessEqual => &[OpCode::Greater, OpCode::Not],
+        Token::Plus => &[OpCode::Add],
+        Token::Minus => &[OpCode::Subtract],
+        Token::Star => &[OpCode::Multiply],
+        Token::Slash => &[OpCode::Divide],
+        Token::Ampersand => &[OpCode::BitAnd],
+        Token::Pipe => &[OpCode::BitOr],
+        Token::Caret => &[OpCode::BitXor],
+        Token::LessLess => &[OpCode::ShiftLeft],
+        Token::GreaterGreater => &[OpCode::ShiftRight],
         _ => unreachable!(),
     };
+    compiler.emit_operator(span, opcodes);
+}
+
+/// Parse `and` as an infix. Assumes the left operand has been compiled, and `and` has been
+/// consumed.
+///
+/// If the left operand is falsy, it is the result, and the right operand is skipped.
+fn and(compiler: &mut Compiler, _can_assign: bool) {
+    let end_jump = compiler.emit_jump(OpCode::JumpIfFalse);
+
+    compiler.emit_instruction(OpCode::Pop);
+    compiler.parse_precedence(Precedence::And);
+
+    compiler.patch_jump(end_jump);
+}
+
+/// Parse `or` as an infix. Assumes the left operand has been compiled, and `or` has been consumed.
+///
+/// If the left operand is truthy, it is the result, and the right operand is skipped.
+fn or(compiler: &mut Compiler, _can_assign: bool) {
+    let else_jump = compiler.emit_jump(OpCode::JumpIfFalse);
+    let end_jump = compiler.emit_jump(OpCode::Jump);
+
+    compiler.patch_jump(else_jump);
+    compiler.emit_instruction(OpCode::Pop);
+    compiler.parse_precedence(Precedence::Or);
+
+    compiler.patch_jump(end_jump);
 }
 
 /// Parse a keyword literal as a prefix. Assumes the keyword has been consumed.
@@ -824,11 +1877,46 @@ fn variable(compiler: &mut Compiler, can_assign: bool) {
     compiler.named_variable(compiler.parser.previous, can_assign);
 }
 
+/// Parse a prefix `++` or `--`. Assumes the operator has been consumed.
+fn prefix_increment(compiler: &mut Compiler, _can_assign: bool) {
+    let (operator, message) = match compiler.previous_token() {
+        Token::PlusPlus => (OpCode::Add, "Expect variable name after '++'."),
+        Token::MinusMinus => (OpCode::Subtract, "Expect variable name after '--'."),
+        _ => unreachable!("prefix_increment() called on wrong token"),
+    };
+
+    compiler.parser.consume(Token::Identifier, message);
+    if compiler.previous_token() != Token::Identifier {
+        return;
+    }
+
+    // Only variables can be incremented, so `++a.b`, `++a()`, and `++a[0]` are mistakes:
+    if compiler.parser.check(Token::Dot)
+        || compiler.parser.check(Token::LeftParen)
+        || compiler.parser.check(Token::LeftBracket)
+    {
+        compiler
+            .parser
+            .error_at_current("Invalid increment target.");
+        return;
+    }
+    let name = compiler.parser.previous;
+    let variable = compiler.resolve_variable(name);
+    compiler.increment(name, variable, operator, Fixity::Prefix);
+}
+
+/// Parse a postfix `++` or `--` that does NOT follow a variable (postfix increments of variables
+/// are handled by [Compiler::named_variable()]).
+fn invalid_increment(compiler: &mut Compiler, _can_assign: bool) {
+    compiler.parser.error("Invalid increment target.");
+}
+
 ////////////////////////////////////////////// Tests //////////////////////////////////////////////
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use serial_test::serial;
 
     #[test]
     fn precedence_confidence_check() {
@@ -848,4 +1936,157 @@ mod test {
         assert_eq!(Precedence::And, Precedence::Or.higher_precedence());
         assert_eq!(Precedence::Factor, Precedence::Term.higher_precedence());
     }
+
+    #[test]
+    #[serial]
+    fn for_loops_with_empty_clauses() {
+        let gc = ActiveGC::install();
+        for source in [
+            "for (;;) print 1;",
+            "for (var i = 0;;) print i;",
+            "for (; true;) print 1;",
+            "for (;; 1) print 1;",
+            "for (var i = 0; i < 1; i = i + 1) print i;",
+        ] {
+            let chunk = compile_with_diagnostics(source, &gc).expect(source);
+            let has_loop = (0..chunk.len())
+                .filter_map(|offset| chunk.get(offset).and_then(|byte| byte.as_opcode()))
+                .any(|opcode| opcode == OpCode::Loop);
+            assert!(has_loop, "{source} should loop");
+        }
+
+        let errors = compile_with_diagnostics("for (;;", &gc).unwrap_err();
+        assert_eq!(1, errors.len());
+    }
+
+    #[test]
+    #[serial]
+    fn only_variables_can_be_incremented() {
+        let gc = ActiveGC::install();
+        for (source, message) in [
+            ("1++;", "Invalid increment target."),
+            ("a.b--;", "Invalid increment target."),
+            ("(a)++;", "Invalid increment target."),
+            ("++1;", "Expect variable name after '++'."),
+            ("--;", "Expect variable name after '--'."),
+            ("--a.b;", "Invalid increment target."),
+        ] {
+            let errors = compile_with_diagnostics(source, &gc).unwrap_err();
+            assert_eq!(message, errors[0].message, "{source}");
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn increments_refer_to_their_variable_once() {
+        let gc = ActiveGC::install();
+        let analysis = analyze("var x = 1; x++; --x; { var y = 2; y--; }", &gc);
+        let uses: Vec<_> = (analysis.references.iter())
+            .map(|name| (name.name, name.span.clone()))
+            .collect();
+        assert_eq!(vec![("x", 11..12), ("x", 18..19), ("y", 34..35)], uses);
+    }
+
+    #[test]
+    #[serial]
+    fn every_statement_with_an_error_is_reported() {
+        let gc = ActiveGC::install();
+        let source = "print 1 +;\nvar = 2;\n{\n  var = 3;\n  print (4;\n}\nfun f( {\n  print 5;\n}\nprint 6;\n";
+        let errors = compile_with_diagnostics(source, &gc).unwrap_err();
+        let lines: Vec<_> = errors.iter().map(|error| error.line).collect();
+        assert_eq!(vec![1, 2, 4, 5, 7], lines);
+        assert_eq!("Expect parameter name.", errors[4].message);
+
+        // Recovering from a missing variable name must not trip up the analysis either:
+        assert_eq!(errors, analyze(source, &gc).diagnostics);
+    }
+
+    #[test]
+    #[serial]
+    fn unused_locals_are_warnings() {
+        let gc = ActiveGC::install();
+        let options = CompileOptions {
+            warnings: true,
+            ..CompileOptions::default()
+        };
+        let source = "var global;\n\
+                      fun f(used, unused, _ignored) {\n\
+                      \x20 var assigned = used;\n\
+                      \x20 assigned = 2;\n\
+                      \x20 { var read = 3; print read; var _skip; }\n\
+                      \x20 for (var i = 0; i < 1; i++) {}\n\
+                      }\n";
+        let (_, warnings) = compile_with_warnings(source, &gc, options).unwrap();
+        let warned: Vec<_> = (warnings.iter())
+            .map(|warning| (warning.line, warning.lexeme.as_str(), warning.severity))
+            .collect();
+        assert_eq!(
+            vec![
+                (2, "unused", Severity::Warning),
+                (3, "assigned", Severity::Warning)
+            ],
+            warned
+        );
+
+        // Warnings are off by default, and never stop code from compiling:
+        let (_, warnings) = compile_with_warnings(source, &gc, CompileOptions::default()).unwrap();
+        assert!(warnings.is_empty());
+
+        // With errors, they're reported in order, along with the errors:
+        let diagnostics = compile_with_warnings("{ var a; }\nprint +;", &gc, options).unwrap_err();
+        let severities: Vec<_> = diagnostics.iter().map(|d| d.severity).collect();
+        assert_eq!(vec![Severity::Warning, Severity::Error], severities);
+
+        // ...but not for a function whose name or parameters could not be parsed:
+        for source in ["fun (a) {}", "fun f(a b) {}", "fun f(a, 1) {}"] {
+            let diagnostics = compile_with_warnings(source, &gc, options).unwrap_err();
+            let warnings = diagnostics
+                .iter()
+                .filter(|d| d.severity == Severity::Warning);
+            assert_eq!(0, warnings.count(), "{source}");
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn unreachable_code_is_a_warning() {
+        let gc = ActiveGC::install();
+        let options = CompileOptions {
+            warnings: true,
+            ..CompileOptions::default()
+        };
+        let source = "fun f() {\n\
+                      \x20 { return 1; print 2; }\n\
+                      \x20 print 3;\n\
+                      }\n\
+                      fun g() { while (true) {} print 4; }\n\
+                      fun h() { for (;;) {} print 5; }\n\
+                      fun i() { while (!true) return; for (; false;) return; print 6; }\n\
+                      while (true or false) {}\n\
+                      print 7;\n";
+        let (_, warnings) = compile_with_warnings(source, &gc, options).unwrap();
+        let unreachable: Vec<_> = (warnings.iter())
+            .filter(|warning| warning.message == "Unreachable code")
+            .map(|warning| warning.line)
+            .collect();
+        // Only the first statement that can't be reached in each block is reported:
+        assert_eq!(vec![2, 3, 5, 6], unreachable);
+    }
+
+    #[test]
+    #[serial]
+    fn options_can_limit_the_number_of_constants() {
+        let gc = ActiveGC::install();
+        let options = CompileOptions {
+            max_constants: 2,
+            ..CompileOptions::default()
+        };
+        assert!(compile_with_options("print 1 + 2;", &gc, options).is_ok());
+        let errors = compile_with_options("print 1 + 2 + 3;", &gc, options).unwrap_err();
+        assert_eq!("Too many constants in one chunk", errors[0].message);
+
+        // Each function has its own chunk, and so its own constants:
+        let source = "fun f() { return 1 + 2; }";
+        assert!(compile_with_options(source, &gc, options).is_ok());
+    }
 }
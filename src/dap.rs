@@ -0,0 +1,616 @@
+//! A [Debug Adapter Protocol][dap] server for Lox, started with the `rlox-dap` binary (which is
+//! built with the `dap` feature).
+//!
+//! The adapter speaks DAP over `stdin` and `stdout`, and debugs the script given as `program` in
+//! the `launch` request. It supports:
+//!
+//!  - breakpoints, by line;
+//!  - continue, step in, step over (`next`), and step out;
+//!  - a stack trace of the function calls in progress;
+//!  - inspecting the global variables, and the VM's value stack.
+//!
+//! Everything the script prints is sent to the client as `output` events. Lox has no threads, so
+//! there is always exactly one thread.
+//!
+//! [dap]: https://microsoft.github.io/debug-adapter-protocol/
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+use serde_json::{json, Value as Json};
+
+use crate::error::InterpretationError;
+use crate::protocol::{read_message, write_message};
+use crate::value::ValueRef;
+use crate::vm::{Debugger, SharedBuffer, Stopped, VM};
+
+/// The ID of the one and only thread.
+const THREAD_ID: u64 = 1;
+
+/// The `variablesReference` of the global variables.
+const GLOBALS_REFERENCE: u64 = 1;
+/// The `variablesReference` of the value stack.
+const STACK_REFERENCE: u64 = 2;
+
+/// The exit code reported when the script stops with a runtime error (`EX_SOFTWARE`).
+const RUNTIME_ERROR_EXIT_CODE: i32 = 70;
+
+/// Serve DAP requests from `input`, writing responses and events to `output`, until the client
+/// disconnects or closes `input`.
+pub fn serve(mut input: impl BufRead, output: impl Write) -> io::Result<()> {
+    let mut client = Client { output, seq: 0 };
+
+    let launch = match configure(&mut input, &mut client)? {
+        Some(launch) => launch,
+        None => return Ok(()),
+    };
+
+    let stdout = SharedBuffer::default();
+    let stderr = SharedBuffer::default();
+    let mut vm = VM::default();
+    // SAFETY: the natives that every VM has never keep a value.
+    unsafe { vm.enable_garbage_collection() };
+    vm.set_stdout(Box::new(stdout.clone()));
+    vm.set_stderr(Box::new(stderr.clone()));
+
+    let chunk = match vm.compile(&launch.source) {
+        Ok(chunk) => chunk,
+        Err(_) => {
+            client.output("stderr", &stderr.take_string())?;
+            client.exited(RUNTIME_ERROR_EXIT_CODE)?;
+            return wait_for_disconnect(&mut input, &mut client);
+        }
+    };
+
+    let mut session = Session {
+        client,
+        debugger: vm.debug(&chunk),
+        program: launch.program,
+        breakpoints: BTreeSet::new(),
+        stdout,
+        stderr,
+    };
+    session.set_breakpoints(launch.breakpoints);
+
+    if launch.stop_on_entry {
+        session.client.stopped("entry")?;
+    } else {
+        let stopped = session.debugger.resume();
+        session.report(stopped)?;
+    }
+
+    while let Some(message) = read_message(&mut input)? {
+        if !session.handle(message)? {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+///////////////////////////////////// Implementation details //////////////////////////////////////
+
+/// Everything the client asked for before the script starts.
+#[derive(Default)]
+struct Launch {
+    /// The path to the script.
+    program: String,
+    /// The script's source code.
+    source: String,
+    stop_on_entry: bool,
+    breakpoints: BTreeSet<usize>,
+}
+
+/// Handles requests until the client has both launched the script and finished configuring
+/// breakpoints. Returns `None` if the client disconnected first.
+fn configure(
+    input: &mut impl BufRead,
+    client: &mut Client<impl Write>,
+) -> io::Result<Option<Launch>> {
+    let mut launch = None;
+    let mut breakpoints = BTreeSet::new();
+    let mut configured = false;
+
+    while launch.is_none() || !configured {
+        let request = match read_message(input)? {
+            Some(request) => request,
+            None => return Ok(None),
+        };
+        let arguments = &request["arguments"];
+
+        match command_of(&request) {
+            "initialize" => {
+                client.respond(
+                    &request,
+                    json!({ "supportsConfigurationDoneRequest": true }),
+                )?;
+                client.event("initialized", json!({}))?;
+            }
+            "launch" => {
+                let program = arguments["program"].as_str().unwrap_or_default();
+                match fs::read_to_string(program) {
+                    Ok(source) => {
+                        client.respond(&request, Json::Null)?;
+                        launch = Some(Launch {
+                            program: program.to_owned(),
+                            source,
+                            stop_on_entry: arguments["stopOnEntry"].as_bool().unwrap_or(false),
+                            ..Launch::default()
+                        });
+                    }
+                    Err(error) => client.fail(&request, &format!("{program}: {error}"))?,
+                }
+            }
+            "setBreakpoints" => {
+                breakpoints = breakpoint_lines(arguments);
+                client.respond(&request, verified(&breakpoints))?;
+            }
+            "configurationDone" => {
+                configured = true;
+                client.respond(&request, Json::Null)?;
+            }
+            "threads" => client.respond(&request, threads())?,
+            "disconnect" | "terminate" => {
+                client.respond(&request, Json::Null)?;
+                return Ok(None);
+            }
+            _ => client.fail(&request, "unsupported request")?,
+        }
+    }
+
+    Ok(launch.map(|launch| Launch {
+        breakpoints,
+        ..launch
+    }))
+}
+
+/// Once there is nothing to debug, answers requests until the client disconnects.
+fn wait_for_disconnect(
+    input: &mut impl BufRead,
+    client: &mut Client<impl Write>,
+) -> io::Result<()> {
+    while let Some(request) = read_message(input)? {
+        match command_of(&request) {
+            "threads" => client.respond(&request, threads())?,
+            "disconnect" | "terminate" => {
+                client.respond(&request, Json::Null)?;
+                break;
+            }
+            _ => client.fail(&request, "the program is not running")?,
+        }
+    }
+    Ok(())
+}
+
+/// A script that is being debugged.
+struct Session<'a, W> {
+    client: Client<W>,
+    debugger: Debugger<'a>,
+    /// The path to the script.
+    program: String,
+    /// The lines that currently have breakpoints.
+    breakpoints: BTreeSet<usize>,
+    /// What the script printed.
+    stdout: SharedBuffer,
+    /// The runtime errors.
+    stderr: SharedBuffer,
+}
+
+impl<W: Write> Session<'_, W> {
+    /// Handles one request. Returns `false` when the client disconnects.
+    fn handle(&mut self, request: Json) -> io::Result<bool> {
+        let arguments = &request["arguments"];
+
+        match command_of(&request) {
+            "threads" => self.client.respond(&request, threads())?,
+            "setBreakpoints" => {
+                let breakpoints = breakpoint_lines(arguments);
+                self.client.respond(&request, verified(&breakpoints))?;
+                self.set_breakpoints(breakpoints);
+            }
+            "stackTrace" => {
+                let body = self.stack_trace();
+                self.client.respond(&request, body)?;
+            }
+            "scopes" => self.client.respond(&request, scopes())?,
+            "variables" => {
+                let reference = arguments["variablesReference"].as_u64();
+                let body = self.variables(reference.unwrap_or_default());
+                self.client.respond(&request, body)?;
+            }
+            "continue" => {
+                let body = json!({ "allThreadsContinued": true });
+                self.client.respond(&request, body)?;
+                let stopped = self.debugger.resume();
+                self.report(stopped)?;
+            }
+            "next" => {
+                self.client.respond(&request, Json::Null)?;
+                let (depth, line) = self.location();
+                let stopped = self.step_until(|d, l| d < depth || (d == depth && l != line));
+                self.report(stopped)?;
+            }
+            "stepIn" => {
+                self.client.respond(&request, Json::Null)?;
+                let (depth, line) = self.location();
+                let stopped = self.step_until(|d, l| d != depth || l != line);
+                self.report(stopped)?;
+            }
+            "stepOut" => {
+                self.client.respond(&request, Json::Null)?;
+                let (depth, _) = self.location();
+                let stopped = self.step_until(|d, _| d < depth);
+                self.report(stopped)?;
+            }
+            // The script only runs while a request is being handled, so it is always paused.
+            "pause" => self.client.respond(&request, Json::Null)?,
+            "disconnect" | "terminate" => {
+                self.client.respond(&request, Json::Null)?;
+                return Ok(false);
+            }
+            _ => self.client.fail(&request, "unsupported request")?,
+        }
+
+        Ok(true)
+    }
+
+    /// Replaces every breakpoint with the given lines.
+    fn set_breakpoints(&mut self, breakpoints: BTreeSet<usize>) {
+        for &line in self.breakpoints.iter() {
+            self.debugger.remove_breakpoint(line);
+        }
+        for &line in breakpoints.iter() {
+            self.debugger.add_breakpoint(line);
+        }
+        self.breakpoints = breakpoints;
+    }
+
+    /// Returns how many calls are in progress, and the line of the next instruction.
+    fn location(&self) -> (usize, Option<usize>) {
+        (self.debugger.backtrace().len(), self.debugger.line())
+    }
+
+    /// Steps one instruction at a time, until `done()` returns true for the new location.
+    fn step_until(
+        &mut self,
+        done: impl Fn(usize, Option<usize>) -> bool,
+    ) -> crate::Result<Stopped> {
+        loop {
+            match self.debugger.step()? {
+                Stopped::Finished => return Ok(Stopped::Finished),
+                stopped => {
+                    let (depth, line) = self.location();
+                    if done(depth, line) {
+                        return Ok(stopped);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Tells the client what the script printed, and why it stopped.
+    fn report(&mut self, stopped: crate::Result<Stopped>) -> io::Result<()> {
+        self.client.output("stdout", &self.stdout.take_string())?;
+        self.client.output("stderr", &self.stderr.take_string())?;
+
+        match stopped {
+            Ok(Stopped::Step) => self.client.stopped("step"),
+            Ok(Stopped::Breakpoint { .. }) => self.client.stopped("breakpoint"),
+            Ok(Stopped::Finished) => self.client.exited(0),
+            Err(InterpretationError::Exit(status)) => self.client.exited(status),
+            Err(_) => self.client.exited(RUNTIME_ERROR_EXIT_CODE),
+        }
+    }
+
+    /// Responds to `stackTrace`.
+    fn stack_trace(&self) -> Json {
+        let name = std::path::Path::new(&self.program)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned());
+        let source = json!({ "name": name, "path": self.program });
+
+        let frames: Vec<_> = (self.debugger.backtrace().into_iter().enumerate())
+            .map(|(id, frame)| {
+                json!({
+                    "id": id,
+                    "name": frame.function.as_deref().unwrap_or("<script>"),
+                    "source": source,
+                    "line": frame.line,
+                    "column": 1,
+                })
+            })
+            .collect();
+
+        json!({ "totalFrames": frames.len(), "stackFrames": frames })
+    }
+
+    /// Responds to `variables`.
+    fn variables(&self, reference: u64) -> Json {
+        let variables: Vec<_> = match reference {
+            GLOBALS_REFERENCE => (self.debugger.globals().into_iter())
+                .map(|(name, value)| variable(name, value))
+                .collect(),
+            STACK_REFERENCE => (self.debugger.stack().into_iter().enumerate())
+                .map(|(slot, value)| variable(&format!("[{slot}]"), value))
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        json!({ "variables": variables })
+    }
+}
+
+/// Sends responses and events to the client.
+struct Client<W> {
+    output: W,
+    /// The sequence number of the last message sent.
+    seq: u64,
+}
+
+impl<W: Write> Client<W> {
+    /// Responds to a request successfully.
+    fn respond(&mut self, request: &Json, body: Json) -> io::Result<()> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": true,
+            "body": body,
+        }))
+    }
+
+    /// Responds to a request with an error.
+    fn fail(&mut self, request: &Json, message: &str) -> io::Result<()> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": false,
+            "message": message,
+        }))
+    }
+
+    /// Sends an event.
+    fn event(&mut self, event: &str, body: Json) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    /// Sends an `output` event, unless there is no output.
+    fn output(&mut self, category: &str, output: &str) -> io::Result<()> {
+        if output.is_empty() {
+            return Ok(());
+        }
+        self.event("output", json!({ "category": category, "output": output }))
+    }
+
+    /// Tells the client that the script stopped, for the given reason.
+    fn stopped(&mut self, reason: &str) -> io::Result<()> {
+        let body = json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true });
+        self.event("stopped", body)
+    }
+
+    /// Tells the client that the script is done.
+    fn exited(&mut self, exit_code: i32) -> io::Result<()> {
+        self.event("exited", json!({ "exitCode": exit_code }))?;
+        self.event("terminated", json!({}))
+    }
+
+    /// Writes one message to the client.
+    fn send(&mut self, mut message: Json) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = self.seq.into();
+        write_message(&mut self.output, &message)
+    }
+}
+
+/// Returns the command of a request.
+fn command_of(request: &Json) -> &str {
+    request["command"].as_str().unwrap_or_default()
+}
+
+/// Returns the lines in the arguments of `setBreakpoints`.
+fn breakpoint_lines(arguments: &Json) -> BTreeSet<usize> {
+    let breakpoints = arguments["breakpoints"].as_array();
+    (breakpoints.into_iter().flatten())
+        .filter_map(|breakpoint| breakpoint["line"].as_u64())
+        .map(|line| line as usize)
+        .collect()
+}
+
+/// The response to `setBreakpoints`. Any line can have a breakpoint, so they are all verified.
+fn verified(breakpoints: &BTreeSet<usize>) -> Json {
+    let breakpoints: Vec<_> = (breakpoints.iter())
+        .map(|line| json!({ "verified": true, "line": line }))
+        .collect();
+    json!({ "breakpoints": breakpoints })
+}
+
+/// The response to `threads`.
+fn threads() -> Json {
+    json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] })
+}
+
+/// The response to `scopes`. Every frame can see the same globals and the same stack.
+fn scopes() -> Json {
+    json!({
+        "scopes": [
+            { "name": "Globals", "variablesReference": GLOBALS_REFERENCE, "expensive": false },
+            { "name": "Stack", "variablesReference": STACK_REFERENCE, "expensive": false },
+        ]
+    })
+}
+
+/// Describes a variable. Strings are quoted, so they can be told apart from other values.
+fn variable(name: &str, value: ValueRef<'_>) -> Json {
+    let value = match value.get::<&str>() {
+        Ok(string) => format!("{string:?}"),
+        Err(_) => value.to_string(),
+    };
+    json!({ "name": name, "value": value, "variablesReference": 0 })
+}
+
+////////////////////////////////////////////// Tests //////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use serial_test::serial;
+
+    use super::*;
+
+    /// Writes the source to a temporary file, and returns its path.
+    fn program(name: &str, source: &str) -> String {
+        let path = std::env::temp_dir().join(format!("rlox-dap-{}-{name}.lox", std::process::id()));
+        fs::write(&path, source).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    /// Makes a request with the given arguments.
+    fn request(seq: u64, command: &str, arguments: Json) -> Json {
+        json!({ "seq": seq, "type": "request", "command": command, "arguments": arguments })
+    }
+
+    /// Runs the adapter on the given requests, and returns every message it sent back.
+    fn run(requests: &[Json]) -> Vec<Json> {
+        let mut input = Vec::new();
+        for request in requests {
+            write_message(&mut input, request).unwrap();
+        }
+        let mut output = Vec::new();
+        serve(&input[..], &mut output).unwrap();
+
+        let mut output = &output[..];
+        let mut messages = Vec::new();
+        while let Some(message) = read_message(&mut output).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    /// Returns the bodies of every event with the given name.
+    fn events<'a>(messages: &'a [Json], event: &str) -> Vec<&'a Json> {
+        (messages.iter())
+            .filter(|message| message["event"] == event)
+            .map(|message| &message["body"])
+            .collect()
+    }
+
+    /// Returns the body of the response to the request with the given sequence number.
+    fn response(messages: &[Json], seq: u64) -> &Json {
+        let response = (messages.iter())
+            .find(|message| message["type"] == "response" && message["request_seq"] == seq)
+            .expect("there should be a response");
+        assert_eq!(true, response["success"], "{response}");
+        &response["body"]
+    }
+
+    #[test]
+    #[serial]
+    fn stops_at_breakpoints() {
+        let path = program("breakpoints", "var a = \"one\";\nprint a;\n");
+        let messages = run(&[
+            request(1, "initialize", json!({ "adapterID": "rlox" })),
+            request(2, "launch", json!({ "program": path })),
+            request(
+                3,
+                "setBreakpoints",
+                json!({ "breakpoints": [{ "line": 2 }] }),
+            ),
+            request(4, "configurationDone", json!({})),
+            request(5, "stackTrace", json!({ "threadId": THREAD_ID })),
+            request(
+                6,
+                "variables",
+                json!({ "variablesReference": GLOBALS_REFERENCE }),
+            ),
+            request(7, "continue", json!({ "threadId": THREAD_ID })),
+            request(8, "disconnect", json!({})),
+        ]);
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(1, events(&messages, "initialized").len());
+        assert_eq!("breakpoint", events(&messages, "stopped")[0]["reason"]);
+
+        let frames = &response(&messages, 5)["stackFrames"];
+        assert_eq!(2, frames[0]["line"]);
+        assert_eq!("<script>", frames[0]["name"]);
+
+        let variables = response(&messages, 6)["variables"].as_array().unwrap();
+        let a = variables.iter().find(|v| v["name"] == "a").unwrap();
+        assert_eq!("\"one\"", a["value"]);
+
+        assert_eq!("one\n", events(&messages, "output")[0]["output"]);
+        assert_eq!(0, events(&messages, "exited")[0]["exitCode"]);
+        assert_eq!(1, events(&messages, "terminated").len());
+    }
+
+    #[test]
+    #[serial]
+    fn steps_in_and_out_of_functions() {
+        let path = program("stepping", "fun f() {\n  return 1;\n}\nprint f();\n");
+        let stack_trace = |seq| request(seq, "stackTrace", json!({ "threadId": THREAD_ID }));
+        let messages = run(&[
+            request(1, "initialize", json!({})),
+            request(2, "launch", json!({ "program": path, "stopOnEntry": true })),
+            request(3, "configurationDone", json!({})),
+            request(4, "next", json!({ "threadId": THREAD_ID })),
+            stack_trace(5),
+            request(6, "stepIn", json!({ "threadId": THREAD_ID })),
+            stack_trace(7),
+            request(8, "stepOut", json!({ "threadId": THREAD_ID })),
+            stack_trace(9),
+            request(10, "continue", json!({ "threadId": THREAD_ID })),
+        ]);
+        fs::remove_file(path).unwrap();
+
+        let reasons: Vec<_> = (events(&messages, "stopped").into_iter())
+            .map(|body| body["reason"].as_str().unwrap())
+            .collect();
+        assert_eq!(vec!["entry", "step", "step", "step"], reasons);
+
+        let top_frame = |seq| {
+            let frame = &response(&messages, seq)["stackFrames"][0];
+            (
+                frame["name"].as_str().unwrap(),
+                frame["line"].as_u64().unwrap(),
+            )
+        };
+        assert_eq!(("<script>", 4), top_frame(5));
+        assert_eq!(("f", 2), top_frame(7));
+        assert_eq!(("<script>", 4), top_frame(9));
+
+        assert_eq!("1\n", events(&messages, "output")[0]["output"]);
+    }
+
+    #[test]
+    #[serial]
+    fn runtime_errors_end_the_session() {
+        let path = program("error", "print -nil;\n");
+        let messages = run(&[
+            request(1, "initialize", json!({})),
+            request(2, "launch", json!({ "program": path })),
+            request(3, "configurationDone", json!({})),
+            request(4, "disconnect", json!({})),
+        ]);
+        fs::remove_file(path).unwrap();
+
+        let output = events(&messages, "output");
+        assert_eq!("stderr", output[0]["category"]);
+        assert_eq!(
+            RUNTIME_ERROR_EXIT_CODE,
+            events(&messages, "exited")[0]["exitCode"]
+        );
+        response(&messages, 4);
+    }
+
+    #[test]
+    fn launching_a_missing_file_fails() {
+        let messages = run(&[request(
+            1,
+            "launch",
+            json!({ "program": "/does/not/exist.lox" }),
+        )]);
+
+        assert_eq!(false, messages[0]["success"]);
+    }
+}
@@ -1,90 +1,251 @@
 //! Helpers to print a debug representations.
+//!
+//! The disassembly can either be printed to `stdout` (like in Crafting Interpreters), or written to
+//! a [String], for tools and tests:
+//!
+//! ```
+//! # use rlox::prelude::*;
+//! # use rlox::debug::disassemble_chunk_to_string;
+//! let mut chunk = Chunk::new();
+//! chunk.write_opcode(OpCode::Nil, 1);
+//! chunk.write_opcode(OpCode::Return, 1);
+//!
+//! let disassembly = disassemble_chunk_to_string(&chunk, "test");
+//! assert_eq!("== test ==\n0000    1           OP_NIL\n0001    |        OP_RETURN\n", disassembly);
+//! ```
+
+use std::fmt::{self, Write};
 
 use crate::chunk::{Chunk, OpCode};
 
 /// Given a chunk, prints its disassembly to `stdout`
 pub fn disassemble_chunk(c: &Chunk, name: &str) {
-    println!("== {name} ==");
+    print!("{}", Disassembly { chunk: c, name });
+}
 
-    let mut offset = 0;
-    while offset < c.len() {
-        offset = disassemble_instruction(c, offset);
-    }
+/// Given a chunk, returns its disassembly, exactly as [disassemble_chunk()] would print it.
+pub fn disassemble_chunk_to_string(c: &Chunk, name: &str) -> String {
+    Disassembly { chunk: c, name }.to_string()
 }
 
 /// Print one instruction from the [Chunk] to `stdout`, taking into account its operands.
+/// Returns the offset of the next instruction.
 pub fn disassemble_instruction(c: &Chunk, offset: usize) -> usize {
-    print!("{:04} ", offset);
+    let mut line = String::new();
+    let next = write_instruction(&mut line, c, offset).expect("writing to a String cannot fail");
+    print!("{line}");
+    next
+}
+
+/// Writes one instruction from the [Chunk], taking into account its operands. Returns the offset
+/// of the next instruction.
+pub fn write_instruction(
+    out: &mut impl Write,
+    c: &Chunk,
+    offset: usize,
+) -> Result<usize, fmt::Error> {
+    write!(out, "{:04} ", offset)?;
+
+    let Some(entry) = c.get(offset) else {
+        writeln!(out, "     <end of chunk>")?;
+        return Ok(offset + 1);
+    };
 
     if offset > 0 && at_same_line_as_previous_offset(c, offset) {
-        print!("   | ");
+        write!(out, "   | ")?;
     } else {
         let line_no = c.line_number_for(offset).unwrap();
-        print!("{line_no:4} ")
+        write!(out, "{line_no:4} ")?;
     }
 
-    let instruction = c
-        .get(offset)
-        .expect("offset too large")
-        .as_opcode()
-        .expect("Invalid byte for opcode");
+    let Some(instruction) = entry.as_opcode() else {
+        writeln!(out, "Unknown opcode {}", entry.as_constant_index())?;
+        return Ok(offset + 1);
+    };
 
     use OpCode::*;
     #[allow(unreachable_patterns)]
     match instruction {
         // This is kind of silly in Rust, tbh
-        Constant => constant_instruction("OP_CONSTANT", c, offset),
-        Nil => simple_instruction("OP_NIL", offset),
-        True => simple_instruction("OP_TRUE", offset),
-        False => simple_instruction("OP_FALSE", offset),
-        Pop => simple_instruction("OP_POP", offset),
-        GetLocal => byte_instruction("OP_GET_LOCAL", c, offset),
-        SetLocal => byte_instruction("OP_SET_LOCAL", c, offset),
-        GetGlobal => constant_instruction("OP_GET_GLOBAL", c, offset),
-        DefineGlobal => constant_instruction("OP_DEFINE_GLOBAL", c, offset),
-        SetGlobal => constant_instruction("OP_SET_GLOBAL", c, offset),
-        Equal => simple_instruction("OP_EQUAL", offset),
-        Greater => simple_instruction("OP_GREATER", offset),
-        Less => simple_instruction("OP_LESS", offset),
-        Add => simple_instruction("OP_ADD", offset),
-        Subtract => simple_instruction("OP_SUBTRACT", offset),
-        Multiply => simple_instruction("OP_MULTIPLY", offset),
-        Divide => simple_instruction("OP_DIVIDE", offset),
-        Not => simple_instruction("OP_NOT", offset),
-        Negate => simple_instruction("OP_NEGATE", offset),
-        Print => simple_instruction("OP_PRINT", offset),
-        Return => simple_instruction("OP_RETURN", offset),
+        Constant => constant_instruction(out, "OP_CONSTANT", c, offset),
+        Nil => simple_instruction(out, "OP_NIL", offset),
+        True => simple_instruction(out, "OP_TRUE", offset),
+        False => simple_instruction(out, "OP_FALSE", offset),
+        Pop => simple_instruction(out, "OP_POP", offset),
+        GetLocal => byte_instruction(out, "OP_GET_LOCAL", c, offset),
+        SetLocal => byte_instruction(out, "OP_SET_LOCAL", c, offset),
+        GetLocalLong => u16_instruction(out, "OP_GET_LOCAL_LONG", c, offset),
+        SetLocalLong => u16_instruction(out, "OP_SET_LOCAL_LONG", c, offset),
+        GetGlobal => constant_instruction(out, "OP_GET_GLOBAL", c, offset),
+        DefineGlobal => constant_instruction(out, "OP_DEFINE_GLOBAL", c, offset),
+        DefineConstGlobal => constant_instruction(out, "OP_DEFINE_CONST_GLOBAL", c, offset),
+        SetGlobal => constant_instruction(out, "OP_SET_GLOBAL", c, offset),
+        Class => constant_instruction(out, "OP_CLASS", c, offset),
+        GetProperty => constant_instruction(out, "OP_GET_PROPERTY", c, offset),
+        SetProperty => constant_instruction(out, "OP_SET_PROPERTY", c, offset),
+        BuildList => byte_instruction(out, "OP_BUILD_LIST", c, offset),
+        BuildMap => byte_instruction(out, "OP_BUILD_MAP", c, offset),
+        IndexGet => simple_instruction(out, "OP_INDEX_GET", offset),
+        IndexSet => simple_instruction(out, "OP_INDEX_SET", offset),
+        Equal => simple_instruction(out, "OP_EQUAL", offset),
+        Greater => simple_instruction(out, "OP_GREATER", offset),
+        Less => simple_instruction(out, "OP_LESS", offset),
+        Add => simple_instruction(out, "OP_ADD", offset),
+        Subtract => simple_instruction(out, "OP_SUBTRACT", offset),
+        Multiply => simple_instruction(out, "OP_MULTIPLY", offset),
+        Divide => simple_instruction(out, "OP_DIVIDE", offset),
+        BitAnd => simple_instruction(out, "OP_BIT_AND", offset),
+        BitOr => simple_instruction(out, "OP_BIT_OR", offset),
+        BitXor => simple_instruction(out, "OP_BIT_XOR", offset),
+        ShiftLeft => simple_instruction(out, "OP_SHIFT_LEFT", offset),
+        ShiftRight => simple_instruction(out, "OP_SHIFT_RIGHT", offset),
+        Not => simple_instruction(out, "OP_NOT", offset),
+        Negate => simple_instruction(out, "OP_NEGATE", offset),
+        Call => byte_instruction(out, "OP_CALL", c, offset),
+        Jump => jump_instruction(out, "OP_JUMP", Direction::Forward, c, offset),
+        JumpIfFalse => jump_instruction(out, "OP_JUMP_IF_FALSE", Direction::Forward, c, offset),
+        Loop => jump_instruction(out, "OP_LOOP", Direction::Backward, c, offset),
+        Print => simple_instruction(out, "OP_PRINT", offset),
+        Import => constant_instruction(out, "OP_IMPORT", c, offset),
+        Return => simple_instruction(out, "OP_RETURN", offset),
+        AddLocals => two_byte_instruction(out, "OP_ADD_LOCALS", c, offset),
+        AddConstant => constant_instruction(out, "OP_ADD_CONSTANT", c, offset),
+        NotJumpIfFalse => {
+            jump_instruction(out, "OP_NOT_JUMP_IF_FALSE", Direction::Forward, c, offset)
+        }
+    }
+}
+
+/// Formats the disassembly of a [Chunk], with a header. Both [Display](fmt::Display) and
+/// [Debug](fmt::Debug) produce the same output, so a disassembly can be used with `{}`, `{:?}`,
+/// or in `assert_eq!()`.
+pub struct Disassembly<'a> {
+    /// The chunk to disassemble.
+    pub chunk: &'a Chunk,
+    /// The name in the header (e.g., the function name).
+    pub name: &'a str,
+}
+
+impl fmt::Display for Disassembly<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "== {} ==", self.name)?;
+
+        let mut offset = 0;
+        while offset < self.chunk.len() {
+            offset = write_instruction(f, self.chunk, offset)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Disassembly<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
     }
 }
 
 /////////////////////////////////////// Instruction printers ///////////////////////////////////////
 
-fn simple_instruction(name: &str, offset: usize) -> usize {
-    println!("{name:>16}");
-    offset + 1
+fn simple_instruction(
+    out: &mut impl Write,
+    name: &str,
+    offset: usize,
+) -> Result<usize, fmt::Error> {
+    writeln!(out, "{name:>16}")?;
+    Ok(offset + 1)
 }
 
-fn constant_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
-    print!("{name:>16}");
+fn constant_instruction(
+    out: &mut impl Write,
+    name: &str,
+    chunk: &Chunk,
+    offset: usize,
+) -> Result<usize, fmt::Error> {
+    write!(out, "{name:>16}")?;
 
-    let (index, value) = chunk
-        .get(offset + 1)
-        .expect("ran out of bytes")
-        .resolve_constant_with_index()
-        .expect("Invalid constant index");
+    match chunk.get(offset + 1) {
+        Some(operand) => match operand.resolve_constant_with_index() {
+            Some((index, value)) => writeln!(out, " {index:4} '{value:?}'")?,
+            None => writeln!(out, " {:4} <no constant>", operand.as_constant_index())?,
+        },
+        None => writeln!(out, " <missing operand>")?,
+    }
 
-    println!(" {index:4} '{value:?}'");
+    Ok(offset + 2)
+}
 
-    offset + 2
+fn byte_instruction(
+    out: &mut impl Write,
+    name: &str,
+    chunk: &Chunk,
+    offset: usize,
+) -> Result<usize, fmt::Error> {
+    match chunk.get(offset + 1) {
+        Some(slot) => writeln!(out, "{name:>16} {:4}", slot.as_constant_index())?,
+        None => writeln!(out, "{name:>16} <missing operand>")?,
+    }
+    Ok(offset + 2)
+}
+
+fn u16_instruction(
+    out: &mut impl Write,
+    name: &str,
+    chunk: &Chunk,
+    offset: usize,
+) -> Result<usize, fmt::Error> {
+    match chunk.read_u16(offset + 1) {
+        Some(slot) => writeln!(out, "{name:>16} {slot:4}")?,
+        None => writeln!(out, "{name:>16} <missing operand>")?,
+    }
+    Ok(offset + 3)
+}
+
+/// An instruction whose two-byte operand is two separate one-byte operands.
+fn two_byte_instruction(
+    out: &mut impl Write,
+    name: &str,
+    chunk: &Chunk,
+    offset: usize,
+) -> Result<usize, fmt::Error> {
+    match (chunk.get(offset + 1), chunk.get(offset + 2)) {
+        (Some(first), Some(second)) => writeln!(
+            out,
+            "{name:>16} {:4} {:4}",
+            first.as_constant_index(),
+            second.as_constant_index()
+        )?,
+        _ => writeln!(out, "{name:>16} <missing operand>")?,
+    }
+    Ok(offset + 3)
+}
+
+/// Which way a jump goes.
+enum Direction {
+    Forward,
+    Backward,
 }
 
-fn byte_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
-    let slot = chunk
-        .get(offset + 1)
-        .expect("ran out of bytes")
-        .as_constant_index();
-    println!("{name:>16} {slot:4}");
-    offset + 2
+fn jump_instruction(
+    out: &mut impl Write,
+    name: &str,
+    direction: Direction,
+    chunk: &Chunk,
+    offset: usize,
+) -> Result<usize, fmt::Error> {
+    let next = offset + 3;
+    let Some(jump) = chunk.read_u16(offset + 1).map(usize::from) else {
+        writeln!(out, "{name:>16} <missing operand>")?;
+        return Ok(next);
+    };
+    let target = match direction {
+        Direction::Forward => Some(next + jump),
+        Direction::Backward => next.checked_sub(jump),
+    };
+    match target {
+        Some(target) => writeln!(out, "{name:>16} {offset:4} -> {target}")?,
+        None => writeln!(out, "{name:>16} {offset:4} -> <before the chunk>")?,
+    }
+    Ok(next)
 }
 
 //////////////////////////////////////////// Utilities ////////////////////////////////////////////
@@ -99,3 +260,44 @@ fn at_same_line_as_previous_offset(chunk: &Chunk, offset: usize) -> bool {
         .map(|(current_line, previous_line)| current_line == previous_line)
         .unwrap()
 }
+
+////////////////////////////////////////////// Tests //////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gc::ActiveGC;
+    use serial_test::serial;
+
+    #[test]
+    #[serial]
+    #[cfg_attr(
+        feature = "integers",
+        ignore = "number literals are integers with the integers feature"
+    )]
+    fn disassemble_to_string() {
+        let gc = ActiveGC::install();
+        let chunk = crate::compiler::compile("var a = 1;\nwhile (a) a = nil;", &gc).unwrap();
+
+        let expected = r#"== <script> ==
+0000    1      OP_CONSTANT    1 'Number(1.0)'
+0002    | OP_DEFINE_GLOBAL    0 'LoxString("a")'
+0004    2    OP_GET_GLOBAL    0 'LoxString("a")'
+0006    | OP_JUMP_IF_FALSE    6 -> 17
+0009    |           OP_POP
+0010    |           OP_NIL
+0011    |    OP_SET_GLOBAL    0 'LoxString("a")'
+0013    |           OP_POP
+0014    |          OP_LOOP   14 -> 4
+0017    |           OP_POP
+0018    |        OP_RETURN
+"#;
+        let disassembly = Disassembly {
+            chunk: &chunk,
+            name: "<script>",
+        };
+        assert_eq!(expected, disassembly.to_string());
+        assert_eq!(expected, format!("{disassembly:?}"));
+        assert_eq!(expected, disassemble_chunk_to_string(&chunk, "<script>"));
+    }
+}
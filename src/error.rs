@@ -1,14 +1,342 @@
 //! Provides [InterpretationError], the error that most things return.
+use std::ops::Range;
+
 use thiserror::Error;
 
 /// Any error that can occur during interpretation.
 #[derive(Debug, Error)]
 pub enum InterpretationError {
-    /// A compile-time error, such as a syntax error, or a name error.
+    /// A compile-time error, such as a syntax error, or a name error. Contains every error that
+    /// was found, in order.
     #[error("compile-time error")]
-    CompileError,
+    CompileError(Vec<Diagnostic>),
     /// A runtime error, such as a type error or exception.
-    #[error("runtime error")]
-    RuntimeError,
-    // TODO: add a variant for "invalid bytecode"?
+    #[error("[line {}] runtime error: {}", .0.line, .0.message)]
+    RuntimeError(RuntimeErrorInfo),
+    /// The bytes given could not be read as a serialized [Chunk](crate::chunk::Chunk).
+    #[error("invalid bytecode file: {0}")]
+    InvalidBytecodeFile(&'static str),
+    /// The bytecode being run is malformed: e.g., it has an unknown opcode, or it pops more values
+    /// than there are on the stack. The compiler never produces such bytecode, but chunks built by
+    /// hand might.
+    #[error("invalid bytecode at offset {offset}: {reason}")]
+    InvalidBytecode {
+        /// The offset in the chunk of the byte where the problem was found.
+        offset: usize,
+        /// What is wrong with the bytecode.
+        reason: &'static str,
+    },
+    /// The VM ran out of fuel before the code finished running. See
+    /// [VM::set_fuel()](crate::vm::VM::set_fuel()).
+    #[error("ran out of fuel")]
+    OutOfFuel,
+    /// The code was stopped by setting the VM's
+    /// [interrupt handle](crate::vm::VM::interrupt_handle()).
+    #[error("interrupted")]
+    Interrupted,
+    /// The code called the `exit(status)` native, which stops it like an error does. The command
+    /// line interpreter exits with the status; embedders can do whatever they like.
+    #[error("exited with status {0}")]
+    Exit(i32),
+    /// The source code could not be read, e.g., by
+    /// [VM::interpret_reader()](crate::vm::VM::interpret_reader()).
+    #[error("could not read source code: {0}")]
+    ReadError(#[from] std::io::Error),
+    /// A `print` statement could not write to the VM's `stdout` (see
+    /// [VM::set_stdout()](crate::vm::VM::set_stdout())).
+    #[error("could not write output: {0}")]
+    WriteError(std::io::Error),
+}
+
+/// What went wrong during a runtime error, and where.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeErrorInfo {
+    /// A human-readable description of the problem.
+    pub message: String,
+    /// The line that was executing when the error occurred.
+    pub line: usize,
+    /// The range of bytes in the source code of the instruction that failed, if it is known (it
+    /// is not known when running bytecode files, for instance).
+    pub span: Option<Range<usize>>,
+    /// The calls that were in progress when the error occurred, innermost first. The last frame
+    /// is always the top-level script.
+    pub backtrace: Vec<BacktraceFrame>,
+}
+
+/// One call in the backtrace of a [RuntimeErrorInfo].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BacktraceFrame {
+    /// The name of the function, or `None` for the top-level script.
+    pub function: Option<String>,
+    /// The line that was executing in this function.
+    pub line: usize,
+}
+
+/// A problem found in Lox source code, such as a syntax error.
+#[cfg_attr(
+    feature = "wasm",
+    wasm_bindgen::prelude::wasm_bindgen(getter_with_clone)
+)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// The line where the problem was found.
+    pub line: usize,
+    /// The column where the problem starts, counting characters from 1.
+    pub column: usize,
+    /// The range of bytes in the source code where the problem was found.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(skip))]
+    pub span: Range<usize>,
+    /// The text of the lexeme where the problem was found. Empty at the end of the file.
+    pub lexeme: String,
+    /// Where the problem was found, in the style of Crafting Interpreters: ` at 'x'`, ` at end`,
+    /// or empty, when the problem is with the token itself (e.g., an unterminated string).
+    pub location: String,
+    /// A human-readable description of the problem.
+    pub message: String,
+    /// Whether the problem stops the code from compiling.
+    pub severity: Severity,
+}
+
+/// How bad the problem described by a [Diagnostic] is.
+#[cfg_attr(feature = "wasm", wasm_bindgen::prelude::wasm_bindgen)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Severity {
+    /// The code cannot be compiled.
+    #[default]
+    Error,
+    /// The code compiles, but it is probably not what was meant, e.g., it has an unused variable.
+    /// Only reported when [CompileOptions::warnings](crate::compiler::CompileOptions::warnings)
+    /// is on.
+    Warning,
+}
+
+impl std::fmt::Display for RuntimeErrorInfo {
+    /// Formats the error the same way the VM prints errors: the message, followed by one line
+    /// per frame in the backtrace:
+    ///
+    /// ```text
+    /// Operand must be a number
+    /// [line 2] in inner()
+    /// [line 5] in script
+    /// ```
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.message)?;
+        for frame in self.backtrace.iter() {
+            match &frame.function {
+                Some(name) => writeln!(f, "[line {}] in {name}()", frame.line)?,
+                None => writeln!(f, "[line {}] in script", frame.line)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    /// Formats the diagnostic the same way the compiler prints errors:
+    /// `[line 1] Error at ';': Expect expression.` Warnings start with `Warning` instead.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "Error",
+            Severity::Warning => "Warning",
+        };
+        write!(
+            f,
+            "[line {}] {severity}{}: {}",
+            self.line, self.location, self.message
+        )
+    }
+}
+
+//////////////////////////////////////// Rendering errors /////////////////////////////////////////
+
+/// Renders a compile error like `rustc` does, with the offending source code underlined:
+///
+/// ```
+/// # use rlox::error::render_diagnostic;
+/// # use rlox::gc::ActiveGC;
+/// let source = "var a = 1;\nprint (a + 1;\n";
+/// let gc = ActiveGC::install();
+/// let diagnostics = rlox::compiler::compile_with_diagnostics(source, &gc).unwrap_err();
+///
+/// let expected = "\
+/// error: Expect ')' after grouping.
+///  --> line 2, column 13
+///   |
+/// 2 | print (a + 1;
+///   |             ^ at ';'
+/// ";
+/// assert_eq!(expected, render_diagnostic(source, &diagnostics[0]));
+/// ```
+pub fn render_diagnostic(source: &str, diagnostic: &Diagnostic) -> String {
+    let note = diagnostic.location.trim_start();
+    let label = match diagnostic.severity {
+        Severity::Error => "error",
+        Severity::Warning => "warning",
+    };
+    render_snippet(
+        source,
+        label,
+        &diagnostic.message,
+        diagnostic.span.clone(),
+        note,
+    )
+}
+
+/// Renders a runtime error like [render_diagnostic()], followed by the backtrace. If it is not
+/// known where the error happened in the source code, this is the same as the error's
+/// [Display](std::fmt::Display) output.
+///
+/// ```
+/// # use rlox::error::{render_runtime_error, InterpretationError};
+/// # use rlox::prelude::*;
+/// let source = "fun f() {\n  return -nil;\n}\nf();";
+/// let mut vm = VM::default();
+/// let Err(InterpretationError::RuntimeError(info)) = vm.interpret(source) else {
+///     unreachable!();
+/// };
+///
+/// let expected = "\
+/// error: Operand must be a number
+///  --> line 2, column 10
+///   |
+/// 2 |   return -nil;
+///   |          ^
+///   = [line 2] in f()
+///   = [line 4] in script
+/// ";
+/// assert_eq!(expected, render_runtime_error(source, &info));
+/// ```
+pub fn render_runtime_error(source: &str, info: &RuntimeErrorInfo) -> String {
+    let Some(span) = info.span.clone() else {
+        return info.to_string();
+    };
+
+    let mut rendered = render_snippet(source, "error", &info.message, span.clone(), "");
+    let gutter = " ".repeat(
+        line_of(source, span.start.min(source.len()))
+            .1
+            .to_string()
+            .len(),
+    );
+    for frame in info.backtrace.iter() {
+        let function = match &frame.function {
+            Some(name) => format!("{name}()"),
+            None => "script".to_owned(),
+        };
+        rendered += &format!("{gutter} = [line {}] in {function}\n", frame.line);
+    }
+    rendered
+}
+
+/// Renders the message (after its label, e.g., `error`), then the line of source code that
+/// contains the start of the span, with the span underlined, and the note after the underline.
+fn render_snippet(
+    source: &str,
+    label: &str,
+    message: &str,
+    span: Range<usize>,
+    note: &str,
+) -> String {
+    let start = span.start.min(source.len());
+    let (line_start, line_number) = line_of(source, start);
+    let line = source[line_start..].lines().next().unwrap_or_default();
+    let line = line.strip_suffix('\r').unwrap_or(line);
+
+    // Columns count characters, starting from 1. Tabs are kept, so that the underline lines up.
+    let before = &source[line_start..start];
+    let column = before.chars().count() + 1;
+    let indent: String = (before.chars())
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    // Underline the whole span, as long as it's on this line, and at least one character:
+    let end = span.end.clamp(start, line_start + line.len());
+    let width = source[start..end].chars().count().max(1);
+
+    let gutter = " ".repeat(line_number.to_string().len());
+    let note = if note.is_empty() {
+        String::new()
+    } else {
+        format!(" {note}")
+    };
+    format!(
+        "{label}: {message}\n\
+         {gutter}--> line {line_number}, column {column}\n\
+         {gutter} |\n\
+         {line_number} | {line}\n\
+         {gutter} | {indent}{underline}{note}\n",
+        underline = "^".repeat(width),
+    )
+}
+
+/// Returns the byte offset where the line containing the given offset starts, and its line
+/// number (starting from 1).
+fn line_of(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset];
+    let start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    (start, before.matches('\n').count() + 1)
+}
+
+////////////////////////////////////////////// Tests //////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn diagnostic(span: Range<usize>, location: &str) -> Diagnostic {
+        Diagnostic {
+            line: 0,
+            column: 0,
+            span,
+            lexeme: String::new(),
+            location: location.to_owned(),
+            message: "Oops.".to_owned(),
+            severity: Severity::Error,
+        }
+    }
+
+    #[test]
+    fn underlines_the_whole_span() {
+        let source = "print\tundefined;\r\n";
+        let expected = "\
+error: Oops.
+ --> line 1, column 7
+  |
+1 | print\tundefined;
+  |      \t^^^^^^^^^ at 'undefined'
+";
+        let rendered = render_diagnostic(source, &diagnostic(6..15, " at 'undefined'"));
+        assert_eq!(expected, rendered);
+    }
+
+    #[test]
+    fn errors_at_the_end_of_the_file() {
+        let source = "\n\n\n\n\n\n\n\n\n{\n  print 1;";
+        let expected = "\
+error: Oops.
+  --> line 11, column 11
+   |
+11 |   print 1;
+   |           ^ at end
+";
+        let end = source.len();
+        let rendered = render_diagnostic(source, &diagnostic(end..end, " at end"));
+        assert_eq!(expected, rendered);
+    }
+
+    #[test]
+    fn runtime_errors_without_a_span() {
+        let info = RuntimeErrorInfo {
+            message: "Oops.".to_owned(),
+            line: 1,
+            span: None,
+            backtrace: vec![BacktraceFrame {
+                function: None,
+                line: 1,
+            }],
+        };
+        assert_eq!(
+            "Oops.\n[line 1] in script\n",
+            render_runtime_error("", &info)
+        );
+    }
 }
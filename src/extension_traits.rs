@@ -44,10 +44,10 @@ pub trait VecLast<T> {
 
 impl<T> VecLast<T> for Vec<T> {
     fn last(&self) -> Option<&T> {
-        self.iter().rev().next()
+        self.as_slice().last()
     }
 
     fn last_mut(&mut self) -> Option<&mut T> {
-        self.iter_mut().rev().next()
+        self.as_mut_slice().last_mut()
     }
 }
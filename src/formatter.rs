@@ -0,0 +1,409 @@
+//! Formats Lox source code in a canonical style.
+//!
+//! The formatter works directly on the token stream (including comments; see
+//! [Scanner::with_trivia()]), so it does not need a syntax tree. It normalizes:
+//!
+//!  - indentation (four spaces per block),
+//!  - spacing between tokens,
+//!  - brace placement (opening braces on the same line; closing braces on their own line).
+//!
+//! Comments and (single) blank lines between statements are preserved.
+//!
+//! # Example
+//!
+//! ```
+//! use rlox::formatter::format;
+//!
+//! let ugly = "var a=1;{print a+2;}";
+//! assert_eq!("var a = 1;\n{\n    print a + 2;\n}\n", format(ugly).unwrap());
+//! ```
+
+use thiserror::Error;
+
+use crate::scanner::{Lexeme, Scanner, Token};
+
+/// The string used for one level of indentation.
+const INDENT: &str = "    ";
+
+/// Format the given Lox source code.
+///
+/// # Errors
+///
+/// Returns a [FormatError] when the source code cannot be scanned (e.g., it contains an
+/// unterminated string). Source code that scans fine, but would not compile, is still formatted.
+pub fn format(source: &str) -> Result<String, FormatError> {
+    let lexemes = Scanner::with_trivia(source)
+        .take_while(|lexeme| lexeme.token() != Token::Eof)
+        .filter(|lexeme| lexeme.token() != Token::Whitespace)
+        .collect::<Vec<_>>();
+
+    let mut formatter = Formatter::default();
+    for (i, &lexeme) in lexemes.iter().enumerate() {
+        if lexeme.token() == Token::Error {
+            return Err(FormatError {
+                line: lexeme.line(),
+                message: lexeme.text().to_owned(),
+            });
+        }
+
+        let next = lexemes.get(i + 1).map(Lexeme::token);
+        formatter.write(lexeme, next);
+    }
+
+    Ok(formatter.finish())
+}
+
+/// The source code could not be formatted.
+#[derive(Debug, Error)]
+#[error("[line {line}] Error: {message}")]
+pub struct FormatError {
+    /// The line where the problem was found.
+    pub line: usize,
+    /// What went wrong.
+    pub message: String,
+}
+
+///////////////////////////////////// Implementation details //////////////////////////////////////
+
+/// State of the formatter as it writes out tokens.
+#[derive(Default)]
+struct Formatter<'a> {
+    output: String,
+    /// The current block depth.
+    indent: usize,
+    /// How many parentheses are currently open (the semicolons in `for (;;)` stay on one line).
+    paren_depth: usize,
+    /// The last lexeme written out (including comments).
+    previous: Option<Lexeme<'a>>,
+    /// Whether the previous lexeme was a prefix (unary) operator.
+    previous_was_unary: bool,
+    /// Set when the next lexeme must start on a new line.
+    newline_pending: bool,
+    /// Set when we're in the middle of a statement that spans multiple lines.
+    in_statement: bool,
+    /// For each brace that is currently open, whether it is a map literal (rather than a block).
+    braces: Vec<bool>,
+}
+
+impl<'a> Formatter<'a> {
+    /// Write one lexeme to the output. `next` is the token that follows it, if any.
+    fn write(&mut self, lexeme: Lexeme<'a>, next: Option<Token>) {
+        use Token::*;
+        let token = lexeme.token();
+        let is_prefix =
+            token == Bang || (matches!(token, Minus | PlusPlus | MinusMinus) && self.is_prefix());
+
+        if token == Comment {
+            return self.write_comment(lexeme);
+        }
+
+        let is_map = match token {
+            LeftBrace => self.starts_map(),
+            RightBrace => self.in_map(),
+            _ => false,
+        };
+
+        if token == RightBrace && !is_map {
+            self.indent = self.indent.saturating_sub(1);
+            self.in_statement = false;
+            // Empty blocks stay on one line: {}
+            let previous = self.previous.map(|lexeme| lexeme.token());
+            self.newline_pending = previous.is_some() && previous != Some(LeftBrace);
+        }
+
+        if self.newline_pending {
+            self.start_line(lexeme);
+        } else if self.previous.is_some()
+            && (self.needs_space_before(token) || self.would_merge_with_previous(lexeme))
+        {
+            self.output.push(' ');
+        }
+
+        self.output.push_str(lexeme.text());
+        self.previous_was_unary = is_prefix;
+        self.previous = Some(lexeme);
+
+        match token {
+            LeftParen => self.paren_depth += 1,
+            RightParen => self.paren_depth = self.paren_depth.saturating_sub(1),
+            // Map literals stay on one line: {"a": 1}
+            LeftBrace if is_map => self.braces.push(true),
+            RightBrace if is_map => {
+                self.braces.pop();
+            }
+            LeftBrace => {
+                self.braces.push(false);
+                self.indent += 1;
+                self.in_statement = false;
+                self.newline_pending = next != Some(RightBrace);
+            }
+            RightBrace => {
+                self.braces.pop();
+                let continues_on_same_line =
+                    matches!(next, Some(Else | Semicolon | Comma | RightParen));
+                self.newline_pending = !continues_on_same_line;
+            }
+            Semicolon if self.paren_depth == 0 => {
+                self.in_statement = false;
+                self.newline_pending = true;
+            }
+            _ => self.in_statement = true,
+        }
+    }
+
+    /// Write a comment. Comments that trail code stay on the same line as that code.
+    fn write_comment(&mut self, comment: Lexeme<'a>) {
+        let trails_code =
+            matches!(self.previous, Some(previous) if previous.line() == start_line(comment));
+
+        if self.previous.is_none() {
+            // Comment at the start of the file.
+        } else if trails_code {
+            self.output.push(' ');
+        } else {
+            self.newline_pending = true;
+            self.start_line(comment);
+        }
+
+        self.output.push_str(comment.text().trim_end());
+        self.previous = Some(comment);
+        // Code can follow a block comment on the same line: var a = /* one */ 1;
+        let is_line_comment = comment.text().starts_with("//");
+        self.newline_pending |= is_line_comment || !trails_code;
+    }
+
+    /// Start a new line, preserving up to one blank line from the original source.
+    fn start_line(&mut self, lexeme: Lexeme) {
+        if self.previous.is_some() || !self.output.is_empty() {
+            self.output.push('\n');
+            let had_blank_line = matches!(
+                self.previous,
+                Some(previous) if start_line(lexeme) > previous.line() + 1
+            );
+            if had_blank_line {
+                self.output.push('\n');
+            }
+        }
+
+        let continuation = usize::from(self.in_statement);
+        for _ in 0..(self.indent + continuation) {
+            self.output.push_str(INDENT);
+        }
+
+        self.newline_pending = false;
+    }
+
+    /// Returns true if a space should separate the previous token and this token.
+    fn needs_space_before(&self, token: Token) -> bool {
+        use Token::*;
+
+        let previous = self.previous.map(|lexeme| lexeme.token());
+        if self.previous_was_unary || matches!(previous, Some(LeftParen | LeftBracket | Dot)) {
+            return false;
+        }
+        if previous == Some(LeftBrace) && self.in_map() {
+            return false;
+        }
+
+        match token {
+            Semicolon | Colon | Comma | RightParen | RightBracket => false,
+            // A number, a dot, and digits would merge into one number: 1 .5
+            Dot => previous == Some(Number),
+            // Postfix increments hug their variable: i++
+            PlusPlus | MinusMinus => !matches!(previous, Some(Identifier)),
+            // Function calls hug their arguments: f(x); declarations too: fun f(x)
+            LeftParen => !matches!(previous, Some(Identifier | RightParen | This | Super)),
+            // Subscripts hug what they index: list[i]
+            LeftBracket => !matches!(previous, Some(Identifier | RightParen | RightBracket)),
+            RightBrace => previous != Some(LeftBrace) && !self.in_map(),
+            _ => true,
+        }
+    }
+
+    /// Returns true if the lexeme, written right after the previous one, would be scanned
+    /// differently: e.g., `!` followed by `=` would become `!=`.
+    fn would_merge_with_previous(&self, lexeme: Lexeme) -> bool {
+        let Some(previous) = self.previous else {
+            return false;
+        };
+        let joined = format!("{}{}", previous.text(), lexeme.text());
+        let first = Scanner::with_trivia(&joined)
+            .next()
+            .map(|first| first.text().len());
+        first != Some(previous.text().len())
+    }
+
+    /// Returns true if a `{` written now would start a map literal, rather than a block.
+    fn starts_map(&self) -> bool {
+        use Token::*;
+        // Blocks start statements, so they follow the end of another statement, or the header of
+        // a class, function, or control flow statement:
+        let starts_block = matches!(
+            self.previous.map(|lexeme| lexeme.token()),
+            None | Some(Semicolon | LeftBrace | RightBrace | Else | Comment)
+        );
+        !starts_block && self.is_prefix()
+    }
+
+    /// Returns true if the innermost open brace is a map literal.
+    fn in_map(&self) -> bool {
+        self.braces.last() == Some(&true)
+    }
+
+    /// Returns true if a `-` written now would be a prefix operator (negation), rather than
+    /// subtraction.
+    fn is_prefix(&self) -> bool {
+        use Token::*;
+        !matches!(
+            self.previous.map(|lexeme| lexeme.token()),
+            Some(
+                Identifier | Number | StrLiteral | RightParen | RightBracket | True | False | Nil | This
+                    // Only postfix increments can be followed by an operator: i++ - 1
+                    | PlusPlus | MinusMinus
+            )
+        )
+    }
+
+    /// Returns the formatted output.
+    fn finish(mut self) -> String {
+        if !self.output.is_empty() {
+            self.output.push('\n');
+        }
+        self.output
+    }
+}
+
+/// Returns the line where the lexeme started. [Lexeme::line()] is where the lexeme ends, which
+/// is different for multi-line strings.
+fn start_line(lexeme: Lexeme) -> usize {
+    lexeme.line() - lexeme.text().matches('\n').count()
+}
+
+////////////////////////////////////////////// Tests //////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Asserts that the source is formatted as expected, and that formatting is idempotent.
+    fn assert_formats_to(expected: &str, source: &str) {
+        let formatted = format(source).unwrap();
+        assert_eq!(expected, formatted);
+        assert_eq!(formatted, format(&formatted).unwrap());
+    }
+
+    #[test]
+    fn spacing_around_operators() {
+        assert_formats_to("print -1 + 2 * -(3 - 4);\n", "print -1+2*-(3-4) ;");
+        assert_formats_to("print !(a == b) != !c;\n", "print ! ( a==b )!=!c;");
+        assert_formats_to("print a.b(c, d);\n", "print a . b ( c,d );");
+        assert_formats_to("print ++a - b-- + -c;\n", "print ++ a-b --+-c;");
+        assert_formats_to("print [1, 2][0] - xs[i];\n", "print [ 1,2 ] [0]-xs [ i ];");
+        assert_formats_to(
+            "var m = {\"a\": {}, 1: [2]};\n{\n    m[1] = {};\n}\n",
+            "var m={ \"a\" :{ } ,1:[2]};{m[1]={};}",
+        );
+    }
+
+    #[test]
+    fn indentation_and_braces() {
+        let source = "{var a=1;\n{\nprint a;}\n}";
+        let expected = "\
+{
+    var a = 1;
+    {
+        print a;
+    }
+}
+";
+        assert_formats_to(expected, source);
+    }
+
+    #[test]
+    fn else_stays_with_closing_brace() {
+        let source = "if (a) { print 1; }\nelse { print 2; }";
+        let expected = "\
+if (a) {
+    print 1;
+} else {
+    print 2;
+}
+";
+        assert_formats_to(expected, source);
+    }
+
+    #[test]
+    fn empty_blocks() {
+        assert_formats_to("fun f() {}\n", "fun f ( )\n{\n}");
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_preserved() {
+        let source = "// header\nvar a = 1;   // trailing   \n\n\n\n// about b\nvar b = 2;\n";
+        let expected = "// header\nvar a = 1; // trailing\n\n// about b\nvar b = 2;\n";
+        assert_formats_to(expected, source);
+    }
+
+    #[test]
+    fn block_comments_can_share_a_line_with_code() {
+        let source = "/* header\n   continued */\nvar a = /* one */1;  /* trailing */\nprint a;";
+        let expected =
+            "/* header\n   continued */\nvar a = /* one */ 1; /* trailing */\nprint a;\n";
+        assert_formats_to(expected, source);
+    }
+
+    #[test]
+    fn statements_split_across_lines_are_indented() {
+        let source = "var a = 1 + // one\n2;";
+        let expected = "var a = 1 + // one\n    2;\n";
+        assert_formats_to(expected, source);
+    }
+
+    #[test]
+    fn tokens_are_never_merged() {
+        assert_formats_to("print - -a;\n", "print - -a;");
+        assert_formats_to("print ! = a;\n", "print ! = a;");
+        assert_formats_to("print 1 .5;\n", "print 1 . 5;");
+    }
+
+    #[test]
+    fn unscannable_source() {
+        let error = format("print \"oops;").unwrap_err();
+        assert_eq!(1, error.line);
+    }
+
+    //////////////////////////////////////// Property tests ///////////////////////////////////////
+
+    use proptest::prelude::*;
+
+    /// Tokens, comments, and whitespace, to be shuffled into (mostly invalid) programs.
+    const PIECES: &[&str] = &[
+        "(", ")", "{", "}", "[", "]", ",", ".", "-", "+", "++", "--", ";", "/", "*", "!", "!=",
+        "=", "==", "<=", ":", "a", "\"s\"", "1", "2.5", "and", "else", "for", "fun", "if", "print",
+        "return", "var", "while", "// c\n", "/* c */", " ", "\n", "\n\n",
+    ];
+
+    /// Returns the tokens of the source code, without any whitespace.
+    fn tokens(source: &str) -> Vec<(Token, &str)> {
+        Scanner::with_trivia(source)
+            .take_while(|lexeme| lexeme.token() != Token::Eof)
+            .filter(|lexeme| lexeme.token() != Token::Whitespace)
+            .map(|lexeme| (lexeme.token(), lexeme.text().trim_end()))
+            .collect()
+    }
+
+    proptest! {
+        #[test]
+        fn formatting_keeps_every_token_and_is_idempotent(
+            pieces in prop::collection::vec(prop::sample::select(PIECES), 0..40)
+        ) {
+            let source = pieces.concat();
+            // e.g., "/" followed by "*" starts an unterminated comment:
+            let Ok(formatted) = format(&source) else {
+                return Ok(());
+            };
+            prop_assert_eq!(tokens(&source), tokens(&formatted));
+            prop_assert_eq!(&formatted, &format(&formatted).unwrap());
+        }
+    }
+}
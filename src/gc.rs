@@ -1,11 +1,13 @@
-//! A garbage collector that pretends to have a `'static` lifetime.
-//!
-//! Normally "GC" stands for "garbage collector", but in this codebase, "GC" just stands for "garbage" 🙃
+//! A mark-and-sweep garbage collector that pretends to have a `'static` lifetime.
 //!
 //! # Usage
 //!
-//! Things like the compiler and the VM require the global [ActiveGC] to be installed. To do this,
-//! call [ActiveGC::install] and keep its return value in scope!
+//! Things like the compiler and the VM require an [ActiveGC] to be installed. To do this, call
+//! [ActiveGC::install] and keep its return value in scope!
+//!
+//! The active GC is per thread, so every thread can have its own GC. Each [VM](crate::vm::VM) owns
+//! its own GC, and only installs it while it is running code, so any number of VMs can be used,
+//! even on the same thread.
 //!
 //! ```
 //! # use rlox::value::Value;
@@ -19,65 +21,687 @@
 //! let lox_string: Value = "hello".into();
 //! assert_eq!(1, ActiveGC::n_strings());
 //!
-//! // when `gc` gets dropped (e.g., by going out of scope), the global GC is dropped too.
+//! // when `gc` gets dropped (e.g., by going out of scope), the active GC is dropped too.
 //! ```
-use std::collections::HashSet;
+//!
+//! # Collection
+//!
+//! The GC does not know what is still in use, so it only collects garbage when asked to, with
+//! [GC::collect()], given all the _roots_: the values that the program can still reach. Everything
+//! that is not reachable from the roots is freed, so collecting is `unsafe`: the caller promises
+//! that no other value will be used again. The [VM](crate::vm::VM) collects garbage between
+//! instructions, when its stack, globals, and call frames are the only roots, once enough has been
+//! allocated (see [GcConfig]). The values it gives out are borrowed from it (see
+//! [ValueRef]), so they cannot be used while it collects.
+//!
+//! With the `stress_gc` Cargo feature, the VM collects garbage after every allocation, which is
+//! slow, but flushes out bugs where a reachable object was not marked.
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::mem::ManuallyDrop;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::value::{Class, Function, Instance, List, Map, Rope, Value, ValueRef};
+
+/// How many bytes can be allocated before the first collection, by default.
+const FIRST_COLLECTION: usize = 1024 * 1024;
+/// After a collection, how much the heap may grow before the next collection, by default.
+const HEAP_GROW_FACTOR: f64 = 2.0;
+
+/// When a [GC] decides that it's time to collect garbage. See [GC::should_collect()].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GcConfig {
+    /// Garbage is first collected once this many bytes are stored. The heap is never allowed to
+    /// grow past this size without collecting.
+    pub initial_threshold: usize,
+    /// After a collection, garbage is collected again once the heap has grown by this factor.
+    pub growth_factor: f64,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        GcConfig {
+            initial_threshold: FIRST_COLLECTION,
+            growth_factor: HEAP_GROW_FACTOR,
+        }
+    }
+}
 
-/// A garbage collector, which is really more of a big store of all dynamic data in the
-/// application. For now, it's just string data, and there is no reference counting so all strings
-/// are kept forever until the GC is dropped. Right now it literally collects garbage. Forever 😇
-#[derive(Clone, Debug, Default)]
+/// A garbage collector, which stores all dynamic data in the application: strings, ropes,
+/// functions, classes, instances, lists, and maps. Objects are kept until they are collected (see
+/// [GC::collect()]) or the GC is dropped.
+#[derive(Debug)]
 pub struct GC {
     strings: HashSet<String>,
+    /// Thin pointers to stored strings, for [PackedValue](crate::nan_boxing::PackedValue)s.
+    /// They are keyed by the address of the string's contents.
+    /// The [Box] gives each (fat) `&str` a stable, thin address.
+    #[cfg(feature = "nan_boxing")]
+    #[allow(clippy::redundant_allocation)]
+    string_handles: HashMap<*const u8, Box<&'static str>>,
+    /// Boxed, so that references to ropes stay valid as more are added.
+    #[allow(clippy::vec_box)]
+    ropes: Vec<Box<Rope>>,
+    /// Boxed, so that references to functions stay valid as more are added.
+    #[allow(clippy::vec_box)]
+    functions: Vec<Box<Function>>,
+    #[allow(clippy::vec_box)]
+    classes: Vec<Box<Class>>,
+    #[allow(clippy::vec_box)]
+    instances: Vec<Box<Instance>>,
+    #[allow(clippy::vec_box)]
+    lists: Vec<Box<List>>,
+    #[allow(clippy::vec_box)]
+    maps: Vec<Box<Map>>,
+    /// How many times something was stored, including duplicate strings.
+    n_allocations: usize,
+    /// (Roughly) how many bytes are currently stored.
+    bytes_allocated: usize,
+    /// Collect garbage once this many bytes are stored.
+    next_collection: usize,
+    /// How many objects were stored since the last collection.
+    allocations_since_collection: usize,
+    /// Set to collect garbage at the next opportunity. See [GC::request_collection()].
+    collection_requested: bool,
+    /// When to collect garbage.
+    config: GcConfig,
+    /// The ids of the objects that have [WeakHandle]s, by address. An object's entry is removed
+    /// when the object is freed. A [RefCell], so that values can be downgraded while they are
+    /// borrowed from the GC.
+    weak_ids: RefCell<HashMap<*const (), u64>>,
+    /// How many times garbage was collected.
+    n_collections: usize,
+    /// Total time spent collecting garbage.
+    collection_time: Duration,
+}
+
+/// What a [GC] is storing, and what it has done so far. See [GC::stats()].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GcStats {
+    /// (Roughly) how many bytes are currently stored.
+    pub bytes_allocated: usize,
+    /// Garbage is collected once this many bytes are stored.
+    pub next_collection: usize,
+    /// How many strings are currently stored.
+    pub strings: usize,
+    /// How many ropes (strings that have not been copied into one piece yet) are currently stored.
+    pub ropes: usize,
+    /// How many functions are currently stored.
+    pub functions: usize,
+    /// How many classes are currently stored.
+    pub classes: usize,
+    /// How many instances are currently stored.
+    pub instances: usize,
+    /// How many lists are currently stored.
+    pub lists: usize,
+    /// How many maps are currently stored.
+    pub maps: usize,
+    /// How many times something has been stored, including duplicate strings.
+    pub allocations: usize,
+    /// How many times garbage has been collected.
+    pub collections: usize,
+    /// The total time spent collecting garbage.
+    pub collection_time: Duration,
+}
+
+/// A reference to a value that does not keep it from being collected. Embedders can hold on to
+/// these between collections, unlike [Value]s, which must not be used once their objects have
+/// been freed. See [GC::downgrade()].
+#[derive(Clone, Copy, Debug)]
+pub struct WeakHandle {
+    target: WeakTarget,
+}
+
+/// What a [WeakHandle] refers to.
+#[derive(Clone, Copy, Debug)]
+enum WeakTarget {
+    /// A value that is not stored in the GC (e.g., a number), and so is never collected.
+    Value(Value),
+    /// An object stored in the GC. The id tells it apart from any later object that is stored at
+    /// the same address once it has been freed.
+    Object { object: Object, id: u64 },
+}
+
+/// The address of an object stored in a GC, which may have been freed.
+#[derive(Clone, Copy, Debug)]
+enum Object {
+    String(*const u8, usize),
+    Rope(*const Rope),
+    Function(*const Function),
+    Class(*const Class),
+    Instance(*const Instance),
+    List(*const List),
+    Map(*const Map),
+}
+
+/// The next id for an object with a [WeakHandle]. Ids are unique across every GC, so that a
+/// handle can only be upgraded by the GC that stores its object.
+static NEXT_WEAK_ID: AtomicU64 = AtomicU64::new(0);
+
+/// The objects found to be reachable during a collection.
+#[derive(Default)]
+struct Marked {
+    strings: HashSet<&'static str>,
+    /// The addresses of ropes, functions, classes, instances, lists, and maps.
+    objects: HashSet<*const ()>,
 }
 
-/// A token that indicates that the global static [GC] has been installed. The only way to obtain
-/// this token is to install the GC somehow (for example, by calling [ActiveGC::install]).
-/// When this token is dropped, the global static GC will be uninstalled and dropped.
-// No doctests because it will cause a ✨race condition✨
+/// A token that indicates that a [GC] has been installed as this thread's active GC. The only way
+/// to obtain this token is to install the GC somehow (for example, by calling
+/// [ActiveGC::install]). When this token is dropped, the GC will be uninstalled and dropped, and
+/// whichever GC was active before it is active again.
 #[derive(Debug)]
-// The private () parameter is used to prevent anything but this module from instantiating an
-// ActiveGC. This is because only ActiveGC::install() should make an ActiveGC come into existence.
-pub struct ActiveGC(());
+// The fields are private to prevent anything but this module from instantiating an ActiveGC.
+// This is because only ActiveGC::install() should make an ActiveGC come into existence.
+pub struct ActiveGC {
+    /// The installed GC, which this token owns. It's a raw pointer (from a [Box]) because the
+    /// thread-local ACTIVE_GC points to it too.
+    gc: NonNull<GC>,
+    /// The GC that was active before this one was installed (possibly null).
+    previous: *mut GC,
+}
+
+thread_local! {
+    /// The [GC] that is active on this thread, or null when none is installed. Install with
+    /// [ActiveGC::activate()].
+    static ACTIVE_GC: Cell<*mut GC> = const { Cell::new(ptr::null_mut()) };
+}
 
-/// The actual static (global) [GC] instance. Install with `into_active_gc()`.
-static mut ACTIVE_GC: Option<GC> = None;
+impl Default for GC {
+    fn default() -> Self {
+        GC::with_config(GcConfig::default())
+    }
+}
 
 impl GC {
+    /// Creates a GC that collects garbage as the config says.
+    pub fn with_config(config: GcConfig) -> Self {
+        GC {
+            strings: HashSet::default(),
+            #[cfg(feature = "nan_boxing")]
+            string_handles: HashMap::default(),
+            ropes: Vec::new(),
+            functions: Vec::new(),
+            classes: Vec::new(),
+            instances: Vec::new(),
+            lists: Vec::new(),
+            maps: Vec::new(),
+            n_allocations: 0,
+            bytes_allocated: 0,
+            next_collection: config.initial_threshold,
+            allocations_since_collection: 0,
+            collection_requested: false,
+            config,
+            weak_ids: RefCell::default(),
+            n_collections: 0,
+            collection_time: Duration::ZERO,
+        }
+    }
+
+    /// Changes when garbage is collected. The next collection is once
+    /// [GcConfig::initial_threshold] bytes are stored.
+    pub fn set_config(&mut self, config: GcConfig) {
+        self.config = config;
+        self.next_collection = config.initial_threshold;
+    }
+
     /// Adds a string to storage. Returns a reference to the stored string.
     pub fn store_string(&mut self, owned: String) -> &str {
         // HACK: with the current HashMap/HashSet API, I cannot figure out how to do things without
         // a clone 😭
         let key = owned.clone();
-        self.strings.insert(owned);
+        self.n_allocations += 1;
+        let size = owned.len();
+        if self.strings.insert(owned) {
+            self.allocated(size);
+        }
         self.strings.get(&key).unwrap()
     }
 
+    /// Returns a thin pointer to a stored string, which stays valid for as long as the string is
+    /// stored. [PackedValue](crate::nan_boxing::PackedValue)s use this, since a `&str` is too
+    /// big to pack.
+    #[cfg(feature = "nan_boxing")]
+    pub fn string_handle(&mut self, string: &'static str) -> &'static &'static str {
+        let handle = (self.string_handles)
+            .entry(string.as_ptr())
+            .or_insert_with(|| Box::new(string));
+        // SAFETY: the box is only dropped when the string is collected, or the GC is dropped.
+        unsafe { &*ptr::from_ref::<&'static str>(handle) }
+    }
+
+    /// Adds a rope to storage. Returns a reference to the stored rope. Its size includes the
+    /// string that it will be flattened into.
+    pub fn store_rope(&mut self, rope: Rope) -> &Rope {
+        self.n_allocations += 1;
+        self.allocated(rope_size(&rope));
+        self.ropes.push(Box::new(rope));
+        self.ropes.last().unwrap()
+    }
+
+    /// Adds a function to storage. Returns a reference to the stored function.
+    pub fn store_function(&mut self, function: Function) -> &Function {
+        self.n_allocations += 1;
+        self.allocated(std::mem::size_of::<Function>());
+        self.functions.push(Box::new(function));
+        self.functions.last().unwrap()
+    }
+
+    /// Adds a class to storage. Returns a reference to the stored class.
+    pub fn store_class(&mut self, class: Class) -> &Class {
+        self.n_allocations += 1;
+        self.allocated(std::mem::size_of::<Class>());
+        self.classes.push(Box::new(class));
+        self.classes.last().unwrap()
+    }
+
+    /// Adds an instance to storage. Returns a reference to the stored instance.
+    pub fn store_instance(&mut self, instance: Instance) -> &Instance {
+        self.n_allocations += 1;
+        self.allocated(std::mem::size_of::<Instance>());
+        self.instances.push(Box::new(instance));
+        self.instances.last().unwrap()
+    }
+
+    /// Adds a list to storage. Returns a reference to the stored list.
+    pub fn store_list(&mut self, list: List) -> &List {
+        self.n_allocations += 1;
+        self.allocated(std::mem::size_of::<List>());
+        self.lists.push(Box::new(list));
+        self.lists.last().unwrap()
+    }
+
+    /// Adds a map to storage. Returns a reference to the stored map.
+    pub fn store_map(&mut self, map: Map) -> &Map {
+        self.n_allocations += 1;
+        self.allocated(std::mem::size_of::<Map>());
+        self.maps.push(Box::new(map));
+        self.maps.last().unwrap()
+    }
+
+    /// Returns true if enough has been allocated that it's time to collect garbage (see
+    /// [GcConfig]), or if a collection was requested. With the `stress_gc` feature, that's after
+    /// every allocation.
+    pub fn should_collect(&self) -> bool {
+        if self.collection_requested {
+            true
+        } else if cfg!(feature = "stress_gc") {
+            self.allocations_since_collection > 0
+        } else {
+            self.bytes_allocated > self.next_collection
+        }
+    }
+
+    /// Frees every object that cannot be reached from the given roots.
+    ///
+    /// ```
+    /// # use rlox::gc::ActiveGC;
+    /// # use rlox::value::Value;
+    /// let _gc = ActiveGC::install();
+    /// let kept: Value = "kept".into();
+    /// let garbage: Value = "garbage".into();
+    ///
+    /// // SAFETY: `garbage` is never used again.
+    /// unsafe { ActiveGC::collect([kept]) };
+    /// assert_eq!(1, ActiveGC::n_strings());
+    /// ```
+    ///
+    /// # Safety
+    ///
+    /// Any value (or reference) to an object that is stored in this GC, but is not reachable
+    /// from the roots, must never be used again, since its object is freed.
+    pub unsafe fn collect(&mut self, roots: impl IntoIterator<Item = Value>) {
+        let start = Instant::now();
+
+        let marked = Self::mark(roots);
+        self.sweep(&marked);
+
+        let grown = self.bytes_allocated as f64 * self.config.growth_factor;
+        self.next_collection = (grown as usize).max(self.config.initial_threshold);
+        self.allocations_since_collection = 0;
+        self.collection_requested = false;
+        self.n_collections += 1;
+        self.collection_time += start.elapsed();
+    }
+
+    /// Asks for garbage to be collected at the next opportunity, even if not much has been
+    /// allocated: [GC::should_collect()] returns true until the next collection.
+    pub fn request_collection(&mut self) {
+        self.collection_requested = true;
+    }
+
+    /// Returns a [WeakHandle] to the value, which can be [upgraded](GC::upgrade()) back to the
+    /// value, as long as the value's object (if any) has not been collected. The value must be
+    /// stored in this GC.
+    ///
+    /// ```
+    /// # use rlox::gc::ActiveGC;
+    /// # use rlox::value::Value;
+    /// let gc = ActiveGC::install();
+    /// let kept: Value = "kept".into();
+    /// let garbage: Value = "garbage".into();
+    /// let (weak_kept, weak_garbage) = (ActiveGC::downgrade(kept), ActiveGC::downgrade(garbage));
+    ///
+    /// // SAFETY: only `kept` is used again.
+    /// unsafe { ActiveGC::collect([kept]) };
+    /// assert_eq!(Ok("kept"), gc.upgrade(&weak_kept).unwrap().get::<&str>());
+    /// assert!(gc.upgrade(&weak_garbage).is_none());
+    /// ```
+    pub fn downgrade(&self, value: Value) -> WeakHandle {
+        let Some(object) = Object::of(value) else {
+            return WeakHandle {
+                target: WeakTarget::Value(value),
+            };
+        };
+
+        let id = *(self.weak_ids.borrow_mut().entry(object.address()))
+            .or_insert_with(|| NEXT_WEAK_ID.fetch_add(1, Ordering::Relaxed));
+        WeakHandle {
+            target: WeakTarget::Object { object, id },
+        }
+    }
+
+    /// Returns the value that the [WeakHandle] refers to, or `None` if it has been collected (or
+    /// was never stored in this GC). The value is borrowed from the GC, so that it cannot be
+    /// collected while it is in use.
+    pub fn upgrade(&self, handle: &WeakHandle) -> Option<ValueRef<'_>> {
+        let value = match handle.target {
+            WeakTarget::Value(value) => value,
+            WeakTarget::Object { object, id } => {
+                let stored = self.weak_ids.borrow().get(&object.address()) == Some(&id);
+                // SAFETY: the object's id is only in weak_ids while the object is stored.
+                stored.then(|| unsafe { object.value() })?
+            }
+        };
+        Some(ValueRef::new(value))
+    }
+
     /// Consume self and convert it into the [ActiveGC].
     #[must_use]
+    #[cfg_attr(not(test), allow(dead_code))]
     fn into_active_gc(self) -> ActiveGC {
-        unsafe {
-            ACTIVE_GC = Some(self);
-        }
-        ActiveGC(())
+        ActiveGC::activate(Box::new(self))
     }
 
     /// Return how many strings are currently stored.
     pub fn n_strings(&self) -> usize {
         self.strings.len()
     }
+
+    /// Return how many objects (other than strings) are currently stored.
+    pub fn n_objects(&self) -> usize {
+        self.ropes.len()
+            + self.functions.len()
+            + self.classes.len()
+            + self.instances.len()
+            + self.lists.len()
+            + self.maps.len()
+    }
+
+    /// Return how many times something has been stored.
+    pub fn n_allocations(&self) -> usize {
+        self.n_allocations
+    }
+
+    /// Return how many times garbage has been collected.
+    pub fn n_collections(&self) -> usize {
+        self.n_collections
+    }
+
+    /// Return the total time spent collecting garbage.
+    pub fn collection_time(&self) -> Duration {
+        self.collection_time
+    }
+
+    /// Returns how much is stored, by type, and how much has been collected.
+    ///
+    /// ```
+    /// # use rlox::gc::GC;
+    /// # use rlox::value::List;
+    /// let mut gc = GC::default();
+    /// gc.store_string("a".to_owned());
+    /// gc.store_string("a".to_owned());
+    /// gc.store_list(List::new(Vec::new()));
+    ///
+    /// let stats = gc.stats();
+    /// assert_eq!((1, 1, 0), (stats.strings, stats.lists, stats.maps));
+    /// assert_eq!(3, stats.allocations);
+    /// assert_eq!(0, stats.collections);
+    /// ```
+    pub fn stats(&self) -> GcStats {
+        GcStats {
+            bytes_allocated: self.bytes_allocated,
+            next_collection: self.next_collection,
+            strings: self.strings.len(),
+            ropes: self.ropes.len(),
+            functions: self.functions.len(),
+            classes: self.classes.len(),
+            instances: self.instances.len(),
+            lists: self.lists.len(),
+            maps: self.maps.len(),
+            allocations: self.n_allocations,
+            collections: self.n_collections,
+            collection_time: self.collection_time,
+        }
+    }
+
+    /// Keeps track of how much is stored.
+    fn allocated(&mut self, size: usize) {
+        self.bytes_allocated += size;
+        self.allocations_since_collection += 1;
+    }
+
+    /// Finds everything reachable from the roots.
+    fn mark(roots: impl IntoIterator<Item = Value>) -> Marked {
+        let mut marked = Marked::default();
+        // The "gray" objects: reachable, but not yet traced.
+        let mut gray: Vec<Value> = roots.into_iter().collect();
+
+        while let Some(value) = gray.pop() {
+            match value {
+                Value::LoxString(string) => {
+                    marked.strings.insert(string);
+                }
+                Value::Rope(rope) => {
+                    if marked.objects.insert(ptr::from_ref(rope).cast()) {
+                        gray.extend(rope.pieces().into_iter().flatten());
+                    }
+                }
+                Value::Function(function) => {
+                    if marked.objects.insert(ptr::from_ref(function).cast()) {
+                        marked.strings.insert(function.name);
+                        gray.extend(function.chunk.constants());
+                    }
+                }
+                Value::Class(class) => {
+                    if marked.objects.insert(ptr::from_ref(class).cast()) {
+                        marked.strings.insert(class.name);
+                    }
+                }
+                Value::Instance(instance) => {
+                    if marked.objects.insert(ptr::from_ref(instance).cast()) {
+                        gray.push(Value::Class(instance.class));
+                        for (&name, &field) in instance.fields.borrow().iter() {
+                            marked.strings.insert(name);
+                            gray.push(field);
+                        }
+                    }
+                }
+                Value::List(list) => {
+                    if marked.objects.insert(ptr::from_ref(list).cast()) {
+                        gray.extend(list.items.borrow().iter().copied());
+                    }
+                }
+                Value::Map(map) => {
+                    if marked.objects.insert(ptr::from_ref(map).cast()) {
+                        for (&key, &value) in map.entries.borrow().iter() {
+                            gray.push(key.value());
+                            gray.push(value);
+                        }
+                    }
+                }
+                Value::Nil | Value::Boolean(_) | Value::Number(_) | Value::NativeFunction(_) => {}
+                #[cfg(feature = "integers")]
+                Value::Integer(_) => {}
+            }
+        }
+
+        marked
+    }
+
+    /// Frees everything that was not marked.
+    fn sweep(&mut self, marked: &Marked) {
+        let mut freed = 0;
+        self.strings.retain(|string| {
+            let keep = marked.strings.contains(string.as_str());
+            if !keep {
+                freed += string.len();
+            }
+            keep
+        });
+
+        #[cfg(feature = "nan_boxing")]
+        self.string_handles
+            .retain(|_, string| marked.strings.contains(**string));
+
+        fn sweep_objects<T>(objects: &mut Vec<Box<T>>, marked: &Marked) -> usize {
+            let before = objects.len();
+            objects.retain(|object| marked.objects.contains(&ptr::from_ref(&**object).cast()));
+            (before - objects.len()) * std::mem::size_of::<T>()
+        }
+        self.ropes.retain(|rope| {
+            let keep = marked.objects.contains(&ptr::from_ref(&**rope).cast());
+            if !keep {
+                freed += rope_size(rope);
+            }
+            keep
+        });
+        freed += sweep_objects(&mut self.functions, marked);
+        freed += sweep_objects(&mut self.classes, marked);
+        freed += sweep_objects(&mut self.instances, marked);
+        freed += sweep_objects(&mut self.lists, marked);
+        freed += sweep_objects(&mut self.maps, marked);
+
+        let weak_ids = self.weak_ids.get_mut();
+        if !weak_ids.is_empty() {
+            let strings: HashSet<*const ()> = marked
+                .strings
+                .iter()
+                .map(|string| string.as_ptr().cast())
+                .collect();
+            weak_ids
+                .retain(|address, _| marked.objects.contains(address) || strings.contains(address));
+        }
+
+        self.bytes_allocated -= freed;
+    }
 }
 
-impl ActiveGC {
-    /// Create a [GC] and install it as the active GC.
+/// Returns (roughly) how many bytes a rope takes up, including the string that it will be
+/// flattened into.
+fn rope_size(rope: &Rope) -> usize {
+    std::mem::size_of::<Rope>() + rope.len()
+}
+
+impl Object {
+    /// Returns the object that the value refers to, or `None` if the value is not stored in a GC.
+    fn of(value: Value) -> Option<Object> {
+        match value {
+            Value::LoxString(string) => Some(Object::String(string.as_ptr(), string.len())),
+            Value::Rope(rope) => Some(Object::Rope(rope)),
+            Value::Function(function) => Some(Object::Function(function)),
+            Value::Class(class) => Some(Object::Class(class)),
+            Value::Instance(instance) => Some(Object::Instance(instance)),
+            Value::List(list) => Some(Object::List(list)),
+            Value::Map(map) => Some(Object::Map(map)),
+            Value::Nil | Value::Boolean(_) | Value::Number(_) | Value::NativeFunction(_) => None,
+            #[cfg(feature = "integers")]
+            Value::Integer(_) => None,
+        }
+    }
+
+    /// The address of the object, as used by [Marked::objects].
+    fn address(self) -> *const () {
+        match self {
+            Object::String(string, _) => string.cast(),
+            Object::Rope(rope) => rope.cast(),
+            Object::Function(function) => function.cast(),
+            Object::Class(class) => class.cast(),
+            Object::Instance(instance) => instance.cast(),
+            Object::List(list) => list.cast(),
+            Object::Map(map) => map.cast(),
+        }
+    }
+
+    /// Returns a value that refers to the object.
+    ///
+    /// # Safety
     ///
-    /// # Panics
+    /// The object must still be stored in its GC.
+    unsafe fn value(self) -> Value {
+        // SAFETY: the caller guarantees that the object is stored, so its address is valid.
+        unsafe {
+            match self {
+                Object::String(string, len) => {
+                    let bytes = std::slice::from_raw_parts(string, len);
+                    Value::LoxString(std::str::from_utf8_unchecked(bytes))
+                }
+                Object::Rope(rope) => Value::Rope(&*rope),
+                Object::Function(function) => Value::Function(&*function),
+                Object::Class(class) => Value::Class(&*class),
+                Object::Instance(instance) => Value::Instance(&*instance),
+                Object::List(list) => Value::List(&*list),
+                Object::Map(map) => Value::Map(&*map),
+            }
+        }
+    }
+}
+
+impl ActiveGC {
+    /// Create a [GC] and install it as this thread's active GC.
     ///
-    /// Only one [GC] instance can be active at a time. It is ✨undefined behaviour✨ if you call this
-    /// while an existing [GC] is already installed.
+    /// Only one [GC] can be active at a time on each thread. If a GC is already installed, it is
+    /// replaced until the returned token is dropped. Tokens must be dropped in the opposite order
+    /// that they were installed.
     #[must_use]
     pub fn install() -> ActiveGC {
-        GC::default().into_active_gc()
+        Self::activate(Box::default())
+    }
+
+    /// Create a [GC] that collects garbage as the config says, and install it as this thread's
+    /// active GC. See [ActiveGC::install()].
+    ///
+    /// ```
+    /// use rlox::gc::{ActiveGC, GcConfig};
+    /// let _gc = ActiveGC::install_with_config(GcConfig {
+    ///     initial_threshold: 64,
+    ///     ..GcConfig::default()
+    /// });
+    /// assert!(!ActiveGC::should_collect());
+    /// ActiveGC::store_string("more than sixty-four bytes, which is more than the threshold".repeat(2));
+    /// assert!(ActiveGC::should_collect());
+    /// ```
+    #[must_use]
+    pub fn install_with_config(config: GcConfig) -> ActiveGC {
+        Self::activate(Box::new(GC::with_config(config)))
+    }
+
+    /// Installs an existing [GC] as this thread's active GC. Use [ActiveGC::deactivate()] to get
+    /// it back, with everything it has stored.
+    #[must_use]
+    pub fn activate(gc: Box<GC>) -> ActiveGC {
+        let gc = NonNull::from(Box::leak(gc));
+        let previous = ACTIVE_GC.with(|active| active.replace(gc.as_ptr()));
+        ActiveGC { gc, previous }
+    }
+
+    /// Uninstalls the GC, **without** dropping it. References to anything it stores stay valid for
+    /// as long as the returned [GC] lives.
+    pub fn deactivate(self) -> Box<GC> {
+        let mut this = ManuallyDrop::new(self);
+        this.uninstall()
     }
 
     ///////////////////////////////////////////////////////////////////////////////////////////////
@@ -96,35 +720,134 @@ impl ActiveGC {
         Self::get().store_string(s)
     }
 
+    /// Returns a thin pointer to a string stored in the active [GC]. See [GC::string_handle()].
+    #[cfg(feature = "nan_boxing")]
+    pub fn string_handle(string: &'static str) -> &'static &'static str {
+        Self::get().string_handle(string)
+    }
+
+    /// Store a function in the active [GC].
+    ///
+    /// Returns a reference to the function's storage. Like [ActiveGC::store_string()], the
+    /// reference lives for as long as the [ActiveGC] is installed, **not** `'static`.
+    pub fn store_function(function: Function) -> &'static Function {
+        Self::get().store_function(function)
+    }
+
+    /// Store a class in the active [GC]. See [ActiveGC::store_function()].
+    pub fn store_class(class: Class) -> &'static Class {
+        Self::get().store_class(class)
+    }
+
+    /// Store an instance in the active [GC]. See [ActiveGC::store_function()].
+    pub fn store_instance(instance: Instance) -> &'static Instance {
+        Self::get().store_instance(instance)
+    }
+
+    /// Store a rope in the active [GC]. See [ActiveGC::store_function()].
+    pub fn store_rope(rope: Rope) -> &'static Rope {
+        Self::get().store_rope(rope)
+    }
+
+    /// Store a list in the active [GC]. See [ActiveGC::store_function()].
+    pub fn store_list(list: List) -> &'static List {
+        Self::get().store_list(list)
+    }
+
+    /// Store a map in the active [GC]. See [ActiveGC::store_function()].
+    pub fn store_map(map: Map) -> &'static Map {
+        Self::get().store_map(map)
+    }
+
     /// Return how many strings are currently stored.
     pub fn n_strings() -> usize {
         Self::get().n_strings()
     }
 
+    /// Return how many times something has been stored.
+    pub fn n_allocations() -> usize {
+        Self::get().n_allocations()
+    }
+
+    /// Return how many times garbage has been collected.
+    pub fn n_collections() -> usize {
+        Self::get().n_collections()
+    }
+
+    /// Return the total time spent collecting garbage.
+    pub fn collection_time() -> Duration {
+        Self::get().collection_time()
+    }
+
+    /// Returns how much the active GC is storing. See [GC::stats()].
+    pub fn stats() -> GcStats {
+        Self::get().stats()
+    }
+
+    /// Returns true if it's time to collect garbage. See [GC::should_collect()].
+    pub fn should_collect() -> bool {
+        Self::get().should_collect()
+    }
+
+    /// Asks for garbage to be collected at the next opportunity. See
+    /// [GC::request_collection()].
+    pub fn request_collection() {
+        Self::get().request_collection()
+    }
+
+    /// Returns a [WeakHandle] to the value. See [GC::downgrade()].
+    pub fn downgrade(value: Value) -> WeakHandle {
+        Self::get().downgrade(value)
+    }
+
+    /// Returns the value that the [WeakHandle] refers to, if it has not been collected. Unlike
+    /// the other methods, it uses this token's GC (rather than whichever is active), so that the
+    /// value can be borrowed from the token, which owns the GC. See [GC::upgrade()].
+    pub fn upgrade(&self, handle: &WeakHandle) -> Option<ValueRef<'_>> {
+        // SAFETY: the pointer came from Box::leak() in ActiveGC::activate(), and the GC is not
+        // dropped until this token is.
+        unsafe { self.gc.as_ref() }.upgrade(handle)
+    }
+
+    /// Frees every object that cannot be reached from the given roots. See [GC::collect()].
+    ///
+    /// # Safety
+    ///
+    /// See [GC::collect()].
+    pub unsafe fn collect(roots: impl IntoIterator<Item = Value>) {
+        Self::get().collect(roots)
+    }
+
     /// Get the current active [GC].
     fn get() -> &'static mut GC {
-        unsafe { &mut ACTIVE_GC }
-            .as_mut()
-            .expect("Tried to get active GC, but it's not installed")
+        let gc = ACTIVE_GC.with(Cell::get);
+        // SAFETY: the pointer is either null, or points to the GC owned by the installed ActiveGC.
+        unsafe { gc.as_mut() }.expect("Tried to get active GC, but it's not installed")
+    }
+
+    /// Reinstates the previously active GC, and takes back ownership of this one.
+    fn uninstall(&mut self) -> Box<GC> {
+        let active = ACTIVE_GC.with(|active| active.replace(self.previous));
+        assert_eq!(
+            self.gc.as_ptr(),
+            active,
+            "Trying to uninstall a GC that is not active"
+        );
+        // SAFETY: the pointer came from Box::leak() in ActiveGC::activate(), and it's no longer
+        // reachable from ACTIVE_GC.
+        unsafe { Box::from_raw(self.gc.as_ptr()) }
     }
 }
 
 impl Drop for ActiveGC {
     fn drop(&mut self) {
-        // Uninstall the GC by taking ownership of it.
-        unsafe {
-            ACTIVE_GC
-                .take()
-                .expect("Trying to drop active GC, but it's not installed")
-        };
+        drop(self.uninstall());
         // GC dropped here!
     }
 }
 
 #[cfg(test)]
 mod test {
-    // Since the active GC is SHARED, MUTABLE STATE 👹, these tests **must** run in serial, or else
-    // they will trample over each others' GC :/
     use serial_test::serial;
 
     use super::*;
@@ -170,4 +893,120 @@ mod test {
 
         ActiveGC::store_string("🍕".to_owned());
     }
+
+    #[test]
+    #[serial]
+    fn test_collect() {
+        let _gc = ActiveGC::install();
+        let name = ActiveGC::store_string("Point".to_owned());
+        let class = ActiveGC::store_class(Class { name });
+        let point = ActiveGC::store_instance(Instance::new(class));
+        let x = ActiveGC::store_string("x".to_owned());
+        let label = Value::from("origin");
+        point.fields.borrow_mut().insert(x, label);
+
+        // Garbage:
+        ActiveGC::store_instance(Instance::new(class));
+        ActiveGC::store_string("garbage".to_owned());
+        assert_eq!((4, 3), (ActiveGC::n_strings(), ActiveGC::get().n_objects()));
+
+        // The instance keeps its class, and its fields alive:
+        // SAFETY: (here and below) nothing that is collected is used again.
+        unsafe { ActiveGC::collect([Value::Instance(point)]) };
+        assert_eq!((3, 2), (ActiveGC::n_strings(), ActiveGC::get().n_objects()));
+        assert_eq!(1, ActiveGC::n_collections());
+
+        unsafe { ActiveGC::collect([]) };
+        assert_eq!((0, 0), (ActiveGC::n_strings(), ActiveGC::get().n_objects()));
+    }
+
+    #[test]
+    #[serial]
+    #[cfg_attr(
+        feature = "stress_gc",
+        ignore = "stress_gc collects after every allocation"
+    )]
+    fn test_collection_thresholds() {
+        let mut gc = GC::with_config(GcConfig {
+            initial_threshold: 100,
+            growth_factor: 3.0,
+        });
+        gc.store_string("a".repeat(60));
+        assert!(!gc.should_collect());
+        gc.store_string("b".repeat(60));
+        assert!(gc.should_collect());
+
+        let _active_gc = gc.into_active_gc();
+        let kept: Value = ActiveGC::store_string("c".repeat(50)).into();
+        // SAFETY: (here and below) only `kept` is used again.
+        unsafe { ActiveGC::collect([kept]) };
+        // The heap may grow to three times the 50 bytes that are left:
+        ActiveGC::store_string("d".repeat(90));
+        assert!(!ActiveGC::should_collect());
+        ActiveGC::store_string("e".repeat(20));
+        assert!(ActiveGC::should_collect());
+
+        unsafe { ActiveGC::collect([kept]) };
+        assert!(!ActiveGC::should_collect());
+        ActiveGC::request_collection();
+        assert!(ActiveGC::should_collect());
+        unsafe { ActiveGC::collect([kept]) };
+        assert!(!ActiveGC::should_collect());
+    }
+
+    #[test]
+    #[serial]
+    fn test_weak_handles() {
+        let gc = ActiveGC::install();
+        let name = ActiveGC::store_string("Point".to_owned());
+        let class = Value::Class(ActiveGC::store_class(Class { name }));
+        let list = Value::List(ActiveGC::store_list(List::new(vec![class])));
+        let string: Value = "garbage".into();
+
+        let handles = [list, class, string, Value::Number(1.0)].map(ActiveGC::downgrade);
+        // SAFETY: (here and below) collected values are only upgraded, never used.
+        unsafe { ActiveGC::collect([list]) };
+        let upgraded = handles
+            .each_ref()
+            .map(|handle| gc.upgrade(handle).map(ValueRef::value));
+        assert_eq!(
+            [Some(list), Some(class), None, Some(Value::Number(1.0))],
+            upgraded
+        );
+
+        // An object stored where a collected object was is a different object:
+        let string: Value = "garbage".into();
+        assert_eq!(None, gc.upgrade(&handles[2]));
+        let handle = ActiveGC::downgrade(string);
+        assert_eq!(Some(string), gc.upgrade(&handle).map(ValueRef::value));
+
+        // Handles only work with the GC that made them:
+        let other = ActiveGC::install();
+        assert_eq!(None, other.upgrade(&handles[0]));
+        drop(other);
+
+        unsafe { ActiveGC::collect([]) };
+        assert!(handles
+            .iter()
+            .take(3)
+            .all(|handle| gc.upgrade(handle).is_none()));
+    }
+
+    #[test]
+    #[serial]
+    fn test_nested_gcs() {
+        let outer = ActiveGC::install();
+        ActiveGC::store_string("outer".to_owned());
+
+        let inner = ActiveGC::install();
+        assert_eq!(0, ActiveGC::n_strings());
+        ActiveGC::store_string("inner".to_owned());
+        ActiveGC::store_string("inner 2".to_owned());
+        let inner = inner.deactivate();
+        assert_eq!(2, inner.n_strings());
+
+        // The outer GC is active again:
+        assert_eq!(1, ActiveGC::n_strings());
+        drop(outer);
+    }
 }
@@ -0,0 +1,242 @@
+//! The table of global variables, and the inline caches that skip looking their names up.
+//!
+//! Every global has a slot in the [Globals] table, which it keeps for as long as the table
+//! exists: redefining a global writes its value into the same slot, and removing it only empties
+//! the slot. So once a [GlobalCache] has recorded which slot a `GetGlobal` instruction found its
+//! global in, the VM can read that slot again without hashing the name. A cached slot is only
+//! trusted if it came from the same table: every table has its own generation, which is recorded
+//! with the slot.
+//!
+//! Globals declared with `const` are marked in their slot, along with the line that declared them,
+//! so that the VM can refuse to assign to them or define them again. Embedders can still replace
+//! a constant with [Globals::insert()], which removes the mark.
+
+use std::cell::{Cell, OnceCell};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::value::Value;
+
+/// The global variables of a VM, by name.
+#[derive(Debug)]
+pub(crate) struct Globals {
+    /// The slot of every name that has ever been defined.
+    indices: HashMap<&'static str, u32>,
+    slots: Vec<Slot>,
+    /// Identifies this table, for the caches.
+    generation: u32,
+}
+
+/// One global variable.
+#[derive(Debug)]
+struct Slot {
+    name: &'static str,
+    /// The value, unless the global has been removed.
+    value: Option<Value>,
+    /// The line that declared the global with `const`, if it was.
+    constant: Option<usize>,
+}
+
+/// Where a `GetGlobal` instruction found its global: the slot in the table of the given
+/// generation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct CachedSlot {
+    generation: u32,
+    index: u32,
+}
+
+/// The inline caches of a chunk: the [CachedSlot] of each `GetGlobal` instruction, by its offset.
+/// Empty until the chunk first reads a global.
+#[derive(Debug, Default)]
+pub(crate) struct GlobalCache {
+    entries: OnceCell<Box<[Cell<Option<CachedSlot>>]>>,
+}
+
+impl Globals {
+    /// Creates an empty table, with a new generation.
+    pub(crate) fn new() -> Self {
+        static NEXT_GENERATION: AtomicU32 = AtomicU32::new(1);
+        Globals {
+            indices: HashMap::new(),
+            slots: Vec::new(),
+            generation: NEXT_GENERATION.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    /// Returns the value of the global with the given name, if it is defined.
+    #[inline]
+    pub(crate) fn get(&self, name: &str) -> Option<Value> {
+        let &index = self.indices.get(name)?;
+        self.slots[index as usize].value
+    }
+
+    /// Defines the global, or replaces its value. Returns its previous value, if it was defined.
+    pub(crate) fn insert(&mut self, name: &'static str, value: Value) -> Option<Value> {
+        let slot = self.slot_mut(name);
+        slot.constant = None;
+        slot.value.replace(value)
+    }
+
+    /// Defines the global as a constant, declared on the given line, replacing any previous
+    /// definition.
+    pub(crate) fn insert_constant(&mut self, name: &'static str, value: Value, line: usize) {
+        let slot = self.slot_mut(name);
+        slot.constant = Some(line);
+        slot.value = Some(value);
+    }
+
+    /// Returns the line that declared the global, if it is defined, and was defined as a constant.
+    pub(crate) fn constant_line(&self, name: &str) -> Option<usize> {
+        let &index = self.indices.get(name)?;
+        let slot = &self.slots[index as usize];
+        slot.value.and(slot.constant)
+    }
+
+    /// Returns the slot of the name, adding an empty one if there isn't one yet.
+    fn slot_mut(&mut self, name: &'static str) -> &mut Slot {
+        let index = match self.indices.get(name) {
+            Some(&index) => index,
+            None => {
+                let index = u32::try_from(self.slots.len()).expect("too many globals");
+                self.indices.insert(name, index);
+                self.slots.push(Slot {
+                    name,
+                    value: None,
+                    constant: None,
+                });
+                index
+            }
+        };
+        &mut self.slots[index as usize]
+    }
+
+    /// Removes the global. Its slot stays, for if it is ever defined again.
+    pub(crate) fn remove(&mut self, name: &str) {
+        if let Some(&index) = self.indices.get(name) {
+            self.slots[index as usize].value = None;
+        }
+    }
+
+    /// Returns the slot of the global with the given name, if it is defined.
+    #[inline]
+    pub(crate) fn slot(&self, name: &str) -> Option<CachedSlot> {
+        let &index = self.indices.get(name)?;
+        self.slots[index as usize].value?;
+        Some(CachedSlot {
+            generation: self.generation,
+            index,
+        })
+    }
+
+    /// Returns the value in the slot, if the slot is from this table and its global is defined.
+    #[inline(always)]
+    pub(crate) fn get_slot(&self, slot: CachedSlot) -> Option<Value> {
+        if slot.generation != self.generation {
+            return None;
+        }
+        self.slots.get(slot.index as usize)?.value
+    }
+
+    /// Returns every global that is defined, with its name, in no particular order.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&'static str, Value)> + '_ {
+        (self.slots.iter()).filter_map(|slot| Some((slot.name, slot.value?)))
+    }
+
+    /// Returns every value that the table refers to, for the GC: the name of every slot (even
+    /// the empty ones, which may be defined again), and every value.
+    pub(crate) fn roots(&self) -> impl Iterator<Item = Value> + '_ {
+        (self.slots.iter())
+            .flat_map(|slot| [Some(Value::LoxString(slot.name)), slot.value])
+            .flatten()
+    }
+}
+
+impl Default for Globals {
+    fn default() -> Self {
+        Globals::new()
+    }
+}
+
+impl GlobalCache {
+    /// Returns the slot that the instruction at the offset found its global in last time.
+    #[inline(always)]
+    pub(crate) fn get(&self, offset: usize) -> Option<CachedSlot> {
+        self.entries.get()?.get(offset)?.get()
+    }
+
+    /// Records the slot that the instruction at the offset found its global in. The chunk is
+    /// `len` bytes long.
+    #[inline]
+    pub(crate) fn set(&self, offset: usize, len: usize, slot: CachedSlot) {
+        let entries = (self.entries).get_or_init(|| (0..len).map(|_| Cell::new(None)).collect());
+        if let Some(entry) = entries.get(offset) {
+            entry.set(Some(slot));
+        }
+    }
+}
+
+impl Clone for GlobalCache {
+    /// The copy starts empty, like a new chunk.
+    fn clone(&self) -> Self {
+        GlobalCache::default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn slots_survive_redefinition_and_removal() {
+        let mut globals = Globals::new();
+        globals.insert("a", Value::Number(1.0));
+        let slot = globals.slot("a").unwrap();
+
+        globals.insert("b", Value::Number(2.0));
+        globals.insert("a", Value::Number(3.0));
+        assert_eq!(Some(slot), globals.slot("a"));
+        assert_eq!(Some(Value::Number(3.0)), globals.get_slot(slot));
+
+        globals.remove("a");
+        assert_eq!(None, globals.slot("a"));
+        assert_eq!(None, globals.get_slot(slot));
+        globals.insert("a", Value::Number(4.0));
+        assert_eq!(Some(Value::Number(4.0)), globals.get_slot(slot));
+
+        // A slot from another table is never trusted:
+        let mut other = Globals::new();
+        other.insert("a", Value::Number(5.0));
+        assert_eq!(None, other.get_slot(slot));
+    }
+
+    #[test]
+    fn redefinition_replaces_constness() {
+        let mut globals = Globals::new();
+        globals.insert_constant("a", Value::Number(1.0), 3);
+        assert_eq!(Some(3), globals.constant_line("a"));
+
+        globals.insert("a", Value::Number(2.0));
+        assert_eq!(None, globals.constant_line("a"));
+        globals.insert_constant("a", Value::Number(3.0), 5);
+        assert_eq!(Some(Value::Number(3.0)), globals.get("a"));
+        assert_eq!(Some(5), globals.constant_line("a"));
+
+        globals.remove("a");
+        assert_eq!(None, globals.constant_line("a"));
+        assert_eq!(None, globals.constant_line("b"));
+    }
+
+    #[test]
+    fn caches_are_by_offset() {
+        let mut globals = Globals::new();
+        globals.insert("a", Value::Number(1.0));
+        let slot = globals.slot("a").unwrap();
+
+        let cache = GlobalCache::default();
+        assert_eq!(None, cache.get(2));
+        cache.set(2, 8, slot);
+        assert_eq!(Some(slot), cache.get(2));
+        assert_eq!(None, cache.get(4));
+        assert_eq!(None, cache.clone().get(2));
+    }
+}
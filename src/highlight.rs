@@ -0,0 +1,144 @@
+//! Classifies the tokens in Lox source code, for syntax highlighting.
+//!
+//! Unlike the compiler, highlighting keeps comments (see [Scanner::with_trivia()]), and never
+//! fails: source code that cannot be scanned is marked as [TokenCategory::Error], and the rest is
+//! still highlighted.
+//!
+//! ```
+//! use rlox::highlight::{highlight, TokenCategory::*};
+//!
+//! let source = "print x + 1; // one";
+//! let categories: Vec<_> = highlight(source)
+//!     .into_iter()
+//!     .map(|(span, category)| (&source[span], category))
+//!     .collect();
+//! assert_eq!(
+//!     vec![
+//!         ("print", Keyword),
+//!         ("x", Identifier),
+//!         ("+", Operator),
+//!         ("1", Number),
+//!         (";", Punctuation),
+//!         ("// one", Comment),
+//!     ],
+//!     categories
+//! );
+//! ```
+
+use std::ops::Range;
+
+use crate::scanner::{Scanner, Token};
+
+/// What kind of token some source code is, for choosing its color.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TokenCategory {
+    /// Reserved words, including the literals `true`, `false`, and `nil`.
+    Keyword,
+    /// Names of variables, functions, classes, and properties.
+    Identifier,
+    /// Number literals.
+    Number,
+    /// String literals, including their quotes.
+    String,
+    /// Line comments and block comments.
+    Comment,
+    /// Arithmetic, comparison, and assignment operators.
+    Operator,
+    /// Brackets, commas, dots, colons, and semicolons.
+    Punctuation,
+    /// Spaces, tabs, and newlines between tokens. [highlight()] leaves whitespace out.
+    Whitespace,
+    /// Source code that could not be scanned, like an unterminated string.
+    Error,
+}
+
+/// Returns the span and category of every token in the source code, in order. Whitespace is not
+/// included.
+pub fn highlight(source: &str) -> Vec<(Range<usize>, TokenCategory)> {
+    Scanner::with_trivia(source)
+        .take_while(|lexeme| lexeme.token() != Token::Eof)
+        .filter(|lexeme| lexeme.token() != Token::Whitespace)
+        .map(|lexeme| (lexeme.span(), TokenCategory::of(lexeme.token())))
+        .collect()
+}
+
+impl TokenCategory {
+    /// Returns the category of the token.
+    pub fn of(token: Token) -> TokenCategory {
+        use Token::*;
+        match token {
+            And | Class | Const | Else | False | For | Fun | If | Import | Nil | Or | Print
+            | Return | Super | This | True | Var | While => TokenCategory::Keyword,
+            Identifier => TokenCategory::Identifier,
+            Number => TokenCategory::Number,
+            StrLiteral => TokenCategory::String,
+            Comment => TokenCategory::Comment,
+            Whitespace => TokenCategory::Whitespace,
+            Minus | Plus | Star | Slash | Bang | BangEqual | MinusMinus | PlusPlus | Equal
+            | EqualEqual | Greater | GreaterEqual | Less | LessEqual | Ampersand | Pipe | Caret
+            | GreaterGreater | LessLess => TokenCategory::Operator,
+            LeftParen | RightParen | LeftBrace | RightBrace | LeftBracket | RightBracket
+            | Colon | Comma | Dot | Semicolon => TokenCategory::Punctuation,
+            Error | Eof => TokenCategory::Error,
+        }
+    }
+}
+
+////////////////////////////////////////////// Tests //////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use super::TokenCategory::*;
+    use super::*;
+
+    /// Returns the text of each highlighted token, and its category.
+    fn categories(source: &str) -> Vec<(&str, TokenCategory)> {
+        highlight(source)
+            .into_iter()
+            .map(|(span, category)| (&source[span], category))
+            .collect()
+    }
+
+    #[test]
+    fn every_kind_of_token() {
+        let source = "/* a\n point */ class P { }\nvar p = [\"s\", nil]; p.x = -1.5;";
+        assert_eq!(
+            vec![
+                ("/* a\n point */", Comment),
+                ("class", Keyword),
+                ("P", Identifier),
+                ("{", Punctuation),
+                ("}", Punctuation),
+                ("var", Keyword),
+                ("p", Identifier),
+                ("=", Operator),
+                ("[", Punctuation),
+                ("\"s\"", String),
+                (",", Punctuation),
+                ("nil", Keyword),
+                ("]", Punctuation),
+                (";", Punctuation),
+                ("p", Identifier),
+                (".", Punctuation),
+                ("x", Identifier),
+                ("=", Operator),
+                ("-", Operator),
+                ("1.5", Number),
+                (";", Punctuation),
+            ],
+            categories(source)
+        );
+    }
+
+    #[test]
+    fn errors_do_not_stop_highlighting() {
+        assert_eq!(
+            vec![("print", Keyword), ("@", Error), ("1", Number)],
+            categories("print @ 1")
+        );
+        assert_eq!(
+            vec![("var", Keyword), ("\"oops", Error)],
+            categories("var \"oops")
+        );
+    }
+}
@@ -0,0 +1,327 @@
+//! Converts Lox values to and from JSON (or any other format that serde supports). Requires the
+//! `serde` feature.
+//!
+//! | Lox          | JSON                                                   |
+//! |--------------|--------------------------------------------------------|
+//! | `nil`        | `null`                                                 |
+//! | booleans     | booleans                                               |
+//! | numbers      | numbers (whole numbers are written without a fraction) |
+//! | strings      | strings                                                |
+//! | lists        | arrays                                                 |
+//! | maps         | objects (numbers and booleans as keys become strings)  |
+//!
+//! Functions, classes, and instances cannot be serialized.
+//!
+//! Deserializing allocates strings, lists, and maps, so it needs an [ActiveGC]: it works in native
+//! functions, or with [VM::set_global()](crate::vm::VM::set_global()), which converts a
+//! [serde_json::Value] while the VM's GC is active.
+
+use std::fmt;
+
+use serde::de::{self, MapAccess, SeqAccess, Visitor};
+use serde::ser::{self, SerializeMap, SerializeSeq};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::gc::ActiveGC;
+use crate::value::{List, Map, MapKey, Value, ValueRef};
+
+/// Lists and maps nested deeper than this are assumed to contain themselves.
+const MAX_DEPTH: usize = 128;
+
+/// Whole numbers smaller than this (2^53) are exactly representable as both `f64` and `i64`.
+const MAX_SAFE_INTEGER: f64 = 9_007_199_254_740_992.0;
+
+impl Value {
+    /// Parses JSON into a Lox value. The [ActiveGC] must be installed.
+    ///
+    /// ```
+    /// # use rlox::prelude::*;
+    /// # use rlox::gc::ActiveGC;
+    /// let _gc = ActiveGC::install();
+    /// let json = r#"{"name": "box", "size": [3, 4.5], "hidden": null}"#;
+    /// let value = Value::from_json(json).unwrap();
+    /// let Value::Map(map) = value else {
+    ///     panic!("expected a map");
+    /// };
+    /// assert_eq!(3, map.len());
+    /// assert!(Value::from_json("[1, 2").is_err());
+    /// ```
+    pub fn from_json(json: &str) -> serde_json::Result<Value> {
+        serde_json::from_str(json)
+    }
+
+    /// Writes the value as JSON. See [ValueRef::to_json()].
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+}
+
+impl ValueRef<'_> {
+    /// Writes the value as JSON.
+    ///
+    /// ```
+    /// # use rlox::prelude::*;
+    /// let mut vm = VM::default();
+    /// vm.interpret(r#"var point = {"x": 1, "y": -2.5}; var origin = [0, nil, true];"#).unwrap();
+    ///
+    /// assert_eq!("[0,null,true]", vm.get_global("origin").unwrap().to_json().unwrap());
+    /// let point = vm.get_global("point").unwrap().to_json().unwrap();
+    /// assert!(point == r#"{"x":1,"y":-2.5}"# || point == r#"{"y":-2.5,"x":1}"#);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Fails if the value is (or contains) a function, a class, or an instance, or if a list or
+    /// map contains itself.
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        self.value().to_json()
+    }
+}
+
+// Convert parsed JSON to a Lox value. Like converting a String, the [ActiveGC] must be installed.
+impl From<serde_json::Value> for Value {
+    fn from(json: serde_json::Value) -> Value {
+        Value::deserialize(json).expect("every JSON value can be a Lox value")
+    }
+}
+
+//////////////////////////////////////////// Serialize ////////////////////////////////////////////
+
+impl Serialize for ValueRef<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.value().serialize(serializer)
+    }
+}
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Nested {
+            value: *self,
+            depth: 0,
+        }
+        .serialize(serializer)
+    }
+}
+
+/// A value inside of however many lists and maps, to detect lists and maps that contain
+/// themselves.
+struct Nested {
+    value: Value,
+    depth: usize,
+}
+
+impl Nested {
+    /// Returns the value inside of this one.
+    fn child(&self, value: Value) -> Nested {
+        Nested {
+            value,
+            depth: self.depth + 1,
+        }
+    }
+}
+
+impl Serialize for Nested {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.depth > MAX_DEPTH {
+            return Err(ser::Error::custom(
+                "too deeply nested: does a list or map contain itself?",
+            ));
+        }
+
+        match self.value {
+            Value::Nil => serializer.serialize_unit(),
+            Value::Boolean(boolean) => serializer.serialize_bool(boolean),
+            Value::Number(number)
+                if number.trunc() == number && number.abs() < MAX_SAFE_INTEGER =>
+            {
+                serializer.serialize_i64(number as i64)
+            }
+            Value::Number(number) => serializer.serialize_f64(number),
+            #[cfg(feature = "integers")]
+            Value::Integer(integer) => serializer.serialize_i64(integer),
+            Value::LoxString(_) | Value::Rope(_) => {
+                serializer.serialize_str(self.value.to_str().expect("a string"))
+            }
+            Value::List(list) => {
+                let items = list.items.borrow();
+                let mut seq = serializer.serialize_seq(Some(items.len()))?;
+                for &item in items.iter() {
+                    seq.serialize_element(&self.child(item))?;
+                }
+                seq.end()
+            }
+            Value::Map(map) => {
+                let entries = map.entries.borrow();
+                let mut serialized = serializer.serialize_map(Some(entries.len()))?;
+                for (key, &value) in entries.iter() {
+                    serialized.serialize_entry(&self.child(key.value()), &self.child(value))?;
+                }
+                serialized.end()
+            }
+            value => Err(ser::Error::custom(format!(
+                "cannot serialize {}",
+                value.type_name()
+            ))),
+        }
+    }
+}
+
+/////////////////////////////////////////// Deserialize ///////////////////////////////////////////
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Value, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+/// Creates Lox values from whatever the deserializer finds. Nothing is collected while
+/// deserializing (the VM only collects between instructions), so partially-built lists and maps
+/// are safe.
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("nil, a boolean, a number, a string, a list, or a map")
+    }
+
+    fn visit_unit<E>(self) -> Result<Value, E> {
+        Ok(Value::Nil)
+    }
+
+    fn visit_none<E>(self) -> Result<Value, E> {
+        Ok(Value::Nil)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        Value::deserialize(deserializer)
+    }
+
+    fn visit_bool<E>(self, boolean: bool) -> Result<Value, E> {
+        Ok(Value::Boolean(boolean))
+    }
+
+    #[cfg(not(feature = "integers"))]
+    fn visit_i64<E>(self, number: i64) -> Result<Value, E> {
+        Ok(Value::Number(number as f64))
+    }
+
+    #[cfg(not(feature = "integers"))]
+    fn visit_u64<E>(self, number: u64) -> Result<Value, E> {
+        Ok(Value::Number(number as f64))
+    }
+
+    #[cfg(feature = "integers")]
+    fn visit_i64<E>(self, number: i64) -> Result<Value, E> {
+        Ok(Value::Integer(number))
+    }
+
+    // Integers too big for an i64 become floats:
+    #[cfg(feature = "integers")]
+    fn visit_u64<E>(self, number: u64) -> Result<Value, E> {
+        Ok(i64::try_from(number).map_or(Value::Number(number as f64), Value::Integer))
+    }
+
+    fn visit_f64<E>(self, number: f64) -> Result<Value, E> {
+        Ok(Value::Number(number))
+    }
+
+    fn visit_str<E>(self, string: &str) -> Result<Value, E> {
+        Ok(string.into())
+    }
+
+    fn visit_string<E>(self, string: String) -> Result<Value, E> {
+        Ok(string.into())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let mut items = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(item) = seq.next_element()? {
+            items.push(item);
+        }
+        Ok(Value::List(ActiveGC::store_list(List::new(items))))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<Value, A::Error> {
+        let map = ActiveGC::store_map(Map::default());
+        while let Some((key, value)) = access.next_entry::<Value, Value>()? {
+            let key = MapKey::new(key).ok_or_else(|| de::Error::custom("NaN cannot be a key"))?;
+            map.entries.borrow_mut().insert(key, value);
+        }
+        Ok(Value::Map(map))
+    }
+}
+
+////////////////////////////////////////////// Tests //////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use serial_test::serial;
+
+    use super::*;
+    use crate::vm::VM;
+
+    #[test]
+    #[serial]
+    fn json_round_trips_through_lox() {
+        let _gc = ActiveGC::install();
+        for json in [
+            "null",
+            "true",
+            "42",
+            "-0.5",
+            r#""snow ☃""#,
+            "[]",
+            r#"[1,[2,"three"],{"four":null}]"#,
+            r#"{"nested":{"list":[false]}}"#,
+        ] {
+            let value = Value::from_json(json).unwrap();
+            assert_eq!(json, value.to_json().unwrap());
+        }
+        assert_eq!("1e300", Value::Number(1e300).to_json().unwrap());
+        assert_eq!("null", Value::Number(f64::NAN).to_json().unwrap());
+    }
+
+    #[test]
+    #[serial]
+    fn scripts_can_use_json_from_the_embedder() {
+        let mut vm = VM::default();
+        let config = r#"{"name": "box", "sizes": [1, 2, 3]}"#;
+        vm.set_global(
+            "config",
+            serde_json::from_str::<serde_json::Value>(config).unwrap(),
+        );
+        vm.interpret(
+            r#"var sizes = config["sizes"];
+               var result = {"label": config["name"] + "!", "area": sizes[1] * sizes[2]};"#,
+        )
+        .unwrap();
+
+        let result: serde_json::Value = serde_json::to_value(vm.get_global("result").unwrap())
+            .expect("the result should be serializable");
+        assert_eq!(serde_json::json!({"label": "box!", "area": 6}), result);
+    }
+
+    #[test]
+    #[serial]
+    fn some_values_cannot_be_serialized() {
+        let mut vm = VM::default();
+        vm.interpret("fun f() {} class C {} var instance = C(); var list = [1]; list[0] = list;")
+            .unwrap();
+
+        for (name, message) in [
+            ("f", "cannot serialize a function"),
+            ("C", "cannot serialize a class"),
+            ("instance", "cannot serialize an instance"),
+            ("env", "cannot serialize a function"),
+            (
+                "list",
+                "too deeply nested: does a list or map contain itself?",
+            ),
+        ] {
+            let error = vm.get_global(name).unwrap().to_json().unwrap_err();
+            assert_eq!(message, error.to_string());
+        }
+    }
+}
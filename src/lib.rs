@@ -6,16 +6,41 @@
 //! [bytecode]: https://craftinginterpreters.com/a-bytecode-virtual-machine.html
 //! [lox]: https://craftinginterpreters.com/the-lox-language.html
 
+#[cfg(feature = "capi")]
+pub mod capi;
 pub mod chunk;
 pub mod compiler;
+#[cfg(feature = "dap")]
+pub mod dap;
 pub mod debug;
 pub mod error;
 pub mod extension_traits;
+pub mod formatter;
 pub mod gc;
+pub mod highlight;
+pub mod lint;
+#[cfg(feature = "lsp")]
+pub mod lsp;
+pub mod modules;
+#[cfg(feature = "nan_boxing")]
+pub mod nan_boxing;
+pub mod optimizer;
+pub mod parallel;
+pub mod rvm;
 pub mod scanner;
+pub mod stdlib;
+pub mod test_runner;
 pub mod value;
 pub mod vm;
+#[cfg(feature = "wasm")]
+pub mod wasm;
 
+mod globals;
+#[cfg(feature = "serde")]
+mod json;
+mod natives;
+#[cfg(any(feature = "lsp", feature = "dap"))]
+mod protocol;
 mod with_try_from_u8;
 
 /// The type returned by various functions that parse, compile, and run Lox code.
@@ -29,6 +54,8 @@ mod with_try_from_u8;
 /// ```
 pub type Result<T> = std::result::Result<T, error::InterpretationError>;
 
+pub use parallel::run_many;
+
 /// Re-exports common items.
 ///
 /// Since Part III of Crafting Interpreters is written in C, which lacks explicit features for
@@ -38,6 +65,6 @@ pub mod prelude {
     pub use crate::chunk::{Chunk, OpCode};
     pub use crate::error::InterpretationError;
     pub use crate::scanner::{Lexeme, Scanner, Token};
-    pub use crate::value::Value;
+    pub use crate::value::{Value, ValueRef};
     pub use crate::vm::VM;
 }
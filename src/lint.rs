@@ -0,0 +1,135 @@
+//! Checks compiled code for likely mistakes that the compiler cannot be sure about.
+//!
+//! The compiler cannot know which globals exist when the code runs: natives are defined by the VM,
+//! and globals can be defined by a prelude, a module, or an earlier entry in the REPL. So reading
+//! a global that does not exist is only an error at runtime. [undefined_globals()] finds the
+//! likely ones ahead of time, as warnings.
+//!
+//! ```
+//! # use rlox::gc::ActiveGC;
+//! use rlox::lint::undefined_globals;
+//!
+//! let gc = ActiveGC::install();
+//! let source = "var greeting = \"hi\";\nprint greting;\nprint clock();";
+//! let chunk = rlox::compiler::compile(source, &gc).unwrap();
+//!
+//! let warnings = undefined_globals(&chunk, source, |name| name == "clock");
+//! assert_eq!(1, warnings.len());
+//! assert_eq!("[line 2] Warning at 'greting': Undefined variable `greting`", warnings[0].to_string());
+//! ```
+
+use std::collections::HashSet;
+
+use crate::chunk::{Chunk, OpCode};
+use crate::error::{Diagnostic, Severity};
+use crate::value::Value;
+
+/// Returns a warning for every `GetGlobal` instruction in the chunk (and in the functions it
+/// defines) whose global is never defined: not by the chunk itself, and not already, according to
+/// `is_defined` (e.g., the natives and globals of the VM that will run the chunk).
+///
+/// Globals that the chunk defines anywhere count as defined, even if they are read before they
+/// are defined, since functions may be called after the definitions they refer to.
+///
+/// The source code is the code the chunk was compiled from, to point out where each read is.
+pub fn undefined_globals(
+    chunk: &Chunk,
+    source: &str,
+    is_defined: impl Fn(&str) -> bool,
+) -> Vec<Diagnostic> {
+    let mut chunks = vec![chunk];
+    let mut i = 0;
+    while i < chunks.len() {
+        for constant in chunks[i].constants() {
+            if let Value::Function(function) = constant {
+                chunks.push(&function.chunk);
+            }
+        }
+        i += 1;
+    }
+
+    let mut defined = HashSet::new();
+    let mut reads = Vec::new();
+    for chunk in chunks {
+        for (offset, opcode, operand) in instructions(chunk) {
+            let Some(Value::LoxString(name)) = chunk.constant(operand) else {
+                continue;
+            };
+            match opcode {
+                OpCode::DefineGlobal | OpCode::DefineConstGlobal => {
+                    defined.insert(name);
+                }
+                OpCode::GetGlobal => reads.push((chunk, offset, name)),
+                _ => {}
+            }
+        }
+    }
+
+    let mut warnings: Vec<_> = (reads.into_iter())
+        .filter(|&(_, _, name)| !defined.contains(name) && !is_defined(name))
+        .map(|(chunk, offset, name)| warning(chunk, offset, name, source))
+        .collect();
+    warnings.sort_by_key(|warning| warning.span.start);
+    warnings
+}
+
+/// Returns the offset, opcode, and one-byte operand (or zero) of each instruction in the chunk.
+fn instructions(chunk: &Chunk) -> impl Iterator<Item = (usize, OpCode, usize)> + '_ {
+    let code = chunk.code();
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        let opcode = OpCode::try_from(*code.get(offset)?).ok()?;
+        let operand = match opcode.operand_len() {
+            1 => *code.get(offset + 1)? as usize,
+            _ => 0,
+        };
+        let instruction = (offset, opcode, operand);
+        offset += 1 + opcode.operand_len();
+        Some(instruction)
+    })
+}
+
+/// Returns the warning for reading the undefined global at the offset.
+fn warning(chunk: &Chunk, offset: usize, name: &str, source: &str) -> Diagnostic {
+    let span = chunk.span_for(offset).unwrap_or_default();
+    let start = span.start.min(source.len());
+    let line_start = source[..start].rfind('\n').map_or(0, |newline| newline + 1);
+    Diagnostic {
+        line: chunk.line_number_for(offset).unwrap_or_default(),
+        column: source[line_start..start].chars().count() + 1,
+        span,
+        lexeme: name.to_owned(),
+        location: format!(" at '{name}'"),
+        message: format!("Undefined variable `{name}`"),
+        severity: Severity::Warning,
+    }
+}
+
+////////////////////////////////////////////// Tests //////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::gc::ActiveGC;
+    use serial_test::serial;
+
+    #[test]
+    #[serial]
+    fn globals_can_be_defined_anywhere_in_the_chunk() {
+        let gc = ActiveGC::install();
+        let source =
+            "fun f() {\n  return later + missing;\n}\nvar later = f;\nprint f() + len(\"\");";
+        let chunk = compile(source, &gc).unwrap();
+
+        let warnings = undefined_globals(&chunk, source, |name| name == "len");
+        let warned: Vec<_> = (warnings.iter())
+            .map(|warning| (warning.line, warning.column, &source[warning.span.clone()]))
+            .collect();
+        assert_eq!(vec![(2, 18, "missing")], warned);
+
+        let warnings = undefined_globals(&chunk, source, |_| false);
+        assert_eq!(2, warnings.len());
+        assert_eq!("len", warnings[1].lexeme);
+    }
+}
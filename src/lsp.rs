@@ -0,0 +1,492 @@
+//! A small [Language Server Protocol][lsp] server for Lox, started with `rlox lsp`.
+//!
+//! The server speaks JSON-RPC over `stdin` and `stdout`, and supports:
+//!
+//!  - diagnostics, whenever a document is opened or changed;
+//!  - go to definition, for local and global variables;
+//!  - hover, which shows whether a variable is a local (and its stack slot) or a global;
+//!  - document symbols, listing the global variables declared in the document.
+//!
+//! Documents are always synchronized in full.
+//!
+//! [lsp]: https://microsoft.github.io/language-server-protocol/
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::ops::Range;
+
+use serde_json::{json, Value as Json};
+
+use crate::compiler::{self, Analysis, Name, Resolution};
+use crate::error::Severity;
+use crate::gc::ActiveGC;
+use crate::protocol::{read_message, write_message};
+
+/// JSON-RPC error code for an unknown method.
+const METHOD_NOT_FOUND: i64 = -32601;
+
+/// LSP's `DiagnosticSeverity` for errors.
+const ERROR: u32 = 1;
+/// LSP's `DiagnosticSeverity` for warnings.
+const WARNING: u32 = 2;
+
+/// LSP's `SymbolKind` for a variable.
+const SYMBOL_KIND_VARIABLE: u32 = 13;
+
+/// Serve LSP requests from `input`, writing responses to `output`, until the client sends `exit`
+/// or closes `input`.
+pub fn serve(mut input: impl BufRead, output: impl Write) -> io::Result<()> {
+    let mut server = Server {
+        output,
+        documents: HashMap::new(),
+    };
+
+    while let Some(message) = read_message(&mut input)? {
+        if !server.handle(message)? {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+///////////////////////////////////// Implementation details //////////////////////////////////////
+
+/// The state of the language server.
+struct Server<W> {
+    output: W,
+    /// The text of every open document, by URI.
+    documents: HashMap<String, String>,
+}
+
+impl<W: Write> Server<W> {
+    /// Handles one message. Returns `false` when the server should exit.
+    fn handle(&mut self, message: Json) -> io::Result<bool> {
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = &message["params"];
+        let id = message.get("id").cloned();
+
+        let result = match method {
+            "initialize" => json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "definitionProvider": true,
+                    "hoverProvider": true,
+                    "documentSymbolProvider": true,
+                },
+                "serverInfo": { "name": "rlox", "version": env!("CARGO_PKG_VERSION") },
+            }),
+            "shutdown" => Json::Null,
+            "exit" => return Ok(false),
+            "textDocument/didOpen" => {
+                let document = &params["textDocument"];
+                let text = document["text"].as_str().unwrap_or_default();
+                self.update(uri_of(params), text.to_owned())?;
+                return Ok(true);
+            }
+            "textDocument/didChange" => {
+                // We only support full document synchronization, so the last change is the text.
+                let changes = params["contentChanges"].as_array();
+                if let Some(text) = changes.and_then(|c| c.last()).map(|c| &c["text"]) {
+                    let text = text.as_str().unwrap_or_default();
+                    self.update(uri_of(params), text.to_owned())?;
+                }
+                return Ok(true);
+            }
+            "textDocument/didClose" => {
+                let uri = uri_of(params);
+                self.documents.remove(&uri);
+                self.publish_diagnostics(&uri, vec![])?;
+                return Ok(true);
+            }
+            "textDocument/definition" => self.definition(params),
+            "textDocument/hover" => self.hover(params),
+            "textDocument/documentSymbol" => self.document_symbols(params),
+            _ => {
+                if let Some(id) = id {
+                    let error = json!({ "code": METHOD_NOT_FOUND, "message": "method not found" });
+                    self.send(json!({ "jsonrpc": "2.0", "id": id, "error": error }))?;
+                }
+                return Ok(true);
+            }
+        };
+
+        if let Some(id) = id {
+            self.send(json!({ "jsonrpc": "2.0", "id": id, "result": result }))?;
+        }
+        Ok(true)
+    }
+
+    /// Stores the new text of a document, and publishes its diagnostics.
+    fn update(&mut self, uri: String, text: String) -> io::Result<()> {
+        let diagnostics = with_analysis(&text, |analysis| {
+            analysis
+                .diagnostics
+                .iter()
+                .map(|diagnostic| {
+                    json!({
+                        "range": range(&text, diagnostic.span.clone()),
+                        "severity": match diagnostic.severity {
+                            Severity::Error => ERROR,
+                            Severity::Warning => WARNING,
+                        },
+                        "source": "rlox",
+                        "message": diagnostic.message,
+                    })
+                })
+                .collect()
+        });
+
+        self.publish_diagnostics(&uri, diagnostics)?;
+        self.documents.insert(uri, text);
+        Ok(())
+    }
+
+    /// Sends the `textDocument/publishDiagnostics` notification.
+    fn publish_diagnostics(&mut self, uri: &str, diagnostics: Vec<Json>) -> io::Result<()> {
+        self.send(json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics },
+        }))
+    }
+
+    /// Responds to `textDocument/definition`.
+    fn definition(&self, params: &Json) -> Json {
+        let uri = uri_of(params);
+        self.with_name_at(params, |text, analysis, name| {
+            definition_of(analysis, name)
+                .map(|span| json!({ "uri": uri, "range": range(text, span) }))
+        })
+    }
+
+    /// Responds to `textDocument/hover`.
+    fn hover(&self, params: &Json) -> Json {
+        self.with_name_at(params, |text, analysis, name| {
+            let description = match &name.resolution {
+                Resolution::Local { slot, .. } => {
+                    format!("local variable `{}` (stack slot {slot})", name.name)
+                }
+                Resolution::Global if definition_of(analysis, name).is_some() => {
+                    format!("global variable `{}`", name.name)
+                }
+                Resolution::Global => {
+                    format!(
+                        "global variable `{}` (not declared in this file)",
+                        name.name
+                    )
+                }
+            };
+
+            Some(json!({
+                "contents": { "kind": "markdown", "value": description },
+                "range": range(text, name.span.clone()),
+            }))
+        })
+    }
+
+    /// Responds to `textDocument/documentSymbol`.
+    fn document_symbols(&self, params: &Json) -> Json {
+        let text = match self.documents.get(&uri_of(params)) {
+            Some(text) => text,
+            None => return Json::Null,
+        };
+
+        with_analysis(text, |analysis| {
+            let symbols = analysis
+                .declarations
+                .iter()
+                .filter(|declaration| declaration.resolution == Resolution::Global)
+                .map(|declaration| {
+                    let range = range(text, declaration.span.clone());
+                    json!({
+                        "name": declaration.name,
+                        "kind": SYMBOL_KIND_VARIABLE,
+                        "range": range,
+                        "selectionRange": range,
+                    })
+                })
+                .collect();
+            Json::Array(symbols)
+        })
+    }
+
+    /// Finds the variable name at the position given in the parameters, and calls `f` with it.
+    /// Returns `null` if there is no variable at that position.
+    fn with_name_at<F>(&self, params: &Json, f: F) -> Json
+    where
+        F: FnOnce(&str, &Analysis, &Name) -> Option<Json>,
+    {
+        let text = match self.documents.get(&uri_of(params)) {
+            Some(text) => text,
+            None => return Json::Null,
+        };
+        let position = &params["position"];
+        let offset = offset_of(
+            text,
+            position["line"].as_u64().unwrap_or_default() as usize,
+            position["character"].as_u64().unwrap_or_default() as usize,
+        );
+
+        with_analysis(text, |analysis| {
+            analysis
+                .references
+                .iter()
+                .chain(analysis.declarations.iter())
+                .find(|name| name.span.start <= offset && offset <= name.span.end)
+                .and_then(|name| f(text, analysis, name))
+                .unwrap_or(Json::Null)
+        })
+    }
+
+    /// Writes one message to the client.
+    fn send(&mut self, message: Json) -> io::Result<()> {
+        write_message(&mut self.output, &message)
+    }
+}
+
+/// Analyzes the source code, and passes the result to `f`.
+fn with_analysis<T>(text: &str, f: impl FnOnce(&Analysis) -> T) -> T {
+    let gc = ActiveGC::install();
+    let analysis = compiler::analyze(text, &gc);
+    f(&analysis)
+}
+
+/// Returns where the name was declared.
+fn definition_of(analysis: &Analysis, name: &Name) -> Option<Range<usize>> {
+    match &name.resolution {
+        Resolution::Local { declaration, .. } => Some(declaration.clone()),
+        Resolution::Global => analysis
+            .declarations
+            .iter()
+            .find(|declaration| {
+                declaration.resolution == Resolution::Global && declaration.name == name.name
+            })
+            .map(|declaration| declaration.span.clone()),
+    }
+}
+
+/// Returns `params.textDocument.uri`.
+fn uri_of(params: &Json) -> String {
+    params["textDocument"]["uri"]
+        .as_str()
+        .unwrap_or_default()
+        .to_owned()
+}
+
+/// Converts a byte range to an LSP range.
+fn range(text: &str, span: Range<usize>) -> Json {
+    json!({ "start": position(text, span.start), "end": position(text, span.end) })
+}
+
+/// Converts a byte offset to an LSP position. LSP counts characters in UTF-16 code units.
+fn position(text: &str, offset: usize) -> Json {
+    let offset = offset.min(text.len());
+    let before = &text[..offset];
+    let line = before.matches('\n').count();
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let character = before[line_start..].encode_utf16().count();
+
+    json!({ "line": line, "character": character })
+}
+
+/// Converts an LSP position to a byte offset.
+fn offset_of(text: &str, line: usize, character: usize) -> usize {
+    let line_start: usize = text.split_inclusive('\n').take(line).map(str::len).sum();
+
+    let mut utf16_count = 0;
+    for (i, c) in text[line_start..].char_indices() {
+        if utf16_count >= character || c == '\n' {
+            return line_start + i;
+        }
+        utf16_count += c.len_utf16();
+    }
+
+    text.len()
+}
+
+////////////////////////////////////////////// Tests //////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use serial_test::serial;
+
+    use super::*;
+
+    /// Frames the messages as the client would send them.
+    fn frame(messages: &[Json]) -> Vec<u8> {
+        let mut input = Vec::new();
+        for message in messages {
+            write_message(&mut input, message).unwrap();
+        }
+        input
+    }
+
+    /// Runs the server on the given messages, and returns every message it sent back.
+    fn run(messages: &[Json]) -> Vec<Json> {
+        let input = frame(messages);
+        let mut output = Vec::new();
+        serve(&input[..], &mut output).unwrap();
+
+        let mut output = &output[..];
+        let mut responses = Vec::new();
+        while let Some(message) = read_message(&mut output).unwrap() {
+            responses.push(message);
+        }
+        responses
+    }
+
+    fn open(text: &str) -> Json {
+        json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": "file:///test.lox", "text": text } },
+        })
+    }
+
+    fn request(id: u32, method: &str, line: u32, character: u32) -> Json {
+        json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": {
+                "textDocument": { "uri": "file:///test.lox" },
+                "position": { "line": line, "character": character },
+            },
+        })
+    }
+
+    #[test]
+    #[serial]
+    fn initialize_and_exit() {
+        let responses = run(&[
+            json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {} }),
+            json!({ "jsonrpc": "2.0", "id": 2, "method": "shutdown" }),
+            json!({ "jsonrpc": "2.0", "method": "exit" }),
+            json!({ "jsonrpc": "2.0", "id": 3, "method": "shutdown" }),
+        ]);
+
+        assert_eq!(2, responses.len());
+        assert_eq!(
+            true,
+            responses[0]["result"]["capabilities"]["hoverProvider"]
+        );
+        assert_eq!(Json::Null, responses[1]["result"]);
+    }
+
+    #[test]
+    #[serial]
+    fn diagnostics_on_open() {
+        let responses = run(&[open("var a = 1;\nprint a +;\n")]);
+
+        assert_eq!("textDocument/publishDiagnostics", responses[0]["method"]);
+        let diagnostics = responses[0]["params"]["diagnostics"].as_array().unwrap();
+        assert_eq!(1, diagnostics.len());
+        assert_eq!(
+            json!({ "line": 1, "character": 9 }),
+            diagnostics[0]["range"]["start"]
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn diagnostics_on_change_and_close() {
+        let change = |text: &str| {
+            json!({
+                "jsonrpc": "2.0",
+                "method": "textDocument/didChange",
+                "params": {
+                    "textDocument": { "uri": "file:///test.lox", "version": 2 },
+                    "contentChanges": [{ "text": text }],
+                },
+            })
+        };
+        let responses = run(&[
+            open("print 1;\n"),
+            change("print 1\nprint 2;\n"),
+            change("print 1;\nprint 2;\n"),
+            json!({
+                "jsonrpc": "2.0",
+                "method": "textDocument/didClose",
+                "params": { "textDocument": { "uri": "file:///test.lox" } },
+            }),
+        ]);
+
+        let counts: Vec<_> = (responses.iter())
+            .map(|response| response["params"]["diagnostics"].as_array().unwrap().len())
+            .collect();
+        assert_eq!(vec![0, 1, 0, 0], counts);
+        assert_eq!(
+            json!({ "line": 1, "character": 0 }),
+            responses[1]["params"]["diagnostics"][0]["range"]["start"]
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn definition_and_hover() {
+        let source = "var g = 1;\n{\n  var l = g;\n  print l;\n}\n";
+        let responses = run(&[
+            open(source),
+            // `g` in `var l = g;`
+            request(1, "textDocument/definition", 2, 10),
+            // `l` in `print l;`
+            request(2, "textDocument/definition", 3, 8),
+            request(3, "textDocument/hover", 3, 8),
+            request(4, "textDocument/hover", 2, 10),
+        ]);
+
+        let global = &responses[1]["result"]["range"];
+        assert_eq!(json!({ "line": 0, "character": 4 }), global["start"]);
+
+        let local = &responses[2]["result"]["range"];
+        assert_eq!(json!({ "line": 2, "character": 6 }), local["start"]);
+
+        let hover = responses[3]["result"]["contents"]["value"]
+            .as_str()
+            .unwrap();
+        assert_eq!("local variable `l` (stack slot 0)", hover);
+
+        let hover = responses[4]["result"]["contents"]["value"]
+            .as_str()
+            .unwrap();
+        assert_eq!("global variable `g`", hover);
+    }
+
+    #[test]
+    #[serial]
+    fn document_symbols() {
+        let responses = run(&[
+            open("var a;\n{ var b; }\nvar c = a;\n"),
+            json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "method": "textDocument/documentSymbol",
+                "params": { "textDocument": { "uri": "file:///test.lox" } },
+            }),
+        ]);
+
+        let names: Vec<_> = responses[1]["result"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|symbol| symbol["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(vec!["a", "c"], names);
+    }
+
+    #[test]
+    fn positions_count_utf16() {
+        let text = "print \"🦀\";\nvar x;";
+        let semicolon = text.find(';').unwrap();
+        assert_eq!(
+            json!({ "line": 0, "character": 10 }),
+            position(text, semicolon)
+        );
+        assert_eq!(semicolon, offset_of(text, 0, 10));
+
+        let x = text.find('x').unwrap();
+        assert_eq!(json!({ "line": 1, "character": 4 }), position(text, x));
+        assert_eq!(x, offset_of(text, 1, 4));
+    }
+}
@@ -1,8 +1,18 @@
 use std::env;
 use std::fs;
-use std::io;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
+use rlox::compiler::{self, CompileOptions, Target};
+use rlox::error::{render_diagnostic, render_runtime_error, Diagnostic};
+use rlox::formatter;
+use rlox::gc::ActiveGC;
+use rlox::lint;
 use rlox::prelude::*;
+use rlox::scanner;
+use rlox::test_runner;
+use rlox::vm::Profile;
 
 /// The conventional exit code in BSD Unixes.
 /// See: man 3 sysexits
@@ -15,36 +25,238 @@ mod ex {
     pub const SOFTWARE: i32 = 70;
     /// An error occured while doing I/O on a file.
     pub const IOERR: i32 = 74;
+    /// Not from sysexits: by convention, a process stopped by Ctrl-C (SIGINT) exits with 128 + 2.
+    pub const INTERRUPTED: i32 = 130;
 }
 
+const USAGE: &str = "Usage: rlox [options] [<path> | -e <code> | -] [--] [args...]
+       rlox run <path> [--] [args...]
+       rlox compile <path> [-o <output>]
+       rlox bench [-n <runs>] [--warmup <runs>] [--registers] <path>
+       rlox check <path>...
+       rlox fmt [--check] <path>...
+       rlox lsp
+       rlox test [<path>...]
+
+Options:
+  -i <prelude>   Run the prelude first (can be repeated)
+  --trace        Print the stack and each instruction as it executes
+  --print-code   Print the bytecode of everything that is compiled
+  --registers    Run on the experimental register VM
+  --profile      Count the instructions executed, and print a summary at exit
+  --profile-out <path>
+                 Write the instructions executed by each call stack to the file at exit,
+                 in the collapsed stack format that flamegraph tools read";
+
 fn main() -> rlox::Result<()> {
-    let args: Vec<_> = env::args().collect();
+    let args: Vec<_> = env::args().skip(1).collect();
 
-    if args.len() <= 1 {
-        repl()
-    } else if args.len() == 2 {
-        let filename = args.get(1).unwrap();
-        run_file(filename)
-    } else {
-        eprintln!("Usage: rlox [path]");
-        std::process::exit(ex::USAGE);
+    match args.as_slice() {
+        [flag, ..] if is_option(flag) => run_with_options(&args),
+        [command] if command == "run" => usage(),
+        [command, rest @ ..] if command == "run" => {
+            run_script(new_vm(), &ProfileOutput::default(), rest)
+        }
+        [command, rest @ ..] if command == "compile" => compile_file(rest),
+        [command, rest @ ..] if command == "bench" => benchmark_file(rest),
+        [command, rest @ ..] if command == "check" => check_files(rest),
+        [command, rest @ ..] if command == "fmt" => format_files(rest),
+        [command, rest @ ..] if command == "test" => run_tests(rest),
+        #[cfg(feature = "lsp")]
+        [command] if command == "lsp" => language_server(),
+        _ => run_script(new_vm(), &ProfileOutput::default(), &args),
     }
 }
 
-/// Use Lox interactively using the read-execute-print loop.
-fn repl() -> rlox::Result<()> {
+/// Returns true for the options that can come before the script. See [run_with_options()].
+fn is_option(arg: &str) -> bool {
+    matches!(
+        arg,
+        "-i" | "--trace" | "--print-code" | "--registers" | "--profile" | "--profile-out"
+    )
+}
+
+/// Handle the options, in order, then start the REPL or run the script that follows:
+///
+///  - `-i <prelude>`: run the prelude file. Preludes are run in the same VM as the script, so
+///    any globals they define are available;
+///  - `--trace`: trace execution to `stdout`;
+///  - `--print-code`: print the disassembly of every chunk that is compiled to `stdout`;
+///  - `--registers`: compile for the [register VM](rlox::rvm), and run on it;
+///  - `--profile`: count the instructions executed, and print a summary to `stderr` at exit;
+///  - `--profile-out <path>`: count the instructions executed by each call stack, and write them
+///    to the file at exit, for making a flamegraph.
+fn run_with_options(args: &[String]) -> rlox::Result<()> {
+    let mut vm = new_vm();
+    let mut options = CompileOptions::default();
+    let mut profile_output = ProfileOutput::default();
+    let mut rest = args;
+
+    while let [flag, rest_after_flag @ ..] = rest {
+        match flag.as_str() {
+            "-i" => {
+                let [prelude, rest_after_prelude @ ..] = rest_after_flag else {
+                    usage();
+                };
+                let source = read_source(prelude);
+                let result = interpret_source(&mut vm, &source);
+                if result.is_err() {
+                    std::process::exit(exit_status(result));
+                }
+                rest = rest_after_prelude;
+                continue;
+            }
+            "--trace" => vm.set_trace_writer(Box::new(io::stdout())),
+            "--print-code" => {
+                options.print_code = true;
+                vm.set_compile_options(options);
+            }
+            "--registers" => {
+                options.target = Target::Register;
+                vm.set_compile_options(options);
+            }
+            "--profile" => {
+                start_profiling(&mut vm);
+                profile_output.summary = true;
+            }
+            "--profile-out" => {
+                let [path, rest_after_path @ ..] = rest_after_flag else {
+                    usage();
+                };
+                start_profiling(&mut vm);
+                profile_output.folded = Some(PathBuf::from(path));
+                rest = rest_after_path;
+                continue;
+            }
+            _ => break,
+        }
+        rest = rest_after_flag;
+    }
+
+    run_script(vm, &profile_output, rest)
+}
+
+/// What to do with the VM's profile when the script exits. See [finish()].
+#[derive(Default)]
+struct ProfileOutput {
+    /// Print a summary to `stderr` (`--profile`).
+    summary: bool,
+    /// Write the call stacks to this file (`--profile-out <path>`).
+    folded: Option<PathBuf>,
+}
+
+/// Enables profiling, unless it has already been enabled by another option.
+fn start_profiling(vm: &mut VM) {
+    if vm.profile_report().is_none() {
+        vm.enable_profiling();
+    }
+}
+
+/// Runs the script given by the arguments (after any preludes):
+///
+///  - no arguments: start the REPL;
+///  - `-e <code>` or `--eval <code>`: run the given code;
+///  - `-`: read the script from `stdin`;
+///  - `<path>`: run the file (write `-- <path>` if the path starts with `-`).
+///
+/// The rest of the arguments, optionally after `--`, are passed to the script.
+fn run_script(mut vm: VM, profile_output: &ProfileOutput, args: &[String]) -> rlox::Result<()> {
+    match args {
+        [] => repl(vm, profile_output),
+        [flag, code, script_args @ ..] if flag == "-e" || flag == "--eval" => {
+            vm.set_args(script_arguments(script_args));
+            let result = interpret_source(&mut vm, code);
+            finish(&vm, profile_output, result)
+        }
+        [flag, script_args @ ..] if flag == "-" => {
+            vm.set_args(script_arguments(script_args));
+            // Errors are reported by the VM, since the whole script is never available:
+            let result = interruptible(&mut vm, |vm| vm.interpret_reader(io::stdin().lock()));
+            finish(&vm, profile_output, result)
+        }
+        [separator, filename, script_args @ ..] if separator == "--" => {
+            run_file(vm, profile_output, filename, script_args)
+        }
+        [flag, ..] if flag.starts_with('-') => usage(),
+        [filename, script_args @ ..] => run_file(vm, profile_output, filename, script_args),
+    }
+}
+
+/// Returns the arguments for the script, without the `--` that may separate them from the
+/// script itself.
+fn script_arguments(args: &[String]) -> Vec<String> {
+    match args {
+        [separator, rest @ ..] if separator == "--" => rest.to_vec(),
+        _ => args.to_vec(),
+    }
+}
+
+/// Creates a VM with the standard library installed.
+fn new_vm() -> VM {
     let mut vm = VM::default();
+    rlox::stdlib::install(&mut vm);
+    // SAFETY: the standard library's natives never keep a value.
+    unsafe { vm.enable_garbage_collection() };
+    vm
+}
+
+/// Print the usage message and exit.
+fn usage() -> ! {
+    eprintln!("{USAGE}");
+    std::process::exit(ex::USAGE);
+}
+
+/// The REPL's commands. They start with `:`, so that they are never mistaken for Lox code.
+const REPL_HELP: &str = "Commands:
+  :help      Show this help
+  :dump      Print the bytecode of the last input
+  :globals   List the global variables and their values
+  :trace     Turn tracing each instruction on or off
+  :quit      Exit";
+
+/// Use Lox interactively using the read-execute-print loop.
+fn repl(mut vm: VM, profile_output: &ProfileOutput) -> rlox::Result<()> {
     let mut line = String::with_capacity(1024);
+    // The lines read so far, while they are incomplete:
+    let mut input = String::with_capacity(1024);
+    let mut last_input = String::new();
 
     let stdin = io::stdin();
 
     loop {
         line.clear();
 
-        print!("> ");
+        print!("{}", if input.is_empty() { "> " } else { "... " });
+        // Like print!(), ignore errors writing the prompt.
+        let _ = io::stdout().flush();
         match stdin.read_line(&mut line) {
+            Ok(0) => {
+                // Ctrl-D:
+                println!();
+                println!("Goodbye!");
+                break;
+            }
+            Ok(_) if input.is_empty() && line.trim_start().starts_with(':') => {
+                repl_command(&mut vm, line.trim(), &last_input, profile_output);
+            }
             Ok(_) => {
-                vm.interpret(&line)?;
+                input.push_str(&line);
+                if scanner::is_incomplete(&input) {
+                    continue;
+                }
+
+                last_input = std::mem::take(&mut input);
+                match interruptible(&mut vm, |vm| vm.interpret_repl(&last_input)) {
+                    Err(InterpretationError::Interrupted) => println!("Interrupted."),
+                    Err(exit @ InterpretationError::Exit(_)) => {
+                        finish(&vm, profile_output, Err(exit))
+                    }
+                    // The VM has already reported these:
+                    Err(InterpretationError::CompileError(_))
+                    | Err(InterpretationError::RuntimeError(_))
+                    | Ok(()) => {}
+                    Err(error) => eprintln!("{error}"),
+                }
             }
             Err(_) => {
                 println!();
@@ -53,25 +265,502 @@ fn repl() -> rlox::Result<()> {
         }
     }
 
+    write_profile(&vm, profile_output);
+    Ok(())
+}
+
+/// Runs one of the REPL's commands (see [REPL_HELP]). `last_input` is the Lox code that was
+/// entered most recently.
+fn repl_command(vm: &mut VM, command: &str, last_input: &str, profile_output: &ProfileOutput) {
+    match command {
+        ":help" => println!("{REPL_HELP}"),
+        ":dump" if last_input.trim().is_empty() => println!("Nothing has been entered yet."),
+        ":dump" => {
+            // Compile it again, exactly like the REPL did, but print the bytecode instead of
+            // running it:
+            let options = vm.compile_options();
+            vm.set_compile_options(CompileOptions {
+                repl: true,
+                print_code: true,
+                ..options
+            });
+            // Compile errors are reported as the code is compiled:
+            let _ = vm.compile(last_input);
+            vm.set_compile_options(options);
+        }
+        ":globals" => {
+            for (name, value) in vm.globals() {
+                if !value.is_native() {
+                    println!("{name} = {value}");
+                }
+            }
+        }
+        ":trace" => match vm.take_trace_writer() {
+            Some(_) => println!("Tracing is off."),
+            None => {
+                vm.set_trace_writer(Box::new(io::stdout()));
+                println!("Tracing is on.");
+            }
+        },
+        ":quit" => finish(vm, profile_output, Ok(())),
+        _ => println!("Unknown command: {command} (type :help for a list of commands)"),
+    }
+}
+
+/// Run a file, which is either Lox source code, or bytecode produced by `rlox compile`.
+/// The script can access its arguments with the `args()` native function.
+fn run_file(
+    mut vm: VM,
+    profile_output: &ProfileOutput,
+    filename: &str,
+    script_args: &[String],
+) -> rlox::Result<()> {
+    let contents = read_file(filename);
+    vm.set_args(script_arguments(script_args));
+    // The script's imports are relative to the script (and each module's, to the module):
+    if let Some(dir) = Path::new(filename).parent() {
+        vm.set_import_dir(dir);
+    }
+
+    let result = if Chunk::is_serialized(&contents) {
+        interruptible(&mut vm, |vm| vm.interpret_bytecode(&contents))
+    } else {
+        match String::from_utf8(contents) {
+            Ok(source) => interpret_source(&mut vm, &source),
+            Err(_) => {
+                eprintln!("{filename}: not UTF-8 source code or Lox bytecode");
+                std::process::exit(ex::DATAERR);
+            }
+        }
+    };
+
+    finish(&vm, profile_output, result)
+}
+
+/// Outputs the profile, then exits with the status for the result of the script.
+fn finish(vm: &VM, profile_output: &ProfileOutput, result: rlox::Result<()>) -> ! {
+    write_profile(vm, profile_output);
+    std::process::exit(exit_status(result))
+}
+
+/// Prints a summary of the VM's profile, and writes its call stacks to a file, as requested.
+fn write_profile(vm: &VM, profile_output: &ProfileOutput) {
+    let Some(profile) = vm.profile_report() else {
+        return;
+    };
+
+    if profile_output.summary {
+        print_profile(profile);
+    }
+
+    if let Some(path) = &profile_output.folded {
+        let written = fs::File::create(path)
+            .map(io::BufWriter::new)
+            .and_then(|mut file| {
+                profile.write_folded(&mut file)?;
+                file.flush()
+            });
+        if written.is_err() {
+            eprintln!("Could not write file: {}", path.display());
+            std::process::exit(ex::IOERR);
+        }
+    }
+}
+
+/// Prints a summary of the profile to `stderr`: every opcode that was executed, the hottest lines,
+/// and every function, from most executed to least.
+fn print_profile(profile: &Profile) {
+    /// How many lines are shown.
+    const HOTTEST_LINES: usize = 10;
+
+    let total = profile.instructions();
+    let percent = |count: u64| 100.0 * count as f64 / total.max(1) as f64;
+
+    let mut opcodes: Vec<_> = profile.opcodes.iter().collect();
+    opcodes.sort_by(|a, b| b.1.cmp(a.1).then((*a.0 as u8).cmp(&(*b.0 as u8))));
+    let mut lines: Vec<_> = profile.lines.iter().collect();
+    lines.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+
+    eprintln!("profile: {total} instructions");
+    eprintln!("  by opcode:");
+    for (opcode, &count) in opcodes {
+        let name = format!("{opcode:?}");
+        eprintln!("    {name:<16} {count:>12} {:>6.1}%", percent(count));
+    }
+    eprintln!("  hottest lines:");
+    for (line, &count) in lines.into_iter().take(HOTTEST_LINES) {
+        let name = format!("line {line}");
+        eprintln!("    {name:<16} {count:>12} {:>6.1}%", percent(count));
+    }
+    eprintln!("  by function (self, then including calls):");
+    for function in profile.functions() {
+        let (exclusive, inclusive) = (
+            function.exclusive_instructions,
+            function.inclusive_instructions,
+        );
+        eprintln!(
+            "    {:<16} {exclusive:>12} {:>6.1}% {inclusive:>12} {:>6.1}% {:>12.3?}",
+            function.name,
+            percent(exclusive),
+            percent(inclusive),
+            function.inclusive_time,
+        );
+    }
+}
+
+/// Interprets Lox source code, reporting errors with [report()].
+fn interpret_source(vm: &mut VM, source: &str) -> rlox::Result<()> {
+    vm.set_stderr(Box::new(io::sink()));
+    let result = interruptible(vm, |vm| vm.interpret(source));
+    vm.set_stderr(Box::new(io::stderr()));
+
+    if let Err(error) = &result {
+        report(source, error);
+    }
+    result
+}
+
+/// Runs the VM so that Ctrl-C stops the Lox code that is running, instead of the whole process.
+fn interruptible<T>(vm: &mut VM, run: impl FnOnce(&mut VM) -> T) -> T {
+    #[cfg(unix)]
+    let registration =
+        signal_hook::flag::register(signal_hook::consts::SIGINT, vm.interrupt_handle()).ok();
+
+    let result = run(vm);
+
+    #[cfg(unix)]
+    if let Some(registration) = registration {
+        signal_hook::low_level::unregister(registration);
+    }
+    result
+}
+
+/// Prints an error in the source code to `stderr`. For people (when `stderr` is a terminal), the
+/// offending source code is shown, and underlined. Otherwise, errors are printed like in Crafting
+/// Interpreters, which is what test suites expect.
+fn report(source: &str, error: &InterpretationError) {
+    use InterpretationError::*;
+    let pretty = io::stderr().is_terminal();
+
+    match error {
+        CompileError(diagnostics) => report_diagnostics(source, diagnostics),
+        RuntimeError(info) if pretty => eprint!("{}", render_runtime_error(source, info)),
+        RuntimeError(info) => eprint!("{info}"),
+        // Reported by exit_status():
+        InvalidBytecodeFile(_)
+        | InvalidBytecode { .. }
+        | ReadError(_)
+        | WriteError(_)
+        | OutOfFuel
+        | Interrupted
+        | Exit(_) => {}
+    }
+}
+
+/// Prints compile errors and warnings to `stderr`, like [report()].
+fn report_diagnostics(source: &str, diagnostics: &[Diagnostic]) {
+    if io::stderr().is_terminal() {
+        let rendered: Vec<_> = (diagnostics.iter())
+            .map(|diagnostic| render_diagnostic(source, diagnostic))
+            .collect();
+        eprint!("{}", rendered.join("\n"));
+    } else {
+        for diagnostic in diagnostics {
+            eprintln!("{diagnostic}");
+        }
+    }
+}
+
+/// Compile a Lox file to a bytecode file, without running it.
+///
+/// Arguments are `<path> [-o <output>]`. By default, the output is written next to the source
+/// file, with a `.loxc` extension.
+fn compile_file(args: &[String]) -> rlox::Result<()> {
+    let (filename, output) = match args {
+        [filename] => (filename, Path::new(filename).with_extension("loxc")),
+        [filename, flag, output] if flag == "-o" => (filename, PathBuf::from(output)),
+        _ => usage(),
+    };
+
+    let source = read_source(filename);
+
+    let gc = ActiveGC::install();
+    let chunk = match compiler::compile_with_diagnostics(&source, &gc) {
+        Ok(chunk) => chunk,
+        Err(diagnostics) => {
+            let error = InterpretationError::CompileError(diagnostics);
+            report(&source, &error);
+            std::process::exit(exit_status(Err(error)))
+        }
+    };
+
+    if fs::write(&output, chunk.serialize()).is_err() {
+        eprintln!("Could not write file: {}", output.display());
+        std::process::exit(ex::IOERR);
+    }
+
+    Ok(())
+}
+
+/// Run a Lox file several times, and report how long it took.
+///
+/// Arguments are `[-n <runs>] [--warmup <runs>] [--registers] <path>`. Warmup runs are not
+/// measured.
+fn benchmark_file(args: &[String]) -> rlox::Result<()> {
+    let mut runs = 10;
+    let mut warmup = 2;
+    let mut target = Target::Stack;
+    let mut filename = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-n" => runs = parse_count(args.next()),
+            "--warmup" => warmup = parse_count(args.next()),
+            "--registers" => target = Target::Register,
+            _ if filename.is_none() => filename = Some(arg),
+            _ => usage(),
+        }
+    }
+    let filename = filename.unwrap_or_else(|| usage());
+    if runs == 0 {
+        usage();
+    }
+
+    let source = read_source(filename);
+    let mut vm = new_vm();
+    vm.set_compile_options(CompileOptions {
+        target,
+        ..CompileOptions::default()
+    });
+
+    for _ in 0..warmup {
+        vm.interpret(&source)?;
+    }
+
+    let mut times = Vec::with_capacity(runs);
+    let mut statistics = Vec::with_capacity(runs);
+    for _ in 0..runs {
+        let start = Instant::now();
+        vm.interpret(&source)?;
+        times.push(start.elapsed());
+        statistics.push(vm.statistics().clone());
+    }
+
+    let total: Duration = times.iter().sum();
+    let mean = total / runs as u32;
+    let min = times.iter().min().unwrap();
+    let max = times.iter().max().unwrap();
+    let instructions: u64 = statistics.iter().map(|s| s.instructions).sum();
+    let allocations: usize = statistics.iter().map(|s| s.allocations).sum();
+    let collections: usize = statistics.iter().map(|s| s.collections).sum();
+    let gc_time: Duration = statistics.iter().map(|s| s.gc_time).sum();
+
+    eprintln!("{filename}: {runs} runs ({warmup} warmup)");
+    eprintln!("  wall time:    mean {mean:?}, min {min:?}, max {max:?}");
+    eprintln!("  instructions: {} per run", instructions / runs as u64);
+    eprintln!("  allocations:  {} per run", allocations / runs);
+    eprintln!("  GC pauses:    {collections} collections, {gc_time:?} total");
+
+    Ok(())
+}
+
+/// Parses the argument after a flag as a count, or exits.
+fn parse_count(arg: Option<&String>) -> usize {
+    arg.and_then(|n| n.parse().ok()).unwrap_or_else(|| usage())
+}
+
+/// Compile Lox files, reporting any errors and warnings, but without running them. Only errors
+/// make the check fail.
+fn check_files(filenames: &[String]) -> rlox::Result<()> {
+    if filenames.is_empty() {
+        usage();
+    }
+
+    let options = CompileOptions {
+        warnings: true,
+        ..CompileOptions::default()
+    };
+    // The natives that every script can use:
+    let natives: Vec<String> = (new_vm().globals().into_iter())
+        .map(|(name, _)| name.to_owned())
+        .collect();
+    let is_native = |name: &str| natives.iter().any(|native| native == name);
+
+    let mut n_failed = 0;
+    for filename in filenames {
+        let source = read_source(filename);
+        let gc = ActiveGC::install();
+        match compiler::compile_with_warnings(&source, &gc, options) {
+            Ok((chunk, mut warnings)) => {
+                warnings.extend(lint::undefined_globals(&chunk, &source, is_native));
+                warnings.sort_by_key(|warning| warning.span.start);
+                report_diagnostics(&source, &warnings);
+            }
+            Err(diagnostics) => {
+                report_diagnostics(&source, &diagnostics);
+                eprintln!("{filename}: could not compile");
+                n_failed += 1;
+            }
+        }
+    }
+
+    if n_failed > 0 {
+        std::process::exit(ex::DATAERR);
+    }
+
+    Ok(())
+}
+
+/// Format Lox files in place.
+///
+/// Arguments are `[--check] <path>...`. With `--check`, files are not modified; instead, the
+/// names of files that would be changed are printed, and the exit status is nonzero if there are
+/// any.
+fn format_files(args: &[String]) -> rlox::Result<()> {
+    let check = args.iter().any(|arg| arg == "--check");
+    let filenames: Vec<_> = args.iter().filter(|&arg| arg != "--check").collect();
+    if filenames.is_empty() {
+        usage();
+    }
+
+    let mut would_change = false;
+    for filename in filenames {
+        let source = read_source(filename);
+        let formatted = match formatter::format(&source) {
+            Ok(formatted) => formatted,
+            Err(error) => {
+                eprintln!("{filename}: {error}");
+                std::process::exit(ex::DATAERR);
+            }
+        };
+
+        if formatted == source {
+            continue;
+        }
+
+        if check {
+            println!("{filename}");
+            would_change = true;
+        } else if fs::write(filename, formatted).is_err() {
+            eprintln!("Could not write file: {filename}");
+            std::process::exit(ex::IOERR);
+        }
+    }
+
+    if would_change {
+        // Like `rustfmt --check`, exit with 1 when files are not formatted.
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+/// Runs every Lox test script in the given files and directories, and reports which ones passed.
+/// Without any paths, runs every `*_test.lox` file under the current directory.
+/// See [rlox::test_runner] for how tests are written.
+fn run_tests(paths: &[String]) -> rlox::Result<()> {
+    let interpreter = env::current_exe().unwrap_or_else(|_| PathBuf::from("rlox"));
+    let found = match paths {
+        [] => vec![(".", test_runner::find_test_files(Path::new(".")))],
+        paths => (paths.iter())
+            .map(|path| (path.as_str(), test_runner::find_tests(Path::new(path))))
+            .collect(),
+    };
+    let mut tests = Vec::new();
+    for (path, found) in found {
+        match found {
+            Ok(found) => tests.extend(found),
+            Err(_) => {
+                eprintln!("Could not read tests from: {path}");
+                std::process::exit(ex::IOERR);
+            }
+        }
+    }
+
+    let (mut passed, mut failed) = (0, 0);
+    for test in tests {
+        let result = match test_runner::run_test(&interpreter, &test) {
+            Ok(Some(result)) => result,
+            Ok(None) => continue,
+            Err(error) => {
+                eprintln!("Could not run {}: {error}", test.display());
+                std::process::exit(ex::IOERR);
+            }
+        };
+
+        if result.passed() {
+            passed += 1;
+        } else {
+            failed += 1;
+            println!("FAIL {}", result.path.display());
+            for failure in result.failures {
+                println!("     {failure}");
+            }
+        }
+    }
+
+    println!("{passed} tests passed. {failed} tests failed.");
+    if failed > 0 {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+/// Runs the language server on `stdin` and `stdout`.
+#[cfg(feature = "lsp")]
+fn language_server() -> rlox::Result<()> {
+    if let Err(error) = rlox::lsp::serve(io::stdin().lock(), io::stdout().lock()) {
+        eprintln!("rlox lsp: {error}");
+        std::process::exit(ex::IOERR);
+    }
+
     Ok(())
 }
 
-fn run_file(filename: &str) -> rlox::Result<()> {
-    let source = match fs::read_to_string(filename) {
+/// Reads the entire file as Lox source code, or exits if that's not possible.
+fn read_source(filename: &str) -> String {
+    match String::from_utf8(read_file(filename)) {
         Ok(s) => s,
+        Err(_) => {
+            eprintln!("{filename}: not UTF-8 source code");
+            std::process::exit(ex::DATAERR);
+        }
+    }
+}
+
+/// Reads the entire file, or exits if that's not possible.
+fn read_file(filename: &str) -> Vec<u8> {
+    match fs::read(filename) {
+        Ok(contents) => contents,
         Err(_) => {
             eprintln!("Could not read file: {filename}");
             std::process::exit(ex::IOERR);
         }
-    };
-    let mut vm = VM::default();
+    }
+}
 
+/// Converts the result of interpretation into an exit status.
+fn exit_status(result: rlox::Result<()>) -> i32 {
     use InterpretationError::*;
-    let status = match vm.interpret(&source) {
+    match result {
         Ok(_) => 0,
-        Err(CompileError) => ex::DATAERR,
-        Err(RuntimeError) => ex::SOFTWARE,
-    };
-
-    std::process::exit(status)
+        Err(CompileError(_)) => ex::DATAERR,
+        Err(RuntimeError(_)) => ex::SOFTWARE,
+        Err(error @ OutOfFuel) => {
+            eprintln!("{error}");
+            ex::SOFTWARE
+        }
+        Err(Interrupted) => ex::INTERRUPTED,
+        Err(Exit(status)) => status,
+        Err(error @ (InvalidBytecodeFile(_) | InvalidBytecode { .. })) => {
+            eprintln!("{error}");
+            ex::DATAERR
+        }
+        Err(error @ (ReadError(_) | WriteError(_))) => {
+            eprintln!("{error}");
+            ex::IOERR
+        }
+    }
 }
@@ -0,0 +1,91 @@
+//! Finds the source code of the modules named by `import` statements.
+//!
+//! By default, a [VM](crate::vm::VM) reads modules from files, with a [FileResolver]. Embedders
+//! can serve modules from anywhere else (memory, an archive, the network, ...) by installing
+//! their own [ModuleResolver] with
+//! [VM::set_module_resolver()](crate::vm::VM::set_module_resolver()). A [HashMap] from names to
+//! source code is a resolver, too:
+//!
+//! ```
+//! # use rlox::prelude::*;
+//! use std::collections::HashMap;
+//!
+//! let modules = HashMap::from([("config".to_owned(), "var debug = true;".to_owned())]);
+//! let mut vm = VM::default();
+//! vm.set_module_resolver(Box::new(modules));
+//! vm.interpret("import \"config\"; var verbose = debug;").unwrap();
+//! assert!(vm.interpret("import \"missing\";").is_err());
+//! ```
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Finds the source code of modules, given the names used in `import` statements.
+pub trait ModuleResolver {
+    /// Returns the source code of the module with the given name.
+    fn resolve(&self, name: &str) -> io::Result<String>;
+
+    /// Returns the same name for every name that refers to the same module, so that a module is
+    /// only run once, no matter what it was called when it was imported. By default, every name
+    /// is its own canonical name.
+    fn canonical_name(&self, name: &str) -> io::Result<String> {
+        Ok(name.to_owned())
+    }
+
+    /// Returns the name to resolve when the module with the canonical name `importer` imports
+    /// the module called `name`. By default, a name means the same thing no matter which module
+    /// imports it.
+    fn relative_name(&self, importer: &str, name: &str) -> String {
+        let _ = importer;
+        name.to_owned()
+    }
+}
+
+/// Reads modules from files. Module names are paths, relative to a directory for the script, and
+/// relative to the module's own directory for the modules it imports.
+#[derive(Debug, Clone)]
+pub struct FileResolver {
+    dir: PathBuf,
+}
+
+impl FileResolver {
+    /// Returns a resolver for the files in the given directory.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        FileResolver { dir: dir.into() }
+    }
+}
+
+impl Default for FileResolver {
+    /// Returns a resolver for the files in the current directory.
+    fn default() -> Self {
+        FileResolver::new(".")
+    }
+}
+
+impl ModuleResolver for FileResolver {
+    fn resolve(&self, name: &str) -> io::Result<String> {
+        fs::read_to_string(self.dir.join(name))
+    }
+
+    /// Returns the module's absolute path, without any `.`, `..`, or symbolic links.
+    fn canonical_name(&self, name: &str) -> io::Result<String> {
+        let path = fs::canonicalize(self.dir.join(name))?;
+        Ok(path.to_string_lossy().into_owned())
+    }
+
+    /// Returns the path relative to the directory of the importing module.
+    fn relative_name(&self, importer: &str, name: &str) -> String {
+        let dir = Path::new(importer).parent().unwrap_or(&self.dir);
+        dir.join(name).to_string_lossy().into_owned()
+    }
+}
+
+impl ModuleResolver for HashMap<String, String> {
+    fn resolve(&self, name: &str) -> io::Result<String> {
+        self.get(name).cloned().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("no module named {name}"))
+        })
+    }
+}
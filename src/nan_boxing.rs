@@ -0,0 +1,205 @@
+//! NaN boxing: packing a [Value] into 8 bytes, as in [chapter 30] of Crafting Interpreters.
+//!
+//! A [Value] is 24 bytes (a `&str` alone is 16 bytes), but a [PackedValue] is the size of an
+//! [f64]. Numbers are stored as themselves. Everything else is stored in the bits of a quiet NaN
+//! that arithmetic never produces:
+//!
+//! ```text
+//!   nil, false, true:  0 | QNAN | 1, 2, or 3
+//!   objects:           1 | QNAN | 48-bit pointer, with the kind of object in the low 3 bits
+//! ```
+//!
+//! Objects are at least 8-byte aligned, so the low 3 bits of their addresses are always zero, and
+//! can be used to tell what kind of object is pointed to. Strings are referred to through a
+//! [string handle](crate::gc::GC::string_handle()), since a `&str` is too big to fit.
+//!
+//! Only the VM's stack holds packed values; everywhere else, values are unpacked, so the rest of
+//! the crate (and its public API) does not change.
+//!
+//! ```
+//! # use rlox::gc::ActiveGC;
+//! # use rlox::value::Value;
+//! use rlox::nan_boxing::PackedValue;
+//!
+//! let _gc = ActiveGC::install();
+//! assert_eq!(8, std::mem::size_of::<PackedValue>());
+//! for value in [Value::Nil, true.into(), 1.5.into(), "hello".into()] {
+//!     assert_eq!(value, PackedValue::from(value).unpack());
+//! }
+//! ```
+//!
+//! [chapter 30]: https://craftinginterpreters.com/optimization.html#nan-boxing
+
+use crate::gc::ActiveGC;
+use crate::value::{Class, Function, Instance, List, Map, Rope, Value};
+
+extern crate static_assertions as sa;
+
+#[cfg(not(target_pointer_width = "64"))]
+compile_error!("the nan_boxing feature requires 64-bit pointers");
+
+#[cfg(feature = "integers")]
+compile_error!("the nan_boxing feature cannot be used with integers, which do not fit in a NaN");
+
+/// A [Value], packed into 8 bytes. Strings and objects in a packed value must be owned by the
+/// [ActiveGC].
+#[derive(Clone, Copy)]
+pub struct PackedValue(u64);
+
+/// The sign bit of an [f64]. It is set for objects.
+const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+/// The bits of a quiet NaN, plus one more bit, so that no "real" NaN looks like a boxed value.
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+
+const TAG_NIL: u64 = 1;
+const TAG_FALSE: u64 = 2;
+const TAG_TRUE: u64 = 3;
+
+/// The low bits of a pointer, which tell what kind of object it is.
+const KIND_MASK: u64 = 0b111;
+const KIND_STRING: u64 = 0;
+const KIND_NATIVE_FUNCTION: u64 = 1;
+const KIND_FUNCTION: u64 = 2;
+const KIND_CLASS: u64 = 3;
+const KIND_INSTANCE: u64 = 4;
+const KIND_LIST: u64 = 5;
+const KIND_MAP: u64 = 6;
+const KIND_ROPE: u64 = 7;
+
+// The kind is stored in the low bits of the pointer, so objects must be aligned:
+sa::const_assert!(std::mem::align_of::<&'static str>() > KIND_MASK as usize);
+sa::const_assert!(std::mem::align_of::<Function>() > KIND_MASK as usize);
+sa::const_assert!(std::mem::align_of::<Class>() > KIND_MASK as usize);
+sa::const_assert!(std::mem::align_of::<Instance>() > KIND_MASK as usize);
+sa::const_assert!(std::mem::align_of::<List>() > KIND_MASK as usize);
+sa::const_assert!(std::mem::align_of::<Map>() > KIND_MASK as usize);
+sa::const_assert!(std::mem::align_of::<Rope>() > KIND_MASK as usize);
+
+impl PackedValue {
+    /// Unpacks the value.
+    #[inline]
+    pub fn unpack(self) -> Value {
+        let bits = self.0;
+        if bits & QNAN != QNAN {
+            return Value::Number(f64::from_bits(bits));
+        }
+
+        if bits & SIGN_BIT == 0 {
+            return match bits & !QNAN {
+                TAG_NIL => Value::Nil,
+                TAG_FALSE => Value::Boolean(false),
+                TAG_TRUE => Value::Boolean(true),
+                _ => unreachable!("invalid packed value: {bits:#x}"),
+            };
+        }
+
+        let payload = bits & !(SIGN_BIT | QNAN);
+        let address = (payload & !KIND_MASK) as usize;
+        // SAFETY: the address was packed from a reference of the same kind, which is still owned
+        // by the active GC.
+        unsafe {
+            match payload & KIND_MASK {
+                KIND_STRING => Value::LoxString(*(address as *const &'static str)),
+                KIND_NATIVE_FUNCTION => Value::NativeFunction(address >> 3),
+                KIND_FUNCTION => Value::Function(&*(address as *const Function)),
+                KIND_CLASS => Value::Class(&*(address as *const Class)),
+                KIND_INSTANCE => Value::Instance(&*(address as *const Instance)),
+                KIND_LIST => Value::List(&*(address as *const List)),
+                KIND_MAP => Value::Map(&*(address as *const Map)),
+                KIND_ROPE => Value::Rope(&*(address as *const Rope)),
+                _ => unreachable!("invalid packed value: {bits:#x}"),
+            }
+        }
+    }
+
+    /// Packs a reference to an object.
+    #[inline]
+    fn object<T>(object: &'static T, kind: u64) -> PackedValue {
+        let address = std::ptr::from_ref(object) as u64;
+        debug_assert_eq!(
+            0,
+            address & !(u64::MAX >> 16),
+            "pointer does not fit in 48 bits"
+        );
+        PackedValue(SIGN_BIT | QNAN | address | kind)
+    }
+}
+
+impl From<Value> for PackedValue {
+    #[inline]
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Number(number) => PackedValue(number.to_bits()),
+            Value::Nil => PackedValue(QNAN | TAG_NIL),
+            Value::Boolean(false) => PackedValue(QNAN | TAG_FALSE),
+            Value::Boolean(true) => PackedValue(QNAN | TAG_TRUE),
+            Value::LoxString(string) => {
+                PackedValue::object(ActiveGC::string_handle(string), KIND_STRING)
+            }
+            Value::NativeFunction(index) => {
+                PackedValue(SIGN_BIT | QNAN | (index as u64) << 3 | KIND_NATIVE_FUNCTION)
+            }
+            Value::Function(function) => PackedValue::object(function, KIND_FUNCTION),
+            Value::Class(class) => PackedValue::object(class, KIND_CLASS),
+            Value::Instance(instance) => PackedValue::object(instance, KIND_INSTANCE),
+            Value::List(list) => PackedValue::object(list, KIND_LIST),
+            Value::Map(map) => PackedValue::object(map, KIND_MAP),
+            Value::Rope(rope) => PackedValue::object(rope, KIND_ROPE),
+        }
+    }
+}
+
+impl From<PackedValue> for Value {
+    #[inline]
+    fn from(packed: PackedValue) -> Self {
+        packed.unpack()
+    }
+}
+
+// Print packed values just like the values they pack:
+impl std::fmt::Debug for PackedValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self.unpack())
+    }
+}
+
+////////////////////////////////////////////// Tests //////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serial_test::serial;
+
+    #[test]
+    #[serial]
+    fn every_kind_of_value_can_be_packed() {
+        let _gc = ActiveGC::install();
+        let name = ActiveGC::store_string("Point".to_owned());
+        let class = ActiveGC::store_class(Class { name });
+
+        for value in [
+            Value::Nil,
+            false.into(),
+            true.into(),
+            (-0.0).into(),
+            f64::INFINITY.into(),
+            "🦀".into(),
+            Value::NativeFunction(42),
+            Value::Class(class),
+            Value::Instance(ActiveGC::store_instance(Instance::new(class))),
+            Value::List(ActiveGC::store_list(List::default())),
+            Value::Map(ActiveGC::store_map(Map::default())),
+            Value::Rope(ActiveGC::store_rope(Rope::new("a".into(), "b".into()))),
+        ] {
+            let unpacked = PackedValue::from(value).unpack();
+            assert!(value.equal(&unpacked), "{value:?} became {unpacked:?}");
+        }
+
+        // NaNs produced by arithmetic are still numbers:
+        for nan in [f64::NAN, -f64::NAN, f64::INFINITY - f64::INFINITY] {
+            assert!(
+                matches!(PackedValue::from(Value::Number(nan)).unpack(), Value::Number(n) if n.is_nan())
+            );
+        }
+    }
+}
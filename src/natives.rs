@@ -0,0 +1,136 @@
+//! Native functions that are available to every Lox program.
+//!
+//!  - `args(i)` returns the `i`th command line argument given to the script (starting from zero),
+//!    or `nil` if there are not that many arguments.
+//!  - `env(name)` returns the value of the environment variable called `name`, or `nil` if it is
+//!    not set.
+//!  - `exit(status)` stops the program, and the interpreter exits with the integer `status` (see
+//!    [InterpretationError::Exit](crate::error::InterpretationError::Exit)).
+//!  - `clock()` returns the number of seconds since the VM was created, for timing code
+//!    (requires `io`).
+//!  - `sleep(ms)` pauses the program for `ms` milliseconds (requires `io`).
+//!  - `gcstats()` returns a map describing the garbage collector's heap: `"bytesAllocated"`,
+//!    `"nextCollection"`, how many `"strings"`, `"ropes"`, `"functions"`, `"classes"`,
+//!    `"instances"`, `"lists"`, and `"maps"` are stored, the number of `"allocations"` and
+//!    `"collections"`, and the `"collectionTime"` in seconds.
+//!  - `collect_garbage()` frees everything that the program can no longer use, right away,
+//!    instead of waiting until enough has been allocated. It does nothing unless the VM collects
+//!    garbage (see [VM::enable_garbage_collection()]).
+//!  - `stackDepth()` returns how many values are on the VM's value stack, not counting the call
+//!    to `stackDepth()` itself: the local variables and temporary values of every call in
+//!    progress.
+//!  - `callDepth()` returns how many calls are in progress, counting the top-level script as one.
+//!
+//! To create a VM without any of these, use [VM::without_natives()].
+
+use std::env;
+#[cfg(feature = "io")]
+use std::time::{Duration, Instant};
+
+use crate::gc::ActiveGC;
+use crate::value::{Map, MapKey, Value};
+use crate::vm::VM;
+
+/// Defines all of the natives on a new VM.
+pub(crate) fn install(vm: &mut VM) {
+    define_args(vm, Vec::new());
+    vm.define_native("env", 1, env_native);
+    define_exit(vm);
+    vm.define_native("gcstats", 0, gcstats_native);
+    vm.define_native("collect_garbage", 0, |_| {
+        // The VM collects garbage before the next instruction, when it knows every root:
+        ActiveGC::request_collection();
+        Ok(Value::Nil)
+    });
+    vm.define_native_with_state("stackDepth", 0, |state, _| {
+        Ok((state.stack_depth() as f64).into())
+    });
+    vm.define_native_with_state("callDepth", 0, |state, _| {
+        Ok((state.call_depth() as f64).into())
+    });
+
+    #[cfg(feature = "io")]
+    {
+        let start = Instant::now();
+        vm.define_native("clock", 0, move |_| {
+            Ok(start.elapsed().as_secs_f64().into())
+        });
+        vm.define_native("sleep", 1, sleep_native);
+    }
+}
+
+/// Defines the `args()` native, which returns the given arguments.
+pub(crate) fn define_args(vm: &mut VM, args: Vec<String>) {
+    vm.define_native("args", 1, move |arguments| {
+        let index = match arguments[0].to_f64() {
+            Some(n) if n >= 0.0 && n.fract() == 0.0 => n as usize,
+            _ => return Err("args() expects a non-negative integer".to_owned()),
+        };
+
+        Ok(args.get(index).map(String::as_str).into())
+    });
+}
+
+/// Defines the `exit()` native, which stops the code that is running in the VM.
+fn define_exit(vm: &mut VM) {
+    let exit_request = vm.exit_request();
+    vm.define_native("exit", 1, move |arguments| {
+        let status = match arguments[0].to_f64() {
+            Some(n) if n.fract() == 0.0 && n.abs() <= i32::MAX as f64 => n as i32,
+            _ => return Err("exit() expects an integer".to_owned()),
+        };
+
+        exit_request.set(Some(status));
+        // The VM sees the request, and stops instead of raising a runtime error:
+        Err("exit() was called".to_owned())
+    });
+}
+
+/// `env(name)`: returns the value of an environment variable.
+fn env_native(arguments: &[Value]) -> Result<Value, String> {
+    let name = arguments[0]
+        .to_str()
+        .ok_or_else(|| "env() expects a string".to_owned())?;
+
+    Ok(env::var(name).ok().into())
+}
+
+/// `gcstats()`: returns a map of statistics about the GC.
+fn gcstats_native(_arguments: &[Value]) -> Result<Value, String> {
+    let stats = ActiveGC::stats();
+    let counts = [
+        ("bytesAllocated", stats.bytes_allocated),
+        ("nextCollection", stats.next_collection),
+        ("strings", stats.strings),
+        ("ropes", stats.ropes),
+        ("functions", stats.functions),
+        ("classes", stats.classes),
+        ("instances", stats.instances),
+        ("lists", stats.lists),
+        ("maps", stats.maps),
+        ("allocations", stats.allocations),
+        ("collections", stats.collections),
+    ];
+    let entries = (counts.into_iter())
+        .map(|(name, count)| (name, count as f64))
+        .chain([("collectionTime", stats.collection_time.as_secs_f64())]);
+
+    let map = ActiveGC::store_map(Map::default());
+    for (name, value) in entries {
+        let key = MapKey::new(name.into()).expect("strings are keys");
+        map.entries.borrow_mut().insert(key, value.into());
+    }
+    Ok(Value::Map(map))
+}
+
+/// `sleep(ms)`: blocks for the given number of milliseconds.
+#[cfg(feature = "io")]
+fn sleep_native(arguments: &[Value]) -> Result<Value, String> {
+    let ms = match arguments[0].to_f64() {
+        Some(ms) if ms >= 0.0 && ms.is_finite() => ms,
+        _ => return Err("sleep() expects a non-negative number".to_owned()),
+    };
+
+    std::thread::sleep(Duration::from_secs_f64(ms / 1000.0));
+    Ok(Value::Nil)
+}
@@ -0,0 +1,486 @@
+//! A peephole optimizer, which rewrites wasteful sequences of instructions in a compiled [Chunk].
+//!
+//! Every rewrite preserves what the program does, including which runtime errors it raises, so
+//! some sequences are only rewritten when the values involved are known to be the right type:
+//!
+//! | Before                                  | After            | Because                       |
+//! |-----------------------------------------|------------------|-------------------------------|
+//! | `Not`, `Not`, `Not`                     | `Not`            | `!!!x` is `!x`                |
+//! | (a boolean), `Not`, `Not`               | (a boolean)      | `!!b` is `b`, for booleans    |
+//! | (a number), `Negate`, `Negate`          | (a number)       | `--n` is `n`, for numbers     |
+//! | `True`, `JumpIfFalse`                   | `True`           | the jump is never taken       |
+//! | `False` (or `Nil`), `JumpIfFalse`       | `False`, `Jump`  | the jump is always taken      |
+//! | (a constant or local), `Pop`            | (nothing)        | the value is never used       |
+//!
+//! Afterwards, [fuse()] replaces common sequences with _superinstructions_, which do the same work
+//! with one dispatch instead of two or three:
+//!
+//! | Before                                  | After                      |
+//! |-----------------------------------------|----------------------------|
+//! | `GetLocal a`, `GetLocal b`, `Add`       | `AddLocals a b`            |
+//! | `Constant c`, `Add`                     | `AddConstant c`            |
+//! | `Not`, `JumpIfFalse`                    | `NotJumpIfFalse`           |
+//!
+//! Instructions that are the target of a jump can only be at the start of a rewritten sequence,
+//! since jumping into the middle of a sequence would skip part of it.
+//!
+//! ```
+//! # use rlox::gc::ActiveGC;
+//! use rlox::optimizer::optimize;
+//!
+//! let gc = ActiveGC::install();
+//! // The compiler optimizes every chunk it compiles:
+//! let optimized = rlox::compiler::compile("print !!(1 < 2);", &gc).unwrap();
+//! // ...so optimizing it again changes nothing:
+//! assert_eq!(optimized.code(), optimize(optimized.clone()).code());
+//! ```
+
+use std::collections::HashSet;
+use std::ops::Range;
+
+use crate::chunk::{Chunk, OpCode};
+use crate::value::Value;
+
+/// Rewrites wasteful sequences of instructions in the chunk. See the [module](self) documentation
+/// for which sequences are rewritten.
+///
+/// The chunk must be well-formed, e.g., produced by the compiler.
+pub fn optimize(chunk: Chunk) -> Chunk {
+    let constants: Vec<Value> = chunk.constants().collect();
+    let mut instructions = decode(&chunk);
+    while rewrite(&mut instructions, &constants) {}
+    encode(&chunk, &instructions)
+}
+
+/// Replaces common sequences of instructions with superinstructions. See the [module](self)
+/// documentation for which sequences are fused. The compiler does this after [optimize()], unless
+/// [CompileOptions::superinstructions](crate::compiler::CompileOptions::superinstructions) is off.
+///
+/// ```
+/// # use rlox::gc::ActiveGC;
+/// use rlox::chunk::OpCode;
+/// use rlox::compiler::{compile_with_options, CompileOptions};
+/// use rlox::optimizer::fuse;
+///
+/// let gc = ActiveGC::install();
+/// let options = CompileOptions {
+///     superinstructions: false,
+///     ..CompileOptions::default()
+/// };
+/// let chunk = compile_with_options("{ var a = 1; var b = 2; print a + b; }", &gc, options).unwrap();
+/// assert!(!chunk.code().contains(&(OpCode::AddLocals as u8)));
+///
+/// let fused = fuse(chunk.clone());
+/// assert!(fused.code().contains(&(OpCode::AddLocals as u8)));
+/// assert!(fused.len() < chunk.len());
+/// ```
+///
+/// The chunk must be well-formed, e.g., produced by the compiler.
+pub fn fuse(chunk: Chunk) -> Chunk {
+    let mut instructions = decode(&chunk);
+    while fuse_next(&mut instructions) {}
+    encode(&chunk, &instructions)
+}
+
+///////////////////////////////////// Implementation details //////////////////////////////////////
+
+/// One decoded instruction.
+#[derive(Clone, Debug)]
+struct Instruction {
+    opcode: OpCode,
+    /// The operand, if the opcode has one (other than a jump).
+    operand: u16,
+    /// For jumps, the index of the instruction that is jumped to.
+    target: usize,
+    /// The line of source code that this instruction came from.
+    line: usize,
+    /// The range of bytes in the source code that this instruction came from, if known.
+    span: Option<Range<usize>>,
+}
+
+/// Splits the chunk into instructions, with jump targets as instruction indices.
+fn decode(chunk: &Chunk) -> Vec<Instruction> {
+    let code = chunk.code();
+    let mut instructions = Vec::new();
+    // Maps byte offsets to instruction indices:
+    let mut index_of = vec![usize::MAX; code.len() + 1];
+    // (instruction index, byte offset of the jump target)
+    let mut jumps = Vec::new();
+
+    let mut offset = 0;
+    while offset < code.len() {
+        let opcode = OpCode::try_from(code[offset]).expect("valid opcode");
+        let next = offset + 1 + opcode.operand_len();
+        index_of[offset] = instructions.len();
+
+        let mut operand = 0;
+        match opcode {
+            _ if opcode.is_jump() => {
+                let jump = chunk.read_u16(offset + 1).expect("jump offset") as usize;
+                let target = match opcode {
+                    OpCode::Loop => next - jump,
+                    _ => next + jump,
+                };
+                jumps.push((instructions.len(), target));
+            }
+            _ if opcode.operand_len() == 1 => operand = code[offset + 1].into(),
+            _ if opcode.operand_len() == 2 => {
+                operand = chunk.read_u16(offset + 1).expect("two-byte operand")
+            }
+            _ => {}
+        }
+
+        instructions.push(Instruction {
+            opcode,
+            operand,
+            target: usize::MAX,
+            line: chunk.line_number_for(offset).expect("line number"),
+            span: chunk.span_for(offset),
+        });
+        offset = next;
+    }
+
+    // A jump may target the very end of the chunk:
+    index_of[code.len()] = instructions.len();
+    for (index, target) in jumps {
+        instructions[index].target = index_of[target];
+    }
+
+    instructions
+}
+
+/// Applies one pass of rewrites. Returns true if anything changed.
+fn rewrite(instructions: &mut Vec<Instruction>, constants: &[Value]) -> bool {
+    use OpCode::*;
+
+    replace_first(instructions, |i, instructions, can_rewrite| {
+        let opcodes: Vec<OpCode> = (instructions[i..].iter().take(3))
+            .map(|instruction| instruction.opcode)
+            .collect();
+
+        match opcodes.as_slice() {
+            [Not, Not, Not] if can_rewrite(i, 3) => Some((3, vec![instructions[i].clone()])),
+            [first, Not, Not] if produces_boolean(*first) && can_rewrite(i, 3) => {
+                Some((3, vec![instructions[i].clone()]))
+            }
+            [_, Negate, Negate]
+                if produces_number(&instructions[i], constants) && can_rewrite(i, 3) =>
+            {
+                Some((3, vec![instructions[i].clone()]))
+            }
+            [True, JumpIfFalse, ..] if can_rewrite(i, 2) => {
+                Some((2, vec![instructions[i].clone()]))
+            }
+            [False | Nil, JumpIfFalse, ..] if can_rewrite(i, 2) => {
+                let jump = Instruction {
+                    opcode: Jump,
+                    ..instructions[i + 1].clone()
+                };
+                Some((2, vec![instructions[i].clone(), jump]))
+            }
+            [Constant | Nil | True | False | GetLocal | GetLocalLong, Pop, ..]
+                if can_rewrite(i, 2) =>
+            {
+                Some((2, vec![]))
+            }
+            _ => None,
+        }
+    })
+}
+
+/// Fuses the first sequence that has a superinstruction. Returns true if anything changed.
+fn fuse_next(instructions: &mut Vec<Instruction>) -> bool {
+    use OpCode::*;
+
+    replace_first(instructions, |i, instructions, can_rewrite| {
+        let sequence = &instructions[i..instructions.len().min(i + 3)];
+        // The superinstruction has one line, so the whole sequence must be on the same line:
+        let same_line = |len: usize| {
+            len <= sequence.len() && sequence[..len].iter().all(|x| x.line == sequence[0].line)
+        };
+        let opcodes: Vec<OpCode> = sequence.iter().map(|x| x.opcode).collect();
+
+        // Runtime errors point to the `Add`, so the superinstruction keeps its span:
+        match opcodes.as_slice() {
+            [GetLocal, GetLocal, Add] if same_line(3) && can_rewrite(i, 3) => {
+                let fused = Instruction {
+                    opcode: AddLocals,
+                    operand: (sequence[0].operand << 8) | sequence[1].operand,
+                    ..sequence[2].clone()
+                };
+                Some((3, vec![fused]))
+            }
+            [Constant, Add, ..] if same_line(2) && can_rewrite(i, 2) => {
+                let fused = Instruction {
+                    opcode: AddConstant,
+                    operand: sequence[0].operand,
+                    ..sequence[1].clone()
+                };
+                Some((2, vec![fused]))
+            }
+            [Not, JumpIfFalse, ..] if same_line(2) && can_rewrite(i, 2) => {
+                let fused = Instruction {
+                    opcode: NotJumpIfFalse,
+                    ..sequence[1].clone()
+                };
+                Some((2, vec![fused]))
+            }
+            _ => None,
+        }
+    })
+}
+
+/// Finds the first sequence that `find` can rewrite, and replaces it. `find` is given the index of
+/// the start of a sequence, and a function that says whether `len` instructions from an index can
+/// be rewritten; it returns how many instructions to remove, and what to replace them with.
+/// Returns true if anything changed.
+fn replace_first<F>(instructions: &mut Vec<Instruction>, find: F) -> bool
+where
+    F: Fn(
+        usize,
+        &[Instruction],
+        &dyn Fn(usize, usize) -> bool,
+    ) -> Option<(usize, Vec<Instruction>)>,
+{
+    let targets: HashSet<usize> = (instructions.iter())
+        .filter(|instruction| instruction.opcode.is_jump())
+        .map(|instruction| instruction.target)
+        .collect();
+    // Only the first instruction of a sequence may be jumped to:
+    let can_rewrite =
+        |start: usize, len: usize| (start + 1..start + len).all(|i| !targets.contains(&i));
+
+    let found = (0..instructions.len()).find_map(|i| {
+        find(i, instructions, &can_rewrite).map(|(len, replacement)| (i, len, replacement))
+    });
+    let Some((start, len, replacement)) = found else {
+        return false;
+    };
+
+    // Jumps past the rewritten sequence (including jumps in the replacement) must account for the
+    // change in length:
+    let removed = len - replacement.len();
+    instructions.splice(start..start + len, replacement);
+    for instruction in instructions.iter_mut() {
+        if instruction.opcode.is_jump() && instruction.target >= start + len {
+            instruction.target -= removed;
+        }
+    }
+
+    true
+}
+
+/// Turns the instructions back into a chunk, with the same constants as the original.
+fn encode(original: &Chunk, instructions: &[Instruction]) -> Chunk {
+    let mut chunk = Chunk::new();
+    for constant in original.constants() {
+        chunk.add_constant(constant);
+    }
+
+    // Jumps are relative, so the offset of every instruction must be known before writing them:
+    let mut offsets = Vec::with_capacity(instructions.len() + 1);
+    let mut offset = 0;
+    for instruction in instructions {
+        offsets.push(offset);
+        offset += 1 + instruction.opcode.operand_len();
+    }
+    offsets.push(offset);
+
+    for (i, instruction) in instructions.iter().enumerate() {
+        let written = chunk.write_opcode_with_span(
+            instruction.opcode,
+            instruction.line,
+            instruction.span.clone(),
+        );
+        match instruction.opcode {
+            OpCode::Loop => {
+                let jump = offsets[i + 1] - offsets[instruction.target];
+                written.with_u16_operand(jump as u16);
+            }
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::NotJumpIfFalse => {
+                let jump = offsets[instruction.target] - offsets[i + 1];
+                written.with_u16_operand(jump as u16);
+            }
+            opcode if opcode.operand_len() == 1 => written.with_operand(instruction.operand as u8),
+            opcode if opcode.operand_len() == 2 => written.with_u16_operand(instruction.operand),
+            _ => {}
+        }
+    }
+
+    chunk
+}
+
+/// Returns true if the instruction always pushes a boolean.
+fn produces_boolean(opcode: OpCode) -> bool {
+    use OpCode::*;
+    matches!(opcode, True | False | Not | Equal | Greater | Less)
+}
+
+/// Returns true if the instruction always pushes a number (when it does not raise a runtime
+/// error).
+fn produces_number(instruction: &Instruction, constants: &[Value]) -> bool {
+    use OpCode::*;
+    // Add is missing, since it also concatenates strings:
+    match instruction.opcode {
+        Subtract | Multiply | Divide | BitAnd | BitOr | BitXor | ShiftLeft | ShiftRight
+        | Negate => true,
+        Constant => constants[instruction.operand as usize].is_number(),
+        _ => false,
+    }
+}
+
+////////////////////////////////////////////// Tests //////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::debug::disassemble_chunk_to_string;
+    use crate::gc::ActiveGC;
+    use serial_test::serial;
+
+    /// Compiles (and therefore optimizes) the source code, and returns its disassembly.
+    fn disassemble(source: &str) -> String {
+        let gc = ActiveGC::install();
+        let chunk = compile(source, &gc).unwrap();
+        disassemble_chunk_to_string(&chunk, "test")
+    }
+
+    #[test]
+    #[serial]
+    #[cfg_attr(
+        feature = "integers",
+        ignore = "number literals are integers with the integers feature"
+    )]
+    fn redundant_operators_are_removed() {
+        let expected = "\
+== test ==
+0000    1      OP_CONSTANT    0 'Number(1.0)'
+0002    |      OP_CONSTANT    1 'Number(2.0)'
+0004    |          OP_LESS
+0005    |         OP_PRINT
+0006    |      OP_CONSTANT    2 'Number(3.0)'
+0008    |         OP_PRINT
+0009    |    OP_GET_GLOBAL    3 'LoxString(\"a\")'
+0011    |           OP_NOT
+0012    |         OP_PRINT
+0013    |        OP_RETURN
+";
+        assert_eq!(
+            expected,
+            disassemble("print !!(1 < 2); print - -3; print !!!a;")
+        );
+
+        // But only when it's certain that they do nothing: !!a converts a to a boolean, and -a
+        // is a runtime error when a is not a number.
+        let expected = "\
+== test ==
+0000    1    OP_GET_GLOBAL    0 'LoxString(\"a\")'
+0002    |           OP_NOT
+0003    |           OP_NOT
+0004    |        OP_NEGATE
+0005    |        OP_NEGATE
+0006    |         OP_PRINT
+0007    |        OP_RETURN
+";
+        assert_eq!(expected, disassemble("print - -!!a;"));
+    }
+
+    #[test]
+    #[serial]
+    #[cfg_attr(
+        feature = "integers",
+        ignore = "number literals are integers with the integers feature"
+    )]
+    fn constant_conditions() {
+        // The condition is always true, so the loop's exit is unreachable:
+        let expected = "\
+== test ==
+0000    1      OP_CONSTANT    0 'Number(1.0)'
+0002    |         OP_PRINT
+0003    |          OP_LOOP    3 -> 0
+0006    |           OP_POP
+0007    |        OP_RETURN
+";
+        assert_eq!(expected, disassemble("while (true) print 1;"));
+
+        let expected = "\
+== test ==
+0000    1           OP_NIL
+0001    |          OP_JUMP    1 -> 7
+0004    |           OP_POP
+0005    |      OP_CONSTANT    0 'Number(1.0)'
+0007    |         OP_PRINT
+0008    |        OP_RETURN
+";
+        assert_eq!(expected, disassemble("print nil and 1;"));
+    }
+
+    #[test]
+    #[serial]
+    fn unused_values_are_not_pushed() {
+        let expected = "\
+== test ==
+0000    1        OP_RETURN
+";
+        assert_eq!(expected, disassemble("{ var a; a; 1; }"));
+    }
+
+    #[test]
+    #[serial]
+    fn superinstructions_are_fused() {
+        let expected = "\
+== test ==
+0000    1           OP_NIL
+0001    |           OP_NIL
+0002    |    OP_ADD_LOCALS    0    1
+0005    |         OP_PRINT
+0006    |     OP_GET_LOCAL    0
+0008    |  OP_ADD_CONSTANT    0 'LoxString(\"!\")'
+0010    |         OP_PRINT
+0011    |     OP_GET_LOCAL    0
+0013    | OP_NOT_JUMP_IF_FALSE   13 -> 19
+0016    |           OP_POP
+0017    |     OP_GET_LOCAL    1
+0019    |         OP_PRINT
+0020    |           OP_POP
+0021    |           OP_POP
+0022    |        OP_RETURN
+";
+        let source = "{ var a; var b; print a + b; print a + \"!\"; print !a and b; }";
+        assert_eq!(expected, disassemble(source));
+
+        // A superinstruction has one line number, so sequences split across lines stay apart:
+        let expected = "\
+== test ==
+0000    1           OP_NIL
+0001    |           OP_NIL
+0002    2     OP_GET_LOCAL    0
+0004    3     OP_GET_LOCAL    1
+0006    |           OP_ADD
+0007    |         OP_PRINT
+0008    |           OP_POP
+0009    |           OP_POP
+0010    |        OP_RETURN
+";
+        assert_eq!(expected, disassemble("{ var a; var b;\nprint a +\n b; }"));
+    }
+
+    #[test]
+    fn jump_targets_are_not_rewritten() {
+        // The `Pop` is jumped to, so `Nil, Pop` must not be removed: that would change what the
+        // jump pops.
+        let mut chunk = Chunk::new();
+        chunk.write_opcode(OpCode::GetLocal, 1).with_operand(0);
+        chunk
+            .write_opcode(OpCode::JumpIfFalse, 1)
+            .with_u16_operand(1);
+        chunk.write_opcode(OpCode::Nil, 1);
+        chunk.write_opcode(OpCode::Pop, 1);
+        chunk.write_opcode(OpCode::Return, 1);
+
+        let before = disassemble_chunk_to_string(&chunk, "test");
+        let after = disassemble_chunk_to_string(&optimize(chunk), "test");
+        assert_eq!(before, after);
+    }
+}
@@ -0,0 +1,128 @@
+//! Runs many independent Lox scripts at once, e.g., for test runners and batch processing.
+//!
+//! Each script gets its own [VM] (with the [standard library](crate::stdlib)), and each VM has its
+//! own garbage collector, so scripts never share any state.
+
+use std::io;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use crate::vm::{SharedBuffer, Statistics, VM};
+
+/// What a script run by [run_many()] did.
+#[derive(Clone, Debug, Default)]
+pub struct Output {
+    /// Everything the script printed.
+    pub stdout: String,
+    /// Statistics about the run. See [VM::statistics()].
+    pub statistics: Statistics,
+}
+
+/// Runs every script concurrently, on as many threads as there are CPUs (but no more threads than
+/// scripts). Returns the result of each script, in the same order as the scripts. Errors are
+/// returned, rather than written to `stderr`.
+///
+/// ```
+/// let results = rlox::run_many(vec!["print 1 + 2;", "print -nil;", "print \"three\";"]);
+/// assert_eq!("3\n", results[0].as_ref().unwrap().stdout);
+/// assert!(results[1].is_err());
+/// assert_eq!("three\n", results[2].as_ref().unwrap().stdout);
+/// ```
+pub fn run_many(sources: Vec<&str>) -> Vec<crate::Result<Output>> {
+    let workers = thread::available_parallelism()
+        .map_or(1, NonZeroUsize::get)
+        .min(sources.len());
+    let next = AtomicUsize::new(0);
+    let results: Vec<_> = sources.iter().map(|_| Mutex::new(None)).collect();
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                // Each worker takes the next script that nobody has started yet:
+                loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(source) = sources.get(index) else {
+                        break;
+                    };
+                    *results[index].lock().expect("result lock") = Some(run(source));
+                }
+            });
+        }
+    });
+
+    results
+        .into_iter()
+        .map(|result| {
+            (result.into_inner().expect("result lock")).expect("every script should have run")
+        })
+        .collect()
+}
+
+/// Runs one script in a fresh VM.
+fn run(source: &str) -> crate::Result<Output> {
+    let stdout = SharedBuffer::default();
+    let mut vm = VM::default();
+    crate::stdlib::install(&mut vm);
+    // SAFETY: the standard library's natives never keep a value.
+    unsafe { vm.enable_garbage_collection() };
+    vm.set_stdout(Box::new(stdout.clone()));
+    vm.set_stderr(Box::new(io::sink()));
+
+    vm.interpret(source)?;
+    let statistics = vm.statistics().clone();
+    drop(vm);
+
+    Ok(Output {
+        stdout: stdout.into_string(),
+        statistics,
+    })
+}
+
+////////////////////////////////////////////// Tests //////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use serial_test::serial;
+
+    use super::*;
+    use crate::error::InterpretationError;
+
+    #[test]
+    #[serial]
+    fn scripts_do_not_share_state() {
+        let source = |n| {
+            format!(
+                "var x = {n}; var list = [];
+                 for (var i = 0; i < 500; i = i + 1) list = [list, x];
+                 print x * 2;"
+            )
+        };
+        let sources: Vec<_> = (0..32).map(source).collect();
+        let results = run_many(sources.iter().map(String::as_str).collect());
+
+        assert_eq!(32, results.len());
+        for (n, result) in results.into_iter().enumerate() {
+            let output = result.expect("the script should run");
+            assert_eq!(format!("{}\n", n * 2), output.stdout);
+            assert!(output.statistics.instructions > 500);
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn errors_are_returned_in_order() {
+        let results = run_many(vec!["var = 1;", "print 1;", "print undefined;"]);
+        assert!(matches!(
+            results[0],
+            Err(InterpretationError::CompileError(_))
+        ));
+        assert_eq!("1\n", results[1].as_ref().unwrap().stdout);
+        assert!(matches!(
+            results[2],
+            Err(InterpretationError::RuntimeError(_))
+        ));
+        assert!(run_many(Vec::new()).is_empty());
+    }
+}
@@ -0,0 +1,49 @@
+//! The base protocol shared by the [Language Server Protocol][lsp] and the [Debug Adapter
+//! Protocol][dap]: every message is JSON, preceded by a `Content-Length` header.
+//!
+//! [lsp]: https://microsoft.github.io/language-server-protocol/
+//! [dap]: https://microsoft.github.io/debug-adapter-protocol/
+
+use std::io::{self, BufRead, Write};
+
+use serde_json::Value as Json;
+
+/// Reads one message. Returns `None` at the end of the input.
+pub(crate) fn read_message(input: &mut impl BufRead) -> io::Result<Option<Json>> {
+    let mut content_length = None;
+    let mut header = String::new();
+
+    loop {
+        header.clear();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                content_length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+
+    let content_length = content_length
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length"))?;
+    let mut body = vec![0; content_length];
+    input.read_exact(&mut body)?;
+
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+/// Writes one message, and flushes the output.
+pub(crate) fn write_message(output: &mut impl Write, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+    output.flush()
+}
@@ -0,0 +1,633 @@
+//! An experimental register-based backend, to compare a register VM with the stack VM on the same
+//! scripts. With [Target::Register](crate::compiler::Target::Register), the compiler translates
+//! the bytecode of every chunk into register [Code], which the VM runs instead of the bytecode.
+//!
+//! The registers of a call frame are the slots of the stack that belong to it: local variables
+//! are registers, and so are the temporary values that the bytecode pushes. Rather than pushing a
+//! local variable or a constant, only for the next instruction to pop it, a register instruction
+//! reads its operands straight from registers or the constant pool, and it can write its result
+//! straight into a local variable:
+//!
+//! | Bytecode                                               | Register code    |
+//! |--------------------------------------------------------|------------------|
+//! | `GetLocal 0`, `Constant 1`, `Add`, `SetLocal 0`, `Pop` | `r0 = r0 + k1`   |
+//! | `GetLocal 0`, `Print`                                  | `print r0`       |
+//!
+//! Each register instruction is at the offset of the bytecode instruction it came from, so that
+//! line numbers, spans, and jumps work the same way in both. Where execution can arrive at a
+//! bytecode instruction that was folded into a later one (e.g., a jump target), it runs that
+//! later instruction. Instructions without a register form (e.g., calls, classes, lists, and
+//! maps) run as bytecode, on the same stack.
+//!
+//! ```
+//! # use rlox::gc::ActiveGC;
+//! use rlox::compiler::{compile_with_options, CompileOptions, Target};
+//!
+//! let gc = ActiveGC::install();
+//! let options = CompileOptions {
+//!     target: Target::Register,
+//!     ..CompileOptions::default()
+//! };
+//! let chunk = compile_with_options("{ var i = 1; i = i + 2; print i; }", &gc, options).unwrap();
+//! let expected = "\
+//! 0000  r0 = k0
+//! 0006  r0 = r0 + k1
+//! 0012  print r0
+//! 0013  stack Pop
+//! 0014  stack Return
+//! ";
+//! assert_eq!(expected, chunk.registers().unwrap().to_string());
+//! ```
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::chunk::{Chunk, OpCode};
+
+/// A register: a slot of the stack, counting from the start of the call frame, like a local
+/// variable.
+pub type Register = u16;
+
+/// Where an instruction reads a value from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operand {
+    /// The value in the register.
+    Register(Register),
+    /// The constant at this index in the chunk's constant pool.
+    Constant(u8),
+    /// `nil`.
+    Nil,
+    /// `true`.
+    True,
+    /// `false`.
+    False,
+}
+
+/// An instruction for the register VM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    /// `dst = src`.
+    Move { dst: Register, src: Operand },
+    /// `dst = op src`, where `op` is [OpCode::Not] or [OpCode::Negate].
+    Unary {
+        op: OpCode,
+        dst: Register,
+        src: Operand,
+    },
+    /// `dst = lhs op rhs`, where `op` is the opcode of a binary operator, like [OpCode::Add].
+    Binary {
+        op: OpCode,
+        dst: Register,
+        lhs: Operand,
+        rhs: Operand,
+    },
+    /// `dst` = the global variable whose name is the constant.
+    GetGlobal { dst: Register, name: u8 },
+    /// Defines the global variable whose name is the constant, as `src`. With `constant`, it is
+    /// defined with `const`.
+    DefineGlobal {
+        name: u8,
+        src: Operand,
+        constant: bool,
+    },
+    /// Assigns `src` to the global variable whose name is the constant. The variable must already
+    /// exist.
+    SetGlobal { name: u8, src: Operand },
+    /// Prints `src` to `stdout`.
+    Print { src: Operand },
+    /// Jumps to the offset.
+    Jump { target: usize },
+    /// Jumps to the offset if `condition` is falsy.
+    JumpIfFalse { condition: Register, target: usize },
+    /// Runs the bytecode instruction at this offset, whose opcode this is.
+    Stack { opcode: OpCode },
+}
+
+/// An [Instruction], and where the VM goes afterwards.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub instruction: Instruction,
+    /// The offset of the bytecode instruction that this instruction came from.
+    pub offset: usize,
+    /// How many registers are in use afterwards, i.e., the height of the frame's stack.
+    pub top: Register,
+    /// The offset of the next instruction. Jumps and [Instruction::Stack] go elsewhere by
+    /// themselves.
+    pub next: usize,
+}
+
+/// The register code for a [Chunk]: an [Entry] at the offset of each bytecode instruction that
+/// has a register instruction. See [translate()].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Code {
+    entries: Vec<Option<Entry>>,
+}
+
+impl Code {
+    /// Returns the entry at the given offset, if there is one.
+    #[inline(always)]
+    pub fn get(&self, offset: usize) -> Option<&Entry> {
+        self.entries.get(offset).and_then(Option::as_ref)
+    }
+
+    /// Returns every instruction, in order. Offsets that run a later instruction are skipped.
+    pub fn iter(&self) -> impl Iterator<Item = &Entry> {
+        (self.entries.iter().enumerate())
+            .filter_map(|(offset, entry)| entry.as_ref().filter(|entry| entry.offset == offset))
+    }
+
+    /// Returns how many instructions there are.
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    /// Returns true if there are no instructions.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Translates the chunk's bytecode into register code. `locals` is how many registers are in use
+/// when the chunk starts running: none for a script, or the function and its arguments.
+///
+/// The chunk must be well-formed, e.g., produced by the compiler.
+pub fn translate(chunk: &Chunk, locals: usize) -> Code {
+    let instructions = decode(chunk);
+    // The chunk starts running at the first instruction, as if it were jumped to:
+    let targets = (instructions.iter())
+        .filter(|instruction| instruction.opcode.is_jump())
+        .map(|instruction| instruction.operand)
+        .chain([0])
+        .collect();
+
+    let mut translator = Translator {
+        entries: vec![None; chunk.len()],
+        targets,
+        resumes: Vec::new(),
+        top: locals as Register,
+        pending: Vec::new(),
+        heights: HashMap::new(),
+        last_write: None,
+    };
+    translator.translate(&instructions);
+
+    // Each instruction goes on to the next one that was written:
+    let mut entries = translator.entries;
+    let mut next = chunk.len();
+    for offset in (0..entries.len()).rev() {
+        if let Some(entry) = &mut entries[offset] {
+            entry.next = next;
+            next = offset;
+        }
+    }
+
+    // Execution that arrives at an instruction that was folded runs the one it was folded into:
+    for offset in translator.resumes {
+        let following = (entries[offset..].iter()).find_map(|entry| *entry);
+        entries[offset] = following;
+    }
+
+    Code { entries }
+}
+
+///////////////////////////////////// Implementation details //////////////////////////////////////
+
+/// One decoded bytecode instruction.
+struct Decoded {
+    offset: usize,
+    opcode: OpCode,
+    /// The operand, if any. For jumps, the offset that is jumped to.
+    operand: usize,
+}
+
+/// Splits the chunk into instructions.
+fn decode(chunk: &Chunk) -> Vec<Decoded> {
+    let code = chunk.code();
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+    while offset < code.len() {
+        let opcode = OpCode::try_from(code[offset]).expect("valid opcode");
+        let next = offset + 1 + opcode.operand_len();
+        let operand = match opcode.operand_len() {
+            1 => code[offset + 1] as usize,
+            2 => chunk.read_u16(offset + 1).expect("two-byte operand") as usize,
+            _ => 0,
+        };
+        let operand = match opcode {
+            OpCode::Loop => next - operand,
+            _ if opcode.is_jump() => next + operand,
+            _ => operand,
+        };
+        instructions.push(Decoded {
+            offset,
+            opcode,
+            operand,
+        });
+        offset = next;
+    }
+    instructions
+}
+
+/// The state of the translation, as it goes through the bytecode in order.
+struct Translator {
+    entries: Vec<Option<Entry>>,
+    /// The offsets that are jumped to, and the start of the chunk.
+    targets: HashSet<usize>,
+    /// The offsets where execution can arrive from elsewhere (jump targets, and after bytecode
+    /// instructions) that did not get an instruction of their own.
+    resumes: Vec<usize>,
+    /// How many registers hold values.
+    top: Register,
+    /// Values that the bytecode pushed on top of the registers, which have not been written to
+    /// registers yet, with the offset of the instruction that pushed each one.
+    pending: Vec<(usize, Operand)>,
+    /// How many registers hold values at each jump target, from the jumps to it.
+    heights: HashMap<usize, Register>,
+    /// The offset of the instruction that wrote the top register, if the top register has not
+    /// changed since.
+    last_write: Option<usize>,
+}
+
+impl Translator {
+    fn translate(&mut self, instructions: &[Decoded]) {
+        use OpCode::*;
+
+        let mut after_stack = false;
+        let mut i = 0;
+        while i < instructions.len() {
+            let Decoded {
+                offset,
+                opcode,
+                operand,
+            } = instructions[i];
+            // Whatever runs next after a jump or a bytecode instruction must have an entry:
+            let resumes = after_stack || self.targets.contains(&offset);
+            if resumes {
+                self.flush();
+                self.last_write = None;
+            }
+            if let Some(&height) = self.heights.get(&offset) {
+                self.top = height;
+            }
+            // Instructions that assign a variable and then pop the value can skip the pop:
+            let then_pops = matches!(instructions.get(i + 1), Some(next)
+                if next.opcode == Pop && !self.targets.contains(&next.offset));
+            after_stack = false;
+
+            match opcode {
+                Constant => self
+                    .pending
+                    .push((offset, Operand::Constant(operand as u8))),
+                Nil => self.pending.push((offset, Operand::Nil)),
+                True => self.pending.push((offset, Operand::True)),
+                False => self.pending.push((offset, Operand::False)),
+                GetLocal | GetLocalLong => self
+                    .pending
+                    .push((offset, Operand::Register(operand as Register))),
+                Pop if !self.pending.is_empty() => {
+                    self.pending.pop();
+                }
+                SetLocal | SetLocalLong if then_pops => {
+                    let dst = operand as Register;
+                    if let Some((_, src)) = self.pending.pop() {
+                        self.flush();
+                        self.emit(offset, Instruction::Move { dst, src });
+                    } else if let Some(write) = self.last_write {
+                        // Write the value straight into the variable instead:
+                        self.top -= 1;
+                        let entry = self.entries[write].as_mut().expect("the last write");
+                        entry.top = self.top;
+                        match &mut entry.instruction {
+                            Instruction::Move { dst: to, .. }
+                            | Instruction::Unary { dst: to, .. }
+                            | Instruction::Binary { dst: to, .. }
+                            | Instruction::GetGlobal { dst: to, .. } => *to = dst,
+                            _ => unreachable!("only instructions that write a register"),
+                        }
+                    } else {
+                        let src = self.pop();
+                        self.emit(offset, Instruction::Move { dst, src });
+                    }
+                    self.last_write = None;
+                    i += 1; // Skip the pop.
+                }
+                SetLocal | SetLocalLong => {
+                    self.flush();
+                    let src = Operand::Register(self.top - 1);
+                    let dst = operand as Register;
+                    self.emit(offset, Instruction::Move { dst, src });
+                }
+                GetGlobal => {
+                    self.flush();
+                    let dst = self.top;
+                    self.top += 1;
+                    self.emit_write(
+                        offset,
+                        Instruction::GetGlobal {
+                            dst,
+                            name: operand as u8,
+                        },
+                    );
+                }
+                DefineGlobal | DefineConstGlobal => {
+                    let src = self.pop();
+                    self.flush();
+                    self.emit(
+                        offset,
+                        Instruction::DefineGlobal {
+                            name: operand as u8,
+                            src,
+                            constant: opcode == DefineConstGlobal,
+                        },
+                    );
+                }
+                SetGlobal if then_pops => {
+                    let src = self.pop();
+                    self.flush();
+                    self.emit(
+                        offset,
+                        Instruction::SetGlobal {
+                            name: operand as u8,
+                            src,
+                        },
+                    );
+                    i += 1; // Skip the pop.
+                }
+                SetGlobal => {
+                    self.flush();
+                    let src = Operand::Register(self.top - 1);
+                    self.emit(
+                        offset,
+                        Instruction::SetGlobal {
+                            name: operand as u8,
+                            src,
+                        },
+                    );
+                }
+                Equal | Greater | Less | Add | Subtract | Multiply | Divide | BitAnd | BitOr
+                | BitXor | ShiftLeft | ShiftRight => {
+                    let rhs = self.pop();
+                    let lhs = self.pop();
+                    self.flush();
+                    let dst = self.top;
+                    self.top += 1;
+                    let instruction = Instruction::Binary {
+                        op: opcode,
+                        dst,
+                        lhs,
+                        rhs,
+                    };
+                    self.emit_write(offset, instruction);
+                }
+                Not | Negate => {
+                    let src = self.pop();
+                    self.flush();
+                    let dst = self.top;
+                    self.top += 1;
+                    self.emit_write(
+                        offset,
+                        Instruction::Unary {
+                            op: opcode,
+                            dst,
+                            src,
+                        },
+                    );
+                }
+                Print => {
+                    let src = self.pop();
+                    self.flush();
+                    self.emit(offset, Instruction::Print { src });
+                }
+                Jump => {
+                    self.flush();
+                    self.heights.insert(operand, self.top);
+                    self.emit(offset, Instruction::Jump { target: operand });
+                }
+                JumpIfFalse => {
+                    self.flush();
+                    self.heights.insert(operand, self.top);
+                    let condition = self.top - 1;
+                    let target = operand;
+                    self.emit(offset, Instruction::JumpIfFalse { condition, target });
+                }
+                _ => {
+                    self.flush();
+                    if opcode.is_jump() {
+                        self.heights.insert(operand, self.top);
+                    }
+                    let (pops, pushes) = stack_effect(opcode, operand);
+                    self.top = (self.top + pushes).saturating_sub(pops);
+                    self.emit(offset, Instruction::Stack { opcode });
+                    after_stack = true;
+                }
+            }
+
+            if resumes && self.entries[offset].is_none() {
+                self.resumes.push(offset);
+            }
+            i += 1;
+        }
+    }
+
+    /// Returns where to read the value on top of the stack, which is popped.
+    fn pop(&mut self) -> Operand {
+        match self.pending.pop() {
+            Some((_, operand)) => operand,
+            None => {
+                self.top -= 1;
+                Operand::Register(self.top)
+            }
+        }
+    }
+
+    /// Writes the pending values to registers, with a move at the offset of each one.
+    fn flush(&mut self) {
+        for (offset, src) in std::mem::take(&mut self.pending) {
+            let dst = self.top;
+            self.top += 1;
+            self.emit_write(offset, Instruction::Move { dst, src });
+        }
+    }
+
+    /// Writes the instruction at the offset.
+    fn emit(&mut self, offset: usize, instruction: Instruction) {
+        self.entries[offset] = Some(Entry {
+            instruction,
+            offset,
+            top: self.top,
+            next: 0,
+        });
+        self.last_write = None;
+    }
+
+    /// Writes an instruction that writes the top register.
+    fn emit_write(&mut self, offset: usize, instruction: Instruction) {
+        self.emit(offset, instruction);
+        self.last_write = Some(offset);
+    }
+}
+
+/// Returns how many values the bytecode instruction pops, and how many it pushes.
+fn stack_effect(opcode: OpCode, operand: usize) -> (Register, Register) {
+    use OpCode::*;
+    let operand = operand as Register;
+    match opcode {
+        Constant | Nil | True | False | GetLocal | GetLocalLong | GetGlobal | Class | Import
+        | AddLocals => (0, 1),
+        Pop | DefineGlobal | DefineConstGlobal | Print | Return => (1, 0),
+        SetLocal | SetLocalLong | SetGlobal | GetProperty | Not | Negate | AddConstant => (1, 1),
+        SetProperty | IndexGet | Equal | Greater | Less | Add | Subtract | Multiply | Divide
+        | BitAnd | BitOr | BitXor | ShiftLeft | ShiftRight => (2, 1),
+        IndexSet => (3, 1),
+        BuildList => (operand, 1),
+        BuildMap => (2 * operand, 1),
+        // The function and its arguments are replaced by what it returns:
+        Call => (operand + 1, 1),
+        Jump | JumpIfFalse | Loop | NotJumpIfFalse => (0, 0),
+    }
+}
+
+/////////////////////////////////////////// Disassembly ///////////////////////////////////////////
+
+impl fmt::Display for Code {
+    /// Lists the instructions, one per line, with their offsets.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for entry in self.iter() {
+            writeln!(f, "{:04}  {}", entry.offset, entry.instruction)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Instruction::*;
+        match *self {
+            Move { dst, src } => write!(f, "r{dst} = {src}"),
+            Unary { op, dst, src } => write!(f, "r{dst} = {}{src}", operator(op)),
+            Binary { op, dst, lhs, rhs } => write!(f, "r{dst} = {lhs} {} {rhs}", operator(op)),
+            GetGlobal { dst, name } => write!(f, "r{dst} = global k{name}"),
+            DefineGlobal {
+                name,
+                src,
+                constant: false,
+            } => write!(f, "define global k{name} = {src}"),
+            DefineGlobal {
+                name,
+                src,
+                constant: true,
+            } => write!(f, "define const global k{name} = {src}"),
+            SetGlobal { name, src } => write!(f, "global k{name} = {src}"),
+            Print { src } => write!(f, "print {src}"),
+            Jump { target } => write!(f, "jump {target:04}"),
+            JumpIfFalse { condition, target } => {
+                write!(f, "jump {target:04} if r{condition} is falsy")
+            }
+            Stack { opcode } => write!(f, "stack {opcode:?}"),
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Register(register) => write!(f, "r{register}"),
+            Operand::Constant(index) => write!(f, "k{index}"),
+            Operand::Nil => write!(f, "nil"),
+            Operand::True => write!(f, "true"),
+            Operand::False => write!(f, "false"),
+        }
+    }
+}
+
+/// Returns how the operator of a unary or binary opcode is written in Lox.
+fn operator(op: OpCode) -> &'static str {
+    use OpCode::*;
+    match op {
+        Equal => "==",
+        Greater => ">",
+        Less => "<",
+        Add => "+",
+        Subtract | Negate => "-",
+        Multiply => "*",
+        Divide => "/",
+        BitAnd => "&",
+        BitOr => "|",
+        BitXor => "^",
+        ShiftLeft => "<<",
+        ShiftRight => ">>",
+        Not => "!",
+        _ => "?",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::{compile_with_options, CompileOptions};
+    use crate::gc::ActiveGC;
+    use serial_test::serial;
+
+    /// Compiles the source code for the register VM, and returns a listing of its register code.
+    fn translate_source(source: &str) -> String {
+        let gc = ActiveGC::install();
+        let options = CompileOptions {
+            target: crate::compiler::Target::Register,
+            ..CompileOptions::default()
+        };
+        let chunk = compile_with_options(source, &gc, options).unwrap();
+        chunk.registers().unwrap().to_string()
+    }
+
+    #[test]
+    #[serial]
+    fn locals_are_registers() {
+        let expected = "\
+0000  r0 = k0
+0006  r1 = r0 < k1
+0007  jump 0025 if r1 is falsy
+0010  stack Pop
+0013  print r0
+0018  r0 = r0 + k2
+0022  stack Loop
+0025  stack Pop
+0026  stack Pop
+0027  stack Return
+";
+        let source = "{ var i = 0; while (i < 3) { print i; i = i + 1; } }";
+        assert_eq!(expected, translate_source(source));
+    }
+
+    #[test]
+    #[serial]
+    fn execution_can_resume_anywhere_it_arrives() {
+        let gc = ActiveGC::install();
+        let options = CompileOptions {
+            target: crate::compiler::Target::Register,
+            ..CompileOptions::default()
+        };
+        let source = "var a = 1; while (a < 10 and a != 5) { a = a * 2 + 1; print a or nil; }";
+        let chunk = compile_with_options(source, &gc, options).unwrap();
+        let code = chunk.registers().unwrap();
+
+        // The start, every jump target, and every instruction after a bytecode instruction
+        // (except the last return):
+        let mut offsets = vec![0];
+        for entry in code.iter() {
+            match entry.instruction {
+                Instruction::Jump { target } | Instruction::JumpIfFalse { target, .. } => {
+                    offsets.push(target)
+                }
+                Instruction::Stack { opcode } => {
+                    offsets.push(entry.offset + 1 + opcode.operand_len())
+                }
+                _ => {}
+            }
+        }
+        for offset in offsets.into_iter().filter(|&offset| offset < chunk.len()) {
+            let entry = code.get(offset);
+            assert!(entry.is_some(), "no instruction at {offset:04}:\n{code}");
+            assert!(entry.unwrap().offset >= offset);
+        }
+    }
+}
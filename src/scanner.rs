@@ -39,6 +39,12 @@ pub struct Lexeme<'a> {
     text: &'a str,
     /// The line where this lexeme came from.
     line: usize,
+    /// The column where this lexeme starts.
+    column: usize,
+    /// The byte offset in the source code where this lexeme starts.
+    start: usize,
+    /// The byte offset in the source code just past the end of this lexeme.
+    end: usize,
 }
 
 /// What _type_ of [Lexeme] you have.
@@ -48,25 +54,41 @@ pub enum Token {
     // Single-character tokens.
     LeftParen, RightParen,
     LeftBrace, RightBrace,
-    Comma, Dot, Minus, Plus,
+    LeftBracket, RightBracket,
+    Colon, Comma, Dot, Minus, Plus,
     Semicolon, Star, Slash,
+    Ampersand, Pipe, Caret,
     // Or or two characte tokens
     Bang, BangEqual,
+    MinusMinus, PlusPlus,
     Equal, EqualEqual,
-    Greater, GreaterEqual,
-    Less, LessEqual,
+    Greater, GreaterEqual, GreaterGreater,
+    Less, LessEqual, LessLess,
     // Literals
     Identifier, StrLiteral, Number,
     // Keywords
-    And, Class, Else, False,
-    For, Fun, If, Nil, Or,
+    And, Class, Const, Else, False,
+    For, Fun, If, Import, Nil, Or,
     Print, Return, Super, This,
     True, Var, While,
 
+    // Trivia (only yielded by Scanner::with_trivia())
+    Comment, Whitespace,
+
     // Others
     Error, Eof
 }
 
+/// Where a [Scanner] is in the source code. A scanner can [resume](Scanner::resume()) from a
+/// position, even in a different version of the source code, as long as everything before the
+/// position is unchanged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    offset: usize,
+    line: usize,
+    column: usize,
+}
+
 /// Scans Lox source code and iteratively yields [Lexeme]s.
 ///
 /// The scanner is stateful, and therefore, can only be used to do one pass over the source code
@@ -77,6 +99,15 @@ pub struct Scanner<'a> {
     start: &'a str,
     current: &'a str,
     line: usize,
+    /// The column of the next character, counting characters from 1.
+    column: usize,
+    /// The column where the lexeme currently being scanned starts.
+    start_column: usize,
+    /// The entire source code, used to compute byte offsets, and to resume scanning.
+    source: &'a str,
+    /// When true, comments and whitespace are yielded as [Token::Comment] and [Token::Whitespace]
+    /// instead of being skipped.
+    keep_trivia: bool,
 }
 
 impl<'a> Scanner<'a> {
@@ -86,33 +117,130 @@ impl<'a> Scanner<'a> {
             start: source,
             current: source,
             line: 1,
+            column: 1,
+            start_column: 1,
+            source,
+            keep_trivia: false,
+        }
+    }
+
+    /// Start scanning the given source code, as if its first line were the given line number.
+    /// This is for source code that is part of something bigger.
+    ///
+    /// ```
+    /// use rlox::scanner::Scanner;
+    /// let mut scanner = Scanner::starting_at_line("print\n1;", 10);
+    /// assert_eq!(10, scanner.scan_token().line());
+    /// assert_eq!(11, scanner.scan_token().line());
+    /// ```
+    pub fn starting_at_line(source: &'a str, line: usize) -> Self {
+        Scanner {
+            line,
+            ..Scanner::new(source)
+        }
+    }
+
+    /// Start scanning the given source code, but yield comments as [Token::Comment] lexemes and
+    /// runs of whitespace as [Token::Whitespace] lexemes instead of discarding them. Together, the
+    /// lexemes cover every character of the source code. This is useful for tools that must
+    /// preserve comments, like the [formatter](crate::formatter).
+    ///
+    /// ```
+    /// use rlox::scanner::{Scanner, Token};
+    /// let scanner = Scanner::with_trivia("print 1; // one");
+    /// let tokens: Vec<_> = scanner
+    ///     .take_while(|lexeme| lexeme.token() != Token::Eof)
+    ///     .map(|lexeme| (lexeme.token(), lexeme.text()))
+    ///     .collect();
+    ///
+    /// use Token::*;
+    /// assert_eq!(
+    ///     vec![
+    ///         (Print, "print"),
+    ///         (Whitespace, " "),
+    ///         (Number, "1"),
+    ///         (Semicolon, ";"),
+    ///         (Whitespace, " "),
+    ///         (Comment, "// one"),
+    ///     ],
+    ///     tokens
+    /// );
+    /// ```
+    pub fn with_trivia(source: &'a str) -> Self {
+        Scanner {
+            keep_trivia: true,
+            ..Scanner::new(source)
         }
     }
 
     /// Yield the next [Lexeme] from the string. Once the scanner has reached the end-of-file, this
     /// function will always return an end-of-file lexeme.
     pub fn scan_token(&mut self) -> Lexeme<'a> {
-        self.skip_whitespace();
+        loop {
+            let lexeme = self.scan_lexeme();
+            if self.keep_trivia || lexeme.token() != Token::Comment {
+                return lexeme;
+            }
+        }
+    }
+
+    /// Scans the next [Lexeme], which may be a [Token::Comment], even if we're not keeping
+    /// trivia. Whitespace is only scanned as a [Token::Whitespace] if we're keeping trivia.
+    fn scan_lexeme(&mut self) -> Lexeme<'a> {
+        if !self.keep_trivia {
+            self.skip_whitespace();
+        }
         self.start = self.current;
+        self.start_column = self.column;
 
         if self.is_at_end() {
             return self.make_lexeme(Token::Eof);
         }
 
         match self.advance() {
+            c if is_whitespace(c) => self.whitespace(),
             c if is_id_start(c) => self.identifier(),
             c if c.is_ascii_digit() => self.number(),
             '(' => self.make_lexeme(Token::LeftParen),
             ')' => self.make_lexeme(Token::RightParen),
             '{' => self.make_lexeme(Token::LeftBrace),
             '}' => self.make_lexeme(Token::RightBrace),
+            '[' => self.make_lexeme(Token::LeftBracket),
+            ']' => self.make_lexeme(Token::RightBracket),
+            ':' => self.make_lexeme(Token::Colon),
             ';' => self.make_lexeme(Token::Semicolon),
             ',' => self.make_lexeme(Token::Comma),
             '.' => self.make_lexeme(Token::Dot),
-            '-' => self.make_lexeme(Token::Minus),
-            '+' => self.make_lexeme(Token::Plus),
-            '/' => self.make_lexeme(Token::Slash),
+            '-' => {
+                let followed_by_minus = self.match_and_advance('-');
+                self.make_lexeme(if followed_by_minus {
+                    Token::MinusMinus
+                } else {
+                    Token::Minus
+                })
+            }
+            '+' => {
+                let followed_by_plus = self.match_and_advance('+');
+                self.make_lexeme(if followed_by_plus {
+                    Token::PlusPlus
+                } else {
+                    Token::Plus
+                })
+            }
+            '/' => {
+                // Line comments only make it this far when we're keeping trivia:
+                if self.match_and_advance('/') {
+                    self.line_comment()
+                } else if self.match_and_advance('*') {
+                    self.block_comment()
+                } else {
+                    self.make_lexeme(Token::Slash)
+                }
+            }
             '*' => self.make_lexeme(Token::Star),
+            '&' => self.make_lexeme(Token::Ampersand),
+            '|' => self.make_lexeme(Token::Pipe),
+            '^' => self.make_lexeme(Token::Caret),
             '!' => {
                 let followed_by_equal = self.match_and_advance('=');
                 self.make_lexeme(if followed_by_equal {
@@ -130,26 +258,69 @@ impl<'a> Scanner<'a> {
                 })
             }
             '<' => {
-                let followed_by_equal = self.match_and_advance('=');
-                self.make_lexeme(if followed_by_equal {
+                let token = if self.match_and_advance('=') {
                     Token::LessEqual
+                } else if self.match_and_advance('<') {
+                    Token::LessLess
                 } else {
                     Token::Less
-                })
+                };
+                self.make_lexeme(token)
             }
             '>' => {
-                let followed_by_equal = self.match_and_advance('=');
-                self.make_lexeme(if followed_by_equal {
+                let token = if self.match_and_advance('=') {
                     Token::GreaterEqual
+                } else if self.match_and_advance('>') {
+                    Token::GreaterGreater
                 } else {
                     Token::Greater
-                })
+                };
+                self.make_lexeme(token)
             }
             '"' => self.string(),
             _ => self.error_token("Unexpected character"),
         }
     }
 
+    /// Returns the position of the next character to scan. Between lexemes, this is the position
+    /// just past the lexeme that was scanned last.
+    pub fn position(&self) -> Position {
+        Position {
+            offset: self.source.len() - self.current.len(),
+            line: self.line,
+            column: self.column,
+        }
+    }
+
+    /// Continues scanning from the position, which came from [Scanner::position()]. This lets
+    /// tools re-scan only the part of a document that changed: keep the position after each
+    /// lexeme, and scan the edited document from the last position before the edit.
+    ///
+    /// ```
+    /// use rlox::scanner::{Scanner, Token};
+    /// let mut scanner = Scanner::new("print 1;\nprint 2;");
+    /// scanner.by_ref().take(3).for_each(drop);
+    /// let position = scanner.position();
+    ///
+    /// let edited = "print 1;\nvar two = 2;";
+    /// let mut scanner = Scanner::new(edited);
+    /// scanner.resume(position);
+    /// let var = scanner.scan_token();
+    /// assert_eq!((Token::Var, 2, 1), (var.token(), var.line(), var.column()));
+    /// assert_eq!("two", &edited[scanner.scan_token().span()]);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// If the position is past the end of the source code, or is not on a character boundary.
+    pub fn resume(&mut self, position: Position) {
+        self.current = &self.source[position.offset..];
+        self.start = self.current;
+        self.line = position.line;
+        self.column = position.column;
+        self.start_column = position.column;
+    }
+
     /// Returns `true` if we've reached the end of the source code.
     pub fn is_at_end(&self) -> bool {
         self.current.is_empty()
@@ -160,11 +331,16 @@ impl<'a> Scanner<'a> {
             token: Token::Error,
             text: message,
             line: 0,
+            column: 0,
+            start: 0,
+            end: 0,
         }
     }
 
     /// Advances self.current, s.t., self.start < self.current are a reference to the same str.
-    /// Returns the next valid char.
+    /// Returns the next valid char. This is the only place that counts lines and columns, so
+    /// newlines are counted the same way in strings, comments, and whitespace. A `\r\n` is one
+    /// newline, since the `\r` is just one more character at the end of the line.
     ///
     /// # Panics
     ///
@@ -179,6 +355,13 @@ impl<'a> Scanner<'a> {
         self.current = &self.current[len..];
         assert!(self.current.len() < self.start.len());
 
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+
         c
     }
 
@@ -201,36 +384,26 @@ impl<'a> Scanner<'a> {
             return false;
         }
 
-        let next_char = self.peek();
-        if next_char != expected {
+        if self.peek() != expected {
             return false;
         }
 
-        self.current = &self.current[next_char.len_utf8()..];
+        self.advance();
         true
     }
 
-    /// Skips whitespace and comments.
+    /// Skips whitespace and line comments. Only used when we're not keeping trivia.
     fn skip_whitespace(&mut self) {
         loop {
             let c = self.peek();
             match c {
-                ' ' | '\r' | '\t' => {
-                    self.advance();
-                }
-                '\n' => {
-                    // Count the newline
-                    self.line += 1;
+                c if is_whitespace(c) => {
                     self.advance();
                 }
                 // Comments are "whitespace"
-                '/' => {
-                    if self.peek_next() == '/' {
-                        while self.peek() != '\n' && !self.is_at_end() {
-                            self.advance();
-                        }
-                    } else {
-                        return;
+                '/' if self.peek_next() == '/' => {
+                    while self.peek() != '\n' && !self.is_at_end() {
+                        self.advance();
                     }
                 }
                 _ => return,
@@ -238,6 +411,43 @@ impl<'a> Scanner<'a> {
         }
     }
 
+    /// Scan a run of whitespace. Expects the first whitespace character to have been consumed.
+    fn whitespace(&mut self) -> Lexeme<'a> {
+        while is_whitespace(self.peek()) {
+            self.advance();
+        }
+
+        self.make_lexeme(Token::Whitespace)
+    }
+
+    /// Scan a comment until the end of the line. Expects the `//` to have been consumed.
+    fn line_comment(&mut self) -> Lexeme<'a> {
+        while self.peek() != '\n' && !self.is_at_end() {
+            self.advance();
+        }
+
+        self.make_lexeme(Token::Comment)
+    }
+
+    /// Scan a block comment, which may contain nested block comments. Expects the `/*` to have
+    /// been consumed.
+    fn block_comment(&mut self) -> Lexeme<'a> {
+        let mut depth = 1;
+        while depth > 0 {
+            if self.is_at_end() {
+                return self.error_token("Unterminated comment");
+            }
+
+            match self.advance() {
+                '/' if self.match_and_advance('*') => depth += 1,
+                '*' if self.match_and_advance('/') => depth -= 1,
+                _ => (),
+            }
+        }
+
+        self.make_lexeme(Token::Comment)
+    }
+
     /// Scan an identifier or keyword.
     fn identifier(&mut self) -> Lexeme<'a> {
         while is_id_continue(self.peek()) {
@@ -250,9 +460,6 @@ impl<'a> Scanner<'a> {
     /// Scan a string literal. Expects the starting quote to have been consumed.
     fn string(&mut self) -> Lexeme<'a> {
         while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
-            }
             self.advance();
         }
 
@@ -291,7 +498,11 @@ impl<'a> Scanner<'a> {
         // index shenanigans that are pointless in Rust.
         match chars.next().unwrap_or('\0') {
             'a' => self.check_keyword("and", Token::And),
-            'c' => self.check_keyword("class", Token::Class),
+            'c' => match chars.next().unwrap_or('\0') {
+                'l' => self.check_keyword("class", Token::Class),
+                'o' => self.check_keyword("const", Token::Const),
+                _ => Token::Identifier,
+            },
             'e' => self.check_keyword("else", Token::Else),
             'f' => match chars.next().unwrap_or('\0') {
                 'a' => self.check_keyword("false", Token::False),
@@ -299,7 +510,11 @@ impl<'a> Scanner<'a> {
                 'u' => self.check_keyword("fun", Token::Fun),
                 _ => Token::Identifier,
             },
-            'i' => self.check_keyword("if", Token::If),
+            'i' => match chars.next().unwrap_or('\0') {
+                'f' => self.check_keyword("if", Token::If),
+                'm' => self.check_keyword("import", Token::Import),
+                _ => Token::Identifier,
+            },
             'n' => self.check_keyword("nil", Token::Nil),
             'o' => self.check_keyword("or", Token::Or),
             'p' => self.check_keyword("print", Token::Print),
@@ -331,10 +546,14 @@ impl<'a> Scanner<'a> {
     /// Returns an lexeme with [Token::Error] as its token.
     fn error_token(&self, message: &'a str) -> Lexeme<'a> {
         assert_ne!(self.start, self.current);
+        let (start, end) = self.span();
         Lexeme {
             token: Token::Error,
             text: message,
             line: self.line,
+            column: self.start_column,
+            start,
+            end,
         }
     }
 
@@ -344,13 +563,25 @@ impl<'a> Scanner<'a> {
         assert!(self.current.len() <= self.start.len());
         let extent = self.start.len() - self.current.len();
         let text = &self.start[..extent];
+        let (start, end) = self.span();
 
         Lexeme {
             token,
             text,
             line: self.line,
+            column: self.start_column,
+            start,
+            end,
         }
     }
+
+    /// Returns the byte offsets of self.start and self.current in the source code.
+    fn span(&self) -> (usize, usize) {
+        (
+            self.source.len() - self.start.len(),
+            self.source.len() - self.current.len(),
+        )
+    }
 }
 
 impl<'a> Iterator for Scanner<'a> {
@@ -366,8 +597,61 @@ impl<'a> Iterator for Scanner<'a> {
     }
 }
 
+/// Returns true if the source code stops in the middle of something: an unclosed `(`, `{`, or
+/// `[`, or an unterminated string or block comment. The REPL uses this to keep reading lines
+/// until the input is complete. Source code that closes more than it opens is complete, so that
+/// the compiler can report the error.
+///
+/// ```
+/// use rlox::scanner::is_incomplete;
+/// assert!(is_incomplete("fun f() {"));
+/// assert!(is_incomplete("print \"hello"));
+/// assert!(!is_incomplete("fun f() {}"));
+/// assert!(!is_incomplete("print 1);"));
+/// ```
+pub fn is_incomplete(source: &str) -> bool {
+    let mut depth = 0isize;
+    for lexeme in Scanner::new(source) {
+        match lexeme.token() {
+            Token::LeftParen | Token::LeftBrace | Token::LeftBracket => depth += 1,
+            Token::RightParen | Token::RightBrace | Token::RightBracket => depth -= 1,
+            // Only the end of the source code leaves these unterminated:
+            Token::Error => {
+                return matches!(
+                    lexeme.text(),
+                    "Unterminated string" | "Unterminated comment"
+                )
+            }
+            Token::Eof => return depth > 0,
+            _ => {}
+        }
+        if depth < 0 {
+            return false;
+        }
+    }
+    unreachable!("the scanner always ends with Eof")
+}
+
+impl Position {
+    /// Returns the byte offset in the source code.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Returns the line number.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// Returns the column, counting characters (not bytes) from 1.
+    pub fn column(&self) -> usize {
+        self.column
+    }
+}
+
 impl<'a> Lexeme<'a> {
-    /// Return the line number this token was found on.
+    /// Return the line number this token was found on. For lexemes that span multiple lines
+    /// (strings and block comments), this is the line where the lexeme ends.
     pub fn line(&self) -> usize {
         self.line
     }
@@ -378,24 +662,63 @@ impl<'a> Lexeme<'a> {
         self.text
     }
 
+    /// Return the column where this lexeme starts, counting characters (not bytes) from 1. Unlike
+    /// [Lexeme::line()], this is where the lexeme _starts_, even if it spans multiple lines.
+    ///
+    /// ```
+    /// use rlox::scanner::Scanner;
+    /// let lexemes: Vec<_> = Scanner::new("print 1;\r\n  \"🦀\" + x;").take(5).collect();
+    /// assert_eq!((1, 1), (lexemes[0].line(), lexemes[0].column()));
+    /// assert_eq!((2, 3), (lexemes[3].line(), lexemes[3].column()));
+    /// assert_eq!((2, 7), (lexemes[4].line(), lexemes[4].column()));
+    /// ```
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
     /// Return the [Token] of this lexeme.
     pub fn token(&self) -> Token {
         self.token
     }
+
+    /// Return the range of bytes in the source code that this lexeme came from.
+    ///
+    /// For [Token::Error] lexemes, this is the span of the offending source code, and **not** the
+    /// span of the error message in [Lexeme::text()].
+    ///
+    /// ```
+    /// use rlox::scanner::Scanner;
+    /// let source = "print cafe;";
+    /// let lexemes: Vec<_> = Scanner::new(source).take(3).collect();
+    /// assert_eq!(6..10, lexemes[1].span());
+    /// assert_eq!(";", &source[lexemes[2].span()]);
+    /// ```
+    pub fn span(&self) -> std::ops::Range<usize> {
+        self.start..self.end
+    }
 }
 
 ///////////////////////////////////////////// Helpers /////////////////////////////////////////////
 
+/// Returns true if this char is whitespace that separates tokens.
+fn is_whitespace(c: char) -> bool {
+    matches!(c, ' ' | '\r' | '\t' | '\n')
+}
+
 /// Returns true if this char can start an identifier or keyword.
 ///
-/// Note: this differs from Crafting Interpreters, as it uses isAlpha().
+/// Note: this differs from Crafting Interpreters, which only allows ASCII letters. Here,
+/// identifiers follow [Unicode Standard Annex #31][uax31], like Rust's, so `café` and `π` are
+/// identifiers too.
+///
+/// [uax31]: https://www.unicode.org/reports/tr31/
 fn is_id_start(c: char) -> bool {
-    c.is_ascii_alphabetic() || c == '_'
+    c == '_' || unicode_ident::is_xid_start(c)
 }
 
 /// Returns true if this char can be used after the first character of an identifier or keyword.
 fn is_id_continue(c: char) -> bool {
-    is_id_start(c) || c.is_ascii_digit()
+    unicode_ident::is_xid_continue(c)
 }
 
 ////////////////////////////////////////////// Tests //////////////////////////////////////////////
@@ -422,6 +745,7 @@ mod test {
                     return returned;
                 }
                 var varied;
+                const constant = co;
                 while (whileLoop) {
                     0;
                 }
@@ -445,6 +769,7 @@ mod test {
                         Return, Identifier, Semicolon,
                     RightBrace,
                     Var, Identifier, Semicolon,
+                    Const, Identifier, Equal, Identifier, Semicolon,
                     While, LeftParen, Identifier, RightParen, LeftBrace,
                         Number, Semicolon,
                     RightBrace,
@@ -458,4 +783,195 @@ mod test {
             .collect();
         assert_eq!(expected_tokens, actual_tokens);
     }
+
+    #[test]
+    fn import_is_a_keyword() {
+        use Token::*;
+
+        let tokens: Vec<_> = Scanner::new("import \"a.lox\"; imports im if")
+            .map(|lexeme| lexeme.token())
+            .take_while(|&token| token != Eof)
+            .collect();
+        assert_eq!(
+            vec![Import, StrLiteral, Semicolon, Identifier, Identifier, If],
+            tokens
+        );
+    }
+
+    #[test]
+    fn block_comments_can_be_nested() {
+        use Token::*;
+
+        let source = "print /* one /* two */\n still one\n */ 3;\nprint 4;";
+        let lexemes: Vec<_> = Scanner::new(source)
+            .take_while(|lexeme| lexeme.token() != Eof)
+            .map(|lexeme| (lexeme.token(), lexeme.line()))
+            .collect();
+        assert_eq!(
+            vec![
+                (Print, 1),
+                (Number, 3),
+                (Semicolon, 3),
+                (Print, 4),
+                (Number, 4),
+                (Semicolon, 4)
+            ],
+            lexemes
+        );
+
+        let comment = Scanner::with_trivia("1 /* /* */ */ 2").nth(2).unwrap();
+        assert_eq!((Comment, "/* /* */ */"), (comment.token(), comment.text()));
+    }
+
+    #[test]
+    fn trivia_covers_the_entire_source() {
+        use Token::*;
+
+        let source = "  var a = 1; // one\r\n\t/* two */\n\nprint a;\n";
+        let lexemes: Vec<_> = Scanner::with_trivia(source)
+            .take_while(|lexeme| lexeme.token() != Eof)
+            .collect();
+        let text: String = lexemes.iter().map(|lexeme| lexeme.text()).collect();
+        assert_eq!(source, text);
+
+        let trivia: Vec<_> = lexemes
+            .iter()
+            .filter(|lexeme| matches!(lexeme.token(), Comment | Whitespace))
+            .map(|lexeme| (lexeme.token(), lexeme.text(), lexeme.span()))
+            .collect();
+        assert_eq!(
+            vec![
+                (Whitespace, "  ", 0..2),
+                (Whitespace, " ", 5..6),
+                (Whitespace, " ", 7..8),
+                (Whitespace, " ", 9..10),
+                (Whitespace, " ", 12..13),
+                (Comment, "// one\r", 13..20),
+                (Whitespace, "\n\t", 20..22),
+                (Comment, "/* two */", 22..31),
+                (Whitespace, "\n\n", 31..33),
+                (Whitespace, " ", 38..39),
+                (Whitespace, "\n", 41..42),
+            ],
+            trivia
+        );
+
+        let without_trivia: Vec<_> = lexemes
+            .iter()
+            .filter(|lexeme| !matches!(lexeme.token(), Comment | Whitespace))
+            .map(|lexeme| (lexeme.token(), lexeme.line(), lexeme.column()))
+            .collect();
+        let by_default: Vec<_> = Scanner::new(source)
+            .take_while(|lexeme| lexeme.token() != Eof)
+            .map(|lexeme| (lexeme.token(), lexeme.line(), lexeme.column()))
+            .collect();
+        assert_eq!(by_default, without_trivia);
+    }
+
+    #[test]
+    fn unterminated_block_comments_are_errors() {
+        let source = "print 1;\n/* /* */\nprint 2;";
+        let error = Scanner::new(source).nth(3).unwrap();
+        assert_eq!(Token::Error, error.token());
+        assert_eq!("Unterminated comment", error.text());
+        assert_eq!(3, error.line());
+        assert_eq!(9..source.len(), error.span());
+    }
+
+    #[test]
+    fn incomplete_source_code() {
+        assert!(is_incomplete("{ print 1;"));
+        assert!(is_incomplete("{\n  fun f(a,\n"));
+        assert!(is_incomplete("var a = [1,"));
+        assert!(is_incomplete("print 1; /* and"));
+        assert!(is_incomplete("print \"{\";\nprint \""));
+
+        assert!(!is_incomplete(""));
+        assert!(!is_incomplete("print \"{\";"));
+        assert!(!is_incomplete("print 1; // {"));
+        assert!(!is_incomplete("} {"));
+        // Other errors are for the compiler to report:
+        assert!(!is_incomplete("print @ {"));
+    }
+
+    #[test]
+    fn resuming_from_any_position() {
+        let source = "fun f(a) {\r\n  /* 🦀 */ print \"a\nb\" + a;\n}\nf(1);";
+        // Each lexeme, and the position after it:
+        let scan = |mut scanner: Scanner| {
+            std::iter::from_fn(move || {
+                let lexeme = scanner.scan_token();
+                (lexeme.token() != Token::Eof).then(|| {
+                    let lexeme = (
+                        lexeme.token(),
+                        lexeme.span(),
+                        lexeme.line(),
+                        lexeme.column(),
+                    );
+                    (lexeme, scanner.position())
+                })
+            })
+            .collect::<Vec<_>>()
+        };
+        let everything = scan(Scanner::with_trivia(source));
+
+        for (i, &(_, position)) in everything.iter().enumerate() {
+            let mut scanner = Scanner::with_trivia(source);
+            scanner.resume(position);
+            assert_eq!(everything[i + 1..], scan(scanner));
+        }
+    }
+
+    #[test]
+    fn crlf_is_one_newline() {
+        let source = "var s = \"a\r\nb\";\r\n/* c\r\n */ print\r\n\r\n  s;";
+        let positions: Vec<_> = Scanner::new(source)
+            .take_while(|lexeme| lexeme.token() != Token::Eof)
+            .map(|lexeme| (lexeme.text(), lexeme.line(), lexeme.column()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("var", 1, 1),
+                ("s", 1, 5),
+                ("=", 1, 7),
+                ("\"a\r\nb\"", 2, 9),
+                (";", 2, 3),
+                ("print", 4, 5),
+                ("s", 6, 3),
+                (";", 6, 4),
+            ],
+            positions
+        );
+    }
+
+    #[test]
+    fn identifiers_can_be_written_in_any_script() {
+        use Token::*;
+
+        let source = "var café = 1; print πr2 + Ελληνικά + 名前 + имя_2 + forêt + 🦀;";
+        let lexemes: Vec<_> = Scanner::new(source)
+            .take_while(|lexeme| lexeme.token() != Eof)
+            .map(|lexeme| (lexeme.token(), lexeme.text()))
+            .filter(|&(token, _)| token == Identifier || token == Error)
+            .collect();
+        assert_eq!(
+            vec![
+                (Identifier, "café"),
+                (Identifier, "πr2"),
+                (Identifier, "Ελληνικά"),
+                (Identifier, "名前"),
+                (Identifier, "имя_2"),
+                // Not the keyword "for":
+                (Identifier, "forêt"),
+                // Emoji are not letters:
+                (Error, "Unexpected character"),
+            ],
+            lexemes
+        );
+
+        // Spans are still in bytes:
+        let cafe = Scanner::new(source).nth(1).unwrap();
+        assert_eq!(4..9, cafe.span());
+        assert_eq!(5, cafe.column());
+    }
 }
@@ -0,0 +1,407 @@
+//! The standard library: native functions that are generally useful to Lox programs.
+//!
+//! Install them on a VM with [install()]:
+//!
+//! ```
+//! # use rlox::prelude::*;
+//! let mut vm = VM::default();
+//! rlox::stdlib::install(&mut vm);
+//! vm.interpret("print sqrt(16) + len(\"abc\");").unwrap(); // prints 7
+//! ```
+//!
+//! | Function                   | Returns                                                                |
+//! |----------------------------|------------------------------------------------------------------------|
+//! | `readLine()`               | the next line of `stdin`, or `nil` at the end of input (requires `io`) |
+//! | `readFile(path)`           | the contents of the file at `path`, as a string (requires `io`)        |
+//! | `writeFile(path, s)`       | `nil`, after replacing the contents of the file at `path` with `s`     |
+//! | `sqrt(n)`                  | the square root of `n`                                                 |
+//! | `floor(n)`                 | the largest integer less than or equal to `n`                          |
+//! | `abs(n)`                   | the absolute value of `n`                                              |
+//! | `random()`                 | a random number between 0 (inclusive) and 1 (exclusive)                |
+//! | `str(value)`               | the value, converted to a string, just like `print` would write it     |
+//! | `num(string)`              | the string, converted to a number, or `nil` if it is not a number      |
+//! | `len(value)`               | the number of characters in a string, or items in a list or map        |
+//! | `substr(s, start, length)` | the `length` characters of `s`, starting at index `start`              |
+//! | `upper(s)`                 | `s` in uppercase                                                       |
+//! | `lower(s)`                 | `s` in lowercase                                                       |
+//! | `indexOf(s, t)`            | the index of the first `t` in `s`, or `nil` if `s` does not contain it |
+//! | `assert(condition, m)`     | `nil`, or a runtime error with the message `m` if `condition` is falsy |
+//! | `assertEqual(a, b)`        | `nil`, or a runtime error describing both values if `a != b`           |
+//!
+//! Strings are indexed by character (not by byte), starting at 0.
+//!
+//! Natives that interact with the outside world are only available with the `io` Cargo feature
+//! (enabled by default), so that sandboxed hosts can leave them out. `readLine()`, `readFile()`,
+//! and `writeFile()` also fail unless the VM allows I/O (see
+//! [VM::set_allow_io()](crate::vm::VM::set_allow_io())), so embedders can forbid them at runtime.
+
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+use crate::value::Value;
+use crate::vm::VM;
+
+/// Defines every function in the standard library as a global in the VM.
+pub fn install(vm: &mut VM) {
+    #[cfg(feature = "io")]
+    {
+        let allowed = vm.io_capability();
+        vm.define_native(
+            "readLine",
+            0,
+            io::guard("readLine", &allowed, io::read_line),
+        );
+        vm.define_native(
+            "readFile",
+            1,
+            io::guard("readFile", &allowed, io::read_file),
+        );
+        vm.define_native(
+            "writeFile",
+            2,
+            io::guard("writeFile", &allowed, io::write_file),
+        );
+    }
+
+    vm.define_native("sqrt", 1, |args| Ok(number("sqrt", args[0])?.sqrt().into()));
+    vm.define_native("floor", 1, |args| {
+        Ok(number("floor", args[0])?.floor().into())
+    });
+    vm.define_native("abs", 1, |args| Ok(number("abs", args[0])?.abs().into()));
+    vm.define_native("random", 0, random());
+    vm.define_native("str", 1, str_native);
+    vm.define_native("num", 1, num_native);
+    vm.define_native("len", 1, len_native);
+    vm.define_native("substr", 3, substr_native);
+    vm.define_native("upper", 1, |args| {
+        Ok(string("upper", args[0])?.to_uppercase().into())
+    });
+    vm.define_native("lower", 1, |args| {
+        Ok(string("lower", args[0])?.to_lowercase().into())
+    });
+    vm.define_native("indexOf", 2, index_of_native);
+    vm.define_native("assert", 2, assert_native);
+    vm.define_native("assertEqual", 2, assert_equal_native);
+}
+
+/// `str(value)`: converts any value to a string.
+fn str_native(args: &[Value]) -> Result<Value, String> {
+    match args[0] {
+        string @ (Value::LoxString(_) | Value::Rope(_)) => Ok(string),
+        value => Ok(value.to_string().into()),
+    }
+}
+
+/// `num(string)`: parses a number. Leading and trailing whitespace is ignored.
+///
+/// Rust parses strings like `"inf"` and `"NaN"` as numbers, but Lox code cannot write such
+/// numbers, so they (and numbers too large to represent) are not numbers to `num()` either.
+fn num_native(args: &[Value]) -> Result<Value, String> {
+    let string = string("num", args[0])?;
+    let number = string.trim().parse::<f64>().ok();
+    Ok(number.filter(|n| n.is_finite()).into())
+}
+
+/// `len(value)`: returns the number of characters in a string, or items in a list or map.
+fn len_native(args: &[Value]) -> Result<Value, String> {
+    let len = match args[0] {
+        Value::LoxString(_) | Value::Rope(_) => string("len", args[0])?.chars().count(),
+        Value::List(list) => list.len(),
+        Value::Map(map) => map.len(),
+        _ => return Err("len() expects a string, list, or map".to_owned()),
+    };
+    Ok((len as f64).into())
+}
+
+/// `substr(string, start, length)`: returns part of a string.
+fn substr_native(args: &[Value]) -> Result<Value, String> {
+    let string = string("substr", args[0])?;
+    let start = index("substr", args[1])?;
+    let length = index("substr", args[2])?;
+
+    let n_chars = string.chars().count();
+    if start > n_chars || length > n_chars - start {
+        return Err(format!(
+            "substr() range {start}..{} is out of bounds for string of length {n_chars}",
+            start.saturating_add(length)
+        ));
+    }
+    Ok(string
+        .chars()
+        .skip(start)
+        .take(length)
+        .collect::<String>()
+        .into())
+}
+
+/// `indexOf(string, substring)`: returns the index of the first occurrence of the substring.
+fn index_of_native(args: &[Value]) -> Result<Value, String> {
+    let haystack = string("indexOf", args[0])?;
+    let needle = string("indexOf", args[1])?;
+
+    // Convert the byte offset into a character index:
+    let index = haystack
+        .find(needle)
+        .map(|offset| haystack[..offset].chars().count() as f64);
+    Ok(index.into())
+}
+
+/// `assert(condition, message)`: fails with the message unless the condition is truthy.
+fn assert_native(args: &[Value]) -> Result<Value, String> {
+    match args[0].is_falsy() {
+        true => Err(format!("Assertion failed: {}", args[1])),
+        false => Ok(Value::Nil),
+    }
+}
+
+/// `assertEqual(a, b)`: fails unless `a == b`.
+fn assert_equal_native(args: &[Value]) -> Result<Value, String> {
+    let (a, b) = (args[0], args[1]);
+    if a.equal(&b) {
+        return Ok(Value::Nil);
+    }
+
+    let (a_text, b_text) = (describe(a), describe(b));
+    let mut message = format!("Assertion failed: {a_text} is not equal to {b_text}");
+    if a_text == b_text {
+        // e.g., two lists with the same items, which are still two different lists:
+        message.push_str(" (they are different objects)");
+    }
+    Err(message)
+}
+
+/// Writes the value like Lox code would, so that e.g., `"1"` and `1` can be told apart.
+fn describe(value: Value) -> String {
+    match value.to_str() {
+        Some(string) => format!("{string:?}"),
+        None => value.to_string(),
+    }
+}
+
+/// Returns the `random()` native. It uses a [xorshift] generator, seeded differently each time.
+///
+/// [xorshift]: https://en.wikipedia.org/wiki/Xorshift
+fn random() -> impl Fn(&[Value]) -> Result<Value, String> {
+    // RandomState is seeded randomly by the standard library, so use it as a seed.
+    // The seed must not be zero:
+    let seed = RandomState::new().build_hasher().finish() | 1;
+    let state = Cell::new(seed);
+
+    move |_args| {
+        let mut x = state.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        state.set(x);
+
+        // Use the upper 53 bits, which is all that fits in the mantissa of an f64:
+        Ok(((x >> 11) as f64 / (1u64 << 53) as f64).into())
+    }
+}
+
+/// Returns the argument as a number, or an error message for the native called `name`.
+fn number(name: &str, value: Value) -> Result<f64, String> {
+    value
+        .to_f64()
+        .ok_or_else(|| format!("{name}() expects a number"))
+}
+
+/// Returns the argument as an index (a non-negative integer), or an error message for the native
+/// called `name`.
+fn index(name: &str, value: Value) -> Result<usize, String> {
+    match value.to_f64() {
+        Some(n) if n >= 0.0 && n.fract() == 0.0 => Ok(n as usize),
+        _ => Err(format!("{name}() expects a non-negative integer")),
+    }
+}
+
+/// Returns the argument as a string, or an error message for the native called `name`.
+fn string(name: &str, value: Value) -> Result<&'static str, String> {
+    value
+        .to_str()
+        .ok_or_else(|| format!("{name}() expects a string"))
+}
+
+/// Natives that interact with the outside world.
+#[cfg(feature = "io")]
+mod io {
+    use std::cell::Cell;
+    use std::fs;
+    use std::io::BufRead;
+    use std::rc::Rc;
+
+    use crate::value::Value;
+
+    /// Returns a native that calls the given one, but only while the VM allows I/O.
+    pub(super) fn guard(
+        name: &'static str,
+        allowed: &Rc<Cell<bool>>,
+        native: fn(&[Value]) -> Result<Value, String>,
+    ) -> impl Fn(&[Value]) -> Result<Value, String> + 'static {
+        let allowed = Rc::clone(allowed);
+        move |args| match allowed.get() {
+            true => native(args),
+            false => Err(format!("{name}() is not allowed: this VM forbids I/O")),
+        }
+    }
+
+    /// `readLine()`: reads one line from `stdin`, without the line ending.
+    pub(super) fn read_line(_args: &[Value]) -> Result<Value, String> {
+        let mut line = String::new();
+        match std::io::stdin().lock().read_line(&mut line) {
+            Ok(0) => Ok(Value::Nil),
+            Ok(_) => {
+                let trimmed = line.trim_end_matches(['\n', '\r']).len();
+                line.truncate(trimmed);
+                Ok(line.into())
+            }
+            Err(error) => Err(format!("readLine() failed: {error}")),
+        }
+    }
+
+    /// `readFile(path)`: reads a whole file, which must be UTF-8.
+    pub(super) fn read_file(args: &[Value]) -> Result<Value, String> {
+        let path = super::string("readFile", args[0])?;
+        match fs::read_to_string(path) {
+            Ok(contents) => Ok(contents.into()),
+            Err(error) => Err(format!("readFile() failed to read {path}: {error}")),
+        }
+    }
+
+    /// `writeFile(path, contents)`: creates or replaces a file.
+    pub(super) fn write_file(args: &[Value]) -> Result<Value, String> {
+        let path = super::string("writeFile", args[0])?;
+        let contents = super::string("writeFile", args[1])?;
+        match fs::write(path, contents) {
+            Ok(()) => Ok(Value::Nil),
+            Err(error) => Err(format!("writeFile() failed to write {path}: {error}")),
+        }
+    }
+}
+
+////////////////////////////////////////////// Tests //////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gc::ActiveGC;
+    use crate::value::List;
+    use serial_test::serial;
+
+    #[test]
+    fn math() {
+        let sqrt = |n: f64| number("sqrt", n.into()).map(f64::sqrt);
+        assert_eq!(Ok(3.0), sqrt(9.0));
+        assert_eq!(
+            Err("floor() expects a number".to_owned()),
+            number("floor", Value::Nil)
+        );
+    }
+
+    #[test]
+    fn random_numbers_are_between_zero_and_one() {
+        let random = random();
+        for _ in 0..1000 {
+            match random(&[]) {
+                Ok(Value::Number(n)) => assert!((0.0..1.0).contains(&n)),
+                other => panic!("unexpected result: {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn strings() {
+        let _gc = ActiveGC::install();
+
+        let s = str_native(&[2.5.into()]).unwrap();
+        assert_eq!(Some("2.5"), s.to_str());
+        assert_eq!(Some("nil"), str_native(&[Value::Nil]).unwrap().to_str());
+
+        assert_eq!(Value::Number(42.0), num_native(&[" 42 ".into()]).unwrap());
+        assert_eq!(Value::Nil, num_native(&["forty-two".into()]).unwrap());
+        assert_eq!(Value::Number(-0.5), num_native(&["-5e-1".into()]).unwrap());
+        for not_a_number in ["inf", "NaN", "-infinity", "1e999", ""] {
+            assert_eq!(Value::Nil, num_native(&[not_a_number.into()]).unwrap());
+        }
+        assert!(num_native(&[42.0.into()]).is_err());
+
+        assert_eq!(Value::Number(4.0), len_native(&["café".into()]).unwrap());
+        assert!(len_native(&[Value::Nil]).is_err());
+
+        let s = substr_native(&["café au lait".into(), 2.0.into(), 5.0.into()]).unwrap();
+        assert_eq!(Some("fé au"), s.to_str());
+        let s = substr_native(&["café".into(), 4.0.into(), 0.0.into()]).unwrap();
+        assert_eq!(Some(""), s.to_str());
+        assert_eq!(
+            Err("substr() range 2..5 is out of bounds for string of length 4".to_owned()),
+            substr_native(&["café".into(), 2.0.into(), 3.0.into()])
+        );
+        assert!(substr_native(&["café".into(), (-1.0).into(), 1.0.into()]).is_err());
+        assert!(substr_native(&["café".into(), 5.0.into(), 0.0.into()]).is_err());
+        assert!(substr_native(&["abc".into(), 1.0.into(), 1e20.into()]).is_err());
+        assert!(substr_native(&["café".into(), 0.5.into(), 1.0.into()]).is_err());
+
+        let find = |haystack: &str, needle: &str| {
+            index_of_native(&[haystack.into(), needle.into()]).unwrap()
+        };
+        assert_eq!(Value::Number(3.0), find("café au lait", "é"));
+        assert_eq!(Value::Number(5.0), find("café au lait", "au"));
+        assert_eq!(Value::Nil, find("café", "tea"));
+        assert!(index_of_native(&["café".into(), Value::Nil]).is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn assertions() {
+        let _gc = ActiveGC::install();
+
+        assert_eq!(Ok(Value::Nil), assert_native(&[0.0.into(), "zero".into()]));
+        assert_eq!(
+            Err("Assertion failed: not nil".to_owned()),
+            assert_native(&[Value::Nil, "not nil".into()])
+        );
+
+        assert_eq!(
+            Ok(Value::Nil),
+            assert_equal_native(&["a".into(), "a".into()])
+        );
+        assert_eq!(
+            Err("Assertion failed: \"1\" is not equal to 1".to_owned()),
+            assert_equal_native(&["1".into(), 1.0.into()])
+        );
+        let list = || Value::List(ActiveGC::store_list(List::new(vec![1.0.into()])));
+        assert_eq!(
+            Err(
+                "Assertion failed: [1] is not equal to [1] (they are different objects)".to_owned()
+            ),
+            assert_equal_native(&[list(), list()])
+        );
+    }
+
+    #[test]
+    #[serial]
+    #[cfg(feature = "io")]
+    fn files_can_be_written_and_read_unless_io_is_forbidden() {
+        let path = std::env::temp_dir().join(format!("rlox-stdlib-{}.txt", std::process::id()));
+        let path = path.to_str().unwrap().to_owned();
+        let mut vm = VM::default();
+        install(&mut vm);
+        vm.set_stderr(Box::new(std::io::sink()));
+        vm.set_global("path", path.as_str());
+
+        vm.interpret("writeFile(path, \"café\"); var contents = readFile(path);")
+            .unwrap();
+        assert_eq!(Ok("café"), vm.get_global("contents").unwrap().get::<&str>());
+        assert!(vm.interpret("readFile(path + \".missing\");").is_err());
+        assert!(vm.interpret("writeFile(path, 42);").is_err());
+
+        vm.set_allow_io(false);
+        assert!(!vm.allows_io());
+        assert!(vm.interpret("writeFile(path, \"nope\");").is_err());
+        assert!(vm.interpret("readFile(path);").is_err());
+        assert_eq!("café", std::fs::read_to_string(&path).unwrap());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,386 @@
+//! Runs Lox test scripts that are annotated with expectation comments, in the same style as the
+//! [Crafting Interpreters test suite][suite].
+//!
+//! Each test is a `.lox` file. Comments in the file describe how it should behave:
+//!
+//! ```text
+//! print 1 + 2; // expect: 3
+//! print nil +; // Error at ';': Expect expression.
+//! // [line 5] Error at end: Expect '}' after block.
+//! print -"a"; // expect runtime error: Operand must be a number
+//! ```
+//!
+//!  - `// expect: <output>` — the program prints this line to `stdout`.
+//!  - `// Error...` — compiling reports this error on the line of the comment.
+//!  - `// [line N] Error...` — compiling reports this error on line N.
+//!  - `// expect runtime error: <message>` — the program stops with this runtime error on the line
+//!    of the comment.
+//!
+//! Files containing `// nontest` are skipped.
+//!
+//! A test without any expectations passes if it runs without printing anything, or failing. Such
+//! tests can check their results with the `assert()` and `assertEqual()` natives in the
+//! [standard library](crate::stdlib) instead. By convention, their names end in `_test.lox`, and
+//! `rlox test` without any paths runs every such file under the current directory (see
+//! [find_test_files()]).
+//!
+//! Tests are run in a separate process, using the interpreter executable that is given, so that
+//! `stdout`, `stderr`, and the exit status can be checked.
+//!
+//! [suite]: https://github.com/munificent/craftinginterpreters/tree/master/test
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The exit status after a compile error.
+const COMPILE_ERROR_STATUS: i32 = 65;
+/// The exit status after a runtime error.
+const RUNTIME_ERROR_STATUS: i32 = 70;
+
+/// What a test script expects to happen when it is run.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Expectations {
+    /// Lines printed to `stdout`, in order.
+    pub output: Vec<String>,
+    /// Compile errors, formatted as `[line N] Error...`, in order.
+    pub compile_errors: Vec<String>,
+    /// The runtime error message, and the line where it happens.
+    pub runtime_error: Option<(usize, String)>,
+}
+
+/// What actually happened when a test script was run.
+#[derive(Debug, Default, Clone)]
+pub struct Outcome {
+    /// Everything written to `stdout`.
+    pub stdout: String,
+    /// Everything written to `stderr`.
+    pub stderr: String,
+    /// The exit status, if the process exited normally.
+    pub status: Option<i32>,
+}
+
+/// The result of running one test script.
+#[derive(Debug, Clone)]
+pub struct TestResult {
+    /// The test script.
+    pub path: PathBuf,
+    /// Why the test failed. Empty if the test passed.
+    pub failures: Vec<String>,
+}
+
+impl Expectations {
+    /// Parses the expectation comments in the source code of a test.
+    ///
+    /// Returns `None` if the file is not a test (it contains `// nontest`).
+    ///
+    /// ```
+    /// use rlox::test_runner::Expectations;
+    /// let source = "print 1; // expect: 1\nprint; // Error at ';': Oops";
+    /// let expectations = Expectations::parse(source).unwrap();
+    /// assert_eq!(vec!["1"], expectations.output);
+    /// assert_eq!(vec!["[line 2] Error at ';': Oops"], expectations.compile_errors);
+    /// ```
+    pub fn parse(source: &str) -> Option<Expectations> {
+        let mut expectations = Expectations::default();
+
+        // Like the official test runner, look for each kind of comment anywhere in the line, so
+        // that a `//` in a string (e.g., a URL) does not hide the comment that follows it.
+        for (i, line) in source.lines().enumerate() {
+            let line_number = i + 1;
+
+            if line.contains("// nontest") {
+                return None;
+            } else if let Some(output) = after(line, "// expect:") {
+                let output = output.strip_prefix(' ').unwrap_or(output);
+                expectations.output.push(output.to_owned());
+            } else if let Some(message) = after(line, "// expect runtime error: ") {
+                expectations.runtime_error = Some((line_number, message.to_owned()));
+            } else if let Some(error) = after(line, "// Error") {
+                let error = format!("[line {line_number}] Error{error}");
+                expectations.compile_errors.push(error);
+            } else if let Some((line, error)) = after(line, "// [").and_then(parse_error_line) {
+                expectations
+                    .compile_errors
+                    .push(format!("[line {line}] {error}"));
+            }
+        }
+
+        Some(expectations)
+    }
+
+    /// Compares what actually happened with what was expected. Returns a list of failures, which
+    /// is empty if everything went as expected.
+    pub fn check(&self, outcome: &Outcome) -> Vec<String> {
+        let mut failures = Vec::new();
+
+        let expected_status = if !self.compile_errors.is_empty() {
+            COMPILE_ERROR_STATUS
+        } else if self.runtime_error.is_some() {
+            RUNTIME_ERROR_STATUS
+        } else {
+            0
+        };
+
+        if let Some((line, message)) = &self.runtime_error {
+            self.check_runtime_error(*line, message, &outcome.stderr, &mut failures);
+        } else {
+            self.check_compile_errors(&outcome.stderr, &mut failures);
+        }
+
+        if outcome.status != Some(expected_status) {
+            let actual = outcome
+                .status
+                .map(|status| status.to_string())
+                .unwrap_or_else(|| "none".to_owned());
+            failures.push(format!(
+                "Expected exit status {expected_status}, but got {actual}."
+            ));
+        }
+
+        let actual_output: Vec<_> = outcome.stdout.lines().collect();
+        for (i, expected) in self.output.iter().enumerate() {
+            match actual_output.get(i) {
+                Some(actual) if actual == expected => (),
+                Some(actual) => failures.push(format!(
+                    "Expected output '{expected}' on line {}, but got '{actual}'.",
+                    i + 1
+                )),
+                None => failures.push(format!("Missing expected output '{expected}'.")),
+            }
+        }
+        for extra in actual_output.iter().skip(self.output.len()) {
+            failures.push(format!("Got unexpected output '{extra}'."));
+        }
+
+        failures
+    }
+
+    /// Checks `stderr` after a runtime error.
+    fn check_runtime_error(
+        &self,
+        line: usize,
+        message: &str,
+        stderr: &str,
+        failures: &mut Vec<String>,
+    ) {
+        let mut lines = stderr.lines();
+        match lines.next() {
+            Some(actual) if actual == message => (),
+            Some(actual) => failures.push(format!(
+                "Expected runtime error '{message}', but got '{actual}'."
+            )),
+            None => {
+                failures.push(format!("Expected runtime error '{message}', but got none."));
+                return;
+            }
+        }
+
+        let trace = format!("[line {line}]");
+        if !lines.any(|actual| actual.starts_with(&trace)) {
+            failures.push(format!("Expected stack trace with '{trace}'."));
+        }
+    }
+
+    /// Checks that `stderr` has exactly the expected compile errors.
+    fn check_compile_errors(&self, stderr: &str, failures: &mut Vec<String>) {
+        let actual: Vec<_> = stderr.lines().filter(|line| !line.is_empty()).collect();
+
+        for expected in self.compile_errors.iter() {
+            if !actual.contains(&expected.as_str()) {
+                failures.push(format!("Missing expected error: {expected}"));
+            }
+        }
+
+        for line in actual {
+            if !self.compile_errors.iter().any(|expected| expected == line) {
+                failures.push(format!("Unexpected output on stderr: {line}"));
+            }
+        }
+    }
+}
+
+/// Runs one test script with the given interpreter executable.
+///
+/// # Errors
+///
+/// Returns an error if the test script could not be read, or if the interpreter could not be
+/// started. Returns `Ok(None)` if the file is not a test.
+pub fn run_test(interpreter: &Path, path: &Path) -> io::Result<Option<TestResult>> {
+    run_test_with_options(interpreter, &[], path)
+}
+
+/// Like [run_test()], but the interpreter is given the options (e.g., `--registers`) before the
+/// test script.
+pub fn run_test_with_options(
+    interpreter: &Path,
+    options: &[&str],
+    path: &Path,
+) -> io::Result<Option<TestResult>> {
+    let source = fs::read_to_string(path)?;
+    let expectations = match Expectations::parse(&source) {
+        Some(expectations) => expectations,
+        None => return Ok(None),
+    };
+
+    let output = Command::new(interpreter).args(options).arg(path).output()?;
+    let outcome = Outcome {
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        status: output.status.code(),
+    };
+
+    Ok(Some(TestResult {
+        path: path.to_owned(),
+        failures: expectations.check(&outcome),
+    }))
+}
+
+/// Finds every `.lox` file under the given path (or just the path, if it's a file), sorted by
+/// name.
+pub fn find_tests(path: &Path) -> io::Result<Vec<PathBuf>> {
+    if path.is_file() {
+        return Ok(vec![path.to_owned()]);
+    }
+
+    find_files(path, &|name| name.ends_with(".lox"))
+}
+
+/// Finds every `*_test.lox` file under the given directory, sorted by name.
+pub fn find_test_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    find_files(dir, &|name| name.ends_with("_test.lox"))
+}
+
+/// Finds every file under the directory whose name matches, sorted by path.
+fn find_files(dir: &Path, matches: &dyn Fn(&str) -> bool) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.extend(find_files(&path, matches)?);
+        } else if path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(matches)
+        {
+            files.push(path);
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+impl TestResult {
+    /// Returns true if there were no failures.
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Returns the rest of the line after the first occurrence of `prefix`.
+fn after<'a>(line: &'a str, prefix: &str) -> Option<&'a str> {
+    line.find(prefix).map(|start| &line[start + prefix.len()..])
+}
+
+/// Parses comments like `[line 3] Error at 'x': message` or `[c line 3] Error...`, after the
+/// opening `[`. Returns the line number and the error.
+fn parse_error_line(comment: &str) -> Option<(usize, &str)> {
+    let rest = comment.strip_prefix("c ").unwrap_or(comment);
+    let rest = rest.strip_prefix("line ")?;
+    let (line, error) = rest.split_once("] ")?;
+
+    if !error.starts_with("Error") {
+        return None;
+    }
+
+    line.parse().ok().map(|line| (line, error))
+}
+
+////////////////////////////////////////////// Tests //////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parsing_expectations() {
+        let source = "\
+print 1; // expect: 1
+print \"\"; // expect:
+// [line 5] Error at end: Expect '}'.
+// [java line 5] Error: ignored.
+{
+print -nil; // expect runtime error: Operand must be a number.
+print \"http://x\"; // expect: http://x
+";
+        let expectations = Expectations::parse(source).unwrap();
+        assert_eq!(vec!["1", "", "http://x"], expectations.output);
+        assert_eq!(
+            vec!["[line 5] Error at end: Expect '}'."],
+            expectations.compile_errors
+        );
+        assert_eq!(
+            Some((6, "Operand must be a number.".to_owned())),
+            expectations.runtime_error
+        );
+    }
+
+    #[test]
+    fn nontest() {
+        assert_eq!(None, Expectations::parse("// nontest\nprint 1;"));
+    }
+
+    #[test]
+    fn checking_output() {
+        let expectations = Expectations::parse("print 1; // expect: 1").unwrap();
+
+        let passing = Outcome {
+            stdout: "1\n".to_owned(),
+            stderr: "".to_owned(),
+            status: Some(0),
+        };
+        assert!(expectations.check(&passing).is_empty());
+
+        let failing = Outcome {
+            stdout: "2\n3\n".to_owned(),
+            ..passing
+        };
+        assert_eq!(2, expectations.check(&failing).len());
+    }
+
+    #[test]
+    fn checking_runtime_errors() {
+        let expectations = Expectations::parse("\n-nil; // expect runtime error: Oops").unwrap();
+        let outcome = Outcome {
+            stdout: "".to_owned(),
+            stderr: "Oops\n[line 2] in script\n".to_owned(),
+            status: Some(RUNTIME_ERROR_STATUS),
+        };
+        assert!(expectations.check(&outcome).is_empty());
+
+        let wrong_line = Outcome {
+            stderr: "Oops\n[line 1] in script\n".to_owned(),
+            ..outcome
+        };
+        assert_eq!(1, expectations.check(&wrong_line).len());
+    }
+
+    #[test]
+    fn checking_compile_errors() {
+        let expectations = Expectations::parse("print; // Error at ';': Oops").unwrap();
+        let outcome = Outcome {
+            stdout: "".to_owned(),
+            stderr: "[line 1] Error at ';': Oops\n".to_owned(),
+            status: Some(COMPILE_ERROR_STATUS),
+        };
+        assert!(expectations.check(&outcome).is_empty());
+
+        let extra_error = Outcome {
+            stderr: "[line 1] Error at ';': Oops\n[line 2] Error: Another\n".to_owned(),
+            ..outcome
+        };
+        assert_eq!(1, expectations.check(&extra_error).len());
+    }
+}
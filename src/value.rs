@@ -1,5 +1,15 @@
 //! Representation of values in Lox.
 
+use std::cell::{OnceCell, RefCell};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::ptr;
+
+use thiserror::Error;
+
+use crate::chunk::Chunk;
 use crate::gc::ActiveGC;
 
 extern crate static_assertions as sa;
@@ -63,10 +73,115 @@ pub enum Value {
     Boolean(bool),
     /// All numbers in Lox are 64-bit floating point.
     Number(f64),
+    /// Integers, written without a decimal point (requires the `integers` feature). Adding,
+    /// subtracting, multiplying, or dividing two integers gives an integer (division truncates); if
+    /// either operand is a float, the integer is converted to a float first.
+    #[cfg(feature = "integers")]
+    Integer(i64),
     /// Strings (the owned contents belong to the [ActiveGC])
     LoxString(&'static str),
+    /// A string made by concatenating two strings, which are only copied once its contents are
+    /// needed (owned by the [ActiveGC]). Lox code cannot tell a rope from any other string.
+    Rope(&'static Rope),
+    /// A function implemented in Rust. The number identifies the function within its
+    /// [VM](crate::vm::VM).
+    NativeFunction(usize),
+    /// A function written in Lox (owned by the [ActiveGC]).
+    Function(&'static Function),
+    /// A class (owned by the [ActiveGC]).
+    Class(&'static Class),
+    /// An instance of a class (owned by the [ActiveGC]).
+    Instance(&'static Instance),
+    /// A list of values (owned by the [ActiveGC]).
+    List(&'static List),
+    /// A map from keys to values (owned by the [ActiveGC]).
+    Map(&'static Map),
+}
+
+/// A function written in Lox, compiled to its own [Chunk].
+pub struct Function {
+    /// The name of the function.
+    pub name: &'static str,
+    /// How many parameters the function takes.
+    pub arity: u8,
+    /// The function's bytecode.
+    pub chunk: Chunk,
+}
+
+/// A class. Calling it creates a new [Instance].
+pub struct Class {
+    /// The name of the class.
+    pub name: &'static str,
+}
+
+/// An instance of a [Class], which has its own fields.
+pub struct Instance {
+    /// The class that this is an instance of.
+    pub class: &'static Class,
+    /// The fields of this instance. Fields can be added and changed at any time, even though the
+    /// instance is shared, hence the [RefCell].
+    pub fields: RefCell<HashMap<&'static str, Value>>,
+}
+
+/// A string made by concatenating two strings (or ropes), without copying them.
+///
+/// `+` makes a rope when its result is long, so that building a string out of many pieces, one at
+/// a time, takes linear time, instead of copying everything built so far on every `+`. The rope
+/// is flattened into one contiguous string the first time its contents are needed (e.g., to
+/// compare it), and then keeps that string. Writing a rope with [Display](std::fmt::Display) or
+/// [Debug] does not flatten it.
+///
+/// ```
+/// # use rlox::gc::ActiveGC;
+/// # use rlox::value::{Rope, Value};
+/// let _gc = ActiveGC::install();
+/// let rope = Rope::new("Hello, ".into(), "world!".into());
+/// assert_eq!(13, rope.len());
+/// assert_eq!("\"Hello, world!\"", format!("{rope:?}"));
+/// assert!(!rope.is_flat());
+/// assert_eq!("Hello, world!", rope.as_str());
+/// assert!(rope.is_flat());
+/// ```
+pub struct Rope {
+    /// The strings that are concatenated. They are not needed (or marked) once flattened.
+    left: Value,
+    right: Value,
+    /// The length of the string, in bytes.
+    len: usize,
+    /// The contents of the string, once flattened.
+    flat: OnceCell<String>,
+}
+
+/// A list, created with a literal like `[1, 2, 3]`. Its items can be changed with `list[i] = x`,
+/// even though the list is shared, hence the [RefCell].
+#[derive(Default)]
+pub struct List {
+    /// The items in the list.
+    pub items: RefCell<Vec<Value>>,
+}
+
+/// A map, created with a literal like `{"a": 1, "b": 2}`. Like a [List], its entries can be
+/// changed with `map[key] = x`.
+#[derive(Default)]
+pub struct Map {
+    /// The entries in the map.
+    pub entries: RefCell<HashMap<MapKey, Value>>,
 }
 
+/// A [Value] that can be used as a key in a [Map].
+///
+/// Keys follow Lox's rules for equality (see [Value::equal()]): strings, numbers, booleans, and
+/// nil are compared by value, and everything else by identity. NaN is never equal to itself, so
+/// it cannot be a key.
+///
+/// ```
+/// # use rlox::value::{MapKey, Value};
+/// assert_eq!(MapKey::new(Value::Number(0.0)), MapKey::new(Value::Number(-0.0)));
+/// assert_eq!(None, MapKey::new(Value::Number(f64::NAN)));
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct MapKey(Value);
+
 /// A collection of values. Useful for a constant pool.
 #[derive(Default, Debug, Clone)]
 pub struct ValueArray {
@@ -74,6 +189,78 @@ pub struct ValueArray {
     values: Vec<Value>,
 }
 
+/// Converts a Lox value to a Rust type, like [TryFrom<Value>], but also for types that cannot
+/// implement [TryFrom<Value>] themselves, like `Option<T>`, where `nil` is `None`. Native
+/// functions defined with [VM::define_typed_native()](crate::vm::VM::define_typed_native()) use
+/// this to convert their arguments.
+///
+/// ```
+/// # use rlox::value::{FromLox, Value};
+/// assert_eq!(Ok(0.5), f64::from_lox(Value::Number(0.5)));
+/// assert_eq!(Ok(None), Option::<bool>::from_lox(Value::Nil));
+/// let error = bool::from_lox(Value::Number(1.0)).unwrap_err();
+/// assert_eq!("expected a boolean, but got a number", error.to_string());
+/// ```
+pub trait FromLox: Sized {
+    /// Converts the value, or returns an error if it has the wrong type.
+    fn from_lox(value: Value) -> Result<Self, ConversionError>;
+}
+
+/// Converts a Rust value to a Lox value. This is implemented for every type that converts
+/// [Into<Value>]. Native functions defined with
+/// [VM::define_typed_native()](crate::vm::VM::define_typed_native()) use this to convert their
+/// return values.
+pub trait IntoLox {
+    /// Converts the Rust value to a Lox value.
+    fn into_lox(self) -> Value;
+}
+
+/// The error when a Lox value does not have the type that a Rust type requires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error("expected {expected}, but got {found}")]
+pub struct ConversionError {
+    /// The type that was required, e.g., `"a number"`.
+    pub expected: &'static str,
+    /// The type of the value, from [Value::type_name()].
+    pub found: &'static str,
+}
+
+/// A [Value] borrowed from wherever it is stored, e.g., by
+/// [VM::get_global()](crate::vm::VM::get_global()). While it is borrowed, the VM cannot run code
+/// or collect garbage, so the objects it refers to stay alive. Unlike a [Value], it cannot be
+/// copied out of the borrow: convert it to a Rust type with [ValueRef::get()] instead.
+///
+/// ```compile_fail
+/// # use rlox::prelude::*;
+/// let mut vm = VM::default();
+/// vm.interpret("var list = [1, 2];").unwrap();
+/// let list = vm.get_global("list").unwrap();
+/// vm.interpret("list = nil;").unwrap(); // error: vm is still borrowed by list
+/// println!("{list}");
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ValueRef<'a> {
+    value: Value,
+    _borrow: PhantomData<&'a ()>,
+}
+
+/// Converts a [ValueRef] to a Rust type, with [ValueRef::get()]. Unlike [FromLox], the result
+/// cannot outlive the borrow: it is either owned, like [f64] or [String], or a `&str` that is
+/// borrowed for as long as the [ValueRef] is.
+///
+/// ```compile_fail
+/// # use rlox::prelude::*;
+/// let mut vm = VM::default();
+/// vm.interpret("var name = \"Ada\";").unwrap();
+/// let name: &str = vm.get_global("name").unwrap().get().unwrap();
+/// vm.interpret("name = nil;").unwrap(); // error: vm is still borrowed by name
+/// println!("{name}");
+/// ```
+pub trait FromValueRef<'a>: Sized {
+    /// Converts the value, or returns an error if it has the wrong type.
+    fn from_value_ref(value: ValueRef<'a>) -> Result<Self, ConversionError>;
+}
+
 ///////////////////////////////////////// Implementation //////////////////////////////////////////
 
 impl Value {
@@ -87,19 +274,37 @@ impl Value {
         matches!(self, Value::Nil)
     }
 
-    /// Returns true if this value is a Lox object.
+    /// Returns true if this value is a Lox object, i.e., it is owned by the [ActiveGC].
+    ///
+    /// ```
+    /// # use rlox::gc::ActiveGC;
+    /// # use rlox::value::Value;
+    /// let _gc = ActiveGC::install();
+    /// assert!(Value::from("hello").is_obj());
+    /// assert!(!Value::Number(42.0).is_obj());
+    /// assert!(!Value::Nil.is_obj());
+    /// ```
     pub fn is_obj(&self) -> bool {
-        unimplemented!("object types don't exist yet");
+        matches!(
+            self,
+            Value::LoxString(_)
+                | Value::Rope(_)
+                | Value::Function(_)
+                | Value::Class(_)
+                | Value::Instance(_)
+                | Value::List(_)
+                | Value::Map(_)
+        )
     }
 
-    /// Returns true if this value is a Lox number.
+    /// Returns true if this value is a Lox number (including an integer).
     pub fn is_number(&self) -> bool {
-        matches!(self, Value::Number(_))
+        self.to_f64().is_some()
     }
 
-    /// Returns true if this value is a Lox string.
+    /// Returns true if this value is a Lox string (including a [Rope]).
     pub fn is_string(&self) -> bool {
-        matches!(self, Value::LoxString(_))
+        matches!(self, Value::LoxString(_) | Value::Rope(_))
     }
 
     /// Returns true if this value is "falsy".
@@ -107,10 +312,39 @@ impl Value {
         matches!(self, Value::Nil | Value::Boolean(false))
     }
 
-    /// Returns a reference to the string contents, if this value is a Lox string.
+    /// Describes the type of this value, for error messages, e.g., `"a number"`.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Boolean(_) => "a boolean",
+            Value::Number(_) => "a number",
+            #[cfg(feature = "integers")]
+            Value::Integer(_) => "an integer",
+            Value::LoxString(_) | Value::Rope(_) => "a string",
+            Value::NativeFunction(_) | Value::Function(_) => "a function",
+            Value::Class(_) => "a class",
+            Value::Instance(_) => "an instance",
+            Value::List(_) => "a list",
+            Value::Map(_) => "a map",
+        }
+    }
+
+    /// Returns the number, if this value is a Lox number. An integer is converted to a float.
+    pub fn to_f64(&self) -> Option<f64> {
+        match *self {
+            Value::Number(number) => Some(number),
+            #[cfg(feature = "integers")]
+            Value::Integer(integer) => Some(integer as f64),
+            _ => None,
+        }
+    }
+
+    /// Returns a reference to the string contents, if this value is a Lox string. A [Rope] is
+    /// flattened.
     pub fn to_str(&self) -> Option<&'static str> {
         match self {
             Value::LoxString(string) => Some(string),
+            Value::Rope(rope) => Some(rope.as_str()),
             _ => None,
         }
     }
@@ -121,9 +355,20 @@ impl Value {
         use Value::*;
         match (self, other) {
             (Number(a), Number(b)) => a == b,
+            #[cfg(feature = "integers")]
+            (Integer(a), Integer(b)) => a == b,
+            #[cfg(feature = "integers")]
+            (Integer(a), Number(b)) | (Number(b), Integer(a)) => *a as f64 == *b,
             (Boolean(a), Boolean(b)) => a == b,
             (Nil, Nil) => true,
             (LoxString(a), LoxString(b)) => a == b,
+            (LoxString(_) | Rope(_), LoxString(_) | Rope(_)) => self.to_str() == other.to_str(),
+            (NativeFunction(a), NativeFunction(b)) => a == b,
+            (Function(a), Function(b)) => a == b,
+            (Class(a), Class(b)) => a == b,
+            (Instance(a), Instance(b)) => a == b,
+            (List(a), List(b)) => a == b,
+            (Map(a), Map(b)) => a == b,
             _ => false,
         }
     }
@@ -134,8 +379,335 @@ impl std::fmt::Display for Value {
         match self {
             Value::Nil => write!(f, "nil"),
             Value::Number(num) => write!(f, "{num}"),
+            #[cfg(feature = "integers")]
+            Value::Integer(integer) => write!(f, "{integer}"),
             Value::Boolean(value) => write!(f, "{value}"),
             Value::LoxString(string) => write!(f, "{string}"),
+            Value::Rope(rope) => write!(f, "{rope}"),
+            Value::NativeFunction(_) => write!(f, "<native fn>"),
+            Value::Function(function) => write!(f, "{function}"),
+            Value::Class(class) => write!(f, "{}", class.name),
+            Value::Instance(instance) => write!(f, "{} instance", instance.class.name),
+            Value::List(list) => write!(f, "{list}"),
+            Value::Map(map) => write!(f, "{map}"),
+        }
+    }
+}
+
+impl std::fmt::Display for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "<fn {}>", self.name)
+    }
+}
+
+// Don't print the entire chunk when debugging values:
+impl std::fmt::Debug for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+// Functions are only equal to themselves.
+impl PartialEq for Function {
+    fn eq(&self, other: &Function) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl Rope {
+    /// Concatenates two strings (or ropes).
+    ///
+    /// # Panics
+    ///
+    /// Panics if either value is not a string.
+    pub fn new(left: Value, right: Value) -> Self {
+        Rope {
+            left,
+            right,
+            len: string_len(left) + string_len(right),
+            flat: OnceCell::new(),
+        }
+    }
+
+    /// Returns the length of the string, in bytes, without flattening it.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if the string is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns true once the rope has been flattened.
+    pub fn is_flat(&self) -> bool {
+        self.flat.get().is_some()
+    }
+
+    /// Returns the contents of the string, flattening the rope if it has not been flattened yet.
+    pub fn as_str(&self) -> &str {
+        self.flat.get_or_init(|| self.flatten())
+    }
+
+    /// Returns the strings that are concatenated, until the rope is flattened. The GC must keep
+    /// these alive.
+    pub(crate) fn pieces(&self) -> Option<[Value; 2]> {
+        (!self.is_flat()).then_some([self.left, self.right])
+    }
+
+    /// Copies every piece into one string.
+    fn flatten(&self) -> String {
+        let mut flat = String::with_capacity(self.len);
+        write!(flat, "{self}").expect("writing to a String cannot fail");
+        flat
+    }
+
+    /// Calls `write` with each piece of the string, in order, without flattening the rope. Ropes
+    /// can be nested very deeply (e.g., by adding to a string in a loop), so this does not
+    /// recurse.
+    fn write_pieces(&self, mut write: impl FnMut(&str) -> std::fmt::Result) -> std::fmt::Result {
+        if let Some(string) = self.flat.get() {
+            return write(string);
+        }
+
+        let mut pending = vec![self.right, self.left];
+        while let Some(piece) = pending.pop() {
+            match piece {
+                Value::LoxString(string) => write(string)?,
+                Value::Rope(rope) => match rope.flat.get() {
+                    Some(string) => write(string)?,
+                    None => pending.extend([rope.right, rope.left]),
+                },
+                _ => unreachable!("ropes only contain strings"),
+            }
+        }
+        Ok(())
+    }
+}
+
+// Printing a rope (e.g., when tracing execution) must not flatten it:
+impl std::fmt::Display for Rope {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.write_pieces(|piece| f.write_str(piece))
+    }
+}
+
+/// Returns the length of a string (or rope), in bytes.
+fn string_len(value: Value) -> usize {
+    match value {
+        Value::LoxString(string) => string.len(),
+        Value::Rope(rope) => rope.len(),
+        _ => panic!("ropes can only contain strings, not {}", value.type_name()),
+    }
+}
+
+// Ropes are strings, so debug them like strings (quoted and escaped, like str's Debug):
+impl std::fmt::Debug for Rope {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_char('"')?;
+        self.write_pieces(|piece| {
+            piece.chars().try_for_each(|c| match c {
+                '\'' => f.write_char(c),
+                _ => write!(f, "{}", c.escape_debug()),
+            })
+        })?;
+        f.write_char('"')
+    }
+}
+
+// Like other objects, ropes are only (Rust) equal to themselves. Lox compares their contents (see
+// Value::equal()).
+impl PartialEq for Rope {
+    fn eq(&self, other: &Rope) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl Instance {
+    /// Creates a new instance of the class, with no fields.
+    pub fn new(class: &'static Class) -> Self {
+        Instance {
+            class,
+            fields: RefCell::default(),
+        }
+    }
+}
+
+impl List {
+    /// Creates a new list with the given items.
+    pub fn new(items: Vec<Value>) -> Self {
+        List {
+            items: RefCell::new(items),
+        }
+    }
+
+    /// Returns how many items are in the list.
+    pub fn len(&self) -> usize {
+        self.items.borrow().len()
+    }
+
+    /// Returns true if the list has no items.
+    pub fn is_empty(&self) -> bool {
+        self.items.borrow().is_empty()
+    }
+}
+
+// Classes, instances, lists, and maps are only equal to themselves.
+impl PartialEq for Class {
+    fn eq(&self, other: &Class) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl PartialEq for Instance {
+    fn eq(&self, other: &Instance) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+// Don't print every field (which may contain this instance!) when debugging values:
+impl std::fmt::Debug for Class {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "<class {}>", self.name)
+    }
+}
+
+impl std::fmt::Debug for Instance {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "<{} instance>", self.class.name)
+    }
+}
+
+impl PartialEq for List {
+    fn eq(&self, other: &List) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl std::fmt::Display for List {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // A list can contain itself, so don't print its items while it's already being printed.
+        let Ok(items) = self.items.try_borrow_mut() else {
+            return write!(f, "[...]");
+        };
+
+        write!(f, "[")?;
+        for (i, item) in items.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{item}")?;
+        }
+        write!(f, "]")
+    }
+}
+
+// Like instances, lists may contain themselves, so only print the items when displaying them:
+impl std::fmt::Debug for List {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "<list of {}>", self.len())
+    }
+}
+
+impl Map {
+    /// Returns how many entries are in the map.
+    pub fn len(&self) -> usize {
+        self.entries.borrow().len()
+    }
+
+    /// Returns true if the map has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.borrow().is_empty()
+    }
+}
+
+impl PartialEq for Map {
+    fn eq(&self, other: &Map) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl std::fmt::Display for Map {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // Like lists, maps can contain themselves:
+        let Ok(entries) = self.entries.try_borrow_mut() else {
+            return write!(f, "{{...}}");
+        };
+
+        // HashMap's order is arbitrary, so sort the entries to print them consistently:
+        let mut entries: Vec<_> = (entries.iter())
+            .map(|(key, value)| (key.0.to_string(), value.to_string()))
+            .collect();
+        entries.sort();
+
+        write!(f, "{{")?;
+        for (i, (key, value)) in entries.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{key}: {value}")?;
+        }
+        write!(f, "}}")
+    }
+}
+
+impl std::fmt::Debug for Map {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "<map of {}>", self.len())
+    }
+}
+
+impl MapKey {
+    /// Returns the key for the value, or `None` if the value cannot be a key.
+    pub fn new(value: Value) -> Option<MapKey> {
+        match value {
+            Value::Number(number) if number.is_nan() => None,
+            _ => Some(MapKey(value)),
+        }
+    }
+
+    /// Returns the value that this key was made from.
+    pub fn value(self) -> Value {
+        self.0
+    }
+}
+
+impl PartialEq for MapKey {
+    fn eq(&self, other: &MapKey) -> bool {
+        self.0.equal(&other.0)
+    }
+}
+
+impl Eq for MapKey {}
+
+impl Hash for MapKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // A rope is equal to a string with the same contents, so they must hash the same:
+        if let Some(string) = self.0.to_str() {
+            return string.hash(state);
+        }
+        // An integer is equal to the float that it is converted to, so they must hash the same:
+        #[cfg(feature = "integers")]
+        if let Value::Integer(integer) = self.0 {
+            return MapKey(Value::Number(integer as f64)).hash(state);
+        }
+
+        std::mem::discriminant(&self.0).hash(state);
+        match self.0 {
+            Value::Nil => {}
+            Value::Boolean(boolean) => boolean.hash(state),
+            // 0.0 == -0.0, so they must hash the same:
+            Value::Number(0.0) => 0.0_f64.to_bits().hash(state),
+            Value::Number(number) => number.to_bits().hash(state),
+            #[cfg(feature = "integers")]
+            Value::Integer(_) => unreachable!("integers were hashed already"),
+            Value::LoxString(_) | Value::Rope(_) => unreachable!("strings were hashed already"),
+            Value::NativeFunction(id) => id.hash(state),
+            Value::Function(function) => ptr::hash(function, state),
+            Value::Class(class) => ptr::hash(class, state),
+            Value::Instance(instance) => ptr::hash(instance, state),
+            Value::List(list) => ptr::hash(list, state),
+            Value::Map(map) => ptr::hash(map, state),
         }
     }
 }
@@ -148,6 +720,24 @@ impl From<f64> for Value {
     }
 }
 
+// Convert any Rust integer into a Lox integer.
+#[cfg(feature = "integers")]
+impl From<i64> for Value {
+    #[inline(always)]
+    fn from(integer: i64) -> Value {
+        Value::Integer(integer)
+    }
+}
+
+// Without integers, convert any Rust integer into a Lox number.
+#[cfg(not(feature = "integers"))]
+impl From<i64> for Value {
+    #[inline(always)]
+    fn from(integer: i64) -> Value {
+        Value::Number(integer as f64)
+    }
+}
+
 // Convert any Rust float into a Lox value.
 impl From<bool> for Value {
     #[inline(always)]
@@ -183,6 +773,183 @@ where
     }
 }
 
+// Convert a Lox number (or integer) to a Rust float.
+impl TryFrom<Value> for f64 {
+    type Error = ConversionError;
+
+    fn try_from(value: Value) -> Result<f64, ConversionError> {
+        value
+            .to_f64()
+            .ok_or_else(|| ConversionError::new("a number", value))
+    }
+}
+
+// Convert a Lox integer to a Rust integer. Floats are not converted, even if they are whole.
+#[cfg(feature = "integers")]
+impl TryFrom<Value> for i64 {
+    type Error = ConversionError;
+
+    fn try_from(value: Value) -> Result<i64, ConversionError> {
+        match value {
+            Value::Integer(integer) => Ok(integer),
+            _ => Err(ConversionError::new("an integer", value)),
+        }
+    }
+}
+
+// Convert a Lox boolean to a Rust bool. Only booleans are converted, not every truthy value.
+impl TryFrom<Value> for bool {
+    type Error = ConversionError;
+
+    fn try_from(value: Value) -> Result<bool, ConversionError> {
+        match value {
+            Value::Boolean(boolean) => Ok(boolean),
+            _ => Err(ConversionError::new("a boolean", value)),
+        }
+    }
+}
+
+// Copy a Lox string's contents to a Rust (owned) string.
+impl TryFrom<Value> for String {
+    type Error = ConversionError;
+
+    fn try_from(value: Value) -> Result<String, ConversionError> {
+        match value.to_str() {
+            Some(string) => Ok(string.to_owned()),
+            None => Err(ConversionError::new("a string", value)),
+        }
+    }
+}
+
+impl FromLox for Value {
+    fn from_lox(value: Value) -> Result<Value, ConversionError> {
+        Ok(value)
+    }
+}
+
+impl FromLox for f64 {
+    fn from_lox(value: Value) -> Result<f64, ConversionError> {
+        value.try_into()
+    }
+}
+
+#[cfg(feature = "integers")]
+impl FromLox for i64 {
+    fn from_lox(value: Value) -> Result<i64, ConversionError> {
+        value.try_into()
+    }
+}
+
+impl FromLox for bool {
+    fn from_lox(value: Value) -> Result<bool, ConversionError> {
+        value.try_into()
+    }
+}
+
+impl FromLox for String {
+    fn from_lox(value: Value) -> Result<String, ConversionError> {
+        value.try_into()
+    }
+}
+
+impl<T: FromLox> FromLox for Option<T> {
+    fn from_lox(value: Value) -> Result<Option<T>, ConversionError> {
+        match value {
+            Value::Nil => Ok(None),
+            _ => T::from_lox(value).map(Some),
+        }
+    }
+}
+
+/// Implements [FromValueRef] for types that do not borrow from the value, using [FromLox].
+macro_rules! impl_from_value_ref {
+    ($($type:ty),*) => {
+        $(
+            impl FromValueRef<'_> for $type {
+                fn from_value_ref(value: ValueRef<'_>) -> Result<$type, ConversionError> {
+                    <$type>::from_lox(value.value)
+                }
+            }
+        )*
+    };
+}
+
+impl_from_value_ref!(f64, bool, String);
+#[cfg(feature = "integers")]
+impl_from_value_ref!(i64);
+
+impl<'a> FromValueRef<'a> for &'a str {
+    fn from_value_ref(value: ValueRef<'a>) -> Result<&'a str, ConversionError> {
+        (value.value.to_str()).ok_or_else(|| ConversionError::new("a string", value.value))
+    }
+}
+
+impl<'a, T: FromValueRef<'a>> FromValueRef<'a> for Option<T> {
+    fn from_value_ref(value: ValueRef<'a>) -> Result<Option<T>, ConversionError> {
+        match value.value {
+            Value::Nil => Ok(None),
+            _ => T::from_value_ref(value).map(Some),
+        }
+    }
+}
+
+impl<T: Into<Value>> IntoLox for T {
+    fn into_lox(self) -> Value {
+        self.into()
+    }
+}
+
+impl<'a> ValueRef<'a> {
+    /// Borrows the value. It is up to the caller to choose a lifetime during which the value's
+    /// objects cannot be freed.
+    pub(crate) fn new(value: Value) -> ValueRef<'a> {
+        ValueRef {
+            value,
+            _borrow: PhantomData,
+        }
+    }
+
+    /// Returns the value itself, for use within the crate, where it cannot escape.
+    pub(crate) fn value(self) -> Value {
+        self.value
+    }
+
+    /// Converts the value to a Rust type. See [FromValueRef].
+    ///
+    /// ```
+    /// # use rlox::prelude::*;
+    /// let mut vm = VM::default();
+    /// vm.interpret("var name = \"Ada\"; var age = 36;").unwrap();
+    /// assert_eq!(Ok("Ada"), vm.get_global("name").unwrap().get::<&str>());
+    /// assert_eq!(Ok(36.0), vm.get_global("age").unwrap().get::<f64>());
+    /// assert!(vm.get_global("age").unwrap().get::<String>().is_err());
+    /// ```
+    pub fn get<T: FromValueRef<'a>>(&self) -> Result<T, ConversionError> {
+        T::from_value_ref(*self)
+    }
+
+    /// Returns true if the value is a native function, i.e., one implemented in Rust.
+    pub fn is_native(&self) -> bool {
+        matches!(self.value, Value::NativeFunction(_))
+    }
+}
+
+impl std::fmt::Display for ValueRef<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.value.fmt(f)
+    }
+}
+
+impl ConversionError {
+    /// Returns the error for converting the value to the expected type.
+    fn new(expected: &'static str, value: Value) -> Self {
+        ConversionError {
+            expected,
+            found: value.type_name(),
+        }
+    }
+}
+
 impl ValueArray {
     /// Return an empty [ValueArray].
     pub fn new() -> Self {
@@ -210,4 +977,9 @@ impl ValueArray {
     pub fn is_empty(&self) -> bool {
         self.values.is_empty()
     }
+
+    /// Returns an iterator over every value in the array.
+    pub fn iter(&self) -> impl Iterator<Item = Value> + '_ {
+        self.values.iter().copied()
+    }
 }
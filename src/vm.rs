@@ -1,273 +1,4368 @@
 //! The bytecode virtual machine.
 
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::chunk::BytecodeEntry;
-use crate::compiler;
-use crate::gc::ActiveGC;
+use crate::chunk::ChunkBuilder;
+use crate::compiler::{self, CompileOptions};
+use crate::error::{BacktraceFrame, Diagnostic, RuntimeErrorInfo};
+use crate::gc::{ActiveGC, GcConfig, GcStats, WeakHandle, GC};
+use crate::globals::Globals;
+use crate::lint;
+use crate::modules::{FileResolver, ModuleResolver};
 use crate::prelude::{Chunk, InterpretationError, OpCode, Value};
+use crate::rvm;
+use crate::scanner::{Scanner, Token};
+use crate::value::{
+    self, ConversionError, FromLox, Function, Instance, IntoLox, List, Map, MapKey, Rope, ValueRef,
+};
 
 /// Used as the minimum capacity of the stack.
 /// Since we're using a growable [Vec], the stack size can be arbitrarily large.
 const STACK_SIZE: usize = 256;
 
+/// What the value stack holds. With the `nan_boxing` feature, values are packed into 8 bytes.
+#[cfg(feature = "nan_boxing")]
+type StackValue = crate::nan_boxing::PackedValue;
+#[cfg(not(feature = "nan_boxing"))]
+type StackValue = Value;
+
 /// Maintains state for the Lox virtual machine.
-#[derive(Default)]
 pub struct VM {
-    // In order to match the interface in Crafting Interpreters, I created this struct.
-    // However, it's inconvenient in Rust because of chunk possibly being None; however, we know
-    // that there's a state in which the VM MUST have a chunk, which is why VmWithChunk exists.
+    /// Statistics from the most recent run.
+    statistics: Statistics,
+    /// Functions implemented in Rust, which are also defined as globals.
+    natives: Vec<Native>,
+    /// The value stack, which is kept between runs. See [VM::stack_snapshot()].
+    stack: Vec<StackValue>,
+    /// The global variables, which persist between calls to [VM::interpret()].
+    globals: Globals,
+    /// The GC, which owns the strings referred to by the globals. It is only installed as the
+    /// [ActiveGC] while the VM is running code (and is `None` during that time).
+    gc: Option<Box<GC>>,
+    /// The constants of every chunk returned by [VM::compile()]. They are never collected, since
+    /// the chunk may be run at any time.
+    pinned: Vec<Value>,
+    /// Where `print` statements write to.
+    stdout: Box<dyn Write>,
+    /// Where compile errors and runtime errors are written to.
+    stderr: Box<dyn Write>,
+    /// Where the stack and each instruction are written to as they execute, if anywhere.
+    trace: Option<Box<dyn Write>>,
+    /// How source code is compiled.
+    compile_options: CompileOptions,
+    /// How big the stacks may grow.
+    limits: Limits,
+    /// How many more instructions may be executed, if limited. See [VM::set_fuel()].
+    fuel: Option<u64>,
+    /// Set to stop the code that is running. See [VM::interrupt_handle()].
+    interrupt: Arc<AtomicBool>,
+    /// Counts of the instructions executed, if profiling. See [VM::enable_profiling()].
+    profile: Option<Profile>,
+    /// Every module that has been imported, by its canonical name. Modules are never collected,
+    /// so that importing one again can be detected.
+    modules: HashMap<String, &'static Function>,
+    /// Finds the modules named by `import` statements. See [VM::set_module_resolver()].
+    module_resolver: Box<dyn ModuleResolver>,
+    /// Whether natives may read and write files and `stdin`. Shared with the natives that do.
+    /// See [VM::set_allow_io()].
+    allow_io: Rc<Cell<bool>>,
+    /// Set by the `exit()` native to the status that the script asked to exit with.
+    exit_request: Rc<Cell<Option<i32>>>,
+    /// See [VM::set_implicit_string_conversion()].
+    implicit_string_conversion: bool,
+    /// Whether garbage is collected while code runs. See [VM::enable_garbage_collection()].
+    garbage_collection: bool,
 }
 
-/// A VM with an active chunk
-struct VmWithChunk<'a> {
-    /// Instruction pointer --- index into the chunk for the next opcode to be executed
-    // TODO: convert to slice?
-    ip: usize,
+/// The Rust signature of a native function. It is given the arguments, and returns either the
+/// return value, or an error message, which will become a runtime error.
+///
+/// Natives may keep their arguments, but a VM that does so must not collect garbage (see
+/// [VM::enable_garbage_collection()]), since the values would not be kept alive.
+pub type NativeFn = dyn Fn(&[Value]) -> Result<Value, String>;
+
+/// The Rust signature of a native function that can also see the code that called it. See
+/// [VM::define_native_with_state()].
+pub type NativeWithStateFn = dyn Fn(&VmState<'_>, &[Value]) -> Result<Value, String>;
+
+/// A Rust function whose arguments and return value are converted to and from Lox values. See
+/// [VM::define_typed_native()]. `Args` is a tuple of the function's argument types.
+pub trait IntoNative<Args> {
+    /// How many arguments the function takes.
+    fn arity(&self) -> u8;
+
+    /// Wraps the function so that it can be called from Lox, as the native called `name`.
+    fn into_native(self, name: &str) -> Box<NativeFn>;
+}
+
+/// What a function given to [VM::define_typed_native()] can return: anything that converts to a
+/// Lox value, or a [Result] of one, whose error becomes a runtime error.
+pub trait NativeResult {
+    /// Converts to the result of a [NativeFn].
+    fn into_native_result(self) -> Result<Value, String>;
+}
+
+impl<T: IntoLox> NativeResult for T {
+    fn into_native_result(self) -> Result<Value, String> {
+        Ok(self.into_lox())
+    }
+}
+
+impl<T: IntoLox, E: std::fmt::Display> NativeResult for Result<T, E> {
+    fn into_native_result(self) -> Result<Value, String> {
+        self.map(IntoLox::into_lox)
+            .map_err(|error| error.to_string())
+    }
+}
+
+/// Implements [IntoNative] for functions that take the given argument types.
+macro_rules! impl_into_native {
+    ($($arg:ident),*) => {
+        impl<F, R, $($arg),*> IntoNative<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> R + 'static,
+            R: NativeResult,
+            $($arg: FromLox,)*
+        {
+            fn arity(&self) -> u8 {
+                <[&str]>::len(&[$(stringify!($arg)),*]) as u8
+            }
+
+            #[allow(non_snake_case, unused_variables, unused_mut)]
+            fn into_native(self, name: &str) -> Box<NativeFn> {
+                let name = name.to_owned();
+                Box::new(move |args| {
+                    // The VM checks the arity before calling the native:
+                    let &[$($arg),*] = args else {
+                        return Err(format!("{name}() called with the wrong number of arguments"));
+                    };
+                    let convert = |error: ConversionError| format!("{name}(): {error}");
+                    self($($arg::from_lox($arg).map_err(convert)?),*).into_native_result()
+                })
+            }
+        }
+    };
+}
+
+impl_into_native!();
+impl_into_native!(A);
+impl_into_native!(A, B);
+impl_into_native!(A, B, C);
+impl_into_native!(A, B, C, D);
+impl_into_native!(A, B, C, D, E);
+impl_into_native!(A, B, C, D, E, G);
+
+/// The arguments given to [VM::call()]: a tuple of anything that converts to a Lox value, like
+/// `(3.0, "three")`, or an array or [Vec] of values of the same type.
+pub trait IntoArgs {
+    /// Converts each argument to a Lox value.
+    fn into_args(self) -> Vec<Value>;
+}
+
+impl<T: IntoLox, const N: usize> IntoArgs for [T; N] {
+    fn into_args(self) -> Vec<Value> {
+        self.into_iter().map(IntoLox::into_lox).collect()
+    }
+}
+
+impl<T: IntoLox> IntoArgs for Vec<T> {
+    fn into_args(self) -> Vec<Value> {
+        self.into_iter().map(IntoLox::into_lox).collect()
+    }
+}
+
+/// Implements [IntoArgs] for tuples of the given types.
+macro_rules! impl_into_args {
+    ($($arg:ident),*) => {
+        impl<$($arg: IntoLox),*> IntoArgs for ($($arg,)*) {
+            #[allow(non_snake_case)]
+            fn into_args(self) -> Vec<Value> {
+                let ($($arg,)*) = self;
+                vec![$($arg.into_lox()),*]
+            }
+        }
+    };
+}
+
+impl_into_args!();
+impl_into_args!(A);
+impl_into_args!(A, B);
+impl_into_args!(A, B, C);
+impl_into_args!(A, B, C, D);
+impl_into_args!(A, B, C, D, E);
+impl_into_args!(A, B, C, D, E, G);
+
+/// A function implemented in Rust that can be called from Lox.
+struct Native {
+    name: String,
+    arity: u8,
+    function: Box<NativeWithStateFn>,
+}
+
+/// What a native function can see of the VM that is running the code that called it: the value
+/// stack and the calls in progress, as they were just before the call. See
+/// [VM::define_native_with_state()].
+pub struct VmState<'a> {
+    /// The stack, without the native or its arguments.
+    stack: &'a [StackValue],
+    frames: &'a [CallFrame<'a>],
+}
+
+impl VmState<'_> {
+    /// Returns how many values are on the value stack.
+    pub fn stack_depth(&self) -> usize {
+        self.stack.len()
+    }
+
+    /// Returns how many calls are in progress, including the top-level script (but not the
+    /// native itself).
+    pub fn call_depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Returns the contents of the value stack, from the bottom to the top.
+    pub fn stack_snapshot(&self) -> Vec<ValueRef<'_>> {
+        self.stack
+            .iter()
+            .map(|&value| ValueRef::new(unpack(value)))
+            .collect()
+    }
+
+    /// Returns the calls in progress, innermost first. The line of the innermost call is the line
+    /// that calls the native.
+    pub fn backtrace(&self) -> Vec<BacktraceFrame> {
+        (self.frames.iter().rev())
+            .map(|frame| BacktraceFrame {
+                function: frame.function.map(|function| function.name.to_owned()),
+                line: frame.current_line(),
+            })
+            .collect()
+    }
+}
+
+/// Statistics about one run of the VM. See [VM::statistics()].
+#[derive(Clone, Debug, Default)]
+pub struct Statistics {
+    /// How many instructions were executed.
+    pub instructions: u64,
+    /// How many objects were allocated by the GC.
+    pub allocations: usize,
+    /// How many times the GC collected garbage.
+    pub collections: usize,
+    /// Total time spent collecting garbage.
+    pub gc_time: std::time::Duration,
+}
+
+/// The default maximum depth of the call stack.
+const FRAMES_MAX: usize = 64;
+/// The default maximum size of the value stack: enough for every frame to have 256 values.
+const STACK_MAX: usize = FRAMES_MAX * 256;
+/// Concatenating strings makes a [Rope] once the result is at least this many bytes long. Shorter
+/// strings are cheap to copy, and faster to compare and hash once they are interned.
+const MIN_ROPE_LEN: usize = 64;
+/// The runtime error for integer arithmetic whose result does not fit in an integer.
+const INTEGER_OVERFLOW: &str = "Integer overflow.";
+
+/// Limits on how big a running VM's stacks may grow, so that runaway recursion is a clean
+/// `Stack overflow.` runtime error, instead of running out of memory. See [VM::set_limits()].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    /// The maximum number of calls in progress, including the top-level script.
+    pub max_frames: usize,
+    /// The maximum number of values on the value stack. This is checked whenever a function is
+    /// called, and whenever a loop repeats, so the stack can only go over by a few values.
+    pub max_stack: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_frames: FRAMES_MAX,
+            max_stack: STACK_MAX,
+        }
+    }
+}
+
+/// A VM that is running code, with a stack of call frames.
+struct RunningVM<'a> {
+    /// One frame for the script, and one for every function call in progress.
+    frames: Vec<CallFrame<'a>>,
+    /// How many instructions have been executed so far.
+    instructions: u64,
+    /// Execution pauses once this many instructions have been executed in total.
+    pause_at: u64,
     /// Value stack -- modified as elements are pushed and popped from the stack.
-    stack: Vec<Value>,
-    chunk: &'a Chunk,
+    stack: Vec<StackValue>,
     /// The globals in this program.
-    globals: HashMap<&'a str, Value>,
-    /// We don't access the GC directly, but we need it to live as long as the VM.
-    _active_gc: &'a ActiveGC,
+    globals: &'a mut Globals,
+    /// Values that must never be collected.
+    pinned: &'a [Value],
+    /// Native functions that can be called.
+    natives: &'a [Native],
+    /// Where `print` statements write to.
+    stdout: &'a mut dyn Write,
+    /// Where runtime errors are written to.
+    stderr: &'a mut dyn Write,
+    /// Where execution is traced to, if anywhere.
+    trace: Option<&'a mut dyn Write>,
+    /// How big the stacks may grow.
+    limits: Limits,
+    /// Set to stop the code that is running.
+    interrupt: &'a AtomicBool,
+    /// Set when a native asks to stop the code that is running, with an exit status.
+    exit_request: &'a Cell<Option<i32>>,
+    /// Where executed instructions are counted, if profiling.
+    profile: Option<&'a mut Profile>,
+    /// Every module that has been imported, by its canonical name.
+    modules: &'a mut HashMap<String, &'static Function>,
+    /// Finds imported modules.
+    module_resolver: &'a dyn ModuleResolver,
+    /// How imported modules are compiled.
+    compile_options: CompileOptions,
+    /// Whether `+` converts a number to a string when it is added to a string.
+    implicit_string_conversion: bool,
+    /// Whether garbage is collected between instructions.
+    garbage_collection: bool,
+    /// The GC, which must live as long as the VM. It's needed to compile imported modules.
+    active_gc: &'a ActiveGC,
 }
 
-/// Gets the value of the current instruction pointer. To be used in conjunction with
-/// [next_bytecode].
-macro_rules! current_ip {
-    ($self: ident) => {
-        $self.ip - 1
-    };
+/// An ongoing call to a function (or the top-level script).
+struct CallFrame<'a> {
+    /// The function being called, or `None` for the top-level script.
+    function: Option<&'static Function>,
+    /// The chunk being executed.
+    chunk: &'a Chunk,
+    /// The chunk's bytecode, cached so that fetching a byte is just indexing a slice.
+    code: &'a [u8],
+    /// The chunk's operand stream (see [Chunk::operands()]), cached like the bytecode.
+    #[cfg(feature = "split_operands")]
+    operands: &'a [u16],
+    /// Instruction pointer --- index into the chunk for the next opcode to be executed
+    ip: usize,
+    /// Index of this frame's first slot in the value stack. Local variables are relative to this.
+    slots: usize,
+}
+
+impl Default for VM {
+    /// Creates a VM with the [natives](crate::natives) that every Lox program can use, like
+    /// `clock()` and `args()`.
+    fn default() -> Self {
+        let mut vm = VM::without_natives();
+        crate::natives::install(&mut vm);
+        vm
+    }
 }
 
 impl VM {
-    /// Interpret some the Lox bytecode in the given [Chunk].
-    pub fn interpret(&mut self, source: &str) -> crate::Result<()> {
-        let active_gc = ActiveGC::install();
-        let chunk = compiler::compile(source, &active_gc)?;
-        let mut vm = VmWithChunk {
-            ip: 0,
+    /// Creates a VM with no native functions at all. Embedders can use this to choose exactly
+    /// which natives Lox code can call, with [VM::define_native()].
+    ///
+    /// ```
+    /// # use rlox::prelude::*;
+    /// let mut vm = VM::without_natives();
+    /// assert!(vm.interpret("clock();").is_err()); // clock is undefined
+    /// ```
+    pub fn without_natives() -> Self {
+        VM {
+            statistics: Statistics::default(),
+            natives: Vec::new(),
             stack: Vec::with_capacity(STACK_SIZE),
-            chunk: &chunk,
-            globals: HashMap::default(),
-            _active_gc: &active_gc,
+            globals: Globals::default(),
+            gc: Some(Box::default()),
+            pinned: Vec::new(),
+            stdout: Box::new(io::stdout()),
+            stderr: Box::new(io::stderr()),
+            trace: if cfg!(feature = "trace_execution") {
+                Some(Box::new(io::stdout()))
+            } else {
+                None
+            },
+            compile_options: CompileOptions::default(),
+            limits: Limits::default(),
+            fuel: None,
+            interrupt: Arc::default(),
+            profile: None,
+            modules: HashMap::default(),
+            module_resolver: Box::<FileResolver>::default(),
+            allow_io: Rc::new(Cell::new(true)),
+            exit_request: Rc::default(),
+            implicit_string_conversion: false,
+            garbage_collection: false,
+        }
+    }
+
+    /// Sets the command line arguments available to Lox scripts, through the `args()` native
+    /// function.
+    ///
+    /// ```
+    /// # use rlox::prelude::*;
+    /// let mut vm = VM::default();
+    /// vm.set_args(vec!["--verbose".to_owned()]);
+    /// vm.interpret("print args(0);").unwrap(); // prints --verbose
+    /// ```
+    pub fn set_args(&mut self, args: Vec<String>) {
+        crate::natives::define_args(self, args);
+    }
+
+    /// Sets the directory where the modules named by `import` statements are found, by
+    /// installing a [FileResolver] for it. By default, this is the current directory. A module is
+    /// only run the first time it is imported, even if it is imported again by another name
+    /// (e.g., `"./util.lox"` and `"util.lox"`). The imports of a module are found relative to the
+    /// module's own directory.
+    ///
+    /// ```no_run
+    /// # use rlox::prelude::*;
+    /// let mut vm = VM::default();
+    /// vm.set_import_dir("scripts/lib");
+    /// vm.interpret("import \"strings.lox\";").unwrap(); // runs scripts/lib/strings.lox
+    /// ```
+    pub fn set_import_dir(&mut self, dir: impl Into<PathBuf>) {
+        self.set_module_resolver(Box::new(FileResolver::new(dir)));
+    }
+
+    /// Sets how the modules named by `import` statements are found, e.g., to serve them from
+    /// memory instead of files. See [ModuleResolver].
+    pub fn set_module_resolver(&mut self, resolver: Box<dyn ModuleResolver>) {
+        self.module_resolver = resolver;
+    }
+
+    /// Allows or forbids the natives that read and write files and `stdin`: `readFile()`,
+    /// `writeFile()`, and `readLine()` in the [standard library](crate::stdlib). Calling one of
+    /// them while I/O is forbidden is a runtime error. By default, I/O is allowed; embedders that
+    /// run untrusted code can forbid it, whether or not the natives are installed yet.
+    ///
+    /// ```
+    /// # use rlox::prelude::*;
+    /// let mut vm = VM::default();
+    /// rlox::stdlib::install(&mut vm);
+    /// vm.set_stderr(Box::new(std::io::sink()));
+    /// vm.set_allow_io(false);
+    /// assert!(vm.interpret("print readFile(\"/etc/passwd\");").is_err());
+    /// ```
+    pub fn set_allow_io(&mut self, allow: bool) {
+        self.allow_io.set(allow);
+    }
+
+    /// Returns whether natives may read and write files and `stdin`. See [VM::set_allow_io()].
+    pub fn allows_io(&self) -> bool {
+        self.allow_io.get()
+    }
+
+    /// Allows or forbids adding a string and a number with `+`, e.g., `"count: " + 3`. When
+    /// allowed, the number is converted to a string, just like `print` would write it. By
+    /// default, this is forbidden (it is a runtime error), as the Lox specification requires.
+    ///
+    /// ```
+    /// # use rlox::prelude::*;
+    /// let mut vm = VM::default();
+    /// vm.set_implicit_string_conversion(true);
+    /// vm.interpret("var message = \"count: \" + 3;").unwrap();
+    /// assert_eq!(Ok("count: 3"), vm.get_global("message").unwrap().get::<&str>());
+    /// ```
+    pub fn set_implicit_string_conversion(&mut self, allow: bool) {
+        self.implicit_string_conversion = allow;
+    }
+
+    /// Returns the flag that natives which read and write files and `stdin` must check before
+    /// they do.
+    #[cfg(feature = "io")]
+    pub(crate) fn io_capability(&self) -> Rc<Cell<bool>> {
+        Rc::clone(&self.allow_io)
+    }
+
+    /// Returns where a native can ask to stop the code that is running, with an exit status. The
+    /// native must then return `Err`, which becomes [InterpretationError::Exit].
+    pub(crate) fn exit_request(&self) -> Rc<Cell<Option<i32>>> {
+        Rc::clone(&self.exit_request)
+    }
+
+    /// Sets where `print` statements write to. By default, this is `stdout`. To capture the output
+    /// of a program, give it a writer that shares its buffer (e.g., through an `Rc`).
+    ///
+    /// ```
+    /// # use rlox::prelude::*;
+    /// let mut vm = VM::default();
+    /// vm.set_stdout(Box::new(std::io::sink()));
+    /// vm.interpret("print \"nobody sees this\";").unwrap();
+    /// ```
+    pub fn set_stdout(&mut self, stdout: Box<dyn Write>) {
+        self.stdout = stdout;
+    }
+
+    /// Sets where compile errors and runtime errors are written to. By default, this is `stderr`.
+    /// Use [io::sink()] to report errors some other way, e.g., with
+    /// [render_diagnostic()](crate::error::render_diagnostic).
+    pub fn set_stderr(&mut self, stderr: Box<dyn Write>) {
+        self.stderr = stderr;
+    }
+
+    /// Traces execution to the given writer: before every instruction, the contents of the stack
+    /// are written on one line, followed by the disassembly of the instruction. With the
+    /// `trace_execution` feature, execution is traced to `stdout` by default.
+    ///
+    /// ```
+    /// # use rlox::prelude::*;
+    /// let mut vm = VM::default();
+    /// vm.set_trace_writer(Box::new(std::io::sink()));
+    /// vm.interpret("print 1 + 2;").unwrap();
+    /// ```
+    pub fn set_trace_writer(&mut self, trace: Box<dyn Write>) {
+        self.trace = Some(trace);
+    }
+
+    /// Stops tracing execution, and returns the writer it was traced to, if it was traced.
+    ///
+    /// ```
+    /// # use rlox::prelude::*;
+    /// let mut vm = VM::default();
+    /// vm.set_trace_writer(Box::new(std::io::sink()));
+    /// assert!(vm.take_trace_writer().is_some());
+    /// assert!(vm.take_trace_writer().is_none());
+    /// ```
+    pub fn take_trace_writer(&mut self) -> Option<Box<dyn Write>> {
+        self.trace.take()
+    }
+
+    /// Sets the options used to compile source code, e.g., by [VM::interpret()] and
+    /// [VM::compile()]. ([VM::interpret_repl()] always compiles in REPL mode.)
+    ///
+    /// ```
+    /// # use rlox::prelude::*;
+    /// use rlox::compiler::CompileOptions;
+    ///
+    /// let mut vm = VM::default();
+    /// vm.set_compile_options(CompileOptions {
+    ///     print_code: true,
+    ///     ..CompileOptions::default()
+    /// });
+    /// vm.interpret("print 1 + 2;").unwrap(); // prints the bytecode, then 3
+    /// ```
+    pub fn set_compile_options(&mut self, options: CompileOptions) {
+        self.compile_options = options;
+    }
+
+    /// Returns the options used to compile source code. See [VM::set_compile_options()].
+    pub fn compile_options(&self) -> CompileOptions {
+        self.compile_options
+    }
+
+    /// Sets how big the stacks may grow while running code. By default, calls can be nested 64
+    /// deep.
+    ///
+    /// ```
+    /// # use rlox::prelude::*;
+    /// use rlox::vm::Limits;
+    ///
+    /// let mut vm = VM::default();
+    /// vm.set_stderr(Box::new(std::io::sink()));
+    /// let source = "fun count(n) { return n < 1 and 0 or 1 + count(n - 1); } print count(100);";
+    /// assert!(vm.interpret(source).is_err()); // Stack overflow.
+    ///
+    /// vm.set_limits(Limits {
+    ///     max_frames: 1000,
+    ///     ..Limits::default()
+    /// });
+    /// vm.interpret(source).unwrap(); // prints 100
+    /// ```
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Limits how many more instructions this VM may execute, in total, across every call to
+    /// [VM::interpret()] and friends. Once the fuel runs out, the code that is running stops
+    /// with [InterpretationError::OutOfFuel]. Use this to stop untrusted code from running
+    /// forever. By default, there is no limit.
+    ///
+    /// ```
+    /// # use rlox::prelude::*;
+    /// let mut vm = VM::default();
+    /// vm.set_fuel(1_000);
+    /// let result = vm.interpret("while (true) {}");
+    /// assert!(matches!(result, Err(InterpretationError::OutOfFuel)));
+    /// assert_eq!(Some(0), vm.fuel());
+    /// ```
+    pub fn set_fuel(&mut self, fuel: u64) {
+        self.fuel = Some(fuel);
+    }
+
+    /// Returns how many more instructions this VM may execute, or `None` if there is no limit.
+    /// See [VM::set_fuel()].
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Starts counting how many times each opcode, each line of source code, and each call stack
+    /// is executed, across every later call to [VM::interpret()] and friends. Calling this again
+    /// starts over. Profiling makes the VM slower, so it is off by default.
+    ///
+    /// ```
+    /// # use rlox::prelude::*;
+    /// let mut vm = VM::default();
+    /// vm.enable_profiling();
+    /// vm.interpret("var a = 1;\nvar b = a + 2;").unwrap();
+    ///
+    /// let profile = vm.profile_report().unwrap();
+    /// assert_eq!(Some(&2), profile.opcodes.get(&OpCode::DefineGlobal));
+    /// assert_eq!(Some(&2), profile.lines.get(&1)); // Constant, DefineGlobal
+    /// // GetGlobal, AddConstant, DefineGlobal, and the script's Return:
+    /// assert_eq!(Some(&4), profile.lines.get(&2));
+    /// ```
+    pub fn enable_profiling(&mut self) {
+        self.profile = Some(Profile::default());
+    }
+
+    /// Returns what has been counted since [VM::enable_profiling()] was called, or `None` if
+    /// profiling is not enabled.
+    pub fn profile_report(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Returns a flag that stops the code that is running when it is set, e.g., from another
+    /// thread, or from a signal handler for Ctrl-C. The code stops with
+    /// [InterpretationError::Interrupted] the next time it calls a function or loops, and the
+    /// flag is cleared again.
+    ///
+    /// ```
+    /// # use rlox::prelude::*;
+    /// use std::sync::atomic::Ordering;
+    ///
+    /// let mut vm = VM::default();
+    /// let interrupt = vm.interrupt_handle();
+    /// std::thread::spawn(move || interrupt.store(true, Ordering::Relaxed));
+    /// let result = vm.interpret("while (true) {}");
+    /// assert!(matches!(result, Err(InterpretationError::Interrupted)));
+    /// ```
+    pub fn interrupt_handle(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.interrupt)
+    }
+
+    /// Defines a native function, which will be available as a global variable with the given
+    /// name. Defining a native with the same name as an existing native replaces it.
+    ///
+    /// The function is given exactly `arity` arguments; calling it with any other number of
+    /// arguments is a runtime error. Returning `Err` raises a runtime error with that message.
+    ///
+    /// ```
+    /// # use rlox::prelude::*;
+    /// let mut vm = VM::default();
+    /// vm.define_native("square", 1, |args| match args[0].to_f64() {
+    ///     Some(n) => Ok((n * n).into()),
+    ///     None => Err("square() expects a number".to_owned()),
+    /// });
+    ///
+    /// vm.interpret("var answer = square(7);").unwrap();
+    /// assert!(vm.interpret("square(nil);").is_err());
+    /// ```
+    pub fn define_native<F>(&mut self, name: &str, arity: u8, function: F)
+    where
+        F: Fn(&[Value]) -> Result<Value, String> + 'static,
+    {
+        self.define_native_with_state(name, arity, move |_, arguments| function(arguments));
+    }
+
+    /// Like [VM::define_native()], but the function is also given a [VmState], so that it can
+    /// inspect the code that called it.
+    ///
+    /// ```
+    /// # use rlox::prelude::*;
+    /// let mut vm = VM::default();
+    /// vm.define_native_with_state("depth", 0, |state, _| Ok((state.call_depth() as f64).into()));
+    ///
+    /// vm.interpret("fun f() { return depth(); } print depth(); print f();").unwrap(); // 1, 2
+    /// ```
+    pub fn define_native_with_state<F>(&mut self, name: &str, arity: u8, function: F)
+    where
+        F: Fn(&VmState<'_>, &[Value]) -> Result<Value, String> + 'static,
+    {
+        let native = Native {
+            name: name.to_owned(),
+            arity,
+            function: Box::new(function),
+        };
+
+        let index = match self.natives.iter().position(|native| native.name == name) {
+            Some(index) => {
+                self.natives[index] = native;
+                index
+            }
+            None => {
+                self.natives.push(native);
+                self.natives.len() - 1
+            }
         };
-        vm.run()
+
+        self.with_gc(|vm, _| vm.define_native_global(index));
     }
-}
 
-impl<'a> VmWithChunk<'a> {
-    /// The main opcode interpreter loop.
-    fn run(&mut self) -> crate::Result<()> {
-        use OpCode::*;
-        let chunk = self.chunk;
+    /// Defines a native function from a Rust function (or closure) that takes and returns Rust
+    /// types. Its arguments are converted with [FromLox], and its arity is the number of
+    /// arguments it takes. It returns anything that converts with [IntoLox], or a [Result] of one,
+    /// where `Err` raises a runtime error. Arguments with the wrong type are runtime errors too.
+    ///
+    /// ```
+    /// # use rlox::prelude::*;
+    /// let mut vm = VM::default();
+    /// vm.define_typed_native("square", |n: f64| n * n);
+    /// vm.define_typed_native("shout", |s: String| format!("{}!", s.to_uppercase()));
+    /// vm.define_typed_native("sqrt", |n: f64| match n {
+    ///     n if n >= 0.0 => Ok(n.sqrt()),
+    ///     _ => Err("sqrt() expects a non-negative number"),
+    /// });
+    ///
+    /// vm.interpret("var answer = square(7); var greeting = shout(\"hi\");").unwrap();
+    /// assert!(vm.interpret("square(nil);").is_err());
+    /// assert!(vm.interpret("sqrt(-1);").is_err());
+    /// ```
+    pub fn define_typed_native<Args>(&mut self, name: &str, function: impl IntoNative<Args>) {
+        let arity = function.arity();
+        let function = function.into_native(name);
+        self.define_native(name, arity, function);
+    }
 
-        loop {
-            if cfg!(feature = "trace_execution") {
-                use crate::debug::disassemble_instruction;
+    /// Compile and interpret the given Lox source code.
+    ///
+    /// Global variables are kept between calls, so this can be called several times to run
+    /// several pieces of code in the same environment:
+    ///
+    /// ```
+    /// # use rlox::prelude::*;
+    /// let mut vm = VM::default();
+    /// vm.interpret("var a = 1;").unwrap();
+    /// vm.interpret("print a;").unwrap(); // prints 1
+    /// ```
+    ///
+    /// # Note
+    ///
+    /// Every VM has its own GC, which it installs as the [ActiveGC] only while it runs code. So
+    /// several VMs can be used at the same time, and their globals are completely separate:
+    ///
+    /// ```
+    /// # use rlox::prelude::*;
+    /// let mut first = VM::default();
+    /// let mut second = VM::default();
+    /// first.interpret("var a = 1;").unwrap();
+    /// assert!(second.interpret("print a;").is_err()); // a is undefined in the second VM
+    /// ```
+    pub fn interpret(&mut self, source: &str) -> crate::Result<()> {
+        self.interpret_with(source, self.compile_options)
+    }
 
-                // Prints the current stack:
-                print!("        ");
-                if self.stack.is_empty() {
-                    print!("<empty>");
-                } else {
-                    for value in self.stack.iter() {
-                        print!("[ {value:?} ]")
-                    }
-                }
-                println!();
+    /// Like [VM::interpret()], but for one entry in a REPL: the value of every expression statement
+    /// at the top level is printed.
+    ///
+    /// ```
+    /// # use rlox::prelude::*;
+    /// let mut vm = VM::default();
+    /// vm.interpret_repl("var a = 2;").unwrap(); // prints nothing
+    /// vm.interpret_repl("a + 1;").unwrap(); // prints 3
+    /// ```
+    pub fn interpret_repl(&mut self, source: &str) -> crate::Result<()> {
+        let options = CompileOptions {
+            repl: true,
+            ..self.compile_options
+        };
+        self.interpret_with(source, options)
+    }
+
+    /// Interprets Lox source code as it is read, one top-level statement at a time. Unlike
+    /// [VM::interpret()], statements start running before the rest of the source code has been
+    /// read (or even written), so this is suitable for long scripts piped in through `stdin`.
+    ///
+    /// Lines are read until they end a statement; that is, until every bracket is closed, and
+    /// the last token is `;` or `}`. Each statement is compiled and run on its own, so an error
+    /// late in the source code is only found after everything before it has run. Line numbers
+    /// in errors count from the start of the reader, but [spans](Diagnostic::span) count from the
+    /// start of the statement.
+    ///
+    /// ```
+    /// # use rlox::prelude::*;
+    /// let source = "var a = 1;\nfun f() {\n  return a + 1;\n}\nprint f();\n";
+    /// let mut vm = VM::default();
+    /// vm.interpret_reader(source.as_bytes()).unwrap(); // prints 2
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Stops at the first compile-time or runtime error, or when reading fails.
+    pub fn interpret_reader(&mut self, reader: impl BufRead) -> crate::Result<()> {
+        let mut statement = String::new();
+        let mut first_line = 1;
 
-                // Print the next instruction:
-                disassemble_instruction(chunk, self.ip);
+        for line in reader.lines() {
+            let line = line?;
+            // Wait for the next line, in case it continues the statement: print a\n  + b;
+            if ends_statement(&statement) && !continues_statement(&line) {
+                self.interpret_from_line(&statement, first_line)?;
+                first_line += statement.lines().count();
+                statement.clear();
             }
 
-            let opcode = self
-                .next_bytecode()
-                .expect("I have an instruction pointer within range")
-                .as_opcode();
+            statement.push_str(&line);
+            statement.push('\n');
+        }
 
-            match opcode {
-                Some(Constant) => {
-                    let constant = self
-                        .next_bytecode()
-                        .expect("there should be an operand")
-                        .resolve_constant()
-                        .expect("there should be a constant at this index");
-                    self.push(constant);
-                }
-                Some(Nil) => self.push(Value::Nil),
-                Some(True) => self.push(true.into()),
-                Some(False) => self.push(false.into()),
-                Some(Pop) => {
-                    self.pop();
-                }
-                Some(GetLocal) => {
-                    let slot = self.next_bytecode().expect("operand").as_constant_index();
-                    self.push(*self.stack.get(slot).expect("local variable"));
-                }
-                Some(SetLocal) => {
-                    let slot = self.next_bytecode().expect("operand").as_constant_index();
-                    let value = self.pop();
-                    self.stack[slot] = value;
-                }
-                Some(GetGlobal) => {
-                    let name = self.next_string_constant();
-                    match self.globals.get(name) {
-                        Some(&value) => self.push(value),
-                        None => {
-                            let message = format!("undefined global variable: {name}");
-                            self.runtime_error(&message)?;
-                        }
-                    };
-                }
-                Some(DefineGlobal) => {
-                    let name = self.next_string_constant();
-                    let value = self.pop();
-                    self.globals.insert(name, value);
-                }
-                Some(SetGlobal) => {
-                    let name = self.next_string_constant();
-                    let value = self.peek(0);
-                    if self.globals.insert(name, value).is_none() {
-                        // Tried to assign to an undefined global variable.
-                        // First, clean-up the variable we accidentally created...
-                        self.globals.remove(name);
-
-                        // THEN, report an error and exit.
-                        let message = format!("Undefined variable: '{name}'");
-                        self.runtime_error(&message)?;
-                    }
-                }
-                Some(Equal) => {
-                    let rhs = self.pop();
-                    let lhs = self.pop();
-                    self.push(lhs.equal(&rhs).into());
-                }
-                Some(Greater) => self.binary_op(|a, b| a > b)?,
-                Some(Less) => self.binary_op(|a, b| a < b)?,
-                Some(Add) => {
-                    let rhs = self.pop();
-                    let lhs = self.pop();
-
-                    match (&lhs, &rhs) {
-                        (Value::Number(a), Value::Number(b)) => self.push((a + b).into()),
-                        (Value::LoxString(a), Value::LoxString(b)) => {
-                            self.push(format!("{a}{b}").into());
-                        }
-                        _ => self.runtime_error("Can only add numbers or strings")?,
-                    }
-                }
-                Some(Subtract) => self.binary_op(|a, b| a - b)?,
-                Some(Multiply) => self.binary_op(|a, b| a * b)?,
-                Some(Divide) => self.binary_op(|a, b| a / b)?,
-                Some(Not) => {
-                    let value = self.pop();
-                    self.push(value.is_falsy().into());
-                }
-                Some(Negate) => {
-                    if let Value::Number(number) = self.pop() {
-                        self.push((-number).into());
-                    } else {
-                        // TODO: rephrase to remove "compiler-speak" from error message:
-                        self.runtime_error("Operand must be a number")?;
-                    }
-                }
-                Some(Print) => {
-                    println!("{}", self.pop());
+        self.interpret_from_line(&statement, first_line)
+    }
+
+    /// Like [VM::interpret()], but the source code starts at the given line.
+    fn interpret_from_line(&mut self, source: &str, first_line: usize) -> crate::Result<()> {
+        let options = CompileOptions {
+            first_line,
+            ..self.compile_options
+        };
+        self.interpret_with(source, options)
+    }
+
+    /// Compiles the source code with the given options, then runs it.
+    fn interpret_with(&mut self, source: &str, options: CompileOptions) -> crate::Result<()> {
+        self.statistics = Statistics::default();
+        self.with_gc(|vm, active_gc| {
+            match compiler::compile_with_warnings(source, active_gc, options) {
+                Ok((chunk, mut warnings)) => {
+                    vm.lint(&chunk, source, options, &mut warnings);
+                    vm.report_diagnostics(&warnings);
+                    vm.run_chunk(&chunk, active_gc).map(drop)
                 }
-                Some(Return) => {
-                    return Ok(());
+                Err(diagnostics) => Err(vm.compile_error(diagnostics)),
+            }
+        })
+    }
+
+    /// Compiles the source code, without running it. The chunk can be run (several times) with
+    /// [VM::interpret_chunk()], so that compiling and running can be timed separately:
+    ///
+    /// ```
+    /// # use rlox::prelude::*;
+    /// let mut vm = VM::default();
+    /// let chunk = vm.compile("var a = 1 + 2;").unwrap();
+    /// vm.interpret_chunk(&chunk).unwrap();
+    /// vm.interpret_chunk(&chunk).unwrap();
+    /// ```
+    ///
+    /// The chunk refers to strings and functions that belong to this VM, so it must only be run
+    /// by this VM. They are kept for as long as the VM exists.
+    pub fn compile(&mut self, source: &str) -> crate::Result<Chunk> {
+        let options = self.compile_options;
+        self.with_gc(|vm, active_gc| {
+            match compiler::compile_with_warnings(source, active_gc, options) {
+                Ok((chunk, mut warnings)) => {
+                    vm.lint(&chunk, source, options, &mut warnings);
+                    vm.report_diagnostics(&warnings);
+                    vm.pinned.extend(chunk.constants());
+                    Ok(chunk)
                 }
-                None => panic!("fetched invalid opcode at {}", current_ip!(self)),
+                Err(diagnostics) => Err(vm.compile_error(diagnostics)),
             }
+        })
+    }
+
+    /// Runs a chunk returned by [VM::compile()] on this VM.
+    pub fn interpret_chunk(&mut self, chunk: &Chunk) -> crate::Result<()> {
+        self.statistics = Statistics::default();
+        self.with_gc(|vm, active_gc| vm.run_chunk(chunk, active_gc).map(drop))
+    }
+
+    /// Starts debugging a chunk returned by [VM::compile()]. Nothing runs until the [Debugger] is
+    /// stepped or resumed.
+    pub fn debug<'a>(&'a mut self, chunk: &'a Chunk) -> Debugger<'a> {
+        Debugger {
+            vm: self,
+            chunk,
+            frames: vec![CallFrame::script(chunk)],
+            stack: Vec::with_capacity(STACK_SIZE),
+            instructions: 0,
+            breakpoints: BTreeSet::new(),
         }
     }
 
-    /// Raises a runtime error
-    fn runtime_error<T>(&mut self, message: &str) -> crate::Result<T> {
-        eprintln!("{message}");
+    /// Reports compile errors, returning them as an [InterpretationError].
+    fn compile_error(&mut self, diagnostics: Vec<Diagnostic>) -> InterpretationError {
+        self.report_diagnostics(&diagnostics);
+        InterpretationError::CompileError(diagnostics)
+    }
 
-        let line = self.chunk.line_number_for(self.ip).expect("line number");
-        eprintln!("[line {line}] in script");
+    /// With [CompileOptions::warnings], adds a warning for every global that the chunk reads, but
+    /// that neither the chunk nor this VM defines (see [lint::undefined_globals()]).
+    fn lint(
+        &self,
+        chunk: &Chunk,
+        source: &str,
+        options: CompileOptions,
+        warnings: &mut Vec<Diagnostic>,
+    ) {
+        if !options.warnings {
+            return;
+        }
+        let is_defined = |name: &str| self.globals.get(name).is_some();
+        warnings.extend(lint::undefined_globals(chunk, source, is_defined));
+        warnings.sort_by_key(|warning| warning.span.start);
+    }
 
-        self.reset_stack();
+    /// Writes compile errors and warnings to `stderr`.
+    fn report_diagnostics(&mut self, diagnostics: &[Diagnostic]) {
+        for diagnostic in diagnostics.iter() {
+            // Like eprintln!(), ignore errors writing errors.
+            let _ = writeln!(self.stderr, "{diagnostic}");
+        }
+    }
 
-        Err(InterpretationError::RuntimeError)
+    /// Interpret bytecode that was previously written by [Chunk::serialize()].
+    pub fn interpret_bytecode(&mut self, bytes: &[u8]) -> crate::Result<()> {
+        self.statistics = Statistics::default();
+        self.with_gc(|vm, active_gc| {
+            let chunk = Chunk::deserialize(bytes)?;
+            vm.run_chunk(&chunk, active_gc).map(drop)
+        })
     }
 
-    /// Pops two operands on the stack to perform a binary operation.
-    fn binary_op<F, T>(&mut self, op: F) -> crate::Result<()>
-    where
-        F: Fn(f64, f64) -> T,
-        T: Into<Value>,
-    {
-        let rhs = self.pop();
-        let lhs = self.pop();
+    /// Returns the value of the global variable with the given name, if it is defined.
+    pub(crate) fn global(&self, name: &str) -> Option<Value> {
+        self.globals.get(name)
+    }
 
-        use Value::Number;
-        match (lhs, rhs) {
-            (Number(a), Number(b)) => self.push(op(a, b).into()),
-            (_, _) => self.runtime_error("Operands must be numbers")?,
-        };
+    /// Returns the value of the global variable with the given name, if it is defined, e.g., to
+    /// get the results of a script.
+    ///
+    /// The value is borrowed from the VM, since its objects (if any) may be freed the next time
+    /// the VM runs code or collects garbage. To keep using an object after that, use
+    /// [VM::downgrade()], or convert the value to a Rust type with [ValueRef::get()].
+    ///
+    /// ```
+    /// # use rlox::prelude::*;
+    /// let mut vm = VM::default();
+    /// vm.interpret("var area = 3 * 3; var label = \"box\";").unwrap();
+    ///
+    /// assert_eq!(Ok(9.0), vm.get_global("area").unwrap().get::<f64>());
+    /// let label: String = vm.get_global("label").unwrap().get().unwrap();
+    /// assert_eq!("box", label);
+    /// assert!(vm.get_global("undefined").is_none());
+    /// ```
+    pub fn get_global(&self, name: &str) -> Option<ValueRef<'_>> {
+        self.global(name).map(ValueRef::new)
+    }
 
-        Ok(())
+    /// Returns every global variable (including natives), sorted by name. Like
+    /// [VM::get_global()], the values are borrowed from the VM.
+    ///
+    /// ```
+    /// # use rlox::prelude::*;
+    /// let mut vm = VM::without_natives();
+    /// vm.interpret("var b = 2; var a = 1;").unwrap();
+    /// let globals: Vec<_> = (vm.globals().into_iter())
+    ///     .map(|(name, value)| format!("{name} = {value}"))
+    ///     .collect();
+    /// assert_eq!(vec!["a = 1", "b = 2"], globals);
+    /// ```
+    pub fn globals(&self) -> Vec<(&str, ValueRef<'_>)> {
+        let mut globals: Vec<_> = (self.globals.iter())
+            .map(|(name, value)| (name, ValueRef::new(value)))
+            .collect();
+        globals.sort_by_key(|&(name, _)| name);
+        globals
     }
 
-    /// Pushes a [Value] on to the value stack.
-    fn push(&mut self, value: Value) {
-        self.stack.push(value);
+    /// Defines (or changes) a global variable, e.g., to give configuration to a script before it
+    /// runs. The value can be anything that converts to a Lox value; a [Value] that refers to an
+    /// object must come from this VM.
+    ///
+    /// ```
+    /// # use rlox::prelude::*;
+    /// let mut vm = VM::default();
+    /// vm.set_global("width", 3.0);
+    /// vm.set_global("name", "box");
+    /// vm.interpret("var area = width * width; var label = name + \"!\";").unwrap();
+    ///
+    /// assert_eq!(Ok(9.0), vm.get_global("area").unwrap().get::<f64>());
+    /// assert_eq!(Ok("box!"), vm.get_global("label").unwrap().get::<&str>());
+    /// ```
+    pub fn set_global(&mut self, name: &str, value: impl IntoLox) {
+        self.with_gc(|vm, _| {
+            let name = ActiveGC::store_string(name.to_owned());
+            vm.globals.insert(name, value.into_lox());
+        });
     }
 
-    /// Pops and returns the top [Value] on the value stack.
+    /// Calls a function defined by code that this VM has already run, e.g., so that a host
+    /// application can call a plugin's functions as often as it needs to without recompiling
+    /// them. The arguments are a tuple (or an array) of anything that converts to a Lox value;
+    /// see [IntoArgs]. Native functions and classes can be called, too.
     ///
-    /// # Panics
+    /// Like [VM::get_global()], the result is borrowed from the VM.
     ///
-    /// Panics when the value stack is empty. Given well-formed Lox bytecode, a pop cannot occur
-    /// when the value stack is empty; therefore the interpreter panics if it is in this state.
-    #[inline(always)]
-    fn pop(&mut self) -> Value {
-        self.stack.pop().expect("value stack is empty")
+    /// ```
+    /// # use rlox::prelude::*;
+    /// let mut vm = VM::default();
+    /// vm.interpret("fun area(width, height) { return width * height; }").unwrap();
+    ///
+    /// let area = vm.call("area", (3.0, 4.0)).unwrap();
+    /// assert_eq!(Ok(12.0), area.get::<f64>());
+    /// assert!(vm.call("area", ()).is_err());
+    /// assert!(vm.call("perimeter", ()).is_err());
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns a runtime error if the function is not defined, it is called with the wrong number
+    /// of arguments, or it fails. At most 255 arguments can be given.
+    pub fn call(
+        &mut self,
+        function_name: &str,
+        args: impl IntoArgs,
+    ) -> crate::Result<ValueRef<'_>> {
+        self.statistics = Statistics::default();
+        let value = self.with_gc(|vm, active_gc| {
+            // Call the function from a tiny script, so that the VM looks up the function, checks
+            // the arguments, and reports errors just like it does for any other call:
+            let mut call = ChunkBuilder::new();
+            let name = ActiveGC::store_string(function_name.to_owned());
+            call.emit_with_constant(OpCode::GetGlobal, Value::LoxString(name));
+            // Convert the arguments now, so that their strings are stored by this VM's GC:
+            let args = args.into_args();
+            for &arg in &args {
+                call.emit_constant(arg);
+            }
+            let Ok(arity) = u8::try_from(args.len()) else {
+                // The call script is a single line, so that is where the error is:
+                let info = RuntimeErrorInfo {
+                    message: "Can't have more than 255 arguments".to_owned(),
+                    line: 1,
+                    span: None,
+                    backtrace: vec![BacktraceFrame {
+                        function: None,
+                        line: 1,
+                    }],
+                };
+                let _ = write!(vm.stderr, "{info}");
+                return Err(InterpretationError::RuntimeError(info));
+            };
+            call.emit_with_operand(OpCode::Call, arity)
+                .emit(OpCode::Return);
+            let chunk = call.build()?;
+            vm.run_chunk(&chunk, active_gc)
+        })?;
+
+        let value = value.expect("the call leaves its result on the stack");
+        Ok(ValueRef::new(value))
     }
 
-    /// Peek the nth value on the stack, starting from the top.
+    /// Returns the contents of the value stack, from the bottom to the top, as they were when the
+    /// VM last stopped running code. Code that finishes (or stops with a runtime error) leaves
+    /// the stack empty, but code that runs out of [fuel](VM::set_fuel()) or is interrupted leaves
+    /// its values where they were:
     ///
-    /// # Panics
+    /// ```
+    /// # use rlox::prelude::*;
+    /// let mut vm = VM::default();
+    /// vm.set_fuel(100);
+    /// assert!(vm.interpret("{ var a = 1; var b = \"two\"; while (true) {} }").is_err());
+    /// let stack: Vec<_> = vm.stack_snapshot().iter().map(ValueRef::to_string).collect();
+    /// assert_eq!(vec!["1", "two"], stack);
     ///
-    /// Panics when trying to get a value to far down the stack.
-    #[inline(always)]
-    fn peek(&self, n: usize) -> Value {
-        *self.stack.iter().rev().nth(n).expect("ran off the stack")
+    /// vm.set_fuel(100);
+    /// vm.interpret("var a = 1;").unwrap();
+    /// assert!(vm.stack_snapshot().is_empty());
+    /// ```
+    ///
+    /// Like [VM::get_global()], the values are borrowed from the VM. While code is running,
+    /// natives can see the stack with [VmState::stack_snapshot()].
+    pub fn stack_snapshot(&self) -> Vec<ValueRef<'_>> {
+        self.stack
+            .iter()
+            .map(|&value| ValueRef::new(unpack(value)))
+            .collect()
     }
 
-    /// Clears the stack.
-    #[inline(always)]
-    fn reset_stack(&mut self) {
-        self.stack.clear()
+    /// Returns statistics about the most recent call to [VM::interpret()] or
+    /// [VM::interpret_bytecode()].
+    ///
+    /// ```
+    /// # use rlox::prelude::*;
+    /// let mut vm = VM::default();
+    /// vm.interpret("var a = 1 + 2;").unwrap();
+    /// // Constant, AddConstant, DefineGlobal, Return:
+    /// assert_eq!(4, vm.statistics().instructions);
+    /// ```
+    pub fn statistics(&self) -> &Statistics {
+        &self.statistics
     }
 
-    /// Fetches the next bytecode in the chunk, **AND** increments the instruction pointer.
+    /// Returns what this VM's GC is storing, and how much it has collected. Lox code can get the
+    /// same statistics with the `gcstats()` native function.
     ///
-    /// Note: use [current_ip] to get the "current" value of the instruction pointer being executed
-    /// right now.
-    #[inline]
-    fn next_bytecode(&mut self) -> Option<BytecodeEntry<'_>> {
-        let byte = self.chunk.get(self.ip);
-        self.ip += 1;
-        byte
+    /// ```
+    /// # use rlox::prelude::*;
+    /// let mut vm = VM::default();
+    /// vm.interpret("var a = [1, 2, 3];").unwrap();
+    /// assert_eq!(1, vm.gc_stats().lists);
+    /// ```
+    pub fn gc_stats(&self) -> GcStats {
+        self.gc.as_ref().expect("the VM's GC is active").stats()
     }
 
-    /// Fetches the next bytecode in the chunk and use it to index the constant pool. The constant
-    /// pulled out should be a string (such as global variable name).
+    /// Returns a [WeakHandle] to a value from this VM, which does not keep the value from being
+    /// collected, unlike holding on to the value itself. See [GC::downgrade()].
     ///
-    /// Note: Like [[next_bytecode]], this advances the instruction pointer.
-    #[inline]
-    fn next_string_constant(&mut self) -> &'static str {
-        self.next_bytecode()
-            .expect("there should be an operand")
-            .resolve_constant()
-            .expect("there should be a constant here")
-            .to_str()
-            .expect("the name must be a string")
+    /// ```
+    /// # use rlox::prelude::*;
+    /// let mut vm = VM::default();
+    /// vm.interpret("var list = [1, 2];").unwrap();
+    /// let list = vm.downgrade(vm.get_global("list").unwrap());
+    ///
+    /// // SAFETY: the natives that every VM has never keep a value.
+    /// unsafe { vm.collect_garbage() };
+    /// assert!(vm.upgrade(&list).is_some());
+    ///
+    /// vm.interpret("list = nil;").unwrap();
+    /// unsafe { vm.collect_garbage() };
+    /// assert!(vm.upgrade(&list).is_none());
+    /// ```
+    pub fn downgrade(&self, value: ValueRef<'_>) -> WeakHandle {
+        (self.gc.as_ref().expect("the VM's GC is active")).downgrade(value.value())
+    }
+
+    /// Returns the value that the [WeakHandle] refers to, or `None` if it has been collected. Like
+    /// [VM::get_global()], the value is borrowed from the VM.
+    pub fn upgrade(&self, handle: &WeakHandle) -> Option<ValueRef<'_>> {
+        (self.gc.as_ref().expect("the VM's GC is active")).upgrade(handle)
+    }
+
+    /// Changes when this VM's GC collects garbage while running code, once garbage collection is
+    /// enabled (see [VM::enable_garbage_collection()]).
+    ///
+    /// ```
+    /// # use rlox::prelude::*;
+    /// use rlox::gc::GcConfig;
+    ///
+    /// let mut vm = VM::default();
+    /// // SAFETY: the natives that every VM has never keep a value.
+    /// unsafe { vm.enable_garbage_collection() };
+    /// vm.set_gc_config(GcConfig {
+    ///     initial_threshold: 4096,
+    ///     growth_factor: 1.5,
+    /// });
+    /// vm.interpret("for (var i = 0; i < 1000; i = i + 1) [i];").unwrap();
+    /// assert!(vm.statistics().collections > 0);
+    /// ```
+    pub fn set_gc_config(&mut self, config: GcConfig) {
+        (self.gc.as_mut().expect("the VM's GC is active")).set_config(config);
+    }
+
+    /// Lets the VM collect garbage on its own while running code, once its heap has grown enough
+    /// (see [VM::set_gc_config()]). Until this is called, the VM never frees anything, so a
+    /// long-running VM should enable garbage collection as soon as it has defined its natives.
+    ///
+    /// ```
+    /// # use rlox::prelude::*;
+    /// let mut vm = VM::default();
+    /// // SAFETY: the natives that every VM has never keep a value.
+    /// unsafe { vm.enable_garbage_collection() };
+    /// vm.interpret("for (var i = 0; i < 100000; i = i + 1) [i];").unwrap();
+    /// ```
+    ///
+    /// # Safety
+    ///
+    /// A [Value] is only valid until the next collection. Every native defined on this VM, and
+    /// every conversion into a [Value] done by the VM (e.g., for the arguments of [VM::call()]),
+    /// must not keep any [Value] that it was given or created after it returns, except by
+    /// returning it to the VM. [Value]s that a native keeps anyway must not be used afterwards.
+    pub unsafe fn enable_garbage_collection(&mut self) {
+        self.garbage_collection = true;
+    }
+
+    /// Frees every object that can no longer be reached from the globals right away, whether or
+    /// not garbage collection is enabled (see [VM::enable_garbage_collection()]).
+    ///
+    /// ```
+    /// # use rlox::prelude::*;
+    /// let mut vm = VM::default();
+    /// vm.interpret("var a = \"kept\"; \"garbage\" + \"!\";").unwrap();
+    /// // SAFETY: the natives that every VM has never keep a value.
+    /// unsafe { vm.collect_garbage() };
+    /// ```
+    ///
+    /// # Safety
+    ///
+    /// Like [VM::enable_garbage_collection()]: no native or conversion into a [Value] may have
+    /// kept a [Value] that it was given or created, unless that [Value] is never used again.
+    pub unsafe fn collect_garbage(&mut self) {
+        self.with_gc(|vm, _| {
+            let stack = vm.stack.iter().copied().map(unpack);
+            let globals = vm.globals.roots();
+            let modules = vm.modules.values().map(|&module| Value::Function(module));
+            let pinned = vm.pinned.iter().copied();
+            // SAFETY: no code is running, so the stack, globals, pinned constants, and modules are
+            // everything Lox can reach, and the values given out by the VM are borrowed from it.
+            // The caller promised that no native kept any other value.
+            unsafe { ActiveGC::collect(stack.chain(globals).chain(pinned).chain(modules)) };
+        });
+    }
+
+    /// Installs this VM's GC as the [ActiveGC] while calling `f`.
+    fn with_gc<R>(&mut self, f: impl FnOnce(&mut VM, &ActiveGC) -> R) -> R {
+        let gc = self.gc.take().expect("the VM's GC is already active");
+        let active_gc = ActiveGC::activate(gc);
+        let result = f(self, &active_gc);
+        self.gc = Some(active_gc.deactivate());
+        result
+    }
+
+    /// Defines a global variable for the native function. The GC must be installed.
+    fn define_native_global(&mut self, index: usize) {
+        let name = ActiveGC::store_string(self.natives[index].name.clone());
+        self.globals.insert(name, Value::NativeFunction(index));
+    }
+
+    /// Run the given [Chunk] to completion. Returns the value left on top of the stack, if any.
+    fn run_chunk(&mut self, chunk: &Chunk, active_gc: &ActiveGC) -> crate::Result<Option<Value>> {
+        let allocations_before = ActiveGC::n_allocations();
+        let collections_before = ActiveGC::n_collections();
+        let gc_time_before = ActiveGC::collection_time();
+
+        let script = CallFrame::script(chunk);
+        let mut stack = std::mem::take(&mut self.stack);
+        stack.clear();
+        let fuel = self.fuel;
+        let mut vm = self.running(vec![script], stack, 0, active_gc);
+        vm.pause_at = fuel.unwrap_or(u64::MAX);
+        let result = match vm.run() {
+            // Only running out of fuel pauses before the script is done:
+            Ok(()) if !vm.frames.is_empty() => Err(InterpretationError::OutOfFuel),
+            result => result.map(|()| vm.stack.pop().map(unpack)),
+        };
+        if result.is_err() {
+            vm.forget_unfinished_modules();
+        }
+        if let Some(profile) = vm.profile.as_deref_mut() {
+            profile.pause();
+        }
+        let instructions = vm.instructions;
+        let stack = std::mem::take(&mut vm.stack);
+        self.stack = stack;
+        self.fuel = fuel.map(|fuel| fuel - instructions);
+
+        self.statistics = Statistics {
+            instructions,
+            allocations: ActiveGC::n_allocations() - allocations_before,
+            collections: ActiveGC::n_collections() - collections_before,
+            gc_time: ActiveGC::collection_time() - gc_time_before,
+        };
+
+        result
+    }
+
+    /// Prepares to run code from the given call frames and stack.
+    fn running<'a>(
+        &'a mut self,
+        frames: Vec<CallFrame<'a>>,
+        stack: Vec<StackValue>,
+        instructions: u64,
+        active_gc: &'a ActiveGC,
+    ) -> RunningVM<'a> {
+        RunningVM {
+            frames,
+            instructions,
+            pause_at: u64::MAX,
+            stack,
+            globals: &mut self.globals,
+            pinned: &self.pinned,
+            natives: &self.natives,
+            stdout: &mut self.stdout,
+            stderr: &mut self.stderr,
+            trace: self.trace.as_mut().map(|trace| trace as &mut dyn Write),
+            limits: self.limits,
+            interrupt: &self.interrupt,
+            exit_request: &self.exit_request,
+            profile: self.profile.as_mut(),
+            modules: &mut self.modules,
+            module_resolver: self.module_resolver.as_ref(),
+            compile_options: self.compile_options,
+            implicit_string_conversion: self.implicit_string_conversion,
+            garbage_collection: self.garbage_collection,
+            active_gc,
+        }
+    }
+}
+
+impl<'a> RunningVM<'a> {
+    /// The main opcode interpreter loop. Runs until the script returns, or until it is time to
+    /// pause (see [RunningVM::pause_at]).
+    fn run(&mut self) -> crate::Result<()> {
+        loop {
+            if self.instructions == self.pause_at {
+                return Ok(());
+            }
+
+            // Between instructions, every reachable value is in the stack, globals, or call frames.
+            if self.garbage_collection && ActiveGC::should_collect() {
+                self.collect_garbage();
+            }
+
+            if self.trace.is_some() {
+                // Tracing is only for debugging, so it should never stop the program.
+                let _ = self.trace_instruction();
+            }
+
+            if self.profile.is_some() {
+                self.profile_instruction();
+            }
+
+            let frame = self.frames.last().expect("there should be a frame");
+            if let Some(code) = frame.chunk.registers() {
+                let Some(&entry) = code.get(frame.ip) else {
+                    let offset = frame.ip;
+                    let reason = "no register instruction at this offset";
+                    return Err(InterpretationError::InvalidBytecode { offset, reason });
+                };
+                self.instructions += 1;
+                if self.execute_register(entry)? {
+                    return Ok(());
+                }
+                continue;
+            }
+
+            let byte = self.next_byte()?;
+            self.instructions += 1;
+
+            if self.dispatch(byte)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Executes one instruction of [register code](crate::rvm), which is at the current offset.
+    /// Returns true once the script has returned.
+    #[inline(always)]
+    fn execute_register(&mut self, entry: rvm::Entry) -> crate::Result<bool> {
+        use rvm::Instruction::*;
+
+        // While the instruction runs, the instruction pointer is just past its opcode, like in
+        // the bytecode, so errors are reported at the right place.
+        self.frame().ip = entry.offset + 1;
+        match entry.instruction {
+            Move { dst, src } => {
+                let value = self.operand(src)?;
+                self.set_register(dst, value, entry.top);
+            }
+            Unary { op, dst, src } => {
+                let value = self.operand(src)?;
+                let result = match op {
+                    OpCode::Not => value.is_falsy().into(),
+                    OpCode::Negate => self.negate(value)?,
+                    _ => return Err(self.invalid_bytecode("not a unary operator")),
+                };
+                self.set_register(dst, result, entry.top);
+            }
+            Binary { op, dst, lhs, rhs } => {
+                let lhs = self.operand(lhs)?;
+                let rhs = self.operand(rhs)?;
+                let result = self.binary(op, lhs, rhs)?;
+                self.set_register(dst, result, entry.top);
+            }
+            GetGlobal { dst, name } => {
+                let value = self.global(entry.offset, name as usize)?;
+                self.set_register(dst, value, entry.top);
+            }
+            DefineGlobal {
+                name,
+                src,
+                constant,
+            } => {
+                let name = self.string_constant(name as usize)?;
+                let value = self.operand(src)?;
+                self.define_global(name, value, constant)?;
+                self.set_top(entry.top);
+            }
+            SetGlobal { name, src } => {
+                let name = self.string_constant(name as usize)?;
+                let value = self.operand(src)?;
+                self.set_global(name, value)?;
+                self.set_top(entry.top);
+            }
+            Print { src } => {
+                let value = self.operand(src)?;
+                writeln!(self.stdout, "{value}").map_err(InterpretationError::WriteError)?;
+                self.set_top(entry.top);
+            }
+            Jump { target } => {
+                self.frame().ip = target;
+                return Ok(false);
+            }
+            JumpIfFalse { condition, target } => {
+                let falsy = self.local(condition as usize)?.is_falsy();
+                self.frame().ip = if falsy { target } else { entry.next };
+                return Ok(false);
+            }
+            Stack { .. } => {
+                // The bytecode instruction moves the instruction pointer by itself:
+                self.frame().ip = entry.offset;
+                let byte = self.next_byte()?;
+                return self.dispatch(byte);
+            }
+        }
+
+        self.frame().ip = entry.next;
+        Ok(false)
+    }
+
+    /// Executes the instruction whose opcode is the given byte: with [RunningVM::execute()], or
+    /// with the `dispatch_table` feature, through [HANDLERS]. Returns true once the script has
+    /// returned.
+    #[cfg(not(feature = "dispatch_table"))]
+    #[inline(always)]
+    fn dispatch(&mut self, byte: u8) -> crate::Result<bool> {
+        self.execute(OpCode::try_from(byte).ok())
+    }
+
+    /// Executes the instruction whose opcode is the given byte: with [RunningVM::execute()], or
+    /// with the `dispatch_table` feature, through [HANDLERS]. Returns true once the script has
+    /// returned.
+    #[cfg(feature = "dispatch_table")]
+    #[inline(always)]
+    fn dispatch(&mut self, byte: u8) -> crate::Result<bool> {
+        HANDLERS[byte as usize](self)
+    }
+
+    /// Executes one instruction, whose opcode has just been fetched (`None` if the byte is not a
+    /// valid opcode). Returns true once the script has returned.
+    #[inline(always)]
+    fn execute(&mut self, opcode: Option<OpCode>) -> crate::Result<bool> {
+        use OpCode::*;
+
+        match opcode {
+            Some(Constant) => {
+                let constant = self.next_constant()?;
+                self.push(constant);
+            }
+            Some(Nil) => self.push(Value::Nil),
+            Some(True) => self.push(true.into()),
+            Some(False) => self.push(false.into()),
+            Some(Pop) => {
+                self.pop()?;
+            }
+            Some(opcode @ (GetLocal | GetLocalLong)) => {
+                let slot = match opcode {
+                    GetLocal => self.next_u8()?,
+                    _ => self.next_u16()?,
+                };
+                let value = self.local(slot)?;
+                self.push(value);
+            }
+            Some(opcode @ (SetLocal | SetLocalLong)) => {
+                let slot = match opcode {
+                    SetLocal => self.next_u8()?,
+                    _ => self.next_u16()?,
+                };
+                let slot = self.frame().slots + slot;
+                // Assignment is an expression, so leave the value on the stack.
+                let value = pack(self.peek(0)?);
+                let Some(local) = self.stack.get_mut(slot) else {
+                    return Err(self.invalid_bytecode("no local variable in this slot"));
+                };
+                *local = value;
+            }
+            Some(GetGlobal) => {
+                let offset = self.frame().ip - 1;
+                let name = self.next_u8()?;
+                let value = self.global(offset, name)?;
+                self.push(value);
+            }
+            Some(DefineGlobal) => {
+                let name = self.next_string_constant()?;
+                let value = self.pop()?;
+                self.define_global(name, value, false)?;
+            }
+            Some(DefineConstGlobal) => {
+                let name = self.next_string_constant()?;
+                let value = self.pop()?;
+                self.define_global(name, value, true)?;
+            }
+            Some(SetGlobal) => {
+                let name = self.next_string_constant()?;
+                let value = self.peek(0)?;
+                self.set_global(name, value)?;
+            }
+            Some(Class) => {
+                let name = self.next_string_constant()?;
+                let class = ActiveGC::store_class(value::Class { name });
+                self.push(Value::Class(class));
+            }
+            Some(GetProperty) => {
+                let instance = match self.peek(0)? {
+                    Value::Instance(instance) => instance,
+                    _ => return self.runtime_error("Only instances have properties."),
+                };
+                let name = self.next_string_constant()?;
+                let value = instance.fields.borrow().get(name).copied();
+                match value {
+                    Some(value) => {
+                        self.pop()?; // the instance
+                        self.push(value);
+                    }
+                    None => {
+                        let message = format!("Undefined property '{name}'.");
+                        self.runtime_error(&message)?;
+                    }
+                }
+            }
+            Some(SetProperty) => {
+                let instance = match self.peek(1)? {
+                    Value::Instance(instance) => instance,
+                    _ => return self.runtime_error("Only instances have fields."),
+                };
+                let name = self.next_string_constant()?;
+                let value = self.pop()?;
+                instance.fields.borrow_mut().insert(name, value);
+                self.pop()?; // the instance
+                self.push(value);
+            }
+            Some(BuildList) => {
+                let item_count = self.next_u8()?;
+                let start = self.top_values_start(item_count)?;
+                let items = self.stack_values(start).into_owned();
+                self.stack.truncate(start);
+                let list = ActiveGC::store_list(List::new(items));
+                self.push(Value::List(list));
+            }
+            Some(BuildMap) => {
+                let entry_count = self.next_u8()?;
+                let start = self.top_values_start(2 * entry_count)?;
+                let entries = self.stack_values(start).into_owned();
+                self.stack.truncate(start);
+                let map = ActiveGC::store_map(Map::default());
+                for entry in entries.chunks_exact(2) {
+                    let key = self.map_key(entry[0])?;
+                    map.entries.borrow_mut().insert(key, entry[1]);
+                }
+                self.push(Value::Map(map));
+            }
+            Some(IndexGet) => {
+                let item = match self.peek(1)? {
+                    Value::List(list) => {
+                        let index = self.list_index(list, self.peek(0)?)?;
+                        list.items.borrow()[index]
+                    }
+                    Value::Map(map) => {
+                        let key = self.map_key(self.peek(0)?)?;
+                        let value = map.entries.borrow().get(&key).copied();
+                        match value {
+                            Some(value) => value,
+                            None => {
+                                let message = format!("Undefined key '{}'.", key.value());
+                                return self.runtime_error(&message);
+                            }
+                        }
+                    }
+                    _ => return self.runtime_error("Only lists and maps can be indexed."),
+                };
+                self.pop()?; // the index
+                self.pop()?; // the list or map
+                self.push(item);
+            }
+            Some(IndexSet) => {
+                let value = self.peek(0)?;
+                match self.peek(2)? {
+                    Value::List(list) => {
+                        let index = self.list_index(list, self.peek(1)?)?;
+                        list.items.borrow_mut()[index] = value;
+                    }
+                    Value::Map(map) => {
+                        let key = self.map_key(self.peek(1)?)?;
+                        map.entries.borrow_mut().insert(key, value);
+                    }
+                    _ => return self.runtime_error("Only lists and maps can be indexed."),
+                }
+                self.pop()?; // the value
+                self.pop()?; // the index
+                self.pop()?; // the list or map
+                self.push(value);
+            }
+            Some(Equal) => self.binary_op(Equal)?,
+            Some(Greater) => self.binary_op(Greater)?,
+            Some(Less) => self.binary_op(Less)?,
+            Some(Add) => self.binary_op(Add)?,
+            Some(Subtract) => self.binary_op(Subtract)?,
+            Some(Multiply) => self.binary_op(Multiply)?,
+            Some(Divide) => self.binary_op(Divide)?,
+            Some(BitAnd) => self.binary_op(BitAnd)?,
+            Some(BitOr) => self.binary_op(BitOr)?,
+            Some(BitXor) => self.binary_op(BitXor)?,
+            Some(ShiftLeft) => self.binary_op(ShiftLeft)?,
+            Some(ShiftRight) => self.binary_op(ShiftRight)?,
+            Some(Not) => {
+                let value = self.pop()?;
+                self.push(value.is_falsy().into());
+            }
+            Some(Negate) => {
+                let value = self.pop()?;
+                let negated = self.negate(value)?;
+                self.push(negated);
+            }
+            Some(Call) => {
+                let arg_count = self.next_u8()?;
+                self.call_value(self.peek(arg_count)?, arg_count)?;
+            }
+            Some(Jump) => {
+                let jump = self.next_u16()?;
+                self.frame().ip += jump;
+            }
+            Some(JumpIfFalse) => {
+                let jump = self.next_u16()?;
+                if self.peek(0)?.is_falsy() {
+                    self.frame().ip += jump;
+                }
+            }
+            Some(Loop) => {
+                let jump = self.next_u16()?;
+                let frame = self.frame();
+                frame.ip = match frame.ip.checked_sub(jump) {
+                    Some(ip) => ip,
+                    None => return Err(self.invalid_bytecode("jumped before the chunk")),
+                };
+                self.check_interrupt()?;
+                // Well-formed code never grows the stack in a loop, but bytecode files might:
+                if self.stack.len() > self.limits.max_stack {
+                    self.runtime_error("Stack overflow.")?;
+                }
+            }
+            Some(Import) => {
+                let name = self.next_string_constant()?;
+                self.import(name)?;
+            }
+            Some(Print) => {
+                let value = self.pop()?;
+                writeln!(self.stdout, "{value}").map_err(InterpretationError::WriteError)?;
+            }
+            Some(AddLocals) => {
+                let slots = self.next_u16()?;
+                let lhs = self.local(slots >> 8)?;
+                let rhs = self.local(slots & 0xff)?;
+                let sum = self.add(lhs, rhs)?;
+                self.push(sum);
+            }
+            Some(AddConstant) => {
+                let rhs = self.next_constant()?;
+                let lhs = self.pop()?;
+                let sum = self.add(lhs, rhs)?;
+                self.push(sum);
+            }
+            Some(NotJumpIfFalse) => {
+                let jump = self.next_u16()?;
+                let condition = !self.pop()?.is_falsy();
+                self.push((!condition).into());
+                if condition {
+                    self.frame().ip += jump;
+                }
+            }
+            Some(Return) => {
+                if self.frames.len() == 1 {
+                    // Returning from the script. There is nothing left to run.
+                    self.frames.pop();
+                    return Ok(true);
+                }
+
+                let result = self.pop()?;
+                let frame = self.frames.pop().expect("there should be a frame");
+                // Discard the callee, its arguments, and its locals:
+                self.stack.truncate(frame.slots);
+                self.push(result);
+            }
+            None => return Err(self.invalid_bytecode("unknown opcode")),
+        }
+
+        Ok(false)
+    }
+
+    /// Runs the module with the given name, unless it has already been imported. Either way, one
+    /// value is pushed: `nil` if the module has already been imported, otherwise, once the module
+    /// returns, whatever it returned.
+    fn import(&mut self, name: &'static str) -> crate::Result<()> {
+        // A module's imports are relative to the module itself:
+        let path = match self.importer() {
+            Some(importer) => self.module_resolver.relative_name(importer, name),
+            None => name.to_owned(),
+        };
+        let key = match self.module_resolver.canonical_name(&path) {
+            Ok(key) => key,
+            Err(error) => {
+                return self.runtime_error(&format!("could not import \"{name}\": {error}"));
+            }
+        };
+        if let Some(&module) = self.modules.get(&key) {
+            if self.is_running(module) {
+                let mut cycle = self.importing().collect::<Vec<_>>();
+                cycle.push(name);
+                return self.runtime_error(&format!("import cycle: {}", cycle.join(" -> ")));
+            }
+            self.push(Value::Nil);
+            return Ok(());
+        }
+
+        let source = match self.module_resolver.resolve(&path) {
+            Ok(source) => source,
+            Err(error) => {
+                return self.runtime_error(&format!("could not import \"{name}\": {error}"));
+            }
+        };
+        let options = self.compile_options;
+        let module = match compiler::compile_module(name, &source, self.active_gc, options) {
+            Ok((module, warnings)) => {
+                for warning in warnings {
+                    let _ = writeln!(self.stderr, "{warning}");
+                }
+                ActiveGC::store_function(module)
+            }
+            Err(diagnostics) => {
+                for diagnostic in diagnostics {
+                    let _ = writeln!(self.stderr, "{diagnostic}");
+                }
+                return self.runtime_error(&format!("could not compile \"{name}\""));
+            }
+        };
+        self.modules.insert(key, module);
+
+        self.push(Value::Function(module));
+        self.call_value(Value::Function(module), 0)
+    }
+
+    /// Returns true if the function has a call frame.
+    fn is_running(&self, function: &Function) -> bool {
+        (self.frames.iter()).any(|frame| frame.function.is_some_and(|f| std::ptr::eq(f, function)))
+    }
+
+    /// Returns the canonical name of the innermost module that is running, if any.
+    fn importer(&self) -> Option<&str> {
+        let module = (self.frames.iter().rev())
+            .filter_map(|frame| frame.function)
+            .find(|&function| {
+                (self.modules.values()).any(|&module| std::ptr::eq(module, function))
+            })?;
+        (self.modules.iter())
+            .find(|(_, &m)| std::ptr::eq(m, module))
+            .map(|(key, _)| key.as_str())
+    }
+
+    /// Returns the names of the modules being imported, outermost first.
+    fn importing(&self) -> impl Iterator<Item = &'static str> + '_ {
+        (self.frames.iter())
+            .filter_map(|frame| frame.function)
+            .filter(|&function| {
+                (self.modules.values()).any(|&module| std::ptr::eq(module, function))
+            })
+            .map(|module| module.name)
+    }
+
+    /// Forgets the modules that were still running when the code stopped, so that importing one
+    /// again runs it from the start.
+    fn forget_unfinished_modules(&mut self) {
+        let frames = &self.frames;
+        self.modules.retain(|_, &mut module| {
+            !(frames.iter()).any(|frame| frame.function.is_some_and(|f| std::ptr::eq(f, module)))
+        });
+    }
+
+    /// Calls the callee with the arguments on top of the stack. The callee and the arguments are
+    /// replaced with the return value.
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> crate::Result<()> {
+        match callee {
+            Value::NativeFunction(index) => {
+                let native = &self.natives[index];
+                if arg_count != native.arity as usize {
+                    let message =
+                        format!("Expected {} arguments but got {arg_count}.", native.arity);
+                    return self.runtime_error(&message);
+                }
+
+                let args_start = self.stack.len() - arg_count;
+                let state = VmState {
+                    stack: &self.stack[..args_start - 1],
+                    frames: &self.frames,
+                };
+                let result = (native.function)(&state, &self.stack_values(args_start));
+                match result {
+                    Ok(value) => {
+                        // Pop the arguments AND the callee:
+                        self.stack.truncate(args_start - 1);
+                        self.push(value);
+                        Ok(())
+                    }
+                    // The exit() native stops the code instead of raising a runtime error:
+                    Err(message) => match self.exit_request.take() {
+                        Some(status) => {
+                            self.reset_stack();
+                            Err(InterpretationError::Exit(status))
+                        }
+                        None => self.runtime_error(&message),
+                    },
+                }
+            }
+            Value::Function(function) => {
+                if arg_count != function.arity as usize {
+                    let message =
+                        format!("Expected {} arguments but got {arg_count}.", function.arity);
+                    return self.runtime_error(&message);
+                }
+                if self.frames.len() >= self.limits.max_frames
+                    || self.stack.len() > self.limits.max_stack
+                {
+                    return self.runtime_error("Stack overflow.");
+                }
+                self.check_interrupt()?;
+
+                self.frames.push(CallFrame {
+                    function: Some(function),
+                    chunk: &function.chunk,
+                    code: function.chunk.code(),
+                    #[cfg(feature = "split_operands")]
+                    operands: function.chunk.operands(),
+                    ip: 0,
+                    // The callee itself is in the first slot, followed by the arguments:
+                    slots: self.stack.len() - arg_count - 1,
+                });
+                Ok(())
+            }
+            Value::Class(class) => {
+                // There are no initializers (yet!), so classes take no arguments.
+                if arg_count != 0 {
+                    let message = format!("Expected 0 arguments but got {arg_count}.");
+                    return self.runtime_error(&message);
+                }
+
+                let instance = ActiveGC::store_instance(Instance::new(class));
+                // Replace the callee with the new instance:
+                self.stack.pop();
+                self.push(Value::Instance(instance));
+                Ok(())
+            }
+            _ => self.runtime_error("Can only call functions and classes."),
+        }
+    }
+
+    /// Checks that `list[index]` is valid, returning the index as a `usize`.
+    fn list_index(&mut self, list: &List, index: Value) -> crate::Result<usize> {
+        let index = match index.to_f64() {
+            Some(index) if index.fract() == 0.0 => index,
+            _ => return self.runtime_error("List index must be an integer."),
+        };
+
+        let len = list.len();
+        if index < 0.0 || index >= len as f64 {
+            let message = format!("List index {index} is out of bounds for list of length {len}.");
+            return self.runtime_error(&message);
+        }
+
+        Ok(index as usize)
+    }
+
+    /// Checks that the value can be used as a key in a map.
+    fn map_key(&mut self, key: Value) -> crate::Result<MapKey> {
+        match MapKey::new(key) {
+            Some(key) => Ok(key),
+            None => self.runtime_error("NaN cannot be a map key."),
+        }
+    }
+
+    /// Frees every object that the running program can no longer reach.
+    fn collect_garbage(&self) {
+        let stack = self.stack.iter().copied().map(unpack);
+        let globals = self.globals.roots();
+        let frames = self.frames.iter().flat_map(|frame| {
+            let function = frame.function.map(Value::Function);
+            function.into_iter().chain(frame.chunk.constants())
+        });
+        let pinned = self.pinned.iter().copied();
+        let modules = self.modules.values().map(|&module| Value::Function(module));
+        let roots = stack
+            .chain(globals)
+            .chain(frames)
+            .chain(pinned)
+            .chain(modules);
+        // SAFETY: garbage is only collected between instructions, when everything the program can
+        // reach is on the stack, in a global, or in a call frame's chunk. Natives have returned,
+        // and whoever enabled collection promised that they kept none of their values (see
+        // VM::enable_garbage_collection()).
+        unsafe { ActiveGC::collect(roots) };
+    }
+
+    /// Raises a runtime error with the message, by unwinding the call stack. Every runtime error
+    /// takes this path, so that once Lox can catch errors, a handler can stop the unwinding here.
+    ///
+    /// Nothing catches errors yet, so every frame is unwound, and the code that was running stops
+    /// with [InterpretationError::RuntimeError], whose message is printed with a stack trace.
+    fn runtime_error<T>(&mut self, message: &str) -> crate::Result<T> {
+        // The backtrace is where the error was raised, not where it was caught:
+        let backtrace: Vec<_> = (self.frames.iter().rev())
+            .map(|frame| BacktraceFrame {
+                function: frame.function.map(|function| function.name.to_owned()),
+                line: frame.current_line(),
+            })
+            .collect();
+        let frame = self.frames.last().expect("there should be a frame");
+        let info = RuntimeErrorInfo {
+            message: message.to_owned(),
+            line: backtrace[0].line,
+            span: frame.chunk.span_for(frame.ip - 1),
+            backtrace,
+        };
+
+        // A handler in a frame would stop the unwinding here.
+        while let Some(frame) = self.frames.pop() {
+            self.stack.truncate(frame.slots);
+            // A module that did not finish running can be imported again:
+            if let Some(function) = frame.function {
+                self.modules
+                    .retain(|_, &mut module| !std::ptr::eq(module, function));
+            }
+        }
+
+        // Like eprintln!(), ignore errors writing errors.
+        let _ = write!(self.stderr, "{info}");
+        self.reset_stack();
+
+        Err(InterpretationError::RuntimeError(info))
+    }
+
+    /// Returns the value of the local variable in the given slot of the current frame.
+    #[inline]
+    fn local(&mut self, slot: usize) -> crate::Result<Value> {
+        let slot = self.frame().slots + slot;
+        match self.stack.get(slot) {
+            Some(&value) => Ok(unpack(value)),
+            None => Err(self.invalid_bytecode("no local variable in this slot")),
+        }
+    }
+
+    /// Returns the value of the global variable that the `GetGlobal` instruction at the offset
+    /// reads, whose name is the constant at the given index. Only the first read looks the name
+    /// up: the instruction's inline cache remembers where the global was (see [crate::globals]).
+    #[inline]
+    fn global(&mut self, offset: usize, name: usize) -> crate::Result<Value> {
+        let chunk = self.frames.last().expect("there should be a frame").chunk;
+        let cached = chunk.global_cache().get(offset);
+        if let Some(value) = cached.and_then(|slot| self.globals.get_slot(slot)) {
+            return Ok(value);
+        }
+
+        let name = self.string_constant(name)?;
+        match self.globals.slot(name) {
+            Some(slot) => {
+                chunk.global_cache().set(offset, chunk.len(), slot);
+                Ok(self.globals.get_slot(slot).expect("the global is defined"))
+            }
+            None => {
+                let message = format!("undefined global variable: {name}");
+                self.runtime_error(&message)
+            }
+        }
+    }
+
+    /// Defines the global variable (or constant) with the given name, unless it is already a
+    /// constant.
+    fn define_global(
+        &mut self,
+        name: &'static str,
+        value: Value,
+        constant: bool,
+    ) -> crate::Result<()> {
+        if let Some(line) = self.globals.constant_line(name) {
+            let message =
+                format!("Cannot redefine `{name}`, which is a constant declared on line {line}");
+            return self.runtime_error(&message);
+        }
+
+        if constant {
+            let line = self.frame().current_line();
+            self.globals.insert_constant(name, value, line);
+        } else {
+            self.globals.insert(name, value);
+        }
+        Ok(())
+    }
+
+    /// Assigns the value to the global variable with the given name, which must already exist.
+    #[inline]
+    fn set_global(&mut self, name: &'static str, value: Value) -> crate::Result<()> {
+        if let Some(line) = self.globals.constant_line(name) {
+            let message =
+                format!("Cannot assign to `{name}`, which is a constant declared on line {line}");
+            return self.runtime_error(&message);
+        }
+
+        if self.globals.insert(name, value).is_none() {
+            // Tried to assign to an undefined global variable.
+            // First, clean-up the variable we accidentally created...
+            self.globals.remove(name);
+
+            // THEN, report an error and exit.
+            let message = format!("Undefined variable: '{name}'");
+            self.runtime_error(&message)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the value of an operand of a register instruction.
+    #[inline(always)]
+    fn operand(&mut self, operand: rvm::Operand) -> crate::Result<Value> {
+        match operand {
+            rvm::Operand::Register(register) => self.local(register as usize),
+            rvm::Operand::Constant(index) => self.constant(index as usize),
+            rvm::Operand::Nil => Ok(Value::Nil),
+            rvm::Operand::True => Ok(true.into()),
+            rvm::Operand::False => Ok(false.into()),
+        }
+    }
+
+    /// Leaves `top` registers of the current frame on the stack, then writes the value to one of
+    /// them.
+    #[inline(always)]
+    fn set_register(&mut self, register: rvm::Register, value: Value, top: rvm::Register) {
+        self.set_top(top);
+        // The translator only writes registers that are in use afterwards:
+        let register = self.frame().slots + register as usize;
+        self.stack[register] = pack(value);
+    }
+
+    /// Leaves `top` registers of the current frame on the stack.
+    #[inline(always)]
+    fn set_top(&mut self, top: rvm::Register) {
+        let top = self.frame().slots + top as usize;
+        self.stack.resize(top, pack(Value::Nil));
+    }
+
+    /// Pops RHS, then LHS, and pushes LHS op RHS. See [RunningVM::binary()].
+    #[inline(always)]
+    fn binary_op(&mut self, op: OpCode) -> crate::Result<()> {
+        let rhs = self.pop()?;
+        let lhs = self.pop()?;
+        let result = self.binary(op, lhs, rhs)?;
+        self.push(result);
+        Ok(())
+    }
+
+    /// Returns LHS op RHS, where op is the opcode of a binary operator.
+    #[inline(always)]
+    fn binary(&mut self, op: OpCode, lhs: Value, rhs: Value) -> crate::Result<Value> {
+        use OpCode::*;
+        match op {
+            Equal => Ok(lhs.equal(&rhs).into()),
+            Greater => self.arithmetic(lhs, rhs, |a, b| a > b, |a, b| Ok(a > b)),
+            Less => self.arithmetic(lhs, rhs, |a, b| a < b, |a, b| Ok(a < b)),
+            Add => self.add(lhs, rhs),
+            Subtract => self.arithmetic(
+                lhs,
+                rhs,
+                |a, b| a - b,
+                |a, b| a.checked_sub(b).ok_or(INTEGER_OVERFLOW),
+            ),
+            Multiply => self.arithmetic(
+                lhs,
+                rhs,
+                |a, b| a * b,
+                |a, b| a.checked_mul(b).ok_or(INTEGER_OVERFLOW),
+            ),
+            Divide => self.arithmetic(lhs, rhs, |a, b| a / b, integer_divide),
+            BitAnd => self.bitwise(lhs, rhs, |a, b| Ok(a & b)),
+            BitOr => self.bitwise(lhs, rhs, |a, b| Ok(a | b)),
+            BitXor => self.bitwise(lhs, rhs, |a, b| Ok(a ^ b)),
+            ShiftLeft => self.bitwise(lhs, rhs, |a, b| shift(a, b, i64::checked_shl)),
+            ShiftRight => self.bitwise(lhs, rhs, |a, b| shift(a, b, i64::checked_shr)),
+            _ => Err(self.invalid_bytecode("not a binary operator")),
+        }
+    }
+
+    /// Returns LHS + RHS: the sum of two numbers, or the concatenation of two strings.
+    fn add(&mut self, lhs: Value, rhs: Value) -> crate::Result<Value> {
+        match (&lhs, &rhs) {
+            (Value::Number(a), Value::Number(b)) => Ok((a + b).into()),
+            #[cfg(feature = "integers")]
+            (Value::Integer(a), Value::Integer(b)) => match a.checked_add(*b) {
+                Some(sum) => Ok(sum.into()),
+                None => self.runtime_error(INTEGER_OVERFLOW),
+            },
+            #[cfg(feature = "integers")]
+            (a, b) if a.is_number() && b.is_number() => {
+                Ok((a.to_f64().unwrap() + b.to_f64().unwrap()).into())
+            }
+            (a, b) if a.is_string() && b.is_string() => Ok(concatenate(lhs, rhs)),
+            (a, b)
+                if self.implicit_string_conversion
+                    && (a.is_number() && b.is_string() || a.is_string() && b.is_number()) =>
+            {
+                let lhs = convert_to_string(lhs);
+                let rhs = convert_to_string(rhs);
+                Ok(concatenate(lhs, rhs))
+            }
+            _ => self.runtime_error("Can only add numbers or strings"),
+        }
+    }
+
+    /// Performs an arithmetic operation (or comparison) on two numbers. With the `integers`
+    /// feature, two integers use `integer_op` instead, which returns the message of the runtime
+    /// error if the result is not an integer.
+    #[cfg_attr(not(feature = "integers"), allow(unused_variables))]
+    #[inline(always)]
+    fn arithmetic<F, T, G, U>(
+        &mut self,
+        lhs: Value,
+        rhs: Value,
+        op: F,
+        integer_op: G,
+    ) -> crate::Result<Value>
+    where
+        F: Fn(f64, f64) -> T,
+        T: Into<Value>,
+        G: Fn(i64, i64) -> Result<U, &'static str>,
+        U: Into<Value>,
+    {
+        use Value::Number;
+        match (lhs, rhs) {
+            (Number(a), Number(b)) => Ok(op(a, b).into()),
+            #[cfg(feature = "integers")]
+            (Value::Integer(a), Value::Integer(b)) => match integer_op(a, b) {
+                Ok(result) => Ok(result.into()),
+                Err(message) => self.runtime_error(message),
+            },
+            // An integer is converted to a float, when the other operand is a float:
+            #[cfg(feature = "integers")]
+            (a, b) if a.is_number() && b.is_number() => {
+                Ok(op(a.to_f64().unwrap(), b.to_f64().unwrap()).into())
+            }
+            (_, _) => self.runtime_error("Operands must be numbers"),
+        }
+    }
+
+    /// Performs a bitwise operation on two numbers. Floats are truncated to integers; the result
+    /// is a float, unless both operands are integers (with the `integers` feature). `op` returns
+    /// the message of the runtime error if the operation is invalid.
+    #[inline(always)]
+    fn bitwise<F>(&mut self, lhs: Value, rhs: Value, op: F) -> crate::Result<Value>
+    where
+        F: Fn(i64, i64) -> Result<i64, &'static str>,
+    {
+        let (Some(a), Some(b)) = (truncate(lhs), truncate(rhs)) else {
+            return self.runtime_error("Operands must be numbers");
+        };
+        let result = match op(a, b) {
+            Ok(result) => result,
+            Err(message) => return self.runtime_error(message),
+        };
+
+        #[cfg(feature = "integers")]
+        if let (Value::Integer(_), Value::Integer(_)) = (lhs, rhs) {
+            return Ok(result.into());
+        }
+        Ok((result as f64).into())
+    }
+
+    /// Returns -value, for numbers.
+    fn negate(&mut self, value: Value) -> crate::Result<Value> {
+        match value {
+            Value::Number(number) => Ok((-number).into()),
+            #[cfg(feature = "integers")]
+            Value::Integer(integer) => match integer.checked_neg() {
+                Some(negated) => Ok(negated.into()),
+                None => self.runtime_error(INTEGER_OVERFLOW),
+            },
+            // TODO: rephrase to remove "compiler-speak" from error message:
+            _ => self.runtime_error("Operand must be a number"),
+        }
+    }
+
+    /// Stops with [InterpretationError::Interrupted] if the VM has been interrupted. Only loops
+    /// and calls check, since code can only run for long by looping or calling.
+    #[inline]
+    fn check_interrupt(&self) -> crate::Result<()> {
+        if self.interrupt.load(Ordering::Relaxed) {
+            self.interrupt.store(false, Ordering::Relaxed);
+            return Err(InterpretationError::Interrupted);
+        }
+        Ok(())
+    }
+
+    /// Pushes a [Value] on to the value stack.
+    fn push(&mut self, value: Value) {
+        self.stack.push(pack(value));
+    }
+
+    /// Pops and returns the top [Value] on the value stack.
+    ///
+    /// # Errors
+    ///
+    /// Given well-formed Lox bytecode, a pop cannot occur when the value stack is empty;
+    /// therefore this is [InterpretationError::InvalidBytecode].
+    #[inline(always)]
+    fn pop(&mut self) -> crate::Result<Value> {
+        match self.stack.pop() {
+            Some(value) => Ok(unpack(value)),
+            None => Err(self.invalid_bytecode("popped from an empty stack")),
+        }
+    }
+
+    /// Peek the nth value on the stack, starting from the top.
+    ///
+    /// # Errors
+    ///
+    /// [InterpretationError::InvalidBytecode] when trying to get a value too far down the stack.
+    #[inline(always)]
+    fn peek(&self, n: usize) -> crate::Result<Value> {
+        match self.stack.len().checked_sub(n + 1) {
+            Some(index) => Ok(unpack(self.stack[index])),
+            None => Err(self.invalid_bytecode("peeked past the bottom of the stack")),
+        }
+    }
+
+    /// Returns where the top `count` values on the stack start.
+    #[inline]
+    fn top_values_start(&self, count: usize) -> crate::Result<usize> {
+        (self.stack.len().checked_sub(count))
+            .ok_or_else(|| self.invalid_bytecode("not enough values on the stack"))
+    }
+
+    /// Returns the values on the stack, from `start` to the top.
+    #[inline]
+    fn stack_values(&self, start: usize) -> Cow<'_, [Value]> {
+        #[cfg(feature = "nan_boxing")]
+        return Cow::Owned(self.stack[start..].iter().copied().map(unpack).collect());
+        #[cfg(not(feature = "nan_boxing"))]
+        return Cow::Borrowed(&self.stack[start..]);
+    }
+
+    /// Clears the stack.
+    #[inline(always)]
+    fn reset_stack(&mut self) {
+        self.stack.clear()
+    }
+
+    /// Writes the current stack and the next instruction to the trace writer.
+    fn trace_instruction(&mut self) -> io::Result<()> {
+        use crate::debug::write_instruction;
+
+        let Some(out) = self.trace.as_mut() else {
+            return Ok(());
+        };
+
+        // The current stack:
+        write!(out, "        ")?;
+        if self.stack.is_empty() {
+            write!(out, "<empty>")?;
+        } else {
+            for &value in self.stack.iter() {
+                write!(out, "[ {:?} ]", unpack(value))?;
+            }
+        }
+        writeln!(out)?;
+
+        // The next instruction:
+        let frame = self.frames.last().expect("there should be a frame");
+        let mut instruction = String::new();
+        write_instruction(&mut instruction, frame.chunk, frame.ip).expect("writing to a String");
+        write!(out, "{instruction}")
+    }
+
+    /// Counts the instruction that is about to be executed in the profile.
+    fn profile_instruction(&mut self) {
+        let frame = self.frame();
+        let opcode = frame.code.get(frame.ip).copied().map(OpCode::try_from);
+        let line = frame.chunk.line_number_for(frame.ip);
+        // Invalid bytecode is reported when the instruction is executed:
+        if let (Some(Ok(opcode)), Some(profile)) = (opcode, self.profile.as_deref_mut()) {
+            profile.record(opcode, line, &self.frames);
+        }
+    }
+
+    /// Returns the frame of the function that is currently running.
+    #[inline(always)]
+    fn frame(&mut self) -> &mut CallFrame<'a> {
+        self.frames.last_mut().expect("there should be a frame")
+    }
+
+    /// Fetches the next byte in the chunk, **AND** increments the instruction pointer.
+    #[inline]
+    fn next_byte(&mut self) -> crate::Result<u8> {
+        let frame = self.frame();
+        let byte = frame.code.get(frame.ip).copied();
+        frame.ip += 1;
+        byte.ok_or_else(|| self.invalid_bytecode("ran past the end of the chunk"))
+    }
+
+    /// Fetches a one-byte operand (e.g., an argument count), **AND** advances the instruction
+    /// pointer past it.
+    #[cfg(not(feature = "split_operands"))]
+    #[inline]
+    fn next_u8(&mut self) -> crate::Result<usize> {
+        Ok(self.next_byte()? as usize)
+    }
+
+    /// Fetches a two-byte operand (e.g., a jump offset), **AND** advances the instruction pointer
+    /// past it.
+    #[cfg(not(feature = "split_operands"))]
+    #[inline]
+    fn next_u16(&mut self) -> crate::Result<usize> {
+        let high = self.next_byte()?;
+        let low = self.next_byte()?;
+        Ok(u16::from_be_bytes([high, low]) as usize)
+    }
+
+    /// Fetches a one-byte operand from the operand stream. See [RunningVM::next_operand()].
+    #[cfg(feature = "split_operands")]
+    #[inline]
+    fn next_u8(&mut self) -> crate::Result<usize> {
+        self.next_operand(1)
+    }
+
+    /// Fetches a two-byte operand from the operand stream. See [RunningVM::next_operand()].
+    #[cfg(feature = "split_operands")]
+    #[inline]
+    fn next_u16(&mut self) -> crate::Result<usize> {
+        self.next_operand(2)
+    }
+
+    /// Fetches the operand of the instruction that was just fetched from the operand stream,
+    /// already decoded, **AND** advances the instruction pointer past its `len` bytes in the byte
+    /// stream.
+    #[cfg(feature = "split_operands")]
+    #[inline]
+    fn next_operand(&mut self, len: usize) -> crate::Result<usize> {
+        let frame = self.frame();
+        let operand = frame.operands[frame.ip - 1];
+        frame.ip += len;
+        if frame.ip > frame.code.len() {
+            return Err(self.invalid_bytecode("ran past the end of the chunk"));
+        }
+        Ok(operand as usize)
+    }
+
+    /// Fetches the next byte in the chunk and uses it to index the constant pool.
+    ///
+    /// Note: Like [[next_byte]], this advances the instruction pointer.
+    #[inline]
+    fn next_constant(&mut self) -> crate::Result<Value> {
+        let index = self.next_u8()?;
+        self.constant(index)
+    }
+
+    /// Returns the constant at the given index in the current chunk's constant pool.
+    #[inline]
+    fn constant(&mut self, index: usize) -> crate::Result<Value> {
+        let constant = self.frame().chunk.constant(index);
+        constant.ok_or_else(|| self.invalid_bytecode("there is no constant at this index"))
+    }
+
+    /// Fetches the next bytecode in the chunk and use it to index the constant pool. The constant
+    /// pulled out should be a string (such as global variable name).
+    ///
+    /// Note: Like [[next_byte]], this advances the instruction pointer.
+    #[inline]
+    fn next_string_constant(&mut self) -> crate::Result<&'static str> {
+        let index = self.next_u8()?;
+        self.string_constant(index)
+    }
+
+    /// Returns the constant at the given index, which should be a string.
+    #[inline]
+    fn string_constant(&mut self, index: usize) -> crate::Result<&'static str> {
+        let name = self.constant(index)?.to_str();
+        name.ok_or_else(|| self.invalid_bytecode("the constant must be a string"))
+    }
+
+    /// Returns an [InterpretationError::InvalidBytecode] for the byte that was just fetched.
+    #[cold]
+    fn invalid_bytecode(&self, reason: &'static str) -> InterpretationError {
+        let offset = self
+            .frames
+            .last()
+            .map_or(0, |frame| frame.ip.saturating_sub(1));
+        InterpretationError::InvalidBytecode { offset, reason }
+    }
+}
+
+/// Concatenates two strings (or ropes), for `+`. Long results are ropes, so that adding to a
+/// string in a loop does not copy the whole string every time.
+fn concatenate(lhs: Value, rhs: Value) -> Value {
+    let rope = Rope::new(lhs, rhs);
+    if rope.len() < MIN_ROPE_LEN {
+        return format!("{lhs}{rhs}").into();
+    }
+    Value::Rope(ActiveGC::store_rope(rope))
+}
+
+/// Converts a number to a string, for `+` with implicit string conversion. Strings are unchanged.
+fn convert_to_string(value: Value) -> Value {
+    match value {
+        value if value.is_number() => value.to_string().into(),
+        value => value,
+    }
+}
+
+/// Returns the number truncated to an integer, for the bitwise operators, or `None` if the value
+/// is not a number.
+fn truncate(value: Value) -> Option<i64> {
+    match value {
+        Value::Number(number) => Some(number as i64),
+        #[cfg(feature = "integers")]
+        Value::Integer(integer) => Some(integer),
+        _ => None,
+    }
+}
+
+/// Shifts an integer, for `<<` and `>>`. Shifting by a negative number of bits, or by more bits
+/// than there are, is an error.
+fn shift(a: i64, bits: i64, op: fn(i64, u32) -> Option<i64>) -> Result<i64, &'static str> {
+    u32::try_from(bits)
+        .ok()
+        .and_then(|bits| op(a, bits))
+        .ok_or("Can only shift by 0 to 63 bits.")
+}
+
+/// Divides two integers, for `/` (with the `integers` feature). The result is truncated.
+fn integer_divide(a: i64, b: i64) -> Result<i64, &'static str> {
+    if b == 0 {
+        return Err("Division by zero.");
+    }
+    a.checked_div(b).ok_or(INTEGER_OVERFLOW)
+}
+
+/// Converts a value to what the stack holds.
+#[inline(always)]
+fn pack(value: Value) -> StackValue {
+    #[cfg(feature = "nan_boxing")]
+    return value.into();
+    #[cfg(not(feature = "nan_boxing"))]
+    return value;
+}
+
+/// Converts what the stack holds back to a value.
+#[inline(always)]
+fn unpack(value: StackValue) -> Value {
+    #[cfg(feature = "nan_boxing")]
+    return value.unpack();
+    #[cfg(not(feature = "nan_boxing"))]
+    return value;
+}
+
+/// Returns true if the source code ends a statement (or has no statements at all): every bracket
+/// is closed, and the last token is `;` or `}`. See [VM::interpret_reader()].
+fn ends_statement(source: &str) -> bool {
+    use Token::*;
+    let mut depth = 0;
+    let mut last = None;
+    for lexeme in Scanner::new(source) {
+        match lexeme.token() {
+            Eof => break,
+            LeftParen | LeftBrace | LeftBracket => depth += 1,
+            RightParen | RightBrace | RightBracket => depth -= 1,
+            _ => (),
+        }
+        // An unterminated string or comment is an error, so it does not end the statement.
+        last = Some(lexeme.token());
+    }
+
+    depth <= 0 && matches!(last, None | Some(Semicolon | RightBrace))
+}
+
+/// Returns true if the line continues the statement before it, because it starts with a token
+/// that cannot start a statement, like `+` or `.`. See [VM::interpret_reader()].
+fn continues_statement(line: &str) -> bool {
+    use Token::*;
+    matches!(
+        Scanner::new(line).scan_token().token(),
+        Semicolon
+            | Dot
+            | Comma
+            | Colon
+            | RightParen
+            | RightBracket
+            | Plus
+            | Star
+            | Slash
+            | Ampersand
+            | Pipe
+            | Caret
+            | Equal
+            | EqualEqual
+            | BangEqual
+            | Less
+            | LessEqual
+            | LessLess
+            | Greater
+            | GreaterEqual
+            | GreaterGreater
+            | And
+            | Or
+            | Else
+    )
+}
+
+impl<'a> CallFrame<'a> {
+    /// Creates the frame for the top-level script.
+    fn script(chunk: &'a Chunk) -> Self {
+        CallFrame {
+            function: None,
+            chunk,
+            code: chunk.code(),
+            #[cfg(feature = "split_operands")]
+            operands: chunk.operands(),
+            ip: 0,
+            slots: 0,
+        }
+    }
+
+    /// Returns the line of the next instruction, if it is the first instruction on that line.
+    fn starting_line(&self) -> Option<usize> {
+        let line = self.chunk.line_number_for(self.ip)?;
+        let previous = (self.ip.checked_sub(1)).and_then(|ip| self.chunk.line_number_for(ip));
+        (previous != Some(line)).then_some(line)
+    }
+
+    /// Returns the line of the instruction that is currently executing in this frame.
+    fn current_line(&self) -> usize {
+        self.chunk
+            .line_number_for(self.ip - 1)
+            .expect("line number")
+    }
+}
+
+////////////////////////////////////////// Dispatch table //////////////////////////////////////////
+
+/// Executes one instruction, like [RunningVM::execute()]. Returns true once the script has
+/// returned.
+#[cfg(feature = "dispatch_table")]
+type Handler = fn(&mut RunningVM<'_>) -> crate::Result<bool>;
+
+/// Builds the table of handlers for the given opcodes, which must be all of them.
+#[cfg(feature = "dispatch_table")]
+macro_rules! handlers {
+    ($($opcode:ident),* $(,)?) => {{
+        // Fails to compile when an opcode is missing:
+        const _: fn(OpCode) = |opcode| match opcode {
+            $(OpCode::$opcode => {})*
+        };
+
+        let mut table: [Handler; 256] = [handle_unknown; 256];
+        $(table[OpCode::$opcode as usize] = handle::<{ OpCode::$opcode as u8 }>;)*
+        table
+    }};
+}
+
+/// With the `dispatch_table` feature, the VM dispatches each instruction by calling the handler
+/// at its opcode in this table, instead of matching on the opcode. Bytes that are not opcodes are
+/// handled as unknown opcodes.
+#[cfg(feature = "dispatch_table")]
+static HANDLERS: [Handler; 256] = handlers![
+    Constant,
+    Nil,
+    True,
+    False,
+    Pop,
+    GetLocal,
+    SetLocal,
+    GetLocalLong,
+    SetLocalLong,
+    GetGlobal,
+    DefineGlobal,
+    DefineConstGlobal,
+    SetGlobal,
+    Class,
+    GetProperty,
+    SetProperty,
+    BuildList,
+    BuildMap,
+    IndexGet,
+    IndexSet,
+    Equal,
+    Greater,
+    Less,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    ShiftRight,
+    Not,
+    Negate,
+    Call,
+    Jump,
+    JumpIfFalse,
+    Loop,
+    Print,
+    Import,
+    Return,
+    AddLocals,
+    AddConstant,
+    NotJumpIfFalse
+];
+
+/// The handler for the opcode `OPCODE`. Since the opcode is a constant, [RunningVM::execute()] is
+/// inlined and reduced to the one arm that handles it.
+#[cfg(feature = "dispatch_table")]
+fn handle<const OPCODE: u8>(vm: &mut RunningVM<'_>) -> crate::Result<bool> {
+    vm.execute(OpCode::try_from(OPCODE).ok())
+}
+
+/// The handler for bytes that are not opcodes.
+#[cfg(feature = "dispatch_table")]
+fn handle_unknown(vm: &mut RunningVM<'_>) -> crate::Result<bool> {
+    vm.execute(None)
+}
+
+//////////////////////////////////////////// Profiler /////////////////////////////////////////////
+
+/// How many times each kind of instruction, each line of source code, and each call stack was
+/// executed while profiling. See [VM::enable_profiling()].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Profile {
+    /// How many instructions with each opcode were executed.
+    pub opcodes: HashMap<OpCode, u64>,
+    /// How many instructions were executed for each line of source code.
+    pub lines: BTreeMap<usize, u64>,
+    /// Every call stack that executed instructions, in the order they were first seen.
+    pub stacks: Vec<StackProfile>,
+    /// The index of each call stack in `stacks`.
+    stack_indices: HashMap<Vec<String>, usize>,
+    /// The call stack that is running: its depth, its index in `stacks`, and since when.
+    current: Option<(usize, usize, Instant)>,
+}
+
+/// What was executed by one call stack, not counting the functions it called.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StackProfile {
+    /// The names of the functions being called, starting with `script`, and ending with the
+    /// function that executed the instructions.
+    pub functions: Vec<String>,
+    /// How many instructions were executed.
+    pub instructions: u64,
+    /// How much (wall clock) time was spent executing them.
+    pub time: Duration,
+}
+
+/// What was executed by one function. See [Profile::functions()].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FunctionProfile {
+    /// The name of the function, or `script` for the top-level script.
+    pub name: String,
+    /// How many instructions were executed by the function itself.
+    pub exclusive_instructions: u64,
+    /// How many instructions were executed by the function, and every function it called.
+    pub inclusive_instructions: u64,
+    /// How much time the function itself spent executing.
+    pub exclusive_time: Duration,
+    /// How much time the function, and every function it called, spent executing.
+    pub inclusive_time: Duration,
+}
+
+impl Profile {
+    /// The total number of instructions executed.
+    pub fn instructions(&self) -> u64 {
+        self.opcodes.values().sum()
+    }
+
+    /// Returns what each function executed, from the most instructions (inclusive) to the least.
+    /// Functions are told apart by their names.
+    pub fn functions(&self) -> Vec<FunctionProfile> {
+        let mut functions = HashMap::<&str, FunctionProfile>::new();
+        for stack in &self.stacks {
+            let Some((innermost, _)) = stack.functions.split_last() else {
+                continue;
+            };
+            // Recursive calls are only included once:
+            let distinct: BTreeSet<_> = stack.functions.iter().collect();
+            for name in distinct {
+                let function = functions.entry(name).or_default();
+                function.inclusive_instructions += stack.instructions;
+                function.inclusive_time += stack.time;
+            }
+            let function = functions.get_mut(innermost.as_str()).unwrap();
+            function.exclusive_instructions += stack.instructions;
+            function.exclusive_time += stack.time;
+        }
+
+        let mut functions: Vec<_> = (functions.into_iter())
+            .map(|(name, function)| FunctionProfile {
+                name: name.to_owned(),
+                ..function
+            })
+            .collect();
+        functions.sort_by(|a, b| {
+            (b.inclusive_instructions.cmp(&a.inclusive_instructions)).then(a.name.cmp(&b.name))
+        });
+        functions
+    }
+
+    /// Writes the instructions executed by each call stack in the "collapsed stack" format that
+    /// [flamegraph.pl](https://github.com/brendangregg/FlameGraph) and
+    /// [inferno](https://github.com/jonhoo/inferno) read: one line per call stack, with the
+    /// function names separated by semicolons, then the number of instructions.
+    ///
+    /// ```
+    /// # use rlox::prelude::*;
+    /// let mut vm = VM::default();
+    /// vm.enable_profiling();
+    /// vm.interpret("fun f() {}\nf();").unwrap();
+    ///
+    /// let mut folded = Vec::new();
+    /// vm.profile_report().unwrap().write_folded(&mut folded).unwrap();
+    /// // Closure, DefineGlobal, GetGlobal, Call, Pop, Return; then Nil, Return in f():
+    /// assert_eq!("script 6\nscript;f 2\n", String::from_utf8(folded).unwrap());
+    /// ```
+    pub fn write_folded(&self, out: &mut impl Write) -> io::Result<()> {
+        let mut stacks: Vec<_> = self.stacks.iter().collect();
+        stacks.sort_by(|a, b| a.functions.cmp(&b.functions));
+        for stack in stacks {
+            writeln!(out, "{} {}", stack.functions.join(";"), stack.instructions)?;
+        }
+        Ok(())
+    }
+
+    /// Counts one execution of an instruction on the given line (if known), by the innermost of
+    /// the given call frames.
+    fn record(&mut self, opcode: OpCode, line: Option<usize>, frames: &[CallFrame]) {
+        *self.opcodes.entry(opcode).or_default() += 1;
+        if let Some(line) = line {
+            *self.lines.entry(line).or_default() += 1;
+        }
+
+        // An instruction calls or returns from at most one function, so the call stack has
+        // changed if, and only if, its depth has changed:
+        let index = match self.current {
+            Some((depth, index, _)) if depth == frames.len() => index,
+            _ => {
+                self.pause();
+                let index = self.stack_index(frames);
+                self.current = Some((frames.len(), index, Instant::now()));
+                index
+            }
+        };
+        self.stacks[index].instructions += 1;
+    }
+
+    /// Stops timing the call stack that is running, e.g., because the VM has stopped.
+    fn pause(&mut self) {
+        if let Some((_, index, since)) = self.current.take() {
+            self.stacks[index].time += since.elapsed();
+        }
+    }
+
+    /// Returns the index of the call stack in `stacks`, adding it if it is new.
+    fn stack_index(&mut self, frames: &[CallFrame]) -> usize {
+        let functions: Vec<_> = (frames.iter())
+            .map(|frame| frame.function.map_or("script", |function| function.name))
+            .map(str::to_owned)
+            .collect();
+        if let Some(&index) = self.stack_indices.get(&functions) {
+            return index;
+        }
+
+        self.stacks.push(StackProfile {
+            functions: functions.clone(),
+            instructions: 0,
+            time: Duration::ZERO,
+        });
+        self.stack_indices.insert(functions, self.stacks.len() - 1);
+        self.stacks.len() - 1
+    }
+}
+
+//////////////////////////////////////////// Debugger ////////////////////////////////////////////
+
+/// Runs a chunk one instruction at a time, so that the program can be inspected while it runs.
+/// Create one with [VM::debug()].
+///
+/// ```
+/// # use rlox::prelude::*;
+/// use rlox::vm::Stopped;
+///
+/// let mut vm = VM::default();
+/// let chunk = vm.compile("var a = 0.5;\nvar b = a + 1;\nprint b;").unwrap();
+/// let mut debugger = vm.debug(&chunk);
+/// debugger.add_breakpoint(3);
+///
+/// assert_eq!(Stopped::Breakpoint { line: 3 }, debugger.resume().unwrap());
+/// assert_eq!(Ok(1.5), debugger.global("b").unwrap().get::<f64>());
+/// assert_eq!(Stopped::Step, debugger.step().unwrap()); // OP_GET_GLOBAL
+/// let stack: Vec<_> = debugger.stack().iter().map(ValueRef::to_string).collect();
+/// assert_eq!(vec!["1.5"], stack);
+/// assert_eq!(Stopped::Finished, debugger.resume().unwrap()); // prints 1.5
+/// ```
+pub struct Debugger<'a> {
+    vm: &'a mut VM,
+    /// The chunk of the top-level script.
+    chunk: &'a Chunk,
+    /// The call frames, which are empty once the script has finished.
+    frames: Vec<CallFrame<'a>>,
+    stack: Vec<StackValue>,
+    instructions: u64,
+    /// Lines that [Debugger::resume()] stops at.
+    breakpoints: BTreeSet<usize>,
+}
+
+/// Why a [Debugger] stopped running.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stopped {
+    /// One instruction was executed.
+    Step,
+    /// The next instruction is the first instruction on a line with a breakpoint.
+    Breakpoint { line: usize },
+    /// The script has returned, or stopped with a runtime error. Nothing is left to run.
+    Finished,
+}
+
+impl Debugger<'_> {
+    /// Executes exactly one instruction. Calling a function steps into it.
+    pub fn step(&mut self) -> crate::Result<Stopped> {
+        self.run(|_| true)
+    }
+
+    /// Runs until the program reaches a breakpoint, or finishes.
+    pub fn resume(&mut self) -> crate::Result<Stopped> {
+        let breakpoints = std::mem::take(&mut self.breakpoints);
+        let stopped = self
+            .run(|frame| (frame.starting_line()).is_some_and(|line| breakpoints.contains(&line)));
+        self.breakpoints = breakpoints;
+
+        match stopped? {
+            Stopped::Step => Ok(Stopped::Breakpoint {
+                line: self.line().expect("stopped at a line"),
+            }),
+            stopped => Ok(stopped),
+        }
+    }
+
+    /// Adds a breakpoint: [Debugger::resume()] will stop before running the given line.
+    pub fn add_breakpoint(&mut self, line: usize) {
+        self.breakpoints.insert(line);
+    }
+
+    /// Removes a breakpoint. Returns whether there was a breakpoint at that line.
+    pub fn remove_breakpoint(&mut self, line: usize) -> bool {
+        self.breakpoints.remove(&line)
+    }
+
+    /// Returns true once there is nothing left to run.
+    pub fn is_finished(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Returns the offset of the next instruction in the current function's chunk.
+    pub fn ip(&self) -> Option<usize> {
+        self.frames.last().map(|frame| frame.ip)
+    }
+
+    /// Returns the line of the next instruction.
+    pub fn line(&self) -> Option<usize> {
+        (self.frames.last()).and_then(|frame| frame.chunk.line_number_for(frame.ip))
+    }
+
+    /// Returns the calls in progress, innermost first. The line of the innermost call is the line
+    /// of the next instruction.
+    pub fn backtrace(&self) -> Vec<BacktraceFrame> {
+        let innermost = self.frames.len().saturating_sub(1);
+        (self.frames.iter().enumerate().rev())
+            .map(|(i, frame)| BacktraceFrame {
+                function: frame.function.map(|function| function.name.to_owned()),
+                line: if i == innermost {
+                    frame.chunk.line_number_for(frame.ip).expect("line number")
+                } else {
+                    frame.current_line()
+                },
+            })
+            .collect()
+    }
+
+    /// Returns the contents of the value stack, from the bottom to the top. The values are
+    /// borrowed from the debugger, since their objects may be freed once the program continues.
+    pub fn stack(&self) -> Vec<ValueRef<'_>> {
+        self.stack
+            .iter()
+            .map(|&value| ValueRef::new(unpack(value)))
+            .collect()
+    }
+
+    /// Returns the value of the global variable with the given name, if it is defined.
+    pub fn global(&self, name: &str) -> Option<ValueRef<'_>> {
+        self.vm.global(name).map(ValueRef::new)
+    }
+
+    /// Returns every global variable (including natives), sorted by name.
+    pub fn globals(&self) -> Vec<(&str, ValueRef<'_>)> {
+        let mut globals: Vec<_> = (self.vm.globals.iter())
+            .map(|(name, value)| (name, ValueRef::new(value)))
+            .collect();
+        globals.sort_by_key(|&(name, _)| name);
+        globals
+    }
+
+    /// Runs one instruction at a time, until `should_stop()` returns true for the frame that is
+    /// about to run, or the program finishes.
+    fn run(&mut self, should_stop: impl Fn(&CallFrame) -> bool) -> crate::Result<Stopped> {
+        if self.is_finished() {
+            return Ok(Stopped::Finished);
+        }
+
+        let chunk = self.chunk;
+        let frames = std::mem::take(&mut self.frames);
+        let stack = std::mem::take(&mut self.stack);
+        let instructions = self.instructions;
+
+        let (result, frames, stack, instructions) = self.vm.with_gc(|vm, active_gc| {
+            let mut vm = vm.running(frames, stack, instructions, active_gc);
+            let result = loop {
+                vm.pause_at = vm.instructions + 1;
+                match vm.run() {
+                    Err(error) => break Err(error),
+                    Ok(()) if vm.frames.is_empty() => break Ok(Stopped::Finished),
+                    Ok(()) if should_stop(vm.frames.last().unwrap()) => break Ok(Stopped::Step),
+                    Ok(()) => continue,
+                }
+            };
+            // Time spent stopped in the debugger is not time spent running:
+            if let Some(profile) = vm.profile.as_deref_mut() {
+                profile.pause();
+            }
+
+            // The frames only borrow their chunks for this call, so borrow them again:
+            let frames = match result {
+                Err(_) => Vec::new(),
+                Ok(_) => (vm.frames.iter())
+                    .map(|frame| {
+                        let chunk = frame.function.map_or(chunk, |function| &function.chunk);
+                        CallFrame {
+                            chunk,
+                            code: chunk.code(),
+                            #[cfg(feature = "split_operands")]
+                            operands: chunk.operands(),
+                            ..*frame
+                        }
+                    })
+                    .collect(),
+            };
+            (
+                result,
+                frames,
+                std::mem::take(&mut vm.stack),
+                vm.instructions,
+            )
+        });
+
+        self.frames = frames;
+        self.stack = stack;
+        self.instructions = instructions;
+        result
+    }
+}
+
+/// A buffer that can be written to by the VM, and read from afterwards.
+#[derive(Clone, Default)]
+pub(crate) struct SharedBuffer(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    /// Returns everything written to the buffer.
+    pub(crate) fn into_string(self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+
+    /// Returns everything written to the buffer so far, and empties it.
+    #[cfg_attr(not(feature = "dap"), allow(dead_code))]
+    pub(crate) fn take_string(&self) -> String {
+        let bytes = std::mem::take(&mut *self.0.borrow_mut());
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////// Tests //////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+
+    use super::*;
+    use serial_test::serial;
+
+    /// Runs the source code in a fresh VM, returning what it printed.
+    fn run(source: &str) -> String {
+        let stdout = SharedBuffer::default();
+        let mut vm = VM::default();
+        // SAFETY: the natives that every VM has never keep a value.
+        unsafe { vm.enable_garbage_collection() };
+        vm.set_stdout(Box::new(stdout.clone()));
+        vm.interpret(source)
+            .expect("program should run without errors");
+        drop(vm);
+        stdout.into_string()
+    }
+
+    #[test]
+    #[serial]
+    fn functions() {
+        let source = "fun add(a, b) { return a + b; } print add(1, 2); print add;";
+        assert_eq!("3\n<fn add>\n", run(source));
+
+        // Functions without a return statement return nil:
+        assert_eq!("nil\n", run("fun f() {} print f();"));
+
+        // Recursion:
+        let source = "fun fib(n) { return n < 2 and n or fib(n - 1) + fib(n - 2); } print fib(10);";
+        assert_eq!("55\n", run(source));
+
+        // Local functions:
+        let source = "{ var a = 1; fun double(n) { return n * 2; } print double(21); print a; }";
+        assert_eq!("42\n1\n", run(source));
+    }
+
+    #[test]
+    #[serial]
+    fn warnings_know_the_globals_of_the_vm() {
+        let stderr = SharedBuffer::default();
+        let mut vm = VM::default();
+        vm.set_stderr(Box::new(stderr.clone()));
+        vm.set_compile_options(CompileOptions {
+            warnings: true,
+            ..CompileOptions::default()
+        });
+
+        vm.interpret("var a = 1;").unwrap();
+        vm.interpret("{ var unused; }\nprint a + clock();").unwrap();
+        assert!(vm.interpret("print b;").is_err());
+        drop(vm);
+
+        let expected = "\
+[line 1] Warning at 'unused': Unused variable `unused` (start its name with `_` if this is on purpose)
+[line 1] Warning at 'b': Undefined variable `b`
+undefined global variable: b
+[line 1] in script
+";
+        assert_eq!(expected, stderr.into_string());
+    }
+
+    #[test]
+    #[serial]
+    fn runtime_errors_in_functions_print_a_stack_trace() {
+        let stderr = SharedBuffer::default();
+        let mut vm = VM::default();
+        vm.set_stderr(Box::new(stderr.clone()));
+
+        let source = "fun inner() {\n  -nil;\n}\nfun outer() { inner(); }\nouter();";
+        let Err(InterpretationError::RuntimeError(info)) = vm.interpret(source) else {
+            panic!("expected a runtime error");
+        };
+        drop(vm);
+
+        let frame = |function: Option<&str>, line| BacktraceFrame {
+            function: function.map(str::to_owned),
+            line,
+        };
+        let backtrace = vec![
+            frame(Some("inner"), 2),
+            frame(Some("outer"), 4),
+            frame(None, 5),
+        ];
+        assert_eq!(backtrace, info.backtrace);
+        assert_eq!(Some("-"), info.span.clone().map(|span| &source[span]));
+
+        let expected = "\
+Operand must be a number
+[line 2] in inner()
+[line 4] in outer()
+[line 5] in script
+";
+        assert_eq!(expected, stderr.into_string());
+        assert_eq!(expected, info.to_string());
+    }
+
+    #[test]
+    #[serial]
+    fn runtime_errors_point_to_the_source_code() {
+        for (source, culprit) in [
+            ("print 1 +\n nil;", "+"),
+            ("print nil + 1;", "+"),
+            ("fun f(a, b) { return a + b; } f(1, nil);", "+"),
+            ("print 1 < 2 == (3 >= nil);", ">="),
+            ("print undefined;", "undefined"),
+            ("class A {} print A().missing;", "missing"),
+            ("var list = [1]; list[1];", "]"),
+        ] {
+            let mut vm = VM::default();
+            vm.set_stderr(Box::new(SharedBuffer::default()));
+            let Err(InterpretationError::RuntimeError(info)) = vm.interpret(source) else {
+                panic!("expected a runtime error: {source}");
+            };
+            assert_eq!(Some(culprit), info.span.clone().map(|span| &source[span]));
+        }
+
+        // Bytecode files do not know about the source code:
+        let mut vm = VM::default();
+        vm.set_stderr(Box::new(SharedBuffer::default()));
+        let bytecode = vm.compile("-nil;").unwrap().serialize();
+        let Err(InterpretationError::RuntimeError(info)) = vm.interpret_bytecode(&bytecode) else {
+            panic!("expected a runtime error");
+        };
+        assert_eq!(None, info.span);
+    }
+
+    #[test]
+    #[serial]
+    fn classes_and_instances() {
+        let source = "class Pair {} var pair = Pair(); print Pair; print pair;";
+        assert_eq!("Pair\nPair instance\n", run(source));
+
+        let source =
+            "class Pair {} var p = Pair(); p.first = 1; p.second = 2; print p.first + p.second;";
+        assert_eq!("3\n", run(source));
+
+        // Assignment to a field is an expression, and fields can be reassigned:
+        let source = "class A {} var a = A(); print a.x = \"x\"; a.x = a.x + \"y\"; print a.x;";
+        assert_eq!("x\nxy\n", run(source));
+
+        // Instances can be nested, and classes can be declared locally:
+        let source = "{ class Node {} var n = Node(); n.next = Node(); n.next.value = 42; print n.next.value; }";
+        assert_eq!("42\n", run(source));
+    }
+
+    #[test]
+    #[serial]
+    fn invalid_property_access() {
+        for (source, message) in [
+            (
+                "class A {} print A().missing;",
+                "Undefined property 'missing'.\n",
+            ),
+            ("var n = 1; print n.x;", "Only instances have properties.\n"),
+            ("var n = 1; n.x = 2;", "Only instances have fields.\n"),
+            ("class A {} A(1);", "Expected 0 arguments but got 1.\n"),
+        ] {
+            let stderr = SharedBuffer::default();
+            let mut vm = VM::default();
+            vm.set_stderr(Box::new(stderr.clone()));
+            assert!(vm.interpret(source).is_err());
+            drop(vm);
+
+            let expected = format!("{message}[line 1] in script\n");
+            assert_eq!(expected, stderr.into_string());
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn lists() {
+        let source = "var xs = [1, \"two\", [3]]; print xs; print xs[1]; print xs[2][0]; print [];";
+        assert_eq!("[1, two, [3]]\ntwo\n3\n[]\n", run(source));
+
+        // Assignment to an item is an expression, and lists are shared:
+        let source =
+            "var xs = [1, 2,]; var ys = xs; print ys[0] = 10; xs[1] = xs[1] + 1; print ys;";
+        assert_eq!("10\n[10, 3]\n", run(source));
+
+        // A list can contain itself:
+        let source = "var xs = [nil]; xs[0] = xs; print xs;";
+        assert_eq!("[[...]]\n", run(source));
+    }
+
+    #[test]
+    #[serial]
+    fn maps() {
+        let source = "var m = {\"b\": 2, \"a\": 1,}; print m; print m[\"a\"]; print {};";
+        assert_eq!("{a: 1, b: 2}\n1\n{}\n", run(source));
+
+        // Keys are compared like Lox values: strings and numbers by value, objects by identity:
+        let source = "
+            class K {}
+            var k = K();
+            var m = {0: \"zero\", k: \"k\", nil: \"nil\"};
+            m[\"x\" + \"y\"] = m[-0] + m[k];
+            print m[\"xy\"];
+            print m[nil];
+        ";
+        assert_eq!("zerok\nnil\n", run(source));
+    }
+
+    #[test]
+    #[serial]
+    #[cfg_attr(
+        feature = "integers",
+        ignore = "number literals are integers with the integers feature"
+    )]
+    fn invalid_map_access() {
+        for (source, message) in [
+            ("print {\"a\": 1}[\"b\"];", "Undefined key 'b'.\n"),
+            ("var m = {}; m[0/0] = 1;", "NaN cannot be a map key.\n"),
+        ] {
+            let stderr = SharedBuffer::default();
+            let mut vm = VM::default();
+            vm.set_stderr(Box::new(stderr.clone()));
+            assert!(vm.interpret(source).is_err());
+            drop(vm);
+
+            let expected = format!("{message}[line 1] in script\n");
+            assert_eq!(expected, stderr.into_string());
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn invalid_list_indexing() {
+        for (source, message) in [
+            (
+                "print [1, 2][2];",
+                "List index 2 is out of bounds for list of length 2.\n",
+            ),
+            (
+                "var xs = [1]; xs[-1] = 0;",
+                "List index -1 is out of bounds for list of length 1.\n",
+            ),
+            ("print [1][0.5];", "List index must be an integer.\n"),
+            ("print [1][\"0\"];", "List index must be an integer.\n"),
+            (
+                "var n = 1; print n[0];",
+                "Only lists and maps can be indexed.\n",
+            ),
+        ] {
+            let stderr = SharedBuffer::default();
+            let mut vm = VM::default();
+            vm.set_stderr(Box::new(stderr.clone()));
+            assert!(vm.interpret(source).is_err());
+            drop(vm);
+
+            let expected = format!("{message}[line 1] in script\n");
+            assert_eq!(expected, stderr.into_string());
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn errors_describe_what_went_wrong() {
+        let mut vm = VM::default();
+        vm.set_stderr(Box::new(SharedBuffer::default()));
+
+        match vm.interpret("var a = 1;\nprint a +;\nvar = 2;") {
+            Err(InterpretationError::CompileError(diagnostics)) => {
+                assert_eq!(2, diagnostics.len());
+                assert_eq!(
+                    (2, ";"),
+                    (diagnostics[0].line, diagnostics[0].lexeme.as_str())
+                );
+                assert_eq!(
+                    (3, "="),
+                    (diagnostics[1].line, diagnostics[1].lexeme.as_str())
+                );
+            }
+            other => panic!("expected a compile error, got {other:?}"),
+        }
+
+        match vm.interpret("var a = 1;\n-nil;") {
+            Err(InterpretationError::RuntimeError(info)) => {
+                assert_eq!("Operand must be a number", info.message);
+                assert_eq!(2, info.line);
+            }
+            other => panic!("expected a runtime error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn typed_natives_convert_their_arguments() {
+        let mut vm = VM::default();
+        vm.set_stderr(Box::new(SharedBuffer::default()));
+        vm.define_typed_native(
+            "repeat",
+            |s: String, times: f64, separator: Option<String>| {
+                vec![s; times as usize].join(&separator.unwrap_or_default())
+            },
+        );
+        vm.define_typed_native("answer", || 42.0);
+
+        let mut message = |source: &str| match vm.interpret(source) {
+            Err(InterpretationError::RuntimeError(info)) => info.message,
+            other => panic!("expected a runtime error, got {other:?}"),
+        };
+        assert_eq!(
+            "repeat(): expected a number, but got a string",
+            message("repeat(\"a\", \"3\", nil);")
+        );
+        assert_eq!(
+            "Expected 3 arguments but got 2.",
+            message("repeat(\"a\", 3);")
+        );
+        assert_eq!("Expected 0 arguments but got 1.", message("answer(1);"));
+
+        let stdout = SharedBuffer::default();
+        vm.set_stdout(Box::new(stdout.clone()));
+        vm.interpret(
+            "print repeat(\"ab\", 3, \"-\"); print repeat(\"c\", 2, nil); print answer();",
+        )
+        .unwrap();
+        drop(vm);
+        assert_eq!("ab-ab-ab\ncc\n42\n", stdout.into_string());
+    }
+
+    #[test]
+    #[serial]
+    fn natives_can_see_the_code_that_called_them() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = VM::default();
+        let record = Rc::clone(&seen);
+        vm.define_native_with_state("inspect", 1, move |state, args| {
+            let stack: Vec<_> = (state.stack_snapshot().iter())
+                .map(ValueRef::to_string)
+                .collect();
+            let lines: Vec<_> = (state.backtrace().iter()).map(|frame| frame.line).collect();
+            let entry = format!("{} {stack:?} {lines:?} {}", args[0], state.call_depth());
+            record.borrow_mut().push(entry);
+            Ok(Value::Nil)
+        });
+        vm.interpret(
+            "inspect(1);
+             fun f(a) {
+               var b = a + \"1\";
+               inspect(b);
+             }
+             f(\"x\");",
+        )
+        .unwrap();
+
+        assert_eq!(
+            vec![
+                "1 [] [1] 1".to_owned(),
+                "x1 [\"<fn f>\", \"x\", \"x1\"] [4, 6] 2".to_owned(),
+            ],
+            *seen.borrow()
+        );
+        assert_eq!("0\n", run("print stackDepth();"));
+        assert_eq!("2\n", run("fun f(a) { return stackDepth(); } print f(1);"));
+        assert_eq!(
+            "1\n2\n",
+            run("fun f() { print callDepth(); } print callDepth(); f();")
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn cached_globals_see_redefinitions() {
+        let stdout = SharedBuffer::default();
+        let mut vm = VM::default();
+        vm.set_stdout(Box::new(stdout.clone()));
+        vm.interpret("var a = 1; fun f() { return a; } print f();")
+            .unwrap();
+        vm.interpret("var a = 2; print f();").unwrap();
+        vm.set_global("a", 3.0);
+        vm.interpret("print f(); a = 4; print f();").unwrap();
+
+        // The same chunk, and so the same cache, every time:
+        let chunk = vm.compile("print a;").unwrap();
+        vm.interpret_chunk(&chunk).unwrap();
+        vm.interpret("var a = \"five\";").unwrap();
+        vm.interpret_chunk(&chunk).unwrap();
+
+        drop(vm);
+        assert_eq!("1\n2\n3\n4\n4\nfive\n", stdout.into_string());
+    }
+
+    #[test]
+    #[serial]
+    fn globals_can_be_set_and_read_by_the_embedder() {
+        let mut vm = VM::default();
+        vm.set_global("limit", 3.0);
+        vm.set_global("verbose", false);
+        vm.set_global("prefix", Some("item "));
+        vm.set_global("missing", None::<f64>);
+        vm.interpret(
+            "var items = [prefix, prefix];
+             var quiet = missing == nil and !verbose;
+             var total = limit * 2;",
+        )
+        .unwrap();
+
+        assert_eq!(Ok(6.0), vm.get_global("total").unwrap().get::<f64>());
+        assert_eq!(Ok(true), vm.get_global("quiet").unwrap().get::<bool>());
+        assert_eq!(
+            "[item , item ]",
+            vm.get_global("items").unwrap().to_string()
+        );
+        assert!(vm.get_global("items").unwrap().get::<f64>().is_err());
+        assert_eq!(
+            Ok(String::from("item ")),
+            vm.get_global("prefix").unwrap().get()
+        );
+        assert_eq!(Ok(None::<f64>), vm.get_global("missing").unwrap().get());
+
+        // Globals can be changed between runs:
+        vm.set_global("limit", 1.0);
+        vm.interpret("var doubled = limit * 2;").unwrap();
+        assert_eq!("2", vm.get_global("doubled").unwrap().to_string());
+    }
+
+    /// Returns a new, empty directory for the test, containing the given files.
+    fn module_dir(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rlox-{}-{test}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, contents) in files {
+            let path = dir.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+        dir
+    }
+
+    /// Returns the message of the runtime error.
+    fn runtime_error_message(result: crate::Result<()>) -> String {
+        match result {
+            Err(InterpretationError::RuntimeError(info)) => info.message,
+            _ => panic!("expected a runtime error, got {result:?}"),
+        }
+    }
+
+    #[test]
+    #[serial]
+    #[cfg_attr(
+        feature = "integers",
+        ignore = "number literals are integers with the integers feature"
+    )]
+    fn modules_are_imported_once() {
+        let dir = module_dir(
+            "import-once",
+            &[
+                ("point.lox", "var created = created + 1; class Point {}"),
+                ("shapes.lox", "import \"point.lox\"; var shapes = 1;"),
+            ],
+        );
+        let mut vm = VM::default();
+        vm.set_import_dir(&dir);
+        vm.set_global("created", 0.0);
+        vm.interpret("import \"shapes.lox\"; import \"point.lox\";")
+            .unwrap();
+        vm.interpret("import \"point.lox\"; var p = Point();")
+            .unwrap();
+
+        assert_eq!(Ok(1.0), vm.get_global("created").unwrap().get::<f64>());
+        assert_eq!(Ok(1.0), vm.get_global("shapes").unwrap().get::<f64>());
+        // Modules survive garbage collection, so they are not imported again:
+        // SAFETY: the natives that every VM has never keep a value.
+        unsafe { vm.collect_garbage() };
+        vm.interpret("import \"point.lox\";").unwrap();
+        assert_eq!(Ok(1.0), vm.get_global("created").unwrap().get::<f64>());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn modules_import_relative_to_themselves() {
+        let dir = module_dir(
+            "import-relative",
+            &[
+                ("d.lox", "var which = \"top\";"),
+                ("sub/c.lox", "import \"d.lox\";"),
+                ("sub/d.lox", "var which = \"sub\";"),
+            ],
+        );
+        let mut vm = VM::default();
+        vm.set_import_dir(&dir);
+        vm.interpret("import \"sub/c.lox\";").unwrap();
+        assert_eq!("sub", vm.get_global("which").unwrap().to_string());
+
+        // The script's own imports are still relative to the import directory:
+        vm.interpret("import \"d.lox\";").unwrap();
+        assert_eq!("top", vm.get_global("which").unwrap().to_string());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn import_errors() {
+        let dir = module_dir(
+            "import-errors",
+            &[
+                ("a.lox", "import \"b.lox\";"),
+                ("b.lox", "import \"a.lox\";"),
+                ("bad.lox", "var = 1;"),
+                ("flaky.lox", "runs = runs + 1; ok or -nil;"),
+            ],
+        );
+        let stderr = SharedBuffer::default();
+        let mut vm = VM::default();
+        vm.set_stderr(Box::new(stderr.clone()));
+        vm.set_import_dir(&dir);
+
+        assert_eq!(
+            "import cycle: a.lox -> b.lox -> a.lox",
+            runtime_error_message(vm.interpret("import \"a.lox\";"))
+        );
+        assert!(
+            runtime_error_message(vm.interpret("import \"missing.lox\";"))
+                .starts_with("could not import \"missing.lox\": ")
+        );
+        assert_eq!(
+            "could not compile \"bad.lox\"",
+            runtime_error_message(vm.interpret("import \"bad.lox\";"))
+        );
+        assert!(stderr
+            .take_string()
+            .contains("[line 1] Error at '=': need a variable name after var"));
+
+        // A module that fails can be imported again:
+        vm.set_global("runs", 0.0);
+        vm.set_global("ok", false);
+        assert!(vm.interpret("import \"flaky.lox\";").is_err());
+        vm.set_global("ok", true);
+        vm.interpret("import \"flaky.lox\"; import \"flaky.lox\";")
+            .unwrap();
+        assert_eq!(Ok(2.0), vm.get_global("runs").unwrap().get::<f64>());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn hosts_can_resolve_modules() {
+        /// Serves modules from memory, ignoring the case of their names.
+        struct CaseInsensitive(HashMap<String, String>);
+
+        impl ModuleResolver for CaseInsensitive {
+            fn resolve(&self, name: &str) -> std::io::Result<String> {
+                self.0.resolve(&name.to_lowercase())
+            }
+
+            fn canonical_name(&self, name: &str) -> std::io::Result<String> {
+                Ok(name.to_lowercase())
+            }
+        }
+
+        let mut vm = VM::default();
+        vm.set_module_resolver(Box::new(CaseInsensitive(HashMap::from([(
+            "counter".to_owned(),
+            "runs = runs + 1;".to_owned(),
+        )]))));
+        vm.set_global("runs", 0.0);
+        vm.interpret("import \"counter\"; import \"COUNTER\"; import \"Counter\";")
+            .unwrap();
+        assert_eq!(Ok(1.0), vm.get_global("runs").unwrap().get::<f64>());
+        assert_eq!(
+            "could not import \"timer\": no module named timer",
+            runtime_error_message(vm.interpret("import \"timer\";"))
+        );
+    }
+
+    #[test]
+    #[serial]
+    #[cfg_attr(
+        feature = "integers",
+        ignore = "number literals are integers with the integers feature"
+    )]
+    fn the_embedder_can_call_script_functions() {
+        let stderr = SharedBuffer::default();
+        let mut vm = VM::default();
+        vm.set_stderr(Box::new(stderr.clone()));
+        vm.define_typed_native("twice", |n: f64| n * 2.0);
+        vm.interpret(
+            "var calls = 0;
+             fun greet(name) { calls = calls + 1; return \"hello, \" + name; }
+             fun fail() { return -nil; }",
+        )
+        .unwrap();
+
+        for _ in 0..3 {
+            let greeting = vm.call("greet", ("world",)).unwrap();
+            assert_eq!(Ok("hello, world"), greeting.get::<&str>());
+        }
+        assert_eq!(Ok(3.0), vm.get_global("calls").unwrap().get::<f64>());
+        assert_eq!(Ok(8.0), vm.call("twice", [4.0]).unwrap().get::<f64>());
+
+        let error_message = |result: crate::Result<ValueRef>| match result {
+            Err(InterpretationError::RuntimeError(info)) => info.message,
+            _ => panic!("expected a runtime error"),
+        };
+        assert_eq!(
+            "Expected 1 arguments but got 0.",
+            error_message(vm.call("greet", ()))
+        );
+        assert_eq!(
+            "undefined global variable: greeting",
+            error_message(vm.call("greeting", ()))
+        );
+        assert_eq!(
+            "Operand must be a number",
+            error_message(vm.call("fail", ()))
+        );
+        assert_eq!(
+            "Can't have more than 255 arguments",
+            error_message(vm.call("twice", [Value::Nil; 256]))
+        );
+
+        // The VM can still be used after an error:
+        assert!(vm.call("greet", ("world",)).is_ok());
+    }
+
+    #[test]
+    fn independent_vms() {
+        // Several VMs on the same thread:
+        let (first, second) = (SharedBuffer::default(), SharedBuffer::default());
+        let mut vm1 = VM::default();
+        let mut vm2 = VM::default();
+        vm1.set_stdout(Box::new(first.clone()));
+        vm2.set_stdout(Box::new(second.clone()));
+        vm1.interpret("var name = \"one\";").unwrap();
+        vm2.interpret("var name = \"two\";").unwrap();
+        vm1.interpret("print name;").unwrap();
+        vm2.interpret("print name;").unwrap();
+        drop((vm1, vm2));
+        assert_eq!(
+            ("one\n", "two\n"),
+            (&*first.into_string(), &*second.into_string())
+        );
+
+        // ...and on several threads at once:
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let source =
+                    format!("var n = 0; for (var j = 0; j < 100; j = j + 1) n = n + {i}; print n;");
+                std::thread::spawn(move || run(&source))
+            })
+            .collect();
+        for (i, thread) in threads.into_iter().enumerate() {
+            assert_eq!(format!("{}\n", 100 * i), thread.join().unwrap());
+        }
+    }
+
+    #[test]
+    fn garbage_is_collected() {
+        let mut vm = VM::default();
+        let stdout = SharedBuffer::default();
+        vm.set_stdout(Box::new(stdout.clone()));
+
+        // Allocates several megabytes of strings, but only the last one is reachable:
+        let source = "
+            var s = \"\";
+            for (var i = 0; i < 2000; i = i + 1) s = s + \"abcdefgh\";
+            print s == s + \"\";
+        ";
+        vm.interpret(source).unwrap();
+        assert_eq!(0, vm.statistics().collections);
+
+        // SAFETY: the natives that every VM has never keep a value.
+        unsafe { vm.enable_garbage_collection() };
+        vm.interpret(source).unwrap();
+        assert!(vm.statistics().collections > 0);
+        drop(vm);
+        assert_eq!("true\ntrue\n", stdout.into_string());
+    }
+
+    #[test]
+    #[serial]
+    #[cfg(feature = "io")]
+    fn clock_measures_time() {
+        let source = "var start = clock(); sleep(20); print clock() - start >= 0.02;";
+        assert_eq!("true\n", run(source));
+    }
+
+    #[test]
+    #[serial]
+    fn gcstats_describes_the_heap() {
+        let source = "
+            var before = gcstats();
+            var list = [1, 2];
+            var after = gcstats();
+            print after[\"lists\"] - before[\"lists\"];
+            print after[\"maps\"] - before[\"maps\"];
+            print after[\"bytesAllocated\"] > before[\"bytesAllocated\"];
+            print after[\"collections\"] >= before[\"collections\"];
+        ";
+        // The list, and the map returned by the first gcstats():
+        assert_eq!("1\n1\ntrue\ntrue\n", run(source));
+    }
+
+    #[test]
+    #[serial]
+    fn scripts_can_collect_garbage() {
+        let source = "
+            var before = gcstats();
+            [1, 2, 3];
+            collect_garbage();
+            var after = gcstats();
+            print after[\"collections\"] - before[\"collections\"] >= 1;
+            print after[\"lists\"];
+        ";
+        assert_eq!("true\n0\n", run(source));
+    }
+
+    #[test]
+    #[serial]
+    fn compiled_chunks_can_be_run_again() {
+        let stdout = SharedBuffer::default();
+        let mut vm = VM::default();
+        vm.set_stdout(Box::new(stdout.clone()));
+
+        let chunk = vm
+            .compile("fun greet() { return \"hello\"; } print greet();")
+            .unwrap();
+        vm.interpret_chunk(&chunk).unwrap();
+        // Nothing refers to the chunk's constants, but they must not be collected:
+        vm.interpret("greet = nil;").unwrap();
+        // SAFETY: the natives that every VM has never keep a value.
+        unsafe { vm.collect_garbage() };
+        vm.interpret_chunk(&chunk).unwrap();
+
+        assert!(vm.compile("print;").is_err());
+        drop(vm);
+        assert_eq!("hello\nhello\n", stdout.into_string());
+    }
+
+    #[test]
+    #[serial]
+    #[cfg_attr(
+        feature = "integers",
+        ignore = "number literals are integers with the integers feature"
+    )]
+    fn execution_can_be_traced() {
+        let trace = SharedBuffer::default();
+        let mut vm = VM::default();
+        vm.set_stdout(Box::new(SharedBuffer::default()));
+        vm.set_trace_writer(Box::new(trace.clone()));
+        vm.interpret("print 1;").unwrap();
+
+        let expected = r#"        <empty>
+0000    1      OP_CONSTANT    0 'Number(1.0)'
+        [ Number(1.0) ]
+0002    |         OP_PRINT
+        <empty>
+0003    |        OP_RETURN
+"#;
+        drop(vm);
+        assert_eq!(expected, trace.into_string());
+    }
+
+    #[test]
+    #[serial]
+    #[cfg_attr(
+        feature = "integers",
+        ignore = "number literals are integers with the integers feature"
+    )]
+    fn debugger_stops_at_breakpoints_in_functions() {
+        let stdout = SharedBuffer::default();
+        let mut vm = VM::default();
+        vm.set_stdout(Box::new(stdout.clone()));
+        let source = "fun add(a, b) {\n  return a + b;\n}\nvar sum = add(1, 2);\nprint sum;";
+        let chunk = vm.compile(source).unwrap();
+
+        let mut debugger = vm.debug(&chunk);
+        debugger.add_breakpoint(2);
+        assert_eq!(Some(0), debugger.ip());
+        assert_eq!(Stopped::Breakpoint { line: 2 }, debugger.resume().unwrap());
+        // The callee, then its arguments:
+        let stack: Vec<_> = debugger.stack().into_iter().map(ValueRef::value).collect();
+        assert!(matches!(stack[0], Value::Function(f) if f.name == "add"));
+        assert_eq!(&[Value::Number(1.0), Value::Number(2.0)], &stack[1..]);
+        assert_eq!(None, debugger.global("sum"));
+
+        assert!(debugger.remove_breakpoint(2));
+        assert_eq!(Stopped::Finished, debugger.resume().unwrap());
+        assert!(debugger.is_finished());
+        assert_eq!(None, debugger.ip());
+        assert_eq!(Ok(3.0), debugger.global("sum").unwrap().get::<f64>());
+
+        drop(vm);
+        assert_eq!("3\n", stdout.into_string());
+    }
+
+    #[test]
+    #[serial]
+    fn debugger_steps_one_instruction_at_a_time() {
+        let mut vm = VM::default();
+        let chunk = vm.compile("var a = 1;\nwhile (a < 3) a = a + 1;").unwrap();
+        vm.interpret_chunk(&chunk).unwrap();
+        let expected = vm.statistics().instructions;
+
+        let mut debugger = vm.debug(&chunk);
+        // The last step returns from the script:
+        let mut steps = 1;
+        while debugger.step().unwrap() == Stopped::Step {
+            steps += 1;
+        }
+        assert_eq!(expected, steps);
+        assert_eq!(Stopped::Finished, debugger.step().unwrap());
+    }
+
+    #[test]
+    #[serial]
+    fn debugger_finishes_after_runtime_errors() {
+        let mut vm = VM::default();
+        vm.set_stderr(Box::new(SharedBuffer::default()));
+        let chunk = vm.compile("var a = 1;\nprint a + nil;").unwrap();
+
+        let mut debugger = vm.debug(&chunk);
+        debugger.add_breakpoint(2);
+        assert_eq!(Stopped::Breakpoint { line: 2 }, debugger.resume().unwrap());
+        assert!(debugger.resume().is_err());
+        assert!(debugger.is_finished());
+        assert_eq!(Stopped::Finished, debugger.step().unwrap());
+    }
+
+    #[test]
+    fn repl_prints_expression_statements() {
+        let stdout = SharedBuffer::default();
+        let mut vm = VM::default();
+        vm.set_stdout(Box::new(stdout.clone()));
+
+        vm.interpret_repl("var a = 1;").unwrap();
+        vm.interpret_repl("a + 2;").unwrap();
+        vm.interpret_repl("a = \"x\"; print a;").unwrap();
+        // Only top-level expression statements are printed:
+        vm.interpret_repl("{ 3; } fun f() { 4; } f();").unwrap();
+        // ...and scripts are unaffected:
+        vm.interpret("5;").unwrap();
+        drop(vm);
+
+        assert_eq!("3\nx\nx\nnil\n", stdout.into_string());
+    }
+
+    #[test]
+    fn increment_and_decrement() {
+        let source = "var i = 1; print i++; print i; print ++i; print i--; print --i; print i;";
+        assert_eq!("1\n2\n3\n3\n1\n1\n", run(source));
+
+        // Locals, and in larger expressions:
+        let source = "{ var a = 5; var b = a++ * 2; print b - --a; print a; }";
+        assert_eq!("5\n5\n", run(source));
+
+        let source = "for (var i = 0; i < 3; i++) print i;";
+        assert_eq!("0\n1\n2\n", run(source));
+    }
+
+    #[test]
+    #[serial]
+    fn logical_operators() {
+        assert_eq!("false\n2\n", run("print false and 1; print true and 2;"));
+        assert_eq!("1\ntrue\n", run("print 1 or false; print nil or true;"));
+        assert_eq!("nil\n", run("print nil and nil or nil;"));
+        // The right operand is NOT evaluated when the left operand decides the result:
+        let source = "var a = 0; false and (a = 1); true or (a = 2); print a;";
+        assert_eq!("0\n", run(source));
+        let source = "var a = 0; true and (a = 1); false or (a = a + 2); print a;";
+        assert_eq!("3\n", run(source));
+    }
+
+    #[test]
+    #[serial]
+    fn for_loops() {
+        let source = "for (var i = 0; i < 3; i = i + 1) print i;";
+        assert_eq!("0\n1\n2\n", run(source));
+
+        // The loop variable is scoped to the loop:
+        let source = "var i = \"global\"; for (var i = 0; i < 1; i = i + 1) {} print i;";
+        assert_eq!("global\n", run(source));
+
+        // Each clause is optional:
+        let source = "var i = 0; for (; i < 2; i = i + 1) print i;";
+        assert_eq!("0\n1\n", run(source));
+        let source = "for (var i = 0; i < 2;) { print i; i = i + 1; }";
+        assert_eq!("0\n1\n", run(source));
+        let source = "var i = 0; for (;i < 2;) { print i; i = i + 1; }";
+        assert_eq!("0\n1\n", run(source));
+    }
+
+    #[test]
+    #[serial]
+    fn while_loops() {
+        let source = "var i = 0; while (i < 3) { print i; i = i + 1; }";
+        assert_eq!("0\n1\n2\n", run(source));
+        assert_eq!("", run("while (false) print 1;"));
+    }
+
+    #[test]
+    #[serial]
+    fn statements_run_as_they_are_read() {
+        let source = "\
+var a = 1;
+fun f(x) {
+  return x
+    + a;
+}
+print f(1)
+  + 1; /* a comment
+  across lines */ print \"a string
+across lines\";
+print [1,
+  2];
+print a + nil;
+print \"never\";
+";
+        let stdout = SharedBuffer::default();
+        let mut vm = VM::default();
+        vm.set_stdout(Box::new(stdout.clone()));
+        vm.set_stderr(Box::new(SharedBuffer::default()));
+        let error = vm.interpret_reader(source.as_bytes()).unwrap_err();
+        drop(vm);
+
+        assert_eq!("3\na string\nacross lines\n[1, 2]\n", stdout.into_string());
+        let InterpretationError::RuntimeError(info) = error else {
+            panic!("expected a runtime error, got {error:?}");
+        };
+        assert_eq!(12, info.line);
+    }
+
+    #[test]
+    #[serial]
+    fn functions_can_have_more_than_256_locals() {
+        let mut source = String::from("fun f() {\n");
+        for i in 0..300 {
+            // Few enough distinct constants to fit in the constant pool:
+            source += &format!("  var v{i} = {};\n", i % 10);
+        }
+        // The function itself is in slot 0, so v255 is the first local in a long slot:
+        source +=
+            "  v299 = v299 + v0 + 1;\n  print v299;\n  print v254 + v255++;\n  print v255;\n}\n";
+        source += "f();\n";
+
+        assert_eq!("10\n9\n6\n", run(&source));
+    }
+
+    #[test]
+    #[serial]
+    fn stack_overflows_are_runtime_errors() {
+        let stdout = SharedBuffer::default();
+        let mut vm = VM::default();
+        vm.set_stdout(Box::new(stdout.clone()));
+        vm.set_stderr(Box::new(SharedBuffer::default()));
+        vm.set_limits(Limits {
+            max_frames: 3,
+            max_stack: 5,
+        });
+
+        // The script, f(), and g() fit in 3 frames; one more call does not:
+        let source = "fun g() { return 1; } fun f() { return g(); } print f();";
+        assert!(vm.interpret(source).is_ok());
+        let source = "fun h() { return 1; } fun g() { return h(); } fun f() { return g(); } f();";
+        let InterpretationError::RuntimeError(info) = vm.interpret(source).unwrap_err() else {
+            panic!("expected a runtime error");
+        };
+        assert_eq!("Stack overflow.", info.message);
+        assert_eq!(3, info.backtrace.len());
+
+        // Too many values on the stack, once the arguments are pushed:
+        let source = "fun f(a, b, c, d, e) { return a; } print f(1, 2, 3, 4, 5);";
+        assert!(vm.interpret(source).is_err());
+
+        drop(vm);
+        assert_eq!("1\n", stdout.into_string());
+    }
+
+    #[test]
+    #[serial]
+    fn fuel_is_used_up_across_runs() {
+        let mut vm = VM::default();
+        // Constant, AddConstant, DefineGlobal, Return:
+        vm.set_fuel(8);
+        vm.interpret("var a = 1 + 2;").unwrap();
+        assert_eq!(Some(4), vm.fuel());
+        vm.interpret("var b = 1 + 2;").unwrap();
+        assert_eq!(Some(0), vm.fuel());
+
+        let result = vm.interpret("var c = 1;");
+        assert!(matches!(result, Err(InterpretationError::OutOfFuel)));
+        assert_eq!(0, vm.statistics().instructions);
+
+        // Refuel:
+        vm.set_fuel(3);
+        vm.interpret("var c = 1;").unwrap();
+        assert_eq!(Some(0), vm.fuel());
+    }
+
+    #[test]
+    #[serial]
+    fn profiling_counts_every_instruction_across_runs() {
+        let mut vm = VM::default();
+        vm.interpret("var a = 1;").unwrap();
+        assert_eq!(None, vm.profile_report());
+
+        vm.enable_profiling();
+        let source = "fun f(n) {\n  return n + 1;\n}\nf(1);\nf(2);";
+        vm.interpret(source).unwrap();
+        let instructions = vm.statistics().instructions;
+        vm.interpret(source).unwrap();
+
+        let profile = vm.profile_report().unwrap();
+        assert_eq!(2 * instructions, profile.instructions());
+        assert_eq!(profile.instructions(), profile.lines.values().sum::<u64>());
+        assert_eq!(Some(&4), profile.opcodes.get(&OpCode::AddConstant));
+        // GetLocal, AddConstant, Return, in both calls, in both runs:
+        assert_eq!(Some(&12), profile.lines.get(&2));
+
+        vm.enable_profiling();
+        assert_eq!(Some(&Profile::default()), vm.profile_report());
+    }
+
+    #[test]
+    #[serial]
+    fn profiling_counts_instructions_by_call_stack() {
+        let mut vm = VM::default();
+        vm.enable_profiling();
+        let source = "fun countdown(n) {\n  return n > 0 and countdown(n - 1);\n}\ncountdown(2);";
+        vm.interpret(source).unwrap();
+        let profile = vm.profile_report().unwrap();
+
+        let mut folded = Vec::new();
+        profile.write_folded(&mut folded).unwrap();
+        assert_eq!(
+            "script 7\n\
+             script;countdown 11\n\
+             script;countdown;countdown 11\n\
+             script;countdown;countdown;countdown 5\n",
+            String::from_utf8(folded).unwrap()
+        );
+
+        let functions: Vec<_> = (profile.functions().into_iter())
+            .map(|f| (f.name, f.exclusive_instructions, f.inclusive_instructions))
+            .collect();
+        assert_eq!(
+            vec![
+                ("script".to_owned(), 7, 34),
+                ("countdown".to_owned(), 27, 27)
+            ],
+            functions
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn interrupts_stop_the_code_once() {
+        let mut vm = VM::default();
+        vm.set_stdout(Box::new(SharedBuffer::default()));
+        vm.interrupt_handle().store(true, Ordering::Relaxed);
+
+        // Straight-line code never checks:
+        vm.interpret("print 1;").unwrap();
+        let result = vm.interpret("fun f() {} f();");
+        assert!(matches!(result, Err(InterpretationError::Interrupted)));
+
+        // The interrupt has been handled:
+        vm.interpret("fun f() {} f();").unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn numbers_are_only_added_to_strings_when_allowed() {
+        let mut vm = VM::default();
+        vm.set_stderr(Box::new(io::sink()));
+        let message = runtime_error_message(vm.interpret("\"count: \" + 3;"));
+        assert_eq!("Can only add numbers or strings", message);
+
+        vm.set_implicit_string_conversion(true);
+        vm.interpret(r#"var a = "count: " + 3; var b = 1.5 + "x"; var c = 1 + 2;"#)
+            .unwrap();
+        assert_eq!(Ok("count: 3"), vm.get_global("a").unwrap().get::<&str>());
+        assert_eq!(Ok("1.5x"), vm.get_global("b").unwrap().get::<&str>());
+        assert_eq!(Ok(3.0), vm.get_global("c").unwrap().get::<f64>());
+        let message = runtime_error_message(vm.interpret("\"yes: \" + true;"));
+        assert_eq!("Can only add numbers or strings", message);
+    }
+
+    #[test]
+    #[serial]
+    #[cfg(feature = "integers")]
+    fn integer_arithmetic() {
+        let source = "print 7 / 2; print -7 / 2; print 7 / 2.0; print 2 * 3 - 1; print 1 + 0.5;
+                      print 1 == 1.0; print 2 < 2.5; var m = {1: \"one\"}; print m[1.0];";
+        assert_eq!("3\n-3\n3.5\n5\n1.5\ntrue\ntrue\none\n", run(source));
+
+        let mut vm = VM::default();
+        vm.set_stderr(Box::new(io::sink()));
+        vm.interpret("var big = 9223372036854775807; var x = 6 * 7;")
+            .unwrap();
+        assert_eq!(Ok(42), vm.get_global("x").unwrap().get::<i64>());
+        assert!(matches!(vm.global("x"), Some(Value::Integer(42))));
+        for (source, message) in [
+            ("1 / 0;", "Division by zero."),
+            ("big + 1;", "Integer overflow."),
+            ("-big - 2;", "Integer overflow."),
+        ] {
+            assert_eq!(message, runtime_error_message(vm.interpret(source)));
+        }
+        assert!(matches!(
+            vm.interpret("print 9223372036854775808;"),
+            Err(InterpretationError::CompileError(_))
+        ));
+    }
+
+    #[test]
+    #[serial]
+    fn long_strings_are_concatenated_as_ropes() {
+        let mut vm = VM::default();
+        // SAFETY: the natives that every VM has never keep a value.
+        unsafe { vm.enable_garbage_collection() };
+        vm.interpret(
+            r#"var s = "";
+               for (var i = 0; i < 1000; i = i + 1) { s = s + "abc"; }
+               var short = "abc" + "def";"#,
+        )
+        .unwrap();
+
+        let Some(Value::Rope(rope)) = vm.global("s") else {
+            panic!("long strings should be ropes");
+        };
+        assert_eq!(3_000, rope.len());
+        assert!(!rope.is_flat());
+        assert!(matches!(
+            vm.global("short"),
+            Some(Value::LoxString("abcdef"))
+        ));
+
+        // Ropes are strings, as far as Lox can tell:
+        vm.interpret(
+            r#"var map = {};
+               map[s] = 1;
+               var t = "";
+               for (var i = 0; i < 1000; i = i + 1) { t = t + "abc"; }
+               var equal = s == t and map[t] == 1;
+               s = nil;
+               collect_garbage();"#,
+        )
+        .unwrap();
+        assert_eq!(Ok(true), vm.get_global("equal").unwrap().get::<bool>());
+        let t = vm.get_global("t").unwrap();
+        assert_eq!("abc".repeat(1_000), t.get::<&str>().unwrap());
+    }
+
+    #[test]
+    #[serial]
+    fn print_reports_errors_writing_to_stdout() {
+        /// A writer whose reader has gone away.
+        struct ClosedPipe;
+
+        impl Write for ClosedPipe {
+            fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::BrokenPipe.into())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        for target in [compiler::Target::Stack, compiler::Target::Register] {
+            let mut vm = VM::default();
+            vm.set_stdout(Box::new(ClosedPipe));
+            vm.set_compile_options(CompileOptions {
+                target,
+                ..CompileOptions::default()
+            });
+            let Err(InterpretationError::WriteError(error)) = vm.interpret("print 1;") else {
+                panic!("expected a write error");
+            };
+            assert_eq!(io::ErrorKind::BrokenPipe, error.kind());
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn exit_stops_the_code_without_an_error_message() {
+        let stdout = SharedBuffer::default();
+        let stderr = SharedBuffer::default();
+        let mut vm = VM::default();
+        vm.set_stdout(Box::new(stdout.clone()));
+        vm.set_stderr(Box::new(stderr.clone()));
+
+        let result = vm.interpret("print 1; fun f() { exit(3); } f(); print 2;");
+        assert!(matches!(result, Err(InterpretationError::Exit(3))));
+        assert_eq!("1\n", stdout.take_string());
+        assert_eq!("", stderr.take_string());
+
+        // The request has been handled, and other errors are still errors:
+        assert!(vm
+            .interpret("-nil;")
+            .is_err_and(|error| error.to_string().contains("Operand")));
+        assert!(vm
+            .interpret("exit(0.5);")
+            .is_err_and(|error| error.to_string().contains("integer")));
+        assert!(matches!(
+            vm.interpret("exit(0);"),
+            Err(InterpretationError::Exit(0))
+        ));
+    }
+
+    #[test]
+    #[serial]
+    fn invalid_bytecode_is_an_error() {
+        fn run_invalid(build: impl FnOnce(&mut Chunk)) -> (usize, &'static str) {
+            let mut chunk = Chunk::new();
+            build(&mut chunk);
+            match VM::default().interpret_chunk(&chunk) {
+                Err(InterpretationError::InvalidBytecode { offset, reason }) => (offset, reason),
+                result => panic!("expected invalid bytecode, got {result:?}"),
+            }
+        }
+
+        let (offset, reason) = run_invalid(|chunk| {
+            // Nil does not have an operand, so this is an opcode:
+            chunk.write_opcode(OpCode::Nil, 1).with_operand(255);
+        });
+        assert_eq!((1, "unknown opcode"), (offset, reason));
+
+        let (offset, reason) = run_invalid(|chunk| {
+            chunk.write_opcode(OpCode::Pop, 1);
+        });
+        assert_eq!((0, "popped from an empty stack"), (offset, reason));
+
+        let (offset, reason) = run_invalid(|chunk| {
+            chunk.write_opcode(OpCode::Nil, 1);
+        });
+        assert_eq!((1, "ran past the end of the chunk"), (offset, reason));
+
+        let (offset, reason) = run_invalid(|chunk| {
+            chunk.write_opcode(OpCode::Constant, 1).with_operand(0);
+        });
+        assert_eq!((1, "there is no constant at this index"), (offset, reason));
+
+        let (offset, reason) = run_invalid(|chunk| {
+            let index = chunk.add_constant(1.0.into()).unwrap();
+            chunk.write_opcode(OpCode::GetGlobal, 1).with_operand(index);
+        });
+        assert_eq!((1, "the constant must be a string"), (offset, reason));
+
+        let (offset, reason) = run_invalid(|chunk| {
+            chunk.write_opcode(OpCode::GetLocal, 1).with_operand(3);
+        });
+        assert_eq!((1, "no local variable in this slot"), (offset, reason));
+    }
+
+    //////////////////////////////////////// Property tests ///////////////////////////////////////
+
+    use proptest::prelude::*;
+
+    /// Pieces of Lox that can be shuffled into programs that mostly parse.
+    const TOKENS: &[&str] = &[
+        "(",
+        ")",
+        "{",
+        "}",
+        "[",
+        "]",
+        ",",
+        ".",
+        "-",
+        "+",
+        ";",
+        "/",
+        "*",
+        "!",
+        "!=",
+        "=",
+        "==",
+        ">",
+        ">=",
+        "<",
+        "<=",
+        ":",
+        "a",
+        "b",
+        "f",
+        "\"s\"",
+        "0",
+        "1",
+        "2.5",
+        "and",
+        "class",
+        "else",
+        "false",
+        "for",
+        "fun",
+        "if",
+        "nil",
+        "or",
+        "print",
+        "return",
+        "super",
+        "this",
+        "true",
+        "var",
+        "while",
+        "// comment\n",
+        "/* comment */",
+    ];
+
+    /// Returns a VM that cannot print or wait, and gives up after a while.
+    fn sandboxed_vm() -> VM {
+        let mut vm = VM::without_natives();
+        vm.set_stdout(Box::new(io::sink()));
+        vm.set_stderr(Box::new(io::sink()));
+        vm.set_fuel(10_000);
+        vm
+    }
+
+    /// Serializes a chunk with the given code, and a few constants for it to use.
+    fn bytecode_file(code: &[u8]) -> Vec<u8> {
+        use crate::chunk::{BYTECODE_MAGIC, BYTECODE_VERSION};
+
+        let mut bytes = BYTECODE_MAGIC.to_vec();
+        bytes.push(BYTECODE_VERSION);
+        bytes.extend((code.len() as u32).to_le_bytes());
+        bytes.extend(code);
+        // All of the code is on line 1:
+        let runs: &[u32] = if code.is_empty() {
+            &[]
+        } else {
+            &[1, code.len() as u32]
+        };
+        bytes.extend((runs.len() as u32 / 2).to_le_bytes());
+        runs.iter().for_each(|n| bytes.extend(n.to_le_bytes()));
+        // nil, 1.5, "a":
+        bytes.extend(3u32.to_le_bytes());
+        bytes.push(0);
+        bytes.push(3);
+        bytes.extend(1.5f64.to_le_bytes());
+        bytes.push(4);
+        bytes.extend(1u32.to_le_bytes());
+        bytes.push(b'a');
+        bytes
+    }
+
+    proptest! {
+        #[test]
+        #[serial]
+        fn arbitrary_text_does_not_panic(source in "\\PC{0,64}") {
+            let _ = sandboxed_vm().interpret(&source);
+        }
+
+        #[test]
+        #[serial]
+        fn arbitrary_tokens_do_not_panic(
+            tokens in prop::collection::vec(prop::sample::select(TOKENS), 0..48)
+        ) {
+            let _ = sandboxed_vm().interpret(&tokens.join(" "));
+        }
+
+        #[test]
+        #[serial]
+        fn arbitrary_bytecode_files_do_not_panic(
+            bytes in prop::collection::vec(any::<u8>(), 0..64)
+        ) {
+            let mut file = crate::chunk::BYTECODE_MAGIC.to_vec();
+            file.push(crate::chunk::BYTECODE_VERSION);
+            file.extend(bytes);
+            let _ = sandboxed_vm().interpret_bytecode(&file);
+        }
+
+        #[test]
+        #[serial]
+        fn arbitrary_code_does_not_panic(code in prop::collection::vec(any::<u8>(), 0..64)) {
+            let result = sandboxed_vm().interpret_bytecode(&bytecode_file(&code));
+            prop_assert!(!matches!(result, Err(InterpretationError::InvalidBytecodeFile(_))));
+        }
     }
 }
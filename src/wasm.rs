@@ -0,0 +1,211 @@
+//! Bindings for running Lox in the browser, using [wasm-bindgen].
+//!
+//! Build with:
+//!
+//! ```text
+//! cargo build --lib --target wasm32-unknown-unknown --no-default-features --features wasm
+//! wasm-bindgen --target web target/wasm32-unknown-unknown/debug/rlox.wasm --out-dir pkg
+//! ```
+//!
+//! Then, from JavaScript:
+//!
+//! ```text
+//! import init, { interpret } from "./pkg/rlox.js";
+//! await init();
+//! const { output, errors } = interpret("print 1 + 2;");
+//! ```
+//!
+//! For a playground that underlines errors in an editor, [run_source()] returns every error as a
+//! [Diagnostic], with its line, column, and span:
+//!
+//! ```text
+//! const { stdout, errors } = runSource("print -nil;");
+//! for (const { line, column, spanStart, spanEnd, message } of errors) { /* ... */ }
+//! ```
+//!
+//! Nothing is shared between runs: each one gets a fresh VM, with its own garbage collector.
+//!
+//! [wasm-bindgen]: https://rustwasm.github.io/docs/wasm-bindgen/
+
+use wasm_bindgen::prelude::*;
+
+use crate::error::{Diagnostic, RuntimeErrorInfo, Severity};
+use crate::prelude::{InterpretationError, VM};
+use crate::vm::SharedBuffer;
+
+/// What happened when some Lox code was interpreted.
+#[wasm_bindgen(getter_with_clone)]
+#[derive(Debug, Clone, Default)]
+pub struct Interpretation {
+    /// Everything the program printed.
+    pub output: String,
+    /// Compile errors and runtime errors, one line per entry.
+    pub errors: Vec<String>,
+}
+
+/// What happened when some Lox code was run by [run_source()].
+#[wasm_bindgen(getter_with_clone)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RunOutcome {
+    /// Everything the program printed.
+    pub stdout: String,
+    /// Every compile error, or the runtime error that stopped the program.
+    pub errors: Vec<Diagnostic>,
+}
+
+#[wasm_bindgen]
+impl Diagnostic {
+    /// The offset of the first byte of the problem in the source code.
+    #[wasm_bindgen(getter, js_name = spanStart)]
+    pub fn span_start(&self) -> usize {
+        self.span.start
+    }
+
+    /// The offset just past the last byte of the problem in the source code.
+    #[wasm_bindgen(getter, js_name = spanEnd)]
+    pub fn span_end(&self) -> usize {
+        self.span.end
+    }
+}
+
+/// Compile and interpret the given Lox source code, in a fresh VM, capturing its output and
+/// errors.
+#[wasm_bindgen]
+pub fn interpret(source: &str) -> Interpretation {
+    let stdout = SharedBuffer::default();
+    let stderr = SharedBuffer::default();
+
+    let mut vm = new_vm(&stdout, Box::new(stderr.clone()));
+    // Errors are reported through stderr:
+    let _ = vm.interpret(source);
+    drop(vm);
+
+    Interpretation {
+        output: stdout.into_string(),
+        errors: stderr.into_string().lines().map(str::to_owned).collect(),
+    }
+}
+
+/// Compile and interpret the given Lox source code, in a fresh VM, returning its output and any
+/// errors as [Diagnostic]s.
+#[wasm_bindgen(js_name = runSource)]
+pub fn run_source(source: &str) -> RunOutcome {
+    let stdout = SharedBuffer::default();
+    let mut vm = new_vm(&stdout, Box::new(std::io::sink()));
+    let errors = match vm.interpret(source) {
+        Ok(()) | Err(InterpretationError::Exit(0)) => Vec::new(),
+        Err(InterpretationError::CompileError(diagnostics)) => diagnostics,
+        Err(InterpretationError::RuntimeError(info)) => vec![runtime_diagnostic(source, info)],
+        Err(error) => vec![runtime_diagnostic(
+            source,
+            RuntimeErrorInfo {
+                message: error.to_string(),
+                line: 1,
+                span: None,
+                backtrace: Vec::new(),
+            },
+        )],
+    };
+    drop(vm);
+
+    RunOutcome {
+        stdout: stdout.into_string(),
+        errors,
+    }
+}
+
+/// Returns a VM with the standard library, that prints to the given buffer and error writer.
+fn new_vm(stdout: &SharedBuffer, stderr: Box<dyn std::io::Write>) -> VM {
+    let mut vm = VM::default();
+    crate::stdlib::install(&mut vm);
+    // SAFETY: the standard library's natives never keep a value.
+    unsafe { vm.enable_garbage_collection() };
+    vm.set_stdout(Box::new(stdout.clone()));
+    vm.set_stderr(stderr);
+    vm
+}
+
+/// Describes a runtime error like a compile error, so that both can be shown the same way. Without
+/// a span, the diagnostic covers the start of the line.
+fn runtime_diagnostic(source: &str, info: RuntimeErrorInfo) -> Diagnostic {
+    let span = info.span.unwrap_or_else(|| {
+        let start = (source.split_inclusive('\n'))
+            .take(info.line.saturating_sub(1))
+            .map(str::len)
+            .sum();
+        start..start
+    });
+    let line_start = source[..span.start]
+        .rfind('\n')
+        .map_or(0, |newline| newline + 1);
+    Diagnostic {
+        line: info.line,
+        column: source[line_start..span.start].chars().count() + 1,
+        lexeme: source[span.clone()].to_owned(),
+        span,
+        location: String::new(),
+        message: info.message,
+        severity: Severity::Error,
+    }
+}
+
+////////////////////////////////////////////// Tests //////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serial_test::serial;
+
+    #[test]
+    #[serial]
+    fn captures_output_and_errors() {
+        let result = interpret("print 1 + 2;\nprint -nil;");
+        assert_eq!("3\n", result.output);
+        assert_eq!(
+            vec!["Operand must be a number", "[line 2] in script"],
+            result.errors
+        );
+
+        let result = interpret("var = 1;");
+        assert_eq!("", result.output);
+        assert_eq!(
+            vec!["[line 1] Error at '=': need a variable name after var"],
+            result.errors
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn runs_are_independent() {
+        let result = run_source("var x = 1; print x;");
+        assert_eq!("1\n", result.stdout);
+        assert!(result.errors.is_empty());
+
+        let result = run_source("print x;");
+        assert_eq!("", result.stdout);
+        assert_eq!(1, result.errors.len());
+        assert_eq!("undefined global variable: x", result.errors[0].message);
+    }
+
+    #[test]
+    #[serial]
+    fn errors_are_diagnostics() {
+        let source = "print 1;\nprint  -nil;";
+        let result = run_source(source);
+        assert_eq!("1\n", result.stdout);
+        let [error] = &result.errors[..] else {
+            panic!("expected one error, got {:?}", result.errors);
+        };
+        assert_eq!((2, 8), (error.line, error.column));
+        assert_eq!("-", &source[error.span.clone()]);
+        assert_eq!(
+            "[line 2] Error: Operand must be a number",
+            error.to_string()
+        );
+
+        let result = run_source("var = 1;\nvar = 2;");
+        let lines: Vec<_> = result.errors.iter().map(|error| error.line).collect();
+        assert_eq!(vec![1, 2], lines);
+        assert_eq!(" at '='", result.errors[0].location);
+    }
+}
@@ -0,0 +1,58 @@
+//! Runs every test script in `tests/lox/` with the `rlox` executable, and checks that each behaves
+//! as its expectation comments say. See [rlox::test_runner] for how tests are written.
+
+use std::path::Path;
+
+use rlox::test_runner::{find_tests, run_test_with_options};
+
+#[test]
+#[cfg_attr(
+    any(feature = "trace_execution", feature = "print_code"),
+    ignore = "debugging output is mixed in with the output of the tests"
+)]
+#[cfg_attr(
+    feature = "integers",
+    ignore = "number literals are integers with the integers feature"
+)]
+fn lox_test_suite() {
+    run_suite(&[]);
+}
+
+#[test]
+#[cfg_attr(
+    any(feature = "trace_execution", feature = "print_code"),
+    ignore = "debugging output is mixed in with the output of the tests"
+)]
+#[cfg_attr(
+    feature = "integers",
+    ignore = "number literals are integers with the integers feature"
+)]
+fn lox_test_suite_on_registers() {
+    run_suite(&["--registers"]);
+}
+
+/// Runs every test with the interpreter, given the options, and fails if any test fails.
+fn run_suite(options: &[&str]) {
+    let interpreter = Path::new(env!("CARGO_BIN_EXE_rlox"));
+    let suite = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lox");
+
+    let tests = find_tests(&suite).expect("should find the test scripts");
+    assert!(!tests.is_empty(), "no tests in {}", suite.display());
+
+    let mut report = String::new();
+    for test in tests {
+        let Some(result) =
+            run_test_with_options(interpreter, options, &test).expect("should run the test")
+        else {
+            continue;
+        };
+        if !result.passed() {
+            report += &format!("FAIL {}\n", result.path.display());
+            for failure in result.failures {
+                report += &format!("     {failure}\n");
+            }
+        }
+    }
+
+    assert!(report.is_empty(), "some Lox tests failed:\n{report}");
+}